@@ -1,28 +1,293 @@
 // src/config.rs
+use std::collections::HashMap;
+use serde::Deserialize;
+use crate::sink::multipart::UploadOptions;
+use crate::sink::rolling::RollingOptions;
+use crate::sink::encoding::{CsvOptions, Encoding, FileFormat};
+use crate::sink::encryption::KeyStore;
+use crate::sink::hadoop_catalog::HadoopCatalog;
+use crate::sink::iceberg::{IcebergCatalog, RestCatalog};
+use crate::sink::parquet_writer::ParquetOptions;
+use crate::sink::sort::{Curve, RowOrder};
+use crate::transform::expr::TransformConfig;
+use crate::transform::pii::ColumnTransform;
+use crate::store::{GcsOptions, Warehouse};
+use crate::retry::RetryPolicy;
+use object_store::RetryConfig;
+
 pub struct Config {
     pub gcp_project: String,
-    pub gcs_bucket: String,              // e.g. "my-lake"
-    pub gcs_prefix: String,              // e.g. "warehouse"
-    pub catalog_uri: String,             // Iceberg REST (BigLake or Nessie)
+    pub warehouse_url: String,           // e.g. "gs://my-lake/warehouse", "file:///var/lib/lake", "memory://"
+    pub gcs_service_account_path: Option<String>, // service-account JSON key file
+    pub gcs_endpoint: Option<String>,    // e.g. "http://localhost:4443" for fake-gcs-server
+    pub gcs_allow_http: bool,            // allow a plain-http endpoint
+    pub catalog_type: String,            // "rest", or "hadoop" for metadata files in the warehouse
+    pub catalog_uri: Option<String>,     // Iceberg REST (BigLake, Nessie or Polaris), e.g. "http://localhost:8181"
+    pub catalog_warehouse: Option<String>, // warehouse the catalog serves tables from
+    pub catalog_token: Option<String>,   // bearer token for the catalog
     pub table_ns: String,                // e.g. "farm"
     pub table_orders: String,            // "orders"
     pub batch_max_rows: usize,           // e.g. 25_000
     pub batch_max_seconds: u64,          // e.g. 30
+    pub pii_hmac_key: Option<String>,    // secret for `hash` column transforms
+    pub upload_part_size_mb: usize,      // e.g. 10
+    pub upload_max_concurrency: usize,   // e.g. 4
+    pub upload_max_retries: usize,       // e.g. 10
+    pub file_target_size_mb: usize,      // e.g. 128
+    pub file_max_age_seconds: u64,       // e.g. 600
+    pub retry_max_attempts: u32,         // e.g. 5
+    pub retry_initial_backoff_ms: u64,   // e.g. 200
+    pub retry_max_backoff_ms: u64,       // e.g. 30_000
+    pub compact_grace_seconds: u64,      // e.g. 3600
+    pub delta_checkpoint_interval: u64,  // e.g. 10
+    pub encryption_keys_path: Option<String>, // JSON file of master keys for encrypted columns
+    pub tables: HashMap<String, TableConfig>, // per-table settings, keyed by collection
   }
-  
+
+/// Per-table settings loaded from the JSON file named by `TABLE_CONFIG_PATH`.
+///
+/// ```json
+/// { "batches": { "columns": { "notes": { "type": "truncate", "max_chars": 32 } } } }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TableConfig {
+    /// Column transforms applied before data is written or logged.
+    pub columns: HashMap<String, ColumnTransform>,
+    /// Derived columns, filters or SQL run over each batch after the column transforms.
+    pub transform: Option<TransformConfig>,
+    /// Parquet writer settings: compression, row groups, pages, dictionaries, statistics, bloom filters.
+    pub parquet: ParquetOptions,
+    /// Partition fields such as `variety_id`, `day(created_at)` or `bucket(16, id)`.
+    /// Without any, files are partitioned by `ingest_date`.
+    pub partition_by: Vec<String>,
+    /// Table format the files are committed to, in addition to the `_committed/` records.
+    pub format: TableFormat,
+    /// Encodings every file is written in, side by side: `parquet`, `ndjson`, `csv` or `ipc`.
+    /// Defaults to Parquet only.
+    pub encodings: Vec<Encoding>,
+    /// CSV writer settings, for tables with the `csv` encoding.
+    pub csv: CsvOptions,
+    /// Sort order applied to each batch before it is written, e.g. `["variety_id", "created_at desc"]`.
+    pub sort_by: Vec<String>,
+    /// Columns `compact` clusters on along `cluster_curve`, instead of sorting by `sort_by`.
+    pub cluster_by: Vec<String>,
+    pub cluster_curve: Curve,
+    /// `upsert` makes each Iceberg commit delete the earlier rows of the documents it writes, and each run
+    /// the rows of documents it no longer found, so readers only see the current version of every document.
+    pub write_mode: WriteMode,
+    /// Iceberg tables with the same group, e.g. `inventory_transactions` and `variety_inventory`, are committed
+    /// in one multi-table transaction once every table in the group has finished its run, so readers see their
+    /// runs together or not at all.
+    pub commit_group: Option<String>,
+    /// Column whose range each Parquet file records as its source update times. Defaults to `_ingest_ts_ms`,
+    /// when the pipeline read the document, since documents don't carry Firestore's update times.
+    pub update_time_column: Option<String>,
+  }
+
+  impl TableConfig {
+    pub fn update_time_column(&self) -> &str {
+      self.update_time_column.as_deref().unwrap_or("_ingest_ts_ms")
+    }
+
+    /// Whether commits replace the earlier rows of the documents they write. Only Iceberg tables can.
+    pub fn upserts(&self) -> anyhow::Result<bool> {
+      if self.write_mode == WriteMode::Upsert && self.format != TableFormat::Iceberg {
+        return Err(anyhow::anyhow!("Tables with \"write_mode\": \"upsert\" need \"format\": \"iceberg\""));
+      }
+      Ok(self.write_mode == WriteMode::Upsert)
+    }
+
+    /// The group of tables this one is committed together with, if any. Only Iceberg tables can be.
+    pub fn commit_group(&self) -> anyhow::Result<Option<&str>> {
+      if self.commit_group.is_some() && self.format != TableFormat::Iceberg {
+        return Err(anyhow::anyhow!("Tables with a \"commit_group\" need \"format\": \"iceberg\""));
+      }
+      Ok(self.commit_group.as_deref())
+    }
+
+    /// The order rows are written in.
+    pub fn row_order(&self) -> anyhow::Result<RowOrder> {
+      RowOrder::sorted(&self.sort_by)
+    }
+
+    /// The order `compact` writes merged rows in.
+    pub fn compaction_order(&self) -> anyhow::Result<RowOrder> {
+      if self.cluster_by.is_empty() {
+        return self.row_order();
+      }
+      RowOrder::clustered(self.cluster_curve, self.cluster_by.clone())
+    }
+
+    /// The file formats to write, with their writer settings.
+    pub fn file_formats(&self) -> anyhow::Result<Vec<FileFormat>> {
+      if self.parquet.encryption.is_some() {
+        // Encrypted files are written without bloom filters or page indexes, which would give values away
+        if !self.parquet.bloom_filter_columns.is_empty() {
+          return Err(anyhow::anyhow!("Tables with encrypted columns can't have \"bloom_filter_columns\""));
+        }
+        if self.parquet.statistics.as_deref().is_some_and(|s| s.eq_ignore_ascii_case("page")) {
+          return Err(anyhow::anyhow!("Tables with encrypted columns can't have \"statistics\": \"page\", as page indexes aren't written: use \"chunk\""));
+        }
+      }
+      if self.encodings.is_empty() {
+        return Ok(vec![FileFormat::Parquet(self.parquet.clone())]);
+      }
+      // Fail on bad Parquet settings now rather than when the first file is opened
+      self.parquet.writer_properties()?;
+      if self.parquet.encryption.is_some() && self.encodings.iter().any(|e| *e != Encoding::Parquet) {
+        // The other encodings would hold the same columns in plaintext
+        return Err(anyhow::anyhow!("Tables with encrypted columns can only be written as Parquet"));
+      }
+      let mut formats = Vec::new();
+      for encoding in &self.encodings {
+        let format = match encoding {
+          Encoding::Parquet => FileFormat::Parquet(self.parquet.clone()),
+          Encoding::Ndjson => FileFormat::Ndjson,
+          Encoding::Csv => FileFormat::Csv(self.csv.clone()),
+          Encoding::Ipc => FileFormat::Ipc,
+        };
+        if !formats.iter().any(|f: &FileFormat| f.encoding() == *encoding) {
+          formats.push(format);
+        }
+      }
+      Ok(formats)
+    }
+  }
+
+/// How a batch relates to the rows already in the table.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteMode {
+    /// Every version of a document is kept as a row of its own.
+    #[default]
+    Append,
+    /// Rows replace the earlier rows with the same `id`, through Iceberg equality deletes.
+    Upsert,
+  }
+
+/// How readers other than this pipeline find a table's files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TableFormat {
+    /// Plain Parquet files, listed under `_committed/`.
+    #[default]
+    Parquet,
+    /// A Delta Lake log under `_delta_log/`.
+    Delta,
+    /// Snapshots of an Iceberg table, committed through the catalog `ICEBERG_CATALOG_TYPE` names.
+    Iceberg,
+  }
+
   impl Config {
     pub fn from_env() -> anyhow::Result<Self> {
       let _ = dotenvy::dotenv();
-      Ok(Self {
+      let cfg = Self {
         gcp_project: std::env::var("GCP_PROJECT")?,
-        gcs_bucket: std::env::var("GCS_BUCKET")?,
-        gcs_prefix: std::env::var("GCS_PREFIX").unwrap_or_else(|_| "warehouse".into()),
-        catalog_uri: std::env::var("ICEBERG_CATALOG_URI")?,     // e.g. BigLake/Nessie
+        warehouse_url: match std::env::var("WAREHOUSE_URL") {
+          Ok(url) => url,
+          // Older deployments only set the GCS bucket and prefix
+          Err(_) => format!("gs://{}/{}",
+            std::env::var("GCS_BUCKET").map_err(|_| anyhow::anyhow!("Set WAREHOUSE_URL, or GCS_BUCKET for a GCS warehouse"))?,
+            std::env::var("GCS_PREFIX").unwrap_or_else(|_| "warehouse".into())),
+        },
+        gcs_service_account_path: std::env::var("GCS_SERVICE_ACCOUNT_PATH").ok(),
+        gcs_endpoint: std::env::var("GCS_ENDPOINT").ok(),
+        gcs_allow_http: std::env::var("GCS_ALLOW_HTTP").ok().and_then(|v| v.parse().ok()).unwrap_or(false),
+        catalog_type: std::env::var("ICEBERG_CATALOG_TYPE").unwrap_or_else(|_| "rest".into()),
+        catalog_uri: std::env::var("ICEBERG_CATALOG_URI").ok(),     // e.g. BigLake/Nessie
+        catalog_warehouse: std::env::var("ICEBERG_CATALOG_WAREHOUSE").ok(),
+        catalog_token: std::env::var("ICEBERG_CATALOG_TOKEN").ok(),
         table_ns: std::env::var("TABLE_NS").unwrap_or_else(|_| "farm".into()),
         table_orders: "orders".into(),
         batch_max_rows: std::env::var("BATCH_MAX_ROWS").ok().and_then(|v| v.parse().ok()).unwrap_or(25_000),
         batch_max_seconds: std::env::var("BATCH_MAX_SECONDS").ok().and_then(|v| v.parse().ok()).unwrap_or(30),
-      })
+        pii_hmac_key: std::env::var("PII_HMAC_KEY").ok(),
+        upload_part_size_mb: std::env::var("UPLOAD_PART_SIZE_MB").ok().and_then(|v| v.parse().ok()).unwrap_or(10),
+        upload_max_concurrency: std::env::var("UPLOAD_MAX_CONCURRENCY").ok().and_then(|v| v.parse().ok()).unwrap_or(4),
+        upload_max_retries: std::env::var("UPLOAD_MAX_RETRIES").ok().and_then(|v| v.parse().ok()).unwrap_or(10),
+        file_target_size_mb: std::env::var("FILE_TARGET_SIZE_MB").ok().and_then(|v| v.parse().ok()).unwrap_or(128),
+        file_max_age_seconds: std::env::var("FILE_MAX_AGE_SECONDS").ok().and_then(|v| v.parse().ok()).unwrap_or(600),
+        retry_max_attempts: std::env::var("RETRY_MAX_ATTEMPTS").ok().and_then(|v| v.parse().ok()).unwrap_or(5),
+        retry_initial_backoff_ms: std::env::var("RETRY_INITIAL_BACKOFF_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(200),
+        retry_max_backoff_ms: std::env::var("RETRY_MAX_BACKOFF_MS").ok().and_then(|v| v.parse().ok()).unwrap_or(30_000),
+        compact_grace_seconds: std::env::var("COMPACT_GRACE_SECONDS").ok().and_then(|v| v.parse().ok()).unwrap_or(3600),
+        delta_checkpoint_interval: std::env::var("DELTA_CHECKPOINT_INTERVAL").ok().and_then(|v| v.parse().ok()).unwrap_or(10),
+        encryption_keys_path: std::env::var("ENCRYPTION_KEYS_PATH").ok(),
+        tables: match std::env::var("TABLE_CONFIG_PATH") {
+          Ok(path) => {
+            let raw = std::fs::read_to_string(&path)
+              .map_err(|e| anyhow::anyhow!("Failed to read table config {}: {}", path, e))?;
+            serde_json::from_str(&raw)
+              .map_err(|e| anyhow::anyhow!("Invalid table config {}: {}", path, e))?
+          }
+          Err(_) => HashMap::new(),
+        },
+      };
+      // GCS and S3 refuse parts under 5 MiB except the last, and uploads can't advance with empty parts
+      if cfg.upload_part_size_mb < 5 {
+        return Err(anyhow::anyhow!("UPLOAD_PART_SIZE_MB must be at least 5, not {}", cfg.upload_part_size_mb));
+      }
+      Ok(cfg)
+    }
+
+    pub fn upload(&self) -> UploadOptions {
+      UploadOptions {
+        part_size: self.upload_part_size_mb * 1024 * 1024,
+        max_concurrency: self.upload_max_concurrency,
+      }
+    }
+
+    /// Object store for `warehouse_url`. Every request is retried with exponential backoff
+    /// up to `upload_max_retries` times. An operation that still fails with a transient error is tried
+    /// again by `retry()` on top, so a request may be sent up to
+    /// `(upload_max_retries + 1) * retry_max_attempts` times in all.
+    pub fn warehouse(&self) -> anyhow::Result<Warehouse> {
+      let retry = RetryConfig { max_retries: self.upload_max_retries, ..Default::default() };
+      let gcs = GcsOptions {
+        service_account_path: self.gcs_service_account_path.clone(),
+        endpoint: self.gcs_endpoint.clone(),
+        allow_http: self.gcs_allow_http,
+      };
+      Warehouse::open(&self.warehouse_url, retry, &gcs)
+    }
+
+    pub fn retry(&self) -> RetryPolicy {
+      RetryPolicy {
+        max_attempts: self.retry_max_attempts.max(1),
+        initial_backoff: std::time::Duration::from_millis(self.retry_initial_backoff_ms),
+        max_backoff: std::time::Duration::from_millis(self.retry_max_backoff_ms),
+      }
+    }
+
+    pub fn rolling(&self) -> RollingOptions {
+      RollingOptions {
+        target_file_size: self.file_target_size_mb * 1024 * 1024,
+        max_file_age: std::time::Duration::from_secs(self.file_max_age_seconds),
+      }
+    }
+
+    /// Master keys for encrypted columns, if a key file is set.
+    pub fn keys(&self) -> anyhow::Result<Option<KeyStore>> {
+      self.encryption_keys_path.as_deref().map(KeyStore::load).transpose()
+    }
+
+    /// The Iceberg catalog for tables in the `iceberg` format: the REST catalog at `ICEBERG_CATALOG_URI`, or
+    /// with `ICEBERG_CATALOG_TYPE=hadoop` metadata files in the warehouse.
+    pub async fn catalog(&self) -> anyhow::Result<IcebergCatalog> {
+      match self.catalog_type.as_str() {
+        "rest" => {
+          let uri = self.catalog_uri.as_deref()
+            .ok_or_else(|| anyhow::anyhow!("Set ICEBERG_CATALOG_URI to commit to Iceberg tables, or ICEBERG_CATALOG_TYPE=hadoop"))?;
+          Ok(IcebergCatalog::Rest(RestCatalog::connect(uri, self.catalog_warehouse.as_deref(), self.catalog_token.as_deref(), self.retry()).await?))
+        }
+        "hadoop" => Ok(IcebergCatalog::Hadoop(HadoopCatalog::new(&self.warehouse()?, self.retry()))),
+        other => Err(anyhow::anyhow!("Unknown ICEBERG_CATALOG_TYPE {}: use rest or hadoop", other)),
+      }
+    }
+
+    /// Settings for `table`, or the defaults when the table isn't configured.
+    pub fn table(&self, table: &str) -> TableConfig {
+      self.tables.get(table).cloned().unwrap_or_default()
     }
   }
-  
\ No newline at end of file
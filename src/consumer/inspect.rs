@@ -0,0 +1,110 @@
+// Prints what a Parquet file in the warehouse says about itself: the key-value metadata recording which run,
+// checkpoint, batches and schema produced it, followed by the schema and the statistics in its footer.
+
+use bytes::Bytes;
+use object_store::path::Path as ObjectStorePath;
+use parquet::basic::{LogicalType, TimeUnit};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::file::statistics::Statistics;
+use parquet::schema::types::ColumnDescriptor;
+use crate::error::Error;
+use crate::retry::RetryPolicy;
+use crate::sink::encryption::{self, KeyStore};
+use crate::sink::stats::StatValue;
+use crate::store::Warehouse;
+
+/// Print the metadata of `file`, a path in the warehouse such as the ones under `_committed/`, or its full URL.
+/// Files with encrypted columns need `keys`.
+pub async fn inspect(warehouse: &Warehouse, retry: RetryPolicy, keys: Option<&KeyStore>, file: &str) -> anyhow::Result<()> {
+    let root = warehouse.url("");
+    let path = ObjectStorePath::parse(file.strip_prefix(&root).unwrap_or(file))?;
+    let bytes = retry.run(&format!("reading {}", path), || async {
+        Ok::<_, Error>(warehouse.store.get(&path).await?.bytes().await?)
+    }).await?;
+    let size = bytes.len();
+    let bytes = if encryption::is_encrypted(&bytes)? {
+        let keys = keys
+            .ok_or_else(|| anyhow::anyhow!("{} has encrypted columns: set ENCRYPTION_KEYS_PATH to a key file with its keys", path))?;
+        Bytes::from(encryption::decrypt(&bytes, keys)?)
+    } else {
+        bytes
+    };
+    let reader = SerializedFileReader::new(bytes)?;
+    let meta = reader.metadata();
+    let file_meta = meta.file_metadata();
+
+    println!("📄 {}", warehouse.url(path.as_ref()));
+    println!("{} bytes, {} rows in {} row groups", size, file_meta.num_rows(), meta.num_row_groups());
+    if let Some(created_by) = file_meta.created_by() {
+        println!("Created by: {}", created_by);
+    }
+
+    println!("\n=== Key-value metadata ===");
+    for kv in file_meta.key_value_metadata().into_iter().flatten() {
+        match &kv.value {
+            // Base64-encoded Arrow IPC, which says nothing the schema below doesn't
+            Some(value) if kv.key == "ARROW:schema" => println!("{}: <{} bytes>", kv.key, value.len()),
+            Some(value) => println!("{}: {}", kv.key, value),
+            None => println!("{}", kv.key),
+        }
+    }
+
+    println!("\n=== Schema ===");
+    let columns = file_meta.schema_descr().columns();
+    for column in columns {
+        let logical = column.logical_type().map(|t| format!(" ({:?})", t)).unwrap_or_default();
+        println!("{}: {}{}", column.path().string(), column.physical_type(), logical);
+    }
+
+    for (i, row_group) in meta.row_groups().iter().enumerate() {
+        println!("\n=== Row group {} ===", i);
+        println!("{} rows, {} bytes compressed, {} uncompressed", row_group.num_rows(), row_group.compressed_size(), row_group.total_byte_size());
+        if let Some(sorting) = row_group.sorting_columns() {
+            let sorting: Vec<String> = sorting.iter()
+                .map(|s| format!("{} {}", columns[s.column_idx as usize].path().string(), if s.descending { "DESC" } else { "ASC" }))
+                .collect();
+            println!("Sorted by: {}", sorting.join(", "));
+        }
+        for (column, descr) in row_group.columns().iter().zip(columns) {
+            let stats = column.statistics().map(|s| describe(s, descr)).unwrap_or_else(|| "no statistics".into());
+            println!("{}: {} bytes {}, {}", column.column_path().string(), column.compressed_size(), column.compression(), stats);
+        }
+    }
+    Ok(())
+}
+
+/// Min, max and null count, with dates and timestamps shown as such.
+fn describe(stats: &Statistics, column: &ColumnDescriptor) -> String {
+    let (min, max) = match stats {
+        Statistics::Boolean(s) => (s.min_opt().map(bool::to_string), s.max_opt().map(bool::to_string)),
+        Statistics::Int32(s) => {
+            let show = |v: &i32| match column.logical_type() {
+                Some(LogicalType::Date) => StatValue::Date(*v).to_string(),
+                _ => v.to_string(),
+            };
+            (s.min_opt().map(show), s.max_opt().map(show))
+        }
+        Statistics::Int64(s) => {
+            let show = |v: &i64| match column.logical_type() {
+                Some(LogicalType::Timestamp { unit, .. }) => StatValue::TimestampMs(match unit {
+                    TimeUnit::MILLIS(_) => *v,
+                    TimeUnit::MICROS(_) => v.div_euclid(1_000),
+                    TimeUnit::NANOS(_) => v.div_euclid(1_000_000),
+                }).to_string(),
+                _ => v.to_string(),
+            };
+            (s.min_opt().map(show), s.max_opt().map(show))
+        }
+        Statistics::Int96(s) => (s.min_opt().map(|v| v.to_string()), s.max_opt().map(|v| v.to_string())),
+        Statistics::Float(s) => (s.min_opt().map(f32::to_string), s.max_opt().map(f32::to_string)),
+        Statistics::Double(s) => (s.min_opt().map(f64::to_string), s.max_opt().map(f64::to_string)),
+        Statistics::ByteArray(s) => {
+            let show = |v: &parquet::data_type::ByteArray| format!("{:?}", String::from_utf8_lossy(v.data()));
+            (s.min_opt().map(show), s.max_opt().map(show))
+        }
+        Statistics::FixedLenByteArray(s) => (s.min_opt().map(|v| hex::encode(v.data())), s.max_opt().map(|v| hex::encode(v.data()))),
+    };
+    let show = |v: Option<String>| v.unwrap_or_else(|| "-".into());
+    let nulls = stats.null_count_opt().map_or_else(|| "-".into(), |n| n.to_string());
+    format!("min {}, max {}, nulls {}", show(min), show(max), nulls)
+}
@@ -1,48 +1,80 @@
-// This module provides functionality to read data from GCS bucket into a Polars dataframe.
-// The bucket structure is: bucket/prefix/namespace/table/data/ where parquet files are stored.
+// This module provides functionality to read data from the warehouse into a Polars dataframe.
+// The warehouse structure is: <warehouse url>/namespace/table/data/ where parquet files are stored.
+// Iceberg tables are read from their catalog instead: the current snapshot's files, less deleted rows.
 
 use polars::prelude::*;
-use object_store::{ObjectStore, path::Path as ObjectStorePath};
-use object_store::gcp::GoogleCloudStorageBuilder;
-use std::sync::Arc;
-use futures::StreamExt;
+use object_store::path::Path as ObjectStorePath;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::Cursor;
+use crate::error::Error;
+use crate::retry::RetryPolicy;
+use crate::sink::encoding::Encoding;
+use crate::sink::encryption::{self, KeyStore};
+use crate::sink::iceberg::{IcebergCatalog, IcebergTable};
+use crate::sink::parquet_commit::ParquetCommit;
+use crate::store::Warehouse;
+
+/// Column that numbers the rows of a file while its position deletes are applied.
+const ROW_INDEX: &str = "__row_index";
 
 pub struct Reader {
-    store: Arc<dyn ObjectStore>,
-    bucket: String,
-    prefix: String,
+    warehouse: Warehouse,
+    retry: RetryPolicy,
+    commit: ParquetCommit,
+    keys: Option<KeyStore>,
+    catalog: Option<IcebergCatalog>,
 }
 
 impl Reader {
-    pub async fn new(bucket: &str, prefix: &str) -> anyhow::Result<Self> {
-        let store = GoogleCloudStorageBuilder::new()
-            .with_bucket_name(bucket)
-            .build()?;
-        
+    pub async fn new(warehouse: &Warehouse, retry: RetryPolicy) -> anyhow::Result<Self> {
         Ok(Self { 
-            store: Arc::new(store),
-            bucket: bucket.to_string(), 
-            prefix: prefix.to_string() 
+            warehouse: warehouse.clone(),
+            retry,
+            commit: ParquetCommit::new(warehouse, retry).await?,
+            keys: None,
+            catalog: None,
         })
     }
 
+    /// Master keys for files with encrypted columns.
+    pub fn with_keys(mut self, keys: Option<KeyStore>) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    /// Read tables as Iceberg tables of `catalog`.
+    pub fn with_catalog(mut self, catalog: IcebergCatalog) -> Self {
+        self.catalog = Some(catalog);
+        self
+    }
+
     pub async fn read(&self, ns: &str, table: &str) -> anyhow::Result<()> {
-        println!("Reading from GCS bucket: {}", self.bucket);
-        println!("Path pattern: {}/{}/{}/data/", self.prefix, ns, table);
-        
-        // List files in the data directory
-        let data_path = format!("{}/{}/{}/data/", self.prefix, ns, table);
-        let prefix_path = ObjectStorePath::from(data_path);
+        // Only committed files: anything else under data/ may be half-published
+        let prefix_path = self.warehouse.table_path(ns, table).child("data");
+        println!("Reading from warehouse: {}", self.warehouse.url(prefix_path.as_ref()));
         
-        println!("Listing files in: {}", prefix_path);
-        
-        let mut files = self.store.list(Some(&prefix_path));
         let mut parquet_files = Vec::new();
-        
-        while let Some(meta) = files.next().await {
-            let meta = meta?;
-            if meta.location.as_ref().ends_with(".parquet") {
-                parquet_files.push(meta.location);
+        let records = self.commit.committed_files(ns, table).await?;
+        match &self.catalog {
+            Some(catalog) => {
+                println!("Listing files of the current Iceberg snapshot of: {}.{}", ns, table);
+                let iceberg = IcebergTable::open(catalog, &self.warehouse, ns, table).await?;
+                // Only the commit records say which files are encrypted
+                let encrypted: HashSet<&str> = records.iter().filter(|r| r.encrypted).map(|r| r.path.as_str()).collect();
+                for file in iceberg.scan(self.keys.as_ref()).await? {
+                    let is_encrypted = encrypted.contains(file.path.as_ref());
+                    parquet_files.push((file.path, is_encrypted, file.deleted, file.deleted_positions));
+                }
+            }
+            None => {
+                println!("Listing committed files in: {}", prefix_path);
+                for record in records {
+                    // Tables may also be written as NDJSON, CSV or IPC; the Parquet files hold the same rows
+                    if Encoding::of(&record.path) != Some(Encoding::Parquet) {
+                        continue;
+                    }
+                    parquet_files.push((ObjectStorePath::parse(&record.path)?, record.encrypted, HashMap::new(), BTreeSet::new()));
+                }
             }
         }
         
@@ -56,15 +88,47 @@ impl Reader {
         // Read all parquet files to get the complete table
         println!("Reading all parquet files to reconstruct the complete table...");
         
-        let scan_args = ScanArgsParquet::default();
+        // Give Polars the same endpoint and credentials as the store
+        let mut scan_args = ScanArgsParquet::default();
+        if self.warehouse.polars_can_scan() {
+            let root = self.warehouse.url("");
+            scan_args.cloud_options = Some(cloud::CloudOptions::from_untyped_config(&root, self.warehouse.options().iter().cloned())?);
+        }
         let mut lazy_frames = Vec::new();
         
-        for file in &parquet_files {
+        for (file, encrypted, deleted, deleted_positions) in &parquet_files {
             println!("Adding file to scan: {}", file);
-            let gcs_path = format!("gs://{}/{}", self.bucket, file);
-            let polars_path = PlPath::new(&gcs_path);
-            
-            let lazy_frame = LazyFrame::scan_parquet(polars_path, scan_args.clone())?;
+            let mut lazy_frame = if *encrypted {
+                // Polars can't decrypt, so decrypt the file here and hand it over as plain Parquet
+                let keys = self.keys.as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("{} has encrypted columns: set ENCRYPTION_KEYS_PATH to a key file with its keys", file))?;
+                let bytes = self.retry.run(&format!("reading {}", file), || async {
+                    Ok::<_, Error>(self.warehouse.store.get(file).await?.bytes().await?)
+                }).await?;
+                let plain = encryption::decrypt(&bytes, keys)
+                    .map_err(|e| anyhow::anyhow!("Failed to decrypt {}: {}", file, e))?;
+                ParquetReader::new(Cursor::new(plain)).finish()?.lazy()
+            } else if self.warehouse.polars_can_scan() {
+                let url = self.warehouse.url(file.as_ref());
+                LazyFrame::scan_parquet(PlPath::new(&url), scan_args.clone())?
+            } else {
+                // Polars has no access to this store, so fetch the file through it instead
+                let bytes = self.retry.run(&format!("reading {}", file), || async {
+                    Ok::<_, Error>(self.warehouse.store.get(file).await?.bytes().await?)
+                }).await?;
+                ParquetReader::new(Cursor::new(bytes)).finish()?.lazy()
+            };
+            if !deleted_positions.is_empty() {
+                // Number the rows before anything filters them
+                let positions = Series::new(ROW_INDEX.into(), deleted_positions.iter().map(|p| *p as IdxSize).collect::<Vec<_>>());
+                lazy_frame = lazy_frame.with_row_index(ROW_INDEX, None)
+                    .filter(col(ROW_INDEX).is_in(lit(positions).implode(), false).not())
+                    .drop(by_name([ROW_INDEX], true));
+            }
+            for (column, values) in deleted {
+                let values = Series::new(column.into(), values.iter().map(String::as_str).collect::<Vec<_>>());
+                lazy_frame = lazy_frame.filter(col(column.as_str()).is_in(lit(values).implode(), false).not());
+            }
             lazy_frames.push(lazy_frame);
         }
         
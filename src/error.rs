@@ -0,0 +1,107 @@
+// src/error.rs
+// Errors from storage and commit operations, classified by whether trying again can help.
+
+use arrow::error::ArrowError;
+use parquet::errors::ParquetError;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  /// Timeouts, throttling, server errors and dropped connections: worth retrying.
+  #[error("transient error: {0}")]
+  Retryable(#[source] BoxError),
+  /// Bad settings, credentials or permissions: retrying won't help until the config is fixed.
+  #[error("configuration error: {0}")]
+  Config(#[source] BoxError),
+  /// The data, or what's already in storage, isn't what was expected: retrying gives the same result.
+  #[error("data error: {0}")]
+  Data(#[source] BoxError),
+}
+
+impl Error {
+  pub fn config(e: impl Into<BoxError>) -> Self {
+    Error::Config(e.into())
+  }
+
+  pub fn is_retryable(&self) -> bool {
+    matches!(self, Error::Retryable(_))
+  }
+}
+
+impl From<object_store::Error> for Error {
+  fn from(e: object_store::Error) -> Self {
+    use object_store::Error as E;
+    match e {
+      // The client has already retried the request itself; its error only helps to retry if the cause was transient
+      E::Generic { ref source, .. } if is_transient(source.as_ref()) => Error::Retryable(Box::new(e)),
+      E::Generic { ref source, .. } if is_misconfigured(source.as_ref()) => Error::Config(Box::new(e)),
+      E::InvalidPath { .. } | E::NotSupported { .. } | E::NotImplemented | E::PermissionDenied { .. }
+        | E::Unauthenticated { .. } | E::UnknownConfigurationKey { .. } => Error::Config(Box::new(e)),
+      _ => Error::Data(Box::new(e)),
+    }
+  }
+}
+
+/// Whether `e`, or an error that caused it, is a timeout, a dropped connection, throttling or a server error.
+fn is_transient(e: &(dyn std::error::Error + 'static)) -> bool {
+  causes(e).any(|e| {
+    if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+      e.is_timeout() || e.is_connect() || e.is_request() || e.is_body()
+        || e.status().is_some_and(|s| s.is_server_error() || s == reqwest::StatusCode::TOO_MANY_REQUESTS)
+    } else if let Some(e) = e.downcast_ref::<std::io::Error>() {
+      use std::io::ErrorKind as K;
+      matches!(e.kind(), K::TimedOut | K::Interrupted | K::ConnectionReset | K::ConnectionAborted | K::BrokenPipe | K::UnexpectedEof)
+    } else {
+      false
+    }
+  })
+}
+
+/// Whether `e`, or an error that caused it, is a request the client couldn't build from its settings.
+fn is_misconfigured(e: &(dyn std::error::Error + 'static)) -> bool {
+  causes(e).any(|e| e.downcast_ref::<reqwest::Error>().is_some_and(|e| e.is_builder()))
+}
+
+/// `e` and the chain of errors that caused it.
+fn causes<'a>(e: &'a (dyn std::error::Error + 'static)) -> impl Iterator<Item = &'a (dyn std::error::Error + 'static)> {
+  std::iter::successors(Some(e), |e| e.source())
+}
+
+impl From<ParquetError> for Error {
+  fn from(e: ParquetError) -> Self {
+    match e {
+      // Raised by `MultipartWriter` when an upload fails
+      ParquetError::External(e) => match e.downcast::<object_store::Error>() {
+        Ok(e) => (*e).into(),
+        Err(e) => Error::Data(e),
+      },
+      e => Error::Data(Box::new(e)),
+    }
+  }
+}
+
+impl From<serde_json::Error> for Error {
+  fn from(e: serde_json::Error) -> Self {
+    Error::Data(Box::new(e))
+  }
+}
+
+impl From<ArrowError> for Error {
+  fn from(e: ArrowError) -> Self {
+    Error::Data(Box::new(e))
+  }
+}
+
+impl From<reqwest::Error> for Error {
+  fn from(e: reqwest::Error) -> Self {
+    // Responses are classified by status where they're handled; what's left failed to send or to parse
+    if e.is_decode() {
+      Error::Data(Box::new(e))
+    } else if e.is_builder() {
+      Error::Config(Box::new(e))
+    } else {
+      Error::Retryable(Box::new(e))
+    }
+  }
+}
@@ -1,8 +1,9 @@
 // src/main.rs
-mod config; mod schema;
-mod consumer { pub mod reader; }
+mod config; mod schema; mod store; mod error; mod retry;
+mod consumer { pub mod reader; pub mod inspect; }
 mod source { pub mod firestore_listen; }
-mod sink { pub mod parquet_writer; pub mod parquet_commit; }
+mod sink { pub mod parquet_writer; pub mod parquet_commit; pub mod multipart; pub mod rolling; pub mod partition; pub mod compact; pub mod stats; pub mod delta; pub mod encoding; pub mod sort; pub mod encryption; pub mod avro; pub mod iceberg; pub mod hadoop_catalog; }
+mod transform { pub mod pii; pub mod expr; }
 
 use clap::{Parser, Subcommand};
 use tracing::*;
@@ -18,7 +19,38 @@ struct Cli {
 enum Cmd { 
   Run { collection: Option<String> },
   Backfill, 
-  Read 
+  Read,
+  /// Print a Parquet file's key-value metadata and footer stats
+  Inspect {
+    /// Path of the file in the warehouse, e.g. `warehouse/farm/orders/data/.../part-00000.parquet`, or its URL
+    file: String,
+  },
+  /// Merge a table's small files into target-size files
+  Compact {
+    table: String,
+    /// Sort merged rows by these columns, e.g. `variety_id,created_at desc`
+    #[arg(long, value_delimiter = ',', conflicts_with = "cluster_by")]
+    sort_by: Vec<String>,
+    /// Cluster merged rows on these columns along a space-filling curve, e.g. `variety_id,created_at`
+    #[arg(long, value_delimiter = ',')]
+    cluster_by: Vec<String>,
+    /// Curve for `--cluster-by`: `zorder` or `hilbert`
+    #[arg(long, default_value = "zorder")]
+    curve: sink::sort::Curve,
+  },
+  /// Expire an Iceberg table's old snapshots, merge its small manifests and delete files no snapshot uses
+  Maintain {
+    table: String,
+    /// Expire snapshots committed more than this many hours ago
+    #[arg(long, default_value_t = 168)]
+    retain_hours: u64,
+    /// Keep at least this many of the latest snapshots, however old
+    #[arg(long, default_value_t = 10)]
+    retain_last: usize,
+    /// Merge manifests smaller than this many bytes
+    #[arg(long, default_value_t = 8 * 1024 * 1024)]
+    manifest_target_size: u64,
+  },
 }
 
 #[tokio::main]
@@ -32,8 +64,9 @@ async fn main() -> anyhow::Result<()> {
           .with_database_id("oltp".to_string());
       let db = FirestoreDb::with_options(db_options).await?;
 
-      let parquet = sink::parquet_writer::ParquetSink::new(&cfg.gcs_bucket, &cfg.gcs_prefix).await?;
-      let commit = sink::parquet_commit::ParquetCommit::new(&cfg.gcs_bucket, &cfg.gcs_prefix).await?;
+      let warehouse = cfg.warehouse()?;
+      let parquet = sink::parquet_writer::ParquetSink::new(&warehouse, cfg.upload(), cfg.retry()).await?.with_keys(cfg.keys()?);
+      let commit = sink::parquet_commit::ParquetCommit::new(&warehouse, cfg.retry()).await?;
 
       // Define all collections to process
       let collections = match collection {
@@ -48,66 +81,163 @@ async fn main() -> anyhow::Result<()> {
         ],
       };
 
+      // Tables of a commit group are committed together after the last of them in the run
+      let mut last_in_group = std::collections::HashMap::new();
+      for collection_name in &collections {
+        if let Some(group) = cfg.table(collection_name).commit_group()? {
+          last_in_group.insert(group.to_string(), collection_name.clone());
+        }
+      }
+      if !last_in_group.is_empty() && cfg.catalog_type != "rest" {
+        return Err(anyhow::anyhow!("Commit groups need ICEBERG_CATALOG_TYPE=rest: only a REST catalog commits several tables at once"));
+      }
+      let mut groups: std::collections::HashMap<String, Vec<sink::iceberg::IcebergTable>> = std::collections::HashMap::new();
+
       for collection_name in collections {
         println!("🚀 Starting ingestion for collection: {}", collection_name);
+        let table_cfg = cfg.table(&collection_name);
+        let formats = table_cfg.file_formats()?;
+        let order = table_cfg.row_order()?;
+        let upserts = table_cfg.upserts()?;
+        let group = table_cfg.commit_group()?.map(String::from);
+        let update_time_column = table_cfg.update_time_column().to_string();
+        let pii = transform::pii::PiiTransformer::new(table_cfg.columns, cfg.pii_hmac_key.as_deref())?;
+        let exprs = transform::expr::ExprTransformer::new(table_cfg.transform.as_ref())?;
+        let spec = sink::partition::PartitionSpec::parse(&table_cfg.partition_by)?;
+        let mut delta = match table_cfg.format {
+          config::TableFormat::Delta => Some(sink::delta::DeltaLog::open(&warehouse, cfg.retry(), &cfg.table_ns, &collection_name, cfg.delta_checkpoint_interval).await?),
+          _ => None,
+        };
+        let mut iceberg = match table_cfg.format {
+          config::TableFormat::Iceberg => {
+            // A missing table gets the columns the transforms leave of the collection's
+            let empty = arrow_array::RecordBatch::new_empty(schema::schema_for(&collection_name)?);
+            let columns = exprs.apply(&pii.apply(&empty)?)?.schema();
+            let iceberg = sink::iceberg::IcebergTable::create(&cfg.catalog().await?, &warehouse, &cfg.table_ns, &collection_name, &columns, &spec, &order).await?;
+            let iceberg = if upserts { iceberg.with_key_column(schema::ID_COLUMN) } else { iceberg };
+            // Committed once the run has read up to a checkpoint, with the checkpoint
+            Some(iceberg.deferred())
+          }
+          _ => None,
+        };
+        // Resume where the data readers see ends: Iceberg's snapshots, the Delta log, or else the commit log
+        let from = match (&iceberg, &delta) {
+          (Some(iceberg), _) => iceberg.last_checkpoint(),
+          (None, Some(delta)) => delta.last_checkpoint(),
+          (None, None) => commit.last_checkpoint(&cfg.table_ns, &collection_name).await?,
+        };
+        if let Some(delta) = &mut delta && order.is_sorted() {
+          delta.set_property(sink::delta::SORT_ORDER_PROPERTY, order.to_string());
+        }
         
         // Route to appropriate stream based on collection name
-        let stream: Box<dyn futures::Stream<Item = serde_json::Value> + Unpin> = match collection_name.as_str() {
-          "orders" => Box::new(source::firestore_listen::stream_orders(&db, &collection_name).await?),
-          "varieties" => Box::new(source::firestore_listen::stream_varieties(&db, &collection_name).await?),
-          "variety_inventory" => Box::new(source::firestore_listen::stream_variety_inventory(&db, &collection_name).await?),
-          "materials" => Box::new(source::firestore_listen::stream_materials(&db, &collection_name).await?),
-          "batches" => Box::new(source::firestore_listen::stream_batches(&db, &collection_name).await?),
-          "inventory_transactions" => Box::new(source::firestore_listen::stream_inventory_transactions(&db, &collection_name).await?),
+        let stream = match collection_name.as_str() {
+          "orders" => source::firestore_listen::stream_orders(&db, &collection_name, from.as_ref()).await?,
+          "varieties" => source::firestore_listen::stream_varieties(&db, &collection_name, from.as_ref()).await?,
+          "variety_inventory" => source::firestore_listen::stream_variety_inventory(&db, &collection_name, from.as_ref()).await?,
+          "materials" => source::firestore_listen::stream_materials(&db, &collection_name, from.as_ref()).await?,
+          "batches" => source::firestore_listen::stream_batches(&db, &collection_name, from.as_ref()).await?,
+          "inventory_transactions" => source::firestore_listen::stream_inventory_transactions(&db, &collection_name, from.as_ref()).await?,
           _ => return Err(anyhow::anyhow!("Unknown collection: {}", collection_name)),
         };
 
         let mut buffer = Vec::with_capacity(cfg.batch_max_rows);
         let mut last_flush = tokio::time::Instant::now();
+        let start = from.as_ref().map_or("snapshot", |c| c.resume_token.as_str());
+        let mut files = sink::rolling::RollingWriter::new(&parquet, &cfg.table_ns, &collection_name, start, formats, spec, cfg.rolling())
+          .with_order(order)
+          .with_update_time_column(&update_time_column);
+        if upserts {
+          files = files.with_key_column(schema::ID_COLUMN);
+        }
+        let mut seq = 0;
+        // Files are only committed at the checkpoint, which holds every change before it
+        let mut run_files = Vec::new();
+        let mut deleted = std::collections::BTreeSet::new();
+        let mut checkpoint = None;
 
         tokio::pin!(stream);
         println!("📊 Processing documents from collection: {}...", collection_name);
         
-        while let Some(doc) = stream.next().await {
-          println!("📄 Processing document: {:?}", doc);
+        while let Some(change) = stream.next().await {
+          let doc = match change? {
+            source::firestore_listen::Change::Doc(doc) => doc,
+            source::firestore_listen::Change::Deleted(id) => {
+              println!("🗑️ Document deleted: {}", id);
+              deleted.insert(id);
+              continue;
+            }
+            source::firestore_listen::Change::Checkpoint(position) => {
+              checkpoint = Some(position);
+              continue;
+            }
+          };
+          // A document created again after its deletion stays
+          if let Some(id) = doc[schema::ID_COLUMN].as_str() {
+            deleted.remove(id);
+          }
+          println!("📄 Processing document: {:?}", pii.redact_doc(&doc));
           buffer.push(doc);
           let time_up = last_flush.elapsed().as_secs() >= cfg.batch_max_seconds;
           let size_up = buffer.len() >= cfg.batch_max_rows;
 
-          if time_up || size_up || buffer.len() > 0 {
+          if time_up || size_up {
             println!("💾 Flushing batch: {} documents", buffer.len());
-            let batch = match collection_name.as_str() {
-              "orders" => schema::to_orders_batch(&buffer)?,
-              "varieties" => schema::to_varieties_batch(&buffer)?,
-              "variety_inventory" => schema::to_variety_inventory_batch(&buffer)?,
-              "materials" => schema::to_materials_batch(&buffer)?,
-              "batches" => schema::to_batches_batch(&buffer)?,
-              "inventory_transactions" => schema::to_inventory_transactions_batch(&buffer)?,
-              _ => return Err(anyhow::anyhow!("Unknown collection: {}", collection_name)),
+            let batch = exprs.apply(&pii.apply(&schema::to_batch(&collection_name, &buffer)?)?)?;
+            let batch = match &mut iceberg {
+              Some(iceberg) => iceberg.with_field_ids(&batch)?,
+              None => batch,
             };
-            let path = parquet.write(&cfg.table_ns, &collection_name, &batch).await?;
-            commit.append_parquet(&cfg.table_ns, &collection_name, &format!("gs://{}/{}", cfg.gcs_bucket, path), 0, batch.num_rows() as i64).await?;
+            run_files.extend(files.write(&batch, seq).await?);
+            seq += 1;
             buffer.clear();
             last_flush = tokio::time::Instant::now();
-            info!("✅ committed {} for {}", path, collection_name);
           }
         }
         
         // Flush any remaining documents
         if !buffer.is_empty() {
           println!("💾 Flushing final batch: {} documents", buffer.len());
-          let batch = match collection_name.as_str() {
-            "orders" => schema::to_orders_batch(&buffer)?,
-            "varieties" => schema::to_varieties_batch(&buffer)?,
-            "variety_inventory" => schema::to_variety_inventory_batch(&buffer)?,
-            "materials" => schema::to_materials_batch(&buffer)?,
-            "batches" => schema::to_batches_batch(&buffer)?,
-            "inventory_transactions" => schema::to_inventory_transactions_batch(&buffer)?,
-            _ => return Err(anyhow::anyhow!("Unknown collection: {}", collection_name)),
+          let batch = exprs.apply(&pii.apply(&schema::to_batch(&collection_name, &buffer)?)?)?;
+          let batch = match &mut iceberg {
+            Some(iceberg) => iceberg.with_field_ids(&batch)?,
+            None => batch,
           };
-          let path = parquet.write(&cfg.table_ns, &collection_name, &batch).await?;
-          commit.append_parquet(&cfg.table_ns, &collection_name, &format!("gs://{}/{}", cfg.gcs_bucket, path), 0, batch.num_rows() as i64).await?;
-          info!("✅ committed final batch: {} for {}", path, collection_name);
+          run_files.extend(files.write(&batch, seq).await?);
+        }
+        run_files.extend(files.close_all().await?);
+        // A document changed more than once in the run keeps only its last row
+        files.mark_superseded(&mut run_files);
+        let checkpoint = checkpoint.ok_or_else(|| anyhow::anyhow!("The changes of {} ended without a checkpoint", collection_name))?;
+        commit.commit_run(&cfg.table_ns, &collection_name, files.run_id(), from.as_ref(), &run_files, &checkpoint).await?;
+        info!("✅ committed {} files of run {} for {} until {}", run_files.len(), files.run_id(), collection_name, checkpoint);
+        // Delta and Iceberg readers only see a file once it is in the log or a snapshot as well
+        if let Some(delta) = &mut delta {
+          delta.set_checkpoint(checkpoint.clone());
+          delta.append(&run_files).await?;
+        }
+        if let Some(iceberg) = &mut iceberg {
+          iceberg.append(&run_files).await?;
+        }
+        commit.finish_run(&cfg.table_ns, &collection_name, files.run_id(), &run_files).await?;
+        if let Some(iceberg) = &mut iceberg {
+          if from.is_none() {
+            // The run read the whole collection, so documents it didn't see were deleted
+            iceberg.delete_missing(&run_files, cfg.keys()?.as_ref()).await?;
+          } else {
+            iceberg.delete_documents(&deleted).await?;
+          }
+          iceberg.set_checkpoint(checkpoint);
+        }
+        if let Some(group) = group {
+          let tables = groups.entry(group.clone()).or_default();
+          tables.extend(iceberg);
+          if last_in_group.get(&group) == Some(&collection_name) {
+            println!("🧊 Committing commit group {}", group);
+            sink::iceberg::commit_together(tables, cfg.keys()?.as_ref()).await?;
+          }
+        } else if let Some(iceberg) = &mut iceberg {
+          sink::iceberg::commit_together(std::slice::from_mut(iceberg), cfg.keys()?.as_ref()).await?;
         }
         
         println!("🏁 Completed ingestion for collection: {}", collection_name);
@@ -121,9 +251,86 @@ async fn main() -> anyhow::Result<()> {
       unimplemented!("Backfill command stub");
     }
     Cmd::Read => {
-      let reader = consumer::reader::Reader::new(&cfg.gcs_bucket, &cfg.gcs_prefix).await?;
+      let mut reader = consumer::reader::Reader::new(&cfg.warehouse()?, cfg.retry()).await?.with_keys(cfg.keys()?);
+      if cfg.table(&cfg.table_orders).format == config::TableFormat::Iceberg {
+        reader = reader.with_catalog(cfg.catalog().await?);
+      }
       reader.read(&cfg.table_ns, &cfg.table_orders).await?;
     }
+    Cmd::Inspect { file } => {
+      let keys = cfg.keys()?;
+      consumer::inspect::inspect(&cfg.warehouse()?, cfg.retry(), keys.as_ref(), &file).await?;
+    }
+    Cmd::Compact { table, sort_by, cluster_by, curve } => {
+      let warehouse = cfg.warehouse()?;
+      let parquet = sink::parquet_writer::ParquetSink::new(&warehouse, cfg.upload(), cfg.retry()).await?.with_keys(cfg.keys()?);
+      let commit = sink::parquet_commit::ParquetCommit::new(&warehouse, cfg.retry()).await?;
+      let table_cfg = cfg.table(&table);
+      if table_cfg.upserts()? {
+        // Merged files get a new sequence number, which the equality deletes of older rows don't apply to
+        return Err(anyhow::anyhow!("{} is written as upserts; compacting it would bring back the rows its deletes removed", table));
+      }
+      // Flags take precedence over the table's settings
+      let order = if !cluster_by.is_empty() {
+        sink::sort::RowOrder::clustered(curve, cluster_by)?
+      } else if !sort_by.is_empty() {
+        sink::sort::RowOrder::sorted(&sort_by)?
+      } else {
+        table_cfg.compaction_order()?
+      };
+      let opts = sink::compact::CompactOptions {
+        target_file_size: cfg.rolling().target_file_size,
+        order: order.clone(),
+        update_time_column: Some(table_cfg.update_time_column().to_string()),
+      };
+
+      println!("🗜️ Compacting table: {}", table);
+      let compactor = sink::compact::Compactor::new(&parquet, &commit, &warehouse, cfg.retry(), table_cfg.parquet.clone(), opts);
+      let summary = compactor.run(&cfg.table_ns, &table).await?;
+      println!("✅ Replaced {} files with {}", summary.files_removed, summary.added.len());
+
+      if table_cfg.format == config::TableFormat::Delta {
+        // Open the log before listing the live files, so files appended meanwhile aren't removed from it
+        let mut delta = sink::delta::DeltaLog::open(&warehouse, cfg.retry(), &cfg.table_ns, &table, cfg.delta_checkpoint_interval).await?;
+        match &order {
+          sink::sort::RowOrder::Sorted(_) => delta.set_property(sink::delta::SORT_ORDER_PROPERTY, order.to_string()),
+          sink::sort::RowOrder::Clustered { .. } => delta.set_property(sink::delta::CLUSTERING_PROPERTY, order.to_string()),
+          sink::sort::RowOrder::Unsorted => {}
+        }
+        let live = commit.committed_files(&cfg.table_ns, &table).await?;
+        delta.sync(&live, &summary.added).await?;
+      }
+      if table_cfg.format == config::TableFormat::Iceberg {
+        // Loaded before listing the live files for the same reason
+        let mut iceberg = sink::iceberg::IcebergTable::open(&cfg.catalog().await?, &warehouse, &cfg.table_ns, &table).await?;
+        let live = commit.committed_files(&cfg.table_ns, &table).await?;
+        iceberg.sync(&live, &summary.added).await?;
+      }
+
+      let grace = std::time::Duration::from_secs(cfg.compact_grace_seconds);
+      let deleted = commit.cleanup(&cfg.table_ns, &table, grace).await?;
+      println!("🧹 Deleted {} files replaced more than {:?} ago", deleted, grace);
+    }
+    Cmd::Maintain { table, retain_hours, retain_last, manifest_target_size } => {
+      let warehouse = cfg.warehouse()?;
+      let commit = sink::parquet_commit::ParquetCommit::new(&warehouse, cfg.retry()).await?;
+      if cfg.table(&table).format != config::TableFormat::Iceberg {
+        return Err(anyhow::anyhow!("{} isn't an Iceberg table; set its \"format\" to \"iceberg\"", table));
+      }
+
+      println!("🧰 Maintaining Iceberg table: {}", table);
+      let mut iceberg = sink::iceberg::IcebergTable::open(&cfg.catalog().await?, &warehouse, &cfg.table_ns, &table).await?;
+      iceberg.merge_manifests(manifest_target_size).await?;
+      let older_than_ms = chrono::Utc::now().timestamp_millis() - (retain_hours * 3600 * 1000) as i64;
+      let expired = iceberg.expire_snapshots(older_than_ms, retain_last).await?;
+      println!("✅ Expired {} snapshots older than {} hours", expired.len(), retain_hours);
+
+      // Files with a commit record are still read without Iceberg, and replaced ones are left to compaction
+      let keep = commit.recorded_files(&cfg.table_ns, &table).await?;
+      let grace = std::time::Duration::from_secs(cfg.compact_grace_seconds);
+      let deleted = iceberg.delete_orphans(&keep, grace).await?;
+      println!("🧹 Deleted {} files no snapshot uses", deleted);
+    }
   }
   Ok(())
 }
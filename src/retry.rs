@@ -0,0 +1,52 @@
+// src/retry.rs
+// Retries storage and commit operations with exponential backoff and full jitter.
+// Only errors classified as retryable are retried; config and data errors fail immediately.
+
+use std::future::Future;
+use std::time::Duration;
+use crate::error::Error;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+  /// Attempts per operation, including the first. The retry budget.
+  pub max_attempts: u32,
+  /// Upper bound of the delay before the first retry; doubles with every attempt.
+  pub initial_backoff: Duration,
+  /// Upper bound of any single delay.
+  pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    Self { max_attempts: 5, initial_backoff: Duration::from_millis(200), max_backoff: Duration::from_secs(30) }
+  }
+}
+
+impl RetryPolicy {
+  /// Run `op` until it succeeds, fails with an error that isn't retryable, or the attempts run out.
+  /// `what` names the operation in log messages.
+  pub async fn run<T, F, Fut>(&self, what: &str, mut op: F) -> Result<T, Error>
+  where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+  {
+    let mut attempt = 1;
+    loop {
+      match op().await {
+        Err(e) if e.is_retryable() && attempt < self.max_attempts => {
+          let delay = self.backoff(attempt);
+          tracing::warn!("⚠️ {} failed (attempt {}/{}), retrying in {:?}: {}", what, attempt, self.max_attempts, delay, e);
+          tokio::time::sleep(delay).await;
+          attempt += 1;
+        }
+        result => return result,
+      }
+    }
+  }
+
+  /// A random delay between zero and the exponential backoff for `attempt`.
+  fn backoff(&self, attempt: u32) -> Duration {
+    let cap = self.initial_backoff.saturating_mul(1 << (attempt - 1).min(16)).min(self.max_backoff);
+    cap.mul_f64(rand::random::<f64>())
+  }
+}
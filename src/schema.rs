@@ -2,6 +2,49 @@
 use arrow_array::{Float64Array, StringArray, RecordBatch, TimestampMillisecondArray, ArrayRef, UInt32Array};
 use arrow::datatypes::{DataType, Field, Schema};
 use std::sync::Arc;
+use sha2::{Digest, Sha256};
+
+/// Version of the schemas below, recorded in every Parquet file. Bump it whenever one of them changes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Column holding each document's id, which upserts match rows on.
+pub const ID_COLUMN: &str = "id";
+
+/// Short hash of a schema's column names, types and nullability. Unlike `SCHEMA_VERSION`, it also tells apart
+/// files whose columns were changed by table transforms.
+pub fn fingerprint(schema: &Schema) -> String {
+    let mut hasher = Sha256::new();
+    for field in schema.fields() {
+        hasher.update(format!("{}:{}:{};", field.name(), field.data_type(), field.is_nullable()));
+    }
+    hex::encode(&hasher.finalize()[..8])
+}
+
+/// The schema of `collection`, before any table transforms.
+pub fn schema_for(collection: &str) -> anyhow::Result<Arc<Schema>> {
+    match collection {
+        "orders" => Ok(orders_schema()),
+        "varieties" => Ok(varieties_schema()),
+        "variety_inventory" => Ok(variety_inventory_schema()),
+        "materials" => Ok(materials_schema()),
+        "batches" => Ok(batches_schema()),
+        "inventory_transactions" => Ok(inventory_transactions_schema()),
+        _ => Err(anyhow::anyhow!("Unknown collection: {}", collection)),
+    }
+}
+
+/// Convert raw documents from `collection` into a batch with that collection's schema.
+pub fn to_batch(collection: &str, rows: &[serde_json::Value]) -> anyhow::Result<RecordBatch> {
+    match collection {
+        "orders" => to_orders_batch(rows),
+        "varieties" => to_varieties_batch(rows),
+        "variety_inventory" => to_variety_inventory_batch(rows),
+        "materials" => to_materials_batch(rows),
+        "batches" => to_batches_batch(rows),
+        "inventory_transactions" => to_inventory_transactions_batch(rows),
+        _ => Err(anyhow::anyhow!("Unknown collection: {}", collection)),
+    }
+}
 
 pub fn orders_schema() -> Arc<Schema> {
     Arc::new(Schema::new(vec![
@@ -0,0 +1,393 @@
+// src/sink/avro.rs
+// Just enough Avro for Iceberg manifests: schemas parsed from their JSON form, a generic value model, and
+// object container files with the `null` and `deflate` codecs. Values are written against a schema, so
+// records read from a file written by another engine can be written again with a different schema.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use serde_json::json;
+use crate::error::Error;
+
+const MAGIC: &[u8] = b"Obj\x01";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schema {
+  Null,
+  Boolean,
+  Int,
+  Long,
+  Float,
+  Double,
+  Bytes,
+  String,
+  Record(Vec<(String, Schema)>),
+  Enum(Vec<String>),
+  Array(Box<Schema>),
+  Map(Box<Schema>),
+  Union(Vec<Schema>),
+  Fixed(usize),
+}
+
+/// A decoded value. Unions decode to the value of their branch, and enums and fixed to strings and bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+  Null,
+  Boolean(bool),
+  Int(i32),
+  Long(i64),
+  Float(f32),
+  Double(f64),
+  Bytes(Vec<u8>),
+  String(String),
+  Record(Vec<(String, Value)>),
+  Array(Vec<Value>),
+  Map(Vec<(String, Value)>),
+}
+
+impl Schema {
+  /// Parse a schema from its JSON form. Logical types and Iceberg's field ids are ignored.
+  pub fn parse(json: &serde_json::Value) -> Result<Self, Error> {
+    parse(json, &mut HashMap::new())
+  }
+}
+
+impl Value {
+  /// A field of a record, or `None` for other values and fields that aren't there.
+  pub fn get(&self, name: &str) -> Option<&Value> {
+    match self {
+      Value::Record(fields) => fields.iter().find(|(n, _)| n == name).map(|(_, v)| v),
+      _ => None,
+    }
+  }
+
+  /// Set a field of a record, adding it if it isn't there.
+  pub fn set(&mut self, name: &str, value: Value) {
+    if let Value::Record(fields) = self {
+      match fields.iter_mut().find(|(n, _)| n == name) {
+        Some((_, v)) => *v = value,
+        None => fields.push((name.to_string(), value)),
+      }
+    }
+  }
+
+  pub fn as_long(&self) -> Option<i64> {
+    match self {
+      Value::Int(v) => Some(*v as i64),
+      Value::Long(v) => Some(*v),
+      _ => None,
+    }
+  }
+
+  pub fn as_str(&self) -> Option<&str> {
+    match self {
+      Value::String(s) => Some(s),
+      _ => None,
+    }
+  }
+}
+
+/// An object container file: a schema, metadata such as Iceberg's `partition-spec`, and records.
+#[derive(Debug, Clone)]
+pub struct Container {
+  /// The schema in its JSON form, which keeps attributes such as field ids that `Schema` drops.
+  pub schema: serde_json::Value,
+  pub metadata: BTreeMap<String, Vec<u8>>,
+  pub records: Vec<Value>,
+}
+
+impl Container {
+  pub fn new(schema: serde_json::Value, metadata: BTreeMap<String, Vec<u8>>, records: Vec<Value>) -> Self {
+    Self { schema, metadata, records }
+  }
+
+  pub fn read(bytes: &[u8]) -> Result<Self, Error> {
+    let mut input = bytes.strip_prefix(MAGIC).ok_or_else(|| invalid("not an Avro object container file"))?;
+    let meta_schema = Schema::Map(Box::new(Schema::Bytes));
+    let Value::Map(entries) = decode(&meta_schema, &mut input)? else { unreachable!() };
+    let mut metadata: BTreeMap<String, Vec<u8>> = entries.into_iter()
+      .map(|(k, v)| match v { Value::Bytes(b) => (k, b), _ => (k, Vec::new()) })
+      .collect();
+    let sync = take(&mut input, 16)?.to_vec();
+
+    let schema_json: serde_json::Value = serde_json::from_slice(&metadata.remove("avro.schema").ok_or_else(|| invalid("no schema"))?)?;
+    let schema = Schema::parse(&schema_json)?;
+    let codec = metadata.remove("avro.codec").map(String::from_utf8).transpose().map_err(|_| invalid("bad codec"))?;
+
+    let mut records = Vec::new();
+    while !input.is_empty() {
+      let count = read_long(&mut input)?;
+      let size = read_long(&mut input)?;
+      let block = take(&mut input, usize::try_from(size).map_err(|_| invalid("bad block size"))?)?;
+      let data = match codec.as_deref() {
+        None | Some("null") => block.to_vec(),
+        Some("deflate") => {
+          let mut data = Vec::new();
+          DeflateDecoder::new(block).read_to_end(&mut data).map_err(|e| Error::Data(Box::new(e)))?;
+          data
+        }
+        Some(codec) => return Err(invalid(&format!("unsupported codec {}", codec))),
+      };
+      let mut data = data.as_slice();
+      for _ in 0..count {
+        records.push(decode(&schema, &mut data)?);
+      }
+      if take(&mut input, 16)? != sync.as_slice() {
+        return Err(invalid("sync marker mismatch"));
+      }
+    }
+    Ok(Self { schema: schema_json, metadata, records })
+  }
+
+  /// The file, with all records in one deflate-compressed block.
+  pub fn write(&self) -> Result<Vec<u8>, Error> {
+    let schema = Schema::parse(&self.schema)?;
+    let mut data = Vec::new();
+    for record in &self.records {
+      encode(&schema, record, &mut data)?;
+    }
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&data).map_err(|e| Error::Data(Box::new(e)))?;
+    let block = encoder.finish().map_err(|e| Error::Data(Box::new(e)))?;
+
+    let mut metadata: Vec<(String, Value)> = self.metadata.iter()
+      .map(|(k, v)| (k.clone(), Value::Bytes(v.clone())))
+      .collect();
+    metadata.push(("avro.schema".into(), Value::Bytes(self.schema.to_string().into_bytes())));
+    metadata.push(("avro.codec".into(), Value::Bytes(b"deflate".to_vec())));
+    let sync: [u8; 16] = rand::random();
+
+    let mut out = MAGIC.to_vec();
+    encode(&Schema::Map(Box::new(Schema::Bytes)), &Value::Map(metadata), &mut out)?;
+    out.extend_from_slice(&sync);
+    if !self.records.is_empty() {
+      write_long(self.records.len() as i64, &mut out);
+      write_long(block.len() as i64, &mut out);
+      out.extend_from_slice(&block);
+      out.extend_from_slice(&sync);
+    }
+    Ok(out)
+  }
+}
+
+/// `[null, <schema>]`, the type of every optional Iceberg field.
+pub fn optional(schema: serde_json::Value) -> serde_json::Value {
+  json!(["null", schema])
+}
+
+fn invalid(what: &str) -> Error {
+  Error::Data(format!("Invalid Avro file: {}", what).into())
+}
+
+fn parse(json: &serde_json::Value, named: &mut HashMap<String, Schema>) -> Result<Schema, Error> {
+  use serde_json::Value as J;
+  Ok(match json {
+    J::String(name) => match name.as_str() {
+      "null" => Schema::Null,
+      "boolean" => Schema::Boolean,
+      "int" => Schema::Int,
+      "long" => Schema::Long,
+      "float" => Schema::Float,
+      "double" => Schema::Double,
+      "bytes" => Schema::Bytes,
+      "string" => Schema::String,
+      name => named.get(name).cloned().ok_or_else(|| invalid(&format!("unknown type {}", name)))?,
+    },
+    J::Array(branches) => Schema::Union(branches.iter().map(|b| parse(b, named)).collect::<Result<_, _>>()?),
+    J::Object(o) => {
+      let schema = match o.get("type") {
+        Some(J::String(t)) if t == "record" || t == "error" => {
+          let fields = o.get("fields").and_then(J::as_array).ok_or_else(|| invalid("record without fields"))?;
+          Schema::Record(fields.iter()
+            .map(|f| {
+              let name = f["name"].as_str().ok_or_else(|| invalid("field without a name"))?;
+              Ok((name.to_string(), parse(&f["type"], named)?))
+            })
+            .collect::<Result<_, Error>>()?)
+        }
+        Some(J::String(t)) if t == "enum" => Schema::Enum(o.get("symbols").and_then(J::as_array)
+          .map(|s| s.iter().filter_map(|s| s.as_str().map(String::from)).collect())
+          .unwrap_or_default()),
+        Some(J::String(t)) if t == "array" => Schema::Array(Box::new(parse(&o["items"], named)?)),
+        Some(J::String(t)) if t == "map" => Schema::Map(Box::new(parse(&o["values"], named)?)),
+        Some(J::String(t)) if t == "fixed" => Schema::Fixed(o.get("size").and_then(J::as_u64).ok_or_else(|| invalid("fixed without a size"))? as usize),
+        Some(t) => parse(t, named)?,
+        None => return Err(invalid("schema without a type")),
+      };
+      if let Some(name) = o.get("name").and_then(J::as_str) {
+        named.insert(name.to_string(), schema.clone());
+      }
+      schema
+    }
+    _ => return Err(invalid("bad schema")),
+  })
+}
+
+fn decode(schema: &Schema, input: &mut &[u8]) -> Result<Value, Error> {
+  Ok(match schema {
+    Schema::Null => Value::Null,
+    Schema::Boolean => Value::Boolean(take(input, 1)?[0] != 0),
+    Schema::Int => Value::Int(i32::try_from(read_long(input)?).map_err(|_| invalid("int out of range"))?),
+    Schema::Long => Value::Long(read_long(input)?),
+    Schema::Float => Value::Float(f32::from_le_bytes(take(input, 4)?.try_into().unwrap())),
+    Schema::Double => Value::Double(f64::from_le_bytes(take(input, 8)?.try_into().unwrap())),
+    Schema::Bytes => Value::Bytes(read_bytes(input)?.to_vec()),
+    Schema::String => Value::String(String::from_utf8(read_bytes(input)?.to_vec()).map_err(|_| invalid("bad string"))?),
+    Schema::Fixed(size) => Value::Bytes(take(input, *size)?.to_vec()),
+    Schema::Enum(symbols) => {
+      let index = read_long(input)?;
+      Value::String(symbols.get(index as usize).cloned().ok_or_else(|| invalid("bad enum index"))?)
+    }
+    Schema::Record(fields) => Value::Record(fields.iter()
+      .map(|(name, schema)| Ok((name.clone(), decode(schema, input)?)))
+      .collect::<Result<_, Error>>()?),
+    Schema::Union(branches) => {
+      let index = read_long(input)?;
+      decode(branches.get(index as usize).ok_or_else(|| invalid("bad union index"))?, input)?
+    }
+    Schema::Array(items) => {
+      let mut values = Vec::new();
+      read_blocks(input, |input| {
+        values.push(decode(items, input)?);
+        Ok(())
+      })?;
+      Value::Array(values)
+    }
+    Schema::Map(values) => {
+      let mut entries = Vec::new();
+      read_blocks(input, |input| {
+        let key = String::from_utf8(read_bytes(input)?.to_vec()).map_err(|_| invalid("bad map key"))?;
+        entries.push((key, decode(values, input)?));
+        Ok(())
+      })?;
+      Value::Map(entries)
+    }
+  })
+}
+
+/// The items of an array or map, in blocks that each start with a count. A negative count is followed
+/// by the block's size in bytes.
+fn read_blocks(input: &mut &[u8], mut item: impl FnMut(&mut &[u8]) -> Result<(), Error>) -> Result<(), Error> {
+  loop {
+    let mut count = read_long(input)?;
+    if count == 0 {
+      return Ok(());
+    }
+    if count < 0 {
+      count = -count;
+      read_long(input)?;
+    }
+    for _ in 0..count {
+      item(input)?;
+    }
+  }
+}
+
+fn encode(schema: &Schema, value: &Value, out: &mut Vec<u8>) -> Result<(), Error> {
+  let mismatch = || Error::Data(format!("Avro value {:?} doesn't match schema {:?}", value, schema).into());
+  match (schema, value) {
+    (Schema::Null, Value::Null) => {}
+    (Schema::Boolean, Value::Boolean(b)) => out.push(*b as u8),
+    (Schema::Int, Value::Int(v)) => write_long(*v as i64, out),
+    (Schema::Long, v) => write_long(v.as_long().ok_or_else(mismatch)?, out),
+    (Schema::Float, Value::Float(v)) => out.extend_from_slice(&v.to_le_bytes()),
+    (Schema::Double, Value::Double(v)) => out.extend_from_slice(&v.to_le_bytes()),
+    (Schema::Bytes, Value::Bytes(b)) => write_bytes(b, out),
+    (Schema::String, Value::String(s)) => write_bytes(s.as_bytes(), out),
+    (Schema::Fixed(size), Value::Bytes(b)) if b.len() == *size => out.extend_from_slice(b),
+    (Schema::Enum(symbols), Value::String(s)) => {
+      write_long(symbols.iter().position(|sym| sym == s).ok_or_else(mismatch)? as i64, out);
+    }
+    (Schema::Record(fields), Value::Record(_)) => {
+      for (name, schema) in fields {
+        // Fields missing from the value can only be left out if they're optional
+        encode(schema, value.get(name).unwrap_or(&Value::Null), out)?;
+      }
+    }
+    (Schema::Union(branches), value) => {
+      let index = branches.iter().position(|b| matches(b, value)).ok_or_else(mismatch)?;
+      write_long(index as i64, out);
+      encode(&branches[index], value, out)?;
+    }
+    (Schema::Array(items), Value::Array(values)) => {
+      if !values.is_empty() {
+        write_long(values.len() as i64, out);
+        for v in values {
+          encode(items, v, out)?;
+        }
+      }
+      out.push(0);
+    }
+    (Schema::Map(schema), Value::Map(entries)) => {
+      if !entries.is_empty() {
+        write_long(entries.len() as i64, out);
+        for (k, v) in entries {
+          write_bytes(k.as_bytes(), out);
+          encode(schema, v, out)?;
+        }
+      }
+      out.push(0);
+    }
+    _ => return Err(mismatch()),
+  }
+  Ok(())
+}
+
+/// Whether `value` belongs in union branch `schema`.
+fn matches(schema: &Schema, value: &Value) -> bool {
+  matches!((schema, value),
+    (Schema::Null, Value::Null)
+      | (Schema::Boolean, Value::Boolean(_))
+      | (Schema::Int, Value::Int(_))
+      | (Schema::Long, Value::Long(_) | Value::Int(_))
+      | (Schema::Float, Value::Float(_))
+      | (Schema::Double, Value::Double(_))
+      | (Schema::Bytes | Schema::Fixed(_), Value::Bytes(_))
+      | (Schema::String | Schema::Enum(_), Value::String(_))
+      | (Schema::Record(_), Value::Record(_))
+      | (Schema::Array(_), Value::Array(_))
+      | (Schema::Map(_), Value::Map(_)))
+}
+
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8], Error> {
+  if input.len() < n {
+    return Err(invalid("unexpected end of data"));
+  }
+  let (head, rest) = input.split_at(n);
+  *input = rest;
+  Ok(head)
+}
+
+/// A zig-zag encoded variable-length long.
+fn read_long(input: &mut &[u8]) -> Result<i64, Error> {
+  let mut n: u64 = 0;
+  for shift in (0..64).step_by(7) {
+    let b = take(input, 1)?[0];
+    n |= ((b & 0x7f) as u64) << shift;
+    if b & 0x80 == 0 {
+      return Ok((n >> 1) as i64 ^ -((n & 1) as i64));
+    }
+  }
+  Err(invalid("varint too long"))
+}
+
+fn read_bytes<'a>(input: &mut &'a [u8]) -> Result<&'a [u8], Error> {
+  let len = read_long(input)?;
+  take(input, usize::try_from(len).map_err(|_| invalid("negative length"))?)
+}
+
+fn write_long(v: i64, out: &mut Vec<u8>) {
+  let mut n = ((v << 1) ^ (v >> 63)) as u64;
+  while n >= 0x80 {
+    out.push((n as u8) | 0x80);
+    n >>= 7;
+  }
+  out.push(n as u8);
+}
+
+fn write_bytes(b: &[u8], out: &mut Vec<u8>) {
+  write_long(b.len() as i64, out);
+  out.extend_from_slice(b);
+}
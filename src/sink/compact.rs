@@ -0,0 +1,140 @@
+// src/sink/compact.rs
+// Merges a table's small files into files of about the target size, partition by partition.
+// Each merged file replaces its inputs in a single commit entry, so readers see either the inputs or the output.
+
+use std::collections::BTreeMap;
+use arrow::compute::concat_batches;
+use arrow_array::RecordBatch;
+use object_store::{ObjectStore, path::Path};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use sha2::{Digest, Sha256};
+use crate::error::Error;
+use crate::retry::RetryPolicy;
+use crate::sink::encoding::{Encoding, FileFormat};
+use crate::sink::encryption;
+use crate::sink::parquet_commit::{CommitRecord, ParquetCommit};
+use crate::sink::parquet_writer::{DataFile, ParquetOptions, ParquetSink, Provenance};
+use crate::sink::partition::PartitionKey;
+use crate::sink::sort::RowOrder;
+use crate::store::Warehouse;
+
+pub struct CompactOptions {
+  /// Files smaller than this are merged, into files of about this size.
+  pub target_file_size: usize,
+  /// Order of the merged rows: a sort order or a Z-order/Hilbert clustering.
+  pub order: RowOrder,
+  /// Column whose range merged files record as their source update times.
+  pub update_time_column: Option<String>,
+}
+
+/// What a compaction did.
+#[derive(Debug, Default)]
+pub struct CompactSummary {
+  pub files_removed: usize,
+  /// The merged files that were committed.
+  pub added: Vec<DataFile>,
+}
+
+pub struct Compactor<'a> {
+  sink: &'a ParquetSink,
+  commit: &'a ParquetCommit,
+  warehouse: Warehouse,
+  retry: RetryPolicy,
+  format: FileFormat,
+  opts: CompactOptions,
+}
+
+impl<'a> Compactor<'a> {
+  pub fn new(sink: &'a ParquetSink, commit: &'a ParquetCommit, warehouse: &Warehouse, retry: RetryPolicy, parquet: ParquetOptions, opts: CompactOptions) -> Self {
+    Self { sink, commit, warehouse: warehouse.clone(), retry, format: FileFormat::Parquet(parquet), opts }
+  }
+
+  pub async fn run(&self, ns: &str, table: &str) -> anyhow::Result<CompactSummary> {
+    let data_prefix = format!("{}/data/", self.warehouse.table_path(ns, table));
+
+    // Small Parquet files by partition directory; every file sits at `data/<partition>/run_id=<id>/<file>`
+    let mut partitions: BTreeMap<String, Vec<CommitRecord>> = BTreeMap::new();
+    for file in self.commit.committed_files(ns, table).await? {
+      if file.size as usize >= self.opts.target_file_size || Encoding::of(&file.path) != Some(Encoding::Parquet) {
+        continue;
+      }
+      let Some(rest) = file.path.strip_prefix(&data_prefix) else { continue };
+      let mut parts = rest.rsplitn(3, '/');
+      if let (Some(_), Some(_), Some(partition)) = (parts.next(), parts.next(), parts.next()) {
+        partitions.entry(partition.to_string()).or_default().push(file);
+      }
+    }
+
+    let mut summary = CompactSummary::default();
+    for (partition, mut files) in partitions {
+      files.sort_by(|a, b| a.path.cmp(&b.path));
+
+      // Pack files into bins of about the target size; a bin of one file has nothing to merge with
+      let mut bins: Vec<Vec<CommitRecord>> = vec![Vec::new()];
+      let mut bin_size = 0;
+      for file in files {
+        if bin_size >= self.opts.target_file_size {
+          bins.push(Vec::new());
+          bin_size = 0;
+        }
+        bin_size += file.size as usize;
+        bins.last_mut().expect("there is always a bin").push(file);
+      }
+
+      for bin in bins.into_iter().filter(|b| b.len() > 1) {
+        let removed: Vec<String> = bin.iter().map(|f| f.path.clone()).collect();
+        let Some(batch) = self.read(&bin).await? else {
+          println!("⚠️ Skipping {} files in {}: their schemas differ", removed.len(), partition);
+          continue;
+        };
+        let batch = self.opts.order.apply(&batch)?;
+
+        // Named after its inputs, so retrying the same compaction writes the same file
+        let id = hex::encode(&Sha256::digest(removed.join("\n").as_bytes())[..8]);
+        let key = PartitionKey { path: partition.clone(), values: Vec::new() };
+        let file = format!("run_id={}/part-00000.parquet", id);
+        let provenance = Provenance {
+          namespace: ns.into(),
+          collection: table.into(),
+          run_id: id,
+          checkpoint: None,
+          update_time_column: self.opts.update_time_column.clone(),
+        };
+        let mut out = self.sink.create(&provenance, &key, &file, batch.schema(), &self.format, &self.opts.order).await?;
+        out.write(&batch, None).await?;
+        let added = out.close().await?;
+
+        println!("🗜️ Compacting {} files ({} rows) into {}", removed.len(), added.rows, added.path);
+        if self.commit.replace_files(ns, table, std::slice::from_ref(&added), &removed).await? {
+          summary.files_removed += removed.len();
+          summary.added.push(added);
+        }
+      }
+    }
+    Ok(summary)
+  }
+
+  /// Read and concatenate `files`, or `None` if their schemas differ.
+  async fn read(&self, files: &[CommitRecord]) -> anyhow::Result<Option<RecordBatch>> {
+    let mut batches = Vec::new();
+    for file in files {
+      let path = Path::parse(&file.path)?;
+      let mut bytes = self.retry.run(&format!("reading {}", path), || async {
+        Ok::<_, Error>(self.warehouse.store.get(&path).await?.bytes().await?)
+      }).await?;
+      if file.encrypted {
+        let keys = self.sink.keys()
+          .ok_or_else(|| Error::config(format!("{} is encrypted: set ENCRYPTION_KEYS_PATH to compact it", path)))?;
+        bytes = encryption::decrypt(&bytes, keys)?.into();
+      }
+      for batch in ParquetRecordBatchReaderBuilder::try_new(bytes)?.build()? {
+        batches.push(batch?);
+      }
+    }
+    let Some(schema) = batches.first().map(|b| b.schema()) else { return Ok(None) };
+    if batches.iter().any(|b| b.schema() != schema) {
+      return Ok(None);
+    }
+    Ok(Some(concat_batches(&schema, &batches)?))
+  }
+}
@@ -0,0 +1,776 @@
+// src/sink/delta.rs
+// Commits the files written by `ParquetSink` to a Delta Lake transaction log under `<table>/_delta_log/`,
+// so Spark, DuckDB and Polars can read the table as Delta. Each version is a `<version>.json` file of
+// actions, created only if it doesn't exist yet: a writer that loses the race reloads the log and tries
+// the next version. Every `checkpoint_interval` versions the table state is also written as a Parquet
+// checkpoint, which readers load instead of replaying the whole log. The commit info of each version holds
+// the source checkpoint its data holds every change until, so a run resumes from the data readers see.
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::Cursor;
+use std::sync::Arc;
+use arrow::datatypes::{DataType, Field, Fields, Schema, TimeUnit};
+use arrow::json::{LineDelimitedWriter, ReaderBuilder};
+use chrono::{DateTime, NaiveDate};
+use futures::StreamExt;
+use object_store::{ObjectStore, PutMode, PutPayload, path::Path};
+use parquet::arrow::ArrowWriter;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use serde_json::{Value, json};
+use crate::error::Error;
+use crate::retry::RetryPolicy;
+use crate::sink::encoding::Encoding;
+use crate::sink::parquet_commit::CommitRecord;
+use crate::sink::parquet_writer::DataFile;
+use crate::sink::partition::{partition_of, partition_values};
+use crate::sink::stats::{FileStats, StatValue};
+use crate::source::firestore_listen::Checkpoint;
+use crate::store::Warehouse;
+
+/// Longest string kept as a min or max value in file stats.
+const STATS_STRING_PREFIX: usize = 32;
+
+/// Table properties recording how rows are ordered within files: the sort order of ingested files
+/// and the clustering of compacted ones.
+pub const SORT_ORDER_PROPERTY: &str = "fire_to_ice.sort_order";
+pub const CLUSTERING_PROPERTY: &str = "fire_to_ice.clustering";
+
+/// Commit info field with the source checkpoint of a version.
+const CHECKPOINT_INFO: &str = "fire_to_ice.checkpoint";
+
+pub struct DeltaLog {
+  warehouse: Warehouse,
+  retry: RetryPolicy,
+  table: String,
+  /// The table directory; add and remove paths are relative to it.
+  root: Path,
+  checkpoint_interval: u64,
+  version: Option<u64>,
+  protocol: Option<Value>,
+  metadata: Option<Value>,
+  /// Columns of the current schema, partition columns included, as Delta struct fields.
+  fields: Vec<Value>,
+  /// Add actions of the files in the current version, by path.
+  files: BTreeMap<String, Value>,
+  /// Table properties to set in the metadata with the next schema written.
+  properties: BTreeMap<String, String>,
+  /// Source checkpoint of the latest version that has one, and the one to commit with the next version.
+  last_checkpoint: Option<Checkpoint>,
+  checkpoint: Option<Checkpoint>,
+}
+
+impl DeltaLog {
+  /// Load the latest version of `ns.table`'s log. A table without a log starts at version 0 on its first commit.
+  pub async fn open(warehouse: &Warehouse, retry: RetryPolicy, ns: &str, table: &str, checkpoint_interval: u64) -> Result<Self, Error> {
+    let mut log = Self {
+      warehouse: warehouse.clone(),
+      retry,
+      table: table.to_string(),
+      root: warehouse.table_path(ns, table),
+      checkpoint_interval: checkpoint_interval.max(1),
+      version: None,
+      protocol: None,
+      metadata: None,
+      fields: Vec::new(),
+      files: BTreeMap::new(),
+      properties: BTreeMap::new(),
+      last_checkpoint: None,
+      checkpoint: None,
+    };
+    log.refresh().await?;
+    Ok(log)
+  }
+
+  /// Set a table property, such as the sort order, in the metadata of the next commit that has files.
+  pub fn set_property(&mut self, key: &str, value: String) {
+    self.properties.insert(key.to_string(), value);
+  }
+
+  /// Record `checkpoint` in the commit info of the versions committed from now on.
+  pub fn set_checkpoint(&mut self, checkpoint: Checkpoint) {
+    self.checkpoint = Some(checkpoint);
+  }
+
+  /// The source checkpoint of the latest version that has one.
+  pub fn last_checkpoint(&self) -> Option<Checkpoint> {
+    self.last_checkpoint.clone()
+  }
+
+  /// Add the files an ingestion run committed. Files already in the log are skipped, so committing
+  /// the same files again, e.g. after a retried run, is a no-op unless the checkpoint set moved on.
+  /// Only Parquet files belong in a Delta table; other encodings are left out. Returns the new version,
+  /// if any.
+  pub async fn append(&mut self, files: &[DataFile]) -> Result<Option<u64>, Error> {
+    let files: Vec<&DataFile> = files.iter().filter(|f| f.encoding == Encoding::Parquet).collect();
+    let Some(last) = files.last() else {
+      // A run without files still moves the table to its checkpoint
+      return self.commit(Vec::new(), Vec::new(), None, true, "WRITE").await;
+    };
+    let partition_columns: Vec<String> = partition_values(&last.partition).into_iter().map(|(name, _)| name).collect();
+    let fields = with_partition_columns(arrow_fields(last.schema.fields())?, &partition_columns);
+
+    let adds = files.iter()
+      .map(|f| self.add(&f.path, &f.partition, f.size, Some(&f.stats), &f.schema, &fields, true))
+      .collect::<Result<Vec<_>, _>>()?;
+    self.commit(adds, Vec::new(), Some((fields, partition_columns)), true, "WRITE").await
+  }
+
+  /// Make the log match `live`, the files committed under `_committed/`, after a compaction. Stats are
+  /// taken from `written` where a file was just written; other files are added with a row count only.
+  /// Open the log before listing `live`, so files appended in between are not mistaken for removed ones.
+  pub async fn sync(&mut self, live: &[CommitRecord], written: &[DataFile]) -> Result<Option<u64>, Error> {
+    let live: Vec<&CommitRecord> = live.iter().filter(|f| Encoding::of(&f.path) == Some(Encoding::Parquet)).collect();
+    let live_paths: HashSet<String> = live.iter().map(|f| self.relative(&f.path)).collect::<Result<_, _>>()?;
+    let removed: Vec<String> = self.files.keys().filter(|p| !live_paths.contains(*p)).cloned().collect();
+
+    let schema = match written.last() {
+      Some(last) => {
+        let partition_columns: Vec<String> = partition_values(&last.partition).into_iter().map(|(name, _)| name).collect();
+        Some((with_partition_columns(arrow_fields(last.schema.fields())?, &partition_columns), partition_columns))
+      }
+      None => None,
+    };
+    let fields = schema.as_ref().map_or(self.fields.clone(), |(fields, _)| fields.clone());
+
+    let mut adds = Vec::new();
+    for record in live {
+      if self.files.contains_key(&self.relative(&record.path)?) {
+        continue;
+      }
+      let add = match written.iter().find(|f| f.path == record.path) {
+        Some(f) => self.add(&f.path, &f.partition, f.size, Some(&f.stats), &f.schema, &fields, false)?,
+        None => {
+          if self.metadata.is_none() && schema.is_none() {
+            return Err(Error::Data(format!("Delta table {} has no schema yet; run an ingestion first", self.table).into()));
+          }
+          let partition = partition_of(&record.path).unwrap_or_default();
+          let stats = FileStats { rows: record.rows, ..Default::default() };
+          self.add(&record.path, partition, record.size, Some(&stats), &Schema::empty(), &fields, false)?
+        }
+      };
+      adds.push(add);
+    }
+    self.commit(adds, removed, schema, false, "OPTIMIZE").await
+  }
+
+  /// Reload the log up to its latest version.
+  async fn refresh(&mut self) -> Result<(), Error> {
+    if self.version.is_none() && let Some(version) = self.last_checkpoint_version().await? {
+      self.load_checkpoint(version).await?;
+    }
+
+    // Versions sort by name, so only list the ones after the version already loaded
+    let log_dir = self.root.child("_delta_log");
+    let offset = self.version.map(|v| log_dir.child(format!("{:020}.json", v)));
+    let mut versions: Vec<(u64, Path)> = self.retry.run(&format!("listing {}", log_dir), || async {
+      let mut objects = match &offset {
+        Some(offset) => self.warehouse.store.list_with_offset(Some(&log_dir), offset),
+        None => self.warehouse.store.list(Some(&log_dir)),
+      };
+      let mut versions = Vec::new();
+      while let Some(meta) = objects.next().await {
+        let location = meta?.location;
+        if let Some(version) = location.filename().and_then(commit_version) {
+          versions.push((version, location));
+        }
+      }
+      Ok(versions)
+    }).await?;
+    versions.sort();
+
+    for (version, location) in versions {
+      if self.version.is_some_and(|v| version <= v) {
+        continue;
+      }
+      let bytes = self.get(&location).await?;
+      for line in bytes.split(|b| *b == b'\n').filter(|l| !l.iter().all(u8::is_ascii_whitespace)) {
+        self.apply(&serde_json::from_slice(line)?);
+      }
+      self.version = Some(version);
+    }
+    Ok(())
+  }
+
+  async fn last_checkpoint_version(&self) -> Result<Option<u64>, Error> {
+    let path = self.root.child("_delta_log").child("_last_checkpoint");
+    let bytes = match self.get(&path).await {
+      Ok(bytes) => bytes,
+      Err(e) if is_not_found(&e) => return Ok(None),
+      Err(e) => return Err(e),
+    };
+    let last: Value = serde_json::from_slice(&bytes)?;
+    Ok(last["version"].as_u64())
+  }
+
+  async fn load_checkpoint(&mut self, version: u64) -> Result<(), Error> {
+    let path = self.root.child("_delta_log").child(format!("{:020}.checkpoint.parquet", version));
+    let bytes = self.get(&path).await?;
+    for batch in ParquetRecordBatchReaderBuilder::try_new(bytes)?.build()? {
+      // Each row holds one action; null columns are left out, which leaves just that action
+      let mut writer = LineDelimitedWriter::new(Vec::new());
+      writer.write(&batch?)?;
+      writer.finish()?;
+      for line in writer.into_inner().split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+        self.apply(&serde_json::from_slice(line)?);
+      }
+    }
+    // Checkpoints leave out commit info, so the source checkpoint is read from the version's commit
+    let commit = self.root.child("_delta_log").child(format!("{:020}.json", version));
+    match self.get(&commit).await {
+      Ok(bytes) => {
+        for line in bytes.split(|b| *b == b'\n').filter(|l| !l.iter().all(u8::is_ascii_whitespace)) {
+          let action: Value = serde_json::from_slice(line)?;
+          if action.get("commitInfo").is_some() {
+            self.apply(&action);
+          }
+        }
+      }
+      Err(e) if is_not_found(&e) => {}
+      Err(e) => return Err(e),
+    }
+    self.version = Some(version);
+    Ok(())
+  }
+
+  fn apply(&mut self, action: &Value) {
+    if let Some(info) = action.get("commitInfo").and_then(|i| i.get(CHECKPOINT_INFO))
+      && let Ok(checkpoint) = serde_json::from_value(info.clone()) {
+      self.last_checkpoint = Some(checkpoint);
+    }
+    if let Some(protocol) = action.get("protocol") {
+      self.protocol = Some(protocol.clone());
+    }
+    if let Some(metadata) = action.get("metaData") {
+      self.fields = metadata["schemaString"].as_str()
+        .and_then(|s| serde_json::from_str::<Value>(s).ok())
+        .and_then(|s| s["fields"].as_array().cloned())
+        .unwrap_or_default();
+      self.metadata = Some(metadata.clone());
+    }
+    if let Some(add) = action.get("add") && let Some(path) = add["path"].as_str() {
+      self.files.insert(path.to_string(), add.clone());
+    }
+    if let Some(remove) = action.get("remove") && let Some(path) = remove["path"].as_str() {
+      self.files.remove(path);
+    }
+  }
+
+  /// Write the next version with `adds` (path and add action) and `removes` (paths), skipping files
+  /// that are already added or already gone. `schema` is the table's columns and partition columns,
+  /// when known. `data_change` is false when the rows stay the same, as in a compaction. The version
+  /// holds the checkpoint set, or else the latest one, and is written without files if only the
+  /// checkpoint moved. Returns the new version, or `None` if there was nothing to commit.
+  async fn commit(&mut self, adds: Vec<(String, Value)>, removes: Vec<String>, schema: Option<(Vec<Value>, Vec<String>)>, data_change: bool, operation: &str) -> Result<Option<u64>, Error> {
+    loop {
+      let version = self.version.map_or(0, |v| v + 1);
+      let adds: Vec<&Value> = adds.iter().filter(|(p, _)| !self.files.contains_key(p)).map(|(_, a)| a).collect();
+      let removes: Vec<&Value> = removes.iter().filter_map(|p| self.files.get(p)).collect();
+      let moved = self.checkpoint.is_some() && self.checkpoint != self.last_checkpoint;
+      if adds.is_empty() && removes.is_empty() && !moved {
+        return Ok(None);
+      }
+
+      let now = chrono::Utc::now().timestamp_millis();
+      let mut info = json!({
+        "timestamp": now,
+        "operation": operation,
+        "operationParameters": {},
+        "isBlindAppend": removes.is_empty(),
+        "engineInfo": concat!("fire-to-ice/", env!("CARGO_PKG_VERSION")),
+      });
+      if let Some(checkpoint) = self.checkpoint.as_ref().or(self.last_checkpoint.as_ref()) {
+        info[CHECKPOINT_INFO] = json!(checkpoint);
+      }
+      let mut actions = vec![json!({ "commitInfo": info })];
+      if let Some((fields, _)) = &schema && let Some(protocol) = self.protocol_update(fields) {
+        actions.push(json!({ "protocol": protocol }));
+      }
+      if let Some((fields, partition_columns)) = &schema && let Some(metadata) = self.metadata_update(fields, partition_columns)? {
+        actions.push(json!({ "metaData": metadata }));
+      }
+      for add in &removes {
+        actions.push(json!({ "remove": {
+          "path": add["path"],
+          "deletionTimestamp": now,
+          "dataChange": data_change,
+          "extendedFileMetadata": true,
+          "partitionValues": add["partitionValues"],
+          "size": add["size"],
+        }}));
+      }
+      actions.extend(adds.into_iter().map(|add| json!({ "add": add })));
+
+      let mut body = String::new();
+      for action in &actions {
+        body.push_str(&action.to_string());
+        body.push('\n');
+      }
+      let path = self.root.child("_delta_log").child(format!("{:020}.json", version));
+      let created = self.retry.run(&format!("writing {}", path), || async {
+        let payload = PutPayload::from(body.clone().into_bytes());
+        match self.warehouse.store.put_opts(&path, payload, PutMode::Create.into()).await {
+          Ok(_) => Ok(true),
+          Err(object_store::Error::AlreadyExists { .. }) => Ok(false),
+          Err(e) => Err(e.into()),
+        }
+      }).await?;
+      if !created {
+        // Another writer took this version; catch up and try the next one
+        println!("⚠️ Delta version {} of {} already exists, reloading the log", version, self.table);
+        self.refresh().await?;
+        continue;
+      }
+
+      for action in &actions {
+        self.apply(action);
+      }
+      self.version = Some(version);
+      println!("🔺 Committed Delta version {} of {}", version, self.table);
+      if version > 0 && version.is_multiple_of(self.checkpoint_interval) {
+        self.checkpoint(version).await?;
+      }
+      return Ok(Some(version));
+    }
+  }
+
+  /// The protocol to write with `fields`, if the current one doesn't cover them.
+  /// Timestamps without a time zone need the `timestampNtz` table feature.
+  fn protocol_update(&self, fields: &[Value]) -> Option<Value> {
+    let ntz = fields.iter().any(|f| f.to_string().contains("\"timestamp_ntz\""));
+    match &self.protocol {
+      None if ntz => Some(json!({
+        "minReaderVersion": 3,
+        "minWriterVersion": 7,
+        "readerFeatures": ["timestampNtz"],
+        "writerFeatures": ["timestampNtz"],
+      })),
+      None => Some(json!({ "minReaderVersion": 1, "minWriterVersion": 2 })),
+      Some(current) if ntz && !has_feature(current, "readerFeatures", "timestampNtz") => {
+        let mut protocol = current.clone();
+        // Writer version 7 lists every feature explicitly, including the ones version 2 implied
+        let implied: &[&str] = if current["minWriterVersion"].as_u64().unwrap_or(0) < 7 { &["appendOnly", "invariants"] } else { &[] };
+        let writer_features: Vec<&str> = implied.iter().copied().chain(["timestampNtz"]).collect();
+        protocol["minReaderVersion"] = json!(current["minReaderVersion"].as_u64().unwrap_or(1).max(3));
+        protocol["minWriterVersion"] = json!(current["minWriterVersion"].as_u64().unwrap_or(2).max(7));
+        for (key, names) in [("readerFeatures", vec!["timestampNtz"]), ("writerFeatures", writer_features)] {
+          let mut features: Vec<Value> = current[key].as_array().cloned().unwrap_or_default();
+          for name in names {
+            if !features.iter().any(|f| f == name) {
+              features.push(json!(name));
+            }
+          }
+          protocol[key] = json!(features);
+        }
+        Some(protocol)
+      }
+      Some(_) => None,
+    }
+  }
+
+  /// The metadata to write with `fields`, if the schema or a property changed. Partition columns can't change.
+  fn metadata_update(&self, fields: &[Value], partition_columns: &[String]) -> Result<Option<Value>, Error> {
+    if let Some(current) = &self.metadata {
+      let current_columns: Vec<&str> = current["partitionColumns"].as_array()
+        .map(|c| c.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+      if current_columns != partition_columns {
+        return Err(Error::Data(format!(
+          "Delta table {} is partitioned by {:?}, not {:?}", self.table, current_columns, partition_columns).into()));
+      }
+    }
+    let current = self.metadata.as_ref();
+    let mut configuration = current.and_then(|m| m["configuration"].as_object().cloned()).unwrap_or_default();
+    for (key, value) in &self.properties {
+      configuration.insert(key.clone(), json!(value));
+    }
+    if current.is_some_and(|m| m["configuration"] == Value::Object(configuration.clone())) && self.fields == fields {
+      return Ok(None);
+    }
+    Ok(Some(json!({
+      "id": current.and_then(|m| m["id"].as_str()).map_or_else(|| uuid::Uuid::new_v4().to_string(), String::from),
+      "name": self.table,
+      "format": { "provider": "parquet", "options": {} },
+      "schemaString": json!({ "type": "struct", "fields": fields }).to_string(),
+      "partitionColumns": partition_columns,
+      "configuration": configuration,
+      "createdTime": current.and_then(|m| m["createdTime"].as_i64()).unwrap_or_else(|| chrono::Utc::now().timestamp_millis()),
+    })))
+  }
+
+  /// Write the state at `version` as a single-part Parquet checkpoint and point `_last_checkpoint` at it.
+  async fn checkpoint(&self, version: u64) -> Result<(), Error> {
+    let mut rows = String::new();
+    let actions = [("protocol", self.protocol.as_ref()), ("metaData", self.metadata.as_ref())].into_iter()
+      .filter_map(|(key, action)| Some((key, action?)))
+      .chain(self.files.values().map(|add| ("add", add)));
+    let mut size = 0;
+    for (key, action) in actions {
+      rows.push_str(&json!({ key: action }).to_string());
+      rows.push('\n');
+      size += 1;
+    }
+
+    let schema = Arc::new(checkpoint_schema());
+    let mut parquet = Vec::new();
+    let mut writer = ArrowWriter::try_new(&mut parquet, schema.clone(), None)?;
+    for batch in ReaderBuilder::new(schema).build(Cursor::new(rows.into_bytes()))? {
+      writer.write(&batch?)?;
+    }
+    writer.close()?;
+
+    let log_dir = self.root.child("_delta_log");
+    let path = log_dir.child(format!("{:020}.checkpoint.parquet", version));
+    let last = log_dir.child("_last_checkpoint");
+    let last_body = json!({ "version": version, "size": size }).to_string();
+    self.retry.run(&format!("writing {}", path), || async {
+      self.warehouse.store.put(&path, PutPayload::from(parquet.clone())).await?;
+      self.warehouse.store.put(&last, PutPayload::from(last_body.clone().into_bytes())).await?;
+      Ok(())
+    }).await?;
+    println!("🔺 Wrote Delta checkpoint {} of {} ({} actions)", version, self.table, size);
+    Ok(())
+  }
+
+  /// An add action for a file at `path`, a full path as in `DataFile::path`.
+  #[allow(clippy::too_many_arguments)]
+  fn add(&self, path: &str, partition: &str, size: u64, stats: Option<&FileStats>, schema: &Schema, fields: &[Value], data_change: bool) -> Result<(String, Value), Error> {
+    let relative = self.relative(path)?;
+    let partitions = partition_values(partition);
+    let partition_values: serde_json::Map<String, Value> = partitions.iter()
+      .map(|(name, raw)| {
+        let value = partition_value(field_type(fields, name), raw.as_deref());
+        (name.clone(), value.map_or(Value::Null, Value::String))
+      })
+      .collect();
+    let mut action = json!({
+      "path": relative,
+      "partitionValues": partition_values,
+      "size": size,
+      "modificationTime": chrono::Utc::now().timestamp_millis(),
+      "dataChange": data_change,
+    });
+    if let Some(stats) = stats {
+      let partition_names: HashSet<&str> = partitions.iter().map(|(name, _)| name.as_str()).collect();
+      action["stats"] = json!(stats_json(stats, schema, &partition_names).to_string());
+    }
+    Ok((relative, action))
+  }
+
+  /// `path` relative to the table directory, in the URI-encoded form Delta stores.
+  fn relative(&self, path: &str) -> Result<String, Error> {
+    let relative = path.strip_prefix(&format!("{}/", self.root))
+      .ok_or_else(|| Error::Data(format!("{} is not in table {}", path, self.root).into()))?;
+    // Partition values are already percent-encoded in the object name, so the `%` itself must be encoded
+    Ok(relative.replace('%', "%25"))
+  }
+
+  async fn get(&self, path: &Path) -> Result<bytes::Bytes, Error> {
+    self.retry.run(&format!("reading {}", path), || async {
+      Ok(self.warehouse.store.get(path).await?.bytes().await?)
+    }).await
+  }
+}
+
+/// Whether `e` is a storage error for a missing object.
+fn is_not_found(e: &Error) -> bool {
+  matches!(e, Error::Data(e) if matches!(e.downcast_ref::<object_store::Error>(), Some(object_store::Error::NotFound { .. })))
+}
+
+/// The version of a commit file named `<version>.json`.
+fn commit_version(name: &str) -> Option<u64> {
+  let digits = name.strip_suffix(".json")?;
+  if digits.len() != 20 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+    return None;
+  }
+  digits.parse().ok()
+}
+
+fn has_feature(protocol: &Value, key: &str, feature: &str) -> bool {
+  protocol[key].as_array().is_some_and(|f| f.iter().any(|f| f == feature))
+}
+
+/// Append the partition columns that aren't data columns. Their types follow from the transform that
+/// named them (see `PartitionField::name`); identity and truncate keep the source column's type.
+fn with_partition_columns(mut fields: Vec<Value>, partition_columns: &[String]) -> Vec<Value> {
+  for name in partition_columns {
+    if field_type(&fields, name).is_some() {
+      continue;
+    }
+    let source_type = name.strip_suffix("_trunc").and_then(|source| field_type(&fields, source)).cloned();
+    let delta_type = if name == "ingest_date" || name.ends_with("_day") {
+      json!("date")
+    } else if name.ends_with("_year") || name.ends_with("_bucket") {
+      json!("integer")
+    } else {
+      source_type.unwrap_or(json!("string"))
+    };
+    fields.push(json!({ "name": name, "type": delta_type, "nullable": true, "metadata": {} }));
+  }
+  fields
+}
+
+fn field_type<'a>(fields: &'a [Value], name: &str) -> Option<&'a Value> {
+  fields.iter().find(|f| f["name"] == name).map(|f| &f["type"])
+}
+
+/// A partition value in Delta's string form. Identity partitions on dates and timestamps are named by
+/// their epoch days or milliseconds.
+fn partition_value(delta_type: Option<&Value>, raw: Option<&str>) -> Option<String> {
+  let raw = raw?;
+  let formatted = match delta_type.and_then(Value::as_str) {
+    Some("date") => raw.parse::<i64>().ok().map(|days| epoch_date(days).to_string()),
+    Some("timestamp" | "timestamp_ntz") => raw.parse::<i64>().ok()
+      .and_then(DateTime::from_timestamp_millis)
+      .map(|t| t.format("%Y-%m-%d %H:%M:%S%.6f").to_string()),
+    _ => None,
+  };
+  Some(formatted.unwrap_or_else(|| raw.to_string()))
+}
+
+fn epoch_date(days: i64) -> NaiveDate {
+  NaiveDate::default() + chrono::Duration::days(days)
+}
+
+/// Delta struct fields for Arrow fields.
+fn arrow_fields(fields: &Fields) -> Result<Vec<Value>, Error> {
+  fields.iter()
+    .map(|f| Ok(json!({ "name": f.name(), "type": delta_type(f.data_type())?, "nullable": f.is_nullable(), "metadata": {} })))
+    .collect()
+}
+
+fn delta_type(data_type: &DataType) -> Result<Value, Error> {
+  Ok(match data_type {
+    DataType::Utf8 | DataType::LargeUtf8 | DataType::Utf8View => json!("string"),
+    DataType::Binary | DataType::LargeBinary | DataType::BinaryView => json!("binary"),
+    DataType::Boolean => json!("boolean"),
+    DataType::Int8 => json!("byte"),
+    DataType::Int16 | DataType::UInt8 => json!("short"),
+    DataType::Int32 | DataType::UInt16 => json!("integer"),
+    DataType::Int64 | DataType::UInt32 => json!("long"),
+    DataType::UInt64 => json!("decimal(20,0)"),
+    DataType::Float32 => json!("float"),
+    DataType::Float64 => json!("double"),
+    DataType::Date32 => json!("date"),
+    DataType::Timestamp(_, None) => json!("timestamp_ntz"),
+    DataType::Timestamp(_, Some(_)) => json!("timestamp"),
+    DataType::Decimal128(precision, scale) => json!(format!("decimal({},{})", precision, scale)),
+    DataType::List(item) | DataType::LargeList(item) => json!({
+      "type": "array",
+      "elementType": delta_type(item.data_type())?,
+      "containsNull": item.is_nullable(),
+    }),
+    DataType::Struct(fields) => json!({ "type": "struct", "fields": arrow_fields(fields)? }),
+    DataType::Map(entries, _) => match entries.data_type() {
+      DataType::Struct(kv) if kv.len() == 2 => json!({
+        "type": "map",
+        "keyType": delta_type(kv[0].data_type())?,
+        "valueType": delta_type(kv[1].data_type())?,
+        "valueContainsNull": kv[1].is_nullable(),
+      }),
+      other => return Err(Error::Data(format!("Unsupported map entries {} in Delta table", other).into())),
+    },
+    other => return Err(Error::Data(format!("Column type {} is not supported in Delta tables", other).into())),
+  })
+}
+
+/// File stats in Delta's JSON form. Partition columns are left out, since readers prune on their values.
+/// Long strings are cut to a prefix for the minimum and left out of the maximum, where a prefix would be too low.
+fn stats_json(stats: &FileStats, schema: &Schema, partition_columns: &HashSet<&str>) -> Value {
+  let mut min_values = serde_json::Map::new();
+  let mut max_values = serde_json::Map::new();
+  let mut null_count = serde_json::Map::new();
+  for (name, column) in &stats.columns {
+    if partition_columns.contains(name.as_str()) {
+      continue;
+    }
+    let Ok(field) = schema.field_with_name(name) else { continue };
+    if field.data_type().is_nested() {
+      continue;
+    }
+    null_count.insert(name.clone(), json!(column.null_count));
+    let utc = matches!(field.data_type(), DataType::Timestamp(TimeUnit::Millisecond, Some(_)));
+    if let Some(min) = column.min.as_ref().and_then(|v| stat_value(v, utc, true)) {
+      min_values.insert(name.clone(), min);
+    }
+    if let Some(max) = column.max.as_ref().and_then(|v| stat_value(v, utc, false)) {
+      max_values.insert(name.clone(), max);
+    }
+  }
+  json!({ "numRecords": stats.rows, "minValues": min_values, "maxValues": max_values, "nullCount": null_count })
+}
+
+fn stat_value(value: &StatValue, utc: bool, is_min: bool) -> Option<Value> {
+  Some(match value {
+    StatValue::Int(v) => json!(v),
+    StatValue::Long(v) => json!(v),
+    StatValue::Float(v) => json!(v.is_finite().then_some(*v)?),
+    StatValue::Double(v) => json!(v.is_finite().then_some(*v)?),
+    StatValue::String(s) if s.chars().count() <= STATS_STRING_PREFIX => json!(s),
+    StatValue::String(s) if is_min => json!(s.chars().take(STATS_STRING_PREFIX).collect::<String>()),
+    StatValue::String(_) => return None,
+    StatValue::Date(days) => json!(epoch_date(*days as i64).to_string()),
+    StatValue::TimestampMs(ms) => {
+      let format = if utc { "%Y-%m-%dT%H:%M:%S%.3fZ" } else { "%Y-%m-%dT%H:%M:%S%.3f" };
+      json!(DateTime::from_timestamp_millis(*ms)?.format(format).to_string())
+    }
+  })
+}
+
+/// Columns of a checkpoint file, one action per row. Unused actions are kept as null columns.
+fn checkpoint_schema() -> Schema {
+  let string = |name: &str| Field::new(name, DataType::Utf8, true);
+  let long = |name: &str| Field::new(name, DataType::Int64, true);
+  let int = |name: &str| Field::new(name, DataType::Int32, true);
+  let boolean = |name: &str| Field::new(name, DataType::Boolean, true);
+  let strings = |name: &str| Field::new_list(name, Field::new("element", DataType::Utf8, true), true);
+  let map = |name: &str| Field::new_map(name, "key_value", Field::new("key", DataType::Utf8, false), Field::new("value", DataType::Utf8, true), false, true);
+  let action = |name: &str, fields: Vec<Field>| Field::new_struct(name, fields, true);
+
+  Schema::new(vec![
+    action("txn", vec![string("appId"), long("version"), long("lastUpdated")]),
+    action("add", vec![
+      string("path"), map("partitionValues"), long("size"), long("modificationTime"), boolean("dataChange"),
+      string("stats"), map("tags"),
+    ]),
+    action("remove", vec![
+      string("path"), long("deletionTimestamp"), boolean("dataChange"), boolean("extendedFileMetadata"),
+      map("partitionValues"), long("size"),
+    ]),
+    action("metaData", vec![
+      string("id"), string("name"), string("description"),
+      action("format", vec![string("provider"), map("options")]),
+      string("schemaString"), strings("partitionColumns"), map("configuration"), long("createdTime"),
+    ]),
+    action("protocol", vec![int("minReaderVersion"), int("minWriterVersion"), strings("readerFeatures"), strings("writerFeatures")]),
+  ])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use arrow_array::{Int64Array, RecordBatch, StringArray};
+  use object_store::RetryConfig;
+  use crate::store::GcsOptions;
+
+  fn warehouse() -> Warehouse {
+    Warehouse::open("memory://", RetryConfig::default(), &GcsOptions::default()).unwrap()
+  }
+
+  /// A file of `farm.orders` in `partition` holding the documents `ids`, with the stats the writer records.
+  fn data_file(warehouse: &Warehouse, name: &str, partition: &str, ids: &[&str]) -> DataFile {
+    let schema = Arc::new(Schema::new(vec![
+      Field::new("id", DataType::Utf8, false),
+      Field::new("qty", DataType::Int64, true),
+    ]));
+    let qty: Vec<Option<i64>> = (0..ids.len() as i64).map(|i| (i > 0).then_some(i)).collect();
+    let batch = RecordBatch::try_new(schema.clone(), vec![
+      Arc::new(StringArray::from(ids.to_vec())),
+      Arc::new(Int64Array::from(qty)),
+    ]).unwrap();
+    let mut stats = FileStats::default();
+    stats.update(&batch);
+    let table = warehouse.table_path("farm", "orders");
+    let dir = if partition.is_empty() { String::new() } else { format!("{}/", partition) };
+    DataFile {
+      path: format!("{}/data/{}{}", table, dir, name),
+      staging: format!("{}/_staging/{}{}", table, dir, name),
+      partition: partition.to_string(),
+      encoding: Encoding::Parquet,
+      schema,
+      size: 100,
+      rows: ids.len() as u64,
+      stats,
+      encrypted: false,
+      keys: None,
+      superseded: Vec::new(),
+    }
+  }
+
+  fn checkpoint(token: &str) -> Checkpoint {
+    Checkpoint { resume_token: token.to_string(), watermark_ms: 1_700_000_000_000 }
+  }
+
+  async fn open(warehouse: &Warehouse) -> DeltaLog {
+    DeltaLog::open(warehouse, RetryPolicy::default(), "farm", "orders", 2).await.unwrap()
+  }
+
+  #[tokio::test]
+  async fn commits_files_with_partition_values_and_stats() {
+    let warehouse = warehouse();
+    let mut log = open(&warehouse).await;
+    log.set_checkpoint(checkpoint("01"));
+    let file = data_file(&warehouse, "a.parquet", "variety_id=v1", &["a", "b", "c"]);
+    assert_eq!(log.append(std::slice::from_ref(&file)).await.unwrap(), Some(0));
+
+    let add = &log.files["data/variety_id=v1/a.parquet"];
+    assert_eq!(add["partitionValues"], json!({ "variety_id": "v1" }));
+    let stats: Value = serde_json::from_str(add["stats"].as_str().unwrap()).unwrap();
+    assert_eq!(stats["numRecords"], 3);
+    assert_eq!(stats["minValues"], json!({ "id": "a", "qty": 1 }));
+    assert_eq!(stats["maxValues"], json!({ "id": "c", "qty": 2 }));
+    assert_eq!(stats["nullCount"], json!({ "id": 0, "qty": 1 }));
+    let metadata = log.metadata.as_ref().unwrap();
+    assert_eq!(metadata["partitionColumns"], json!(["variety_id"]));
+
+    // The same files again are a no-op
+    assert_eq!(log.append(&[file]).await.unwrap(), None);
+  }
+
+  #[tokio::test]
+  async fn reopens_from_the_checkpoint_and_the_versions_after_it() {
+    let warehouse = warehouse();
+    let mut log = open(&warehouse).await;
+    for (version, name) in ["a", "b", "c"].into_iter().enumerate() {
+      log.set_checkpoint(checkpoint(&format!("0{}", version)));
+      let file = data_file(&warehouse, &format!("{}.parquet", name), "", &[name]);
+      assert_eq!(log.append(&[file]).await.unwrap(), Some(version as u64));
+    }
+    // Version 2 was checkpointed; a version without files still moves the source checkpoint on
+    log.set_checkpoint(checkpoint("03"));
+    assert_eq!(log.append(&[]).await.unwrap(), Some(3));
+    let log_dir = warehouse.table_path("farm", "orders").child("_delta_log");
+    let last: Value = serde_json::from_slice(&log.get(&log_dir.child("_last_checkpoint")).await.unwrap()).unwrap();
+    assert_eq!(last, json!({ "version": 2, "size": 5 }));
+
+    // Readers only need the checkpoint and the versions after it
+    for version in [0, 1] {
+      warehouse.store.delete(&log_dir.child(format!("{:020}.json", version))).await.unwrap();
+    }
+    let reopened = open(&warehouse).await;
+    assert_eq!(reopened.version, Some(3));
+    assert_eq!(reopened.files.keys().collect::<Vec<_>>(), vec!["data/a.parquet", "data/b.parquet", "data/c.parquet"]);
+    assert_eq!(reopened.files, log.files);
+    assert_eq!(reopened.fields, log.fields);
+    assert_eq!(reopened.protocol, log.protocol);
+    assert_eq!(reopened.last_checkpoint(), Some(checkpoint("03")));
+
+    // Without the version after it, the checkpoint's own commit has the source checkpoint
+    warehouse.store.delete(&log_dir.child(format!("{:020}.json", 3))).await.unwrap();
+    assert_eq!(open(&warehouse).await.last_checkpoint(), Some(checkpoint("02")));
+  }
+
+  #[tokio::test]
+  async fn a_writer_that_loses_the_race_commits_the_next_version() {
+    let warehouse = warehouse();
+    let mut first = open(&warehouse).await;
+    let mut second = open(&warehouse).await;
+    assert_eq!(first.append(&[data_file(&warehouse, "a.parquet", "", &["a"])]).await.unwrap(), Some(0));
+    assert_eq!(second.append(&[data_file(&warehouse, "b.parquet", "", &["b"])]).await.unwrap(), Some(1));
+
+    let reopened = open(&warehouse).await;
+    assert_eq!(reopened.files.keys().collect::<Vec<_>>(), vec!["data/a.parquet", "data/b.parquet"]);
+  }
+
+  #[tokio::test]
+  async fn sync_replaces_compacted_files() {
+    let warehouse = warehouse();
+    let mut log = open(&warehouse).await;
+    log.append(&[data_file(&warehouse, "a.parquet", "", &["a"]), data_file(&warehouse, "b.parquet", "", &["b"])]).await.unwrap();
+
+    let merged = data_file(&warehouse, "merged.parquet", "", &["a", "b"]);
+    let live = [CommitRecord { path: merged.path.clone(), size: merged.size, rows: merged.rows, encrypted: false }];
+    assert_eq!(log.sync(&live, &[merged]).await.unwrap(), Some(1));
+    assert_eq!(log.files.keys().collect::<Vec<_>>(), vec!["data/merged.parquet"]);
+    assert_eq!(log.files["data/merged.parquet"]["dataChange"], false);
+  }
+}
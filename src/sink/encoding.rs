@@ -0,0 +1,169 @@
+// src/sink/encoding.rs
+// File encodings besides Parquet, for consumers that can't read it: newline-delimited JSON, CSV and Arrow IPC.
+// Batches are encoded in memory and streamed into the same multipart upload as Parquet, so these files follow
+// the same path layout and commit flow and differ only in their extension.
+
+use arrow::csv::WriterBuilder;
+use arrow::datatypes::Schema;
+use arrow::ipc::writer::FileWriter;
+use arrow::json::LineDelimitedWriter;
+use arrow_array::RecordBatch;
+use serde::Deserialize;
+use crate::error::Error;
+use crate::sink::parquet_writer::ParquetOptions;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+  Parquet,
+  Ndjson,
+  Csv,
+  /// Arrow IPC file format, also known as Feather v2.
+  Ipc,
+}
+
+impl Encoding {
+  pub fn extension(&self) -> &'static str {
+    match self {
+      Encoding::Parquet => "parquet",
+      Encoding::Ndjson => "ndjson",
+      Encoding::Csv => "csv",
+      Encoding::Ipc => "arrow",
+    }
+  }
+
+  /// The encoding of a file, from its extension.
+  pub fn of(path: &str) -> Option<Self> {
+    let (_, extension) = path.rsplit_once('.')?;
+    [Encoding::Parquet, Encoding::Ndjson, Encoding::Csv, Encoding::Ipc].into_iter()
+      .find(|e| e.extension() == extension)
+  }
+}
+
+/// CSV writer settings. Formats use chrono's `strftime` syntax; unset ones keep the RFC 3339 defaults.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CsvOptions {
+  /// Write the column names as the first line.
+  pub header: bool,
+  pub delimiter: char,
+  pub quote: char,
+  /// Written for null values; empty by default.
+  pub null_value: Option<String>,
+  pub date_format: Option<String>,
+  /// For timestamps without a time zone.
+  pub timestamp_format: Option<String>,
+  /// For timestamps with a time zone.
+  pub timestamp_tz_format: Option<String>,
+  pub time_format: Option<String>,
+}
+
+impl Default for CsvOptions {
+  fn default() -> Self {
+    Self {
+      header: true,
+      delimiter: ',',
+      quote: '"',
+      null_value: None,
+      date_format: None,
+      timestamp_format: None,
+      timestamp_tz_format: None,
+      time_format: None,
+    }
+  }
+}
+
+impl CsvOptions {
+  fn builder(&self) -> Result<WriterBuilder, Error> {
+    let byte = |c: char, what: &str| u8::try_from(c).ok().filter(u8::is_ascii)
+      .ok_or_else(|| Error::config(format!("CSV {} must be a single ASCII character, not {:?}", what, c)));
+    let mut builder = WriterBuilder::new()
+      .with_delimiter(byte(self.delimiter, "delimiter")?)
+      .with_quote(byte(self.quote, "quote")?);
+    if let Some(null) = &self.null_value {
+      builder = builder.with_null(null.clone());
+    }
+    if let Some(format) = &self.date_format {
+      builder = builder.with_date_format(format.clone());
+    }
+    if let Some(format) = &self.timestamp_format {
+      builder = builder.with_timestamp_format(format.clone());
+    }
+    if let Some(format) = &self.timestamp_tz_format {
+      builder = builder.with_timestamp_tz_format(format.clone());
+    }
+    if let Some(format) = &self.time_format {
+      builder = builder.with_time_format(format.clone());
+    }
+    Ok(builder)
+  }
+}
+
+/// An encoding with its writer settings.
+#[derive(Debug, Clone)]
+pub enum FileFormat {
+  Parquet(ParquetOptions),
+  Ndjson,
+  Csv(CsvOptions),
+  Ipc,
+}
+
+impl FileFormat {
+  pub fn encoding(&self) -> Encoding {
+    match self {
+      FileFormat::Parquet(_) => Encoding::Parquet,
+      FileFormat::Ndjson => Encoding::Ndjson,
+      FileFormat::Csv(_) => Encoding::Csv,
+      FileFormat::Ipc => Encoding::Ipc,
+    }
+  }
+}
+
+/// Encodes batches for the formats that have no async writer. Each call returns the bytes to append to the file.
+pub enum BatchEncoder {
+  Ndjson,
+  Csv { builder: WriterBuilder, header: bool },
+  Ipc(FileWriter<Vec<u8>>),
+}
+
+impl BatchEncoder {
+  pub fn csv(opts: &CsvOptions) -> Result<Self, Error> {
+    Ok(BatchEncoder::Csv { builder: opts.builder()?, header: opts.header })
+  }
+
+  pub fn ipc(schema: &Schema) -> Result<Self, Error> {
+    Ok(BatchEncoder::Ipc(FileWriter::try_new(Vec::new(), schema)?))
+  }
+
+  pub fn encode(&mut self, batch: &RecordBatch) -> Result<Vec<u8>, Error> {
+    match self {
+      BatchEncoder::Ndjson => {
+        let mut writer = LineDelimitedWriter::new(Vec::new());
+        writer.write(batch)?;
+        writer.finish()?;
+        Ok(writer.into_inner())
+      }
+      BatchEncoder::Csv { builder, header } => {
+        // Only the first batch of a file gets the header
+        let mut writer = builder.clone().with_header(std::mem::take(header)).build(Vec::new());
+        writer.write(batch)?;
+        Ok(writer.into_inner())
+      }
+      BatchEncoder::Ipc(writer) => {
+        writer.write(batch)?;
+        Ok(std::mem::take(writer.get_mut()))
+      }
+    }
+  }
+
+  /// The bytes that end the file, e.g. the IPC footer.
+  pub fn finish(&mut self) -> Result<Vec<u8>, Error> {
+    match self {
+      BatchEncoder::Ipc(writer) => {
+        writer.finish()?;
+        Ok(std::mem::take(writer.get_mut()))
+      }
+      _ => Ok(Vec::new()),
+    }
+  }
+}
@@ -0,0 +1,775 @@
+// src/sink/encryption.rs
+// Parquet modular encryption (AES_GCM_V1) for columns that must stay encrypted at rest beyond bucket-level encryption.
+// Files are written as plain Parquet, and each row group has the pages and metadata of the chosen columns encrypted
+// as soon as it is flushed, so that it can be uploaded before the next one is written; the footer is optionally
+// encrypted too. Every file gets fresh data keys, wrapped by master keys from a local key file that stands in for a
+// KMS; the wrapped keys travel in the file's key metadata, so the key file is all a reader needs.
+// Encrypted files have no page indexes or bloom filters: tables that encrypt columns can't ask for them.
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use parquet::file::metadata::RowGroupMetaData;
+use parquet::format::{
+  AesGcmV1, ColumnChunk, ColumnCryptoMetaData, ColumnMetaData, EncryptionAlgorithm, EncryptionWithColumnKey, EncryptionWithFooterKey,
+  FileCryptoMetaData, FileMetaData, PageHeader, PageType, RowGroup,
+};
+use parquet::thrift::{TCompactOutputProtocol, TSerializable};
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use ring::aead::{AES_128_GCM, AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use thrift::protocol::TCompactInputProtocol;
+use crate::error::Error;
+
+const PLAINTEXT_MAGIC: &[u8; 4] = b"PAR1";
+/// Files with an encrypted footer start and end with this instead of `PAR1`.
+const ENCRYPTED_MAGIC: &[u8; 4] = b"PARE";
+const TAG_LEN: usize = 16;
+/// Data keys are AES-128, whatever the size of the master key wrapping them.
+const DATA_KEY_LEN: usize = 16;
+
+// Module types, part of each module's AAD so that modules can't be swapped around within a file
+const FOOTER: u8 = 0;
+const COLUMN_META_DATA: u8 = 1;
+const DATA_PAGE: u8 = 2;
+const DICTIONARY_PAGE: u8 = 3;
+const DATA_PAGE_HEADER: u8 = 4;
+const DICTIONARY_PAGE_HEADER: u8 = 5;
+
+/// Which columns of a table's Parquet files are encrypted, and with which master keys.
+///
+/// ```json
+/// { "footer_key": "footer", "columns": { "customer_email": "pii", "shipping_address": "pii" } }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionOptions {
+  /// Master key of the footer. Columns listed with this key are encrypted with the footer's data key.
+  pub footer_key: String,
+  /// Master key of each encrypted column, by name; the fields of a struct column share its key.
+  /// Other columns stay plaintext.
+  #[serde(default)]
+  pub columns: HashMap<String, String>,
+  /// Sign the footer instead of encrypting it, so readers without keys can still read the plaintext columns.
+  #[serde(default)]
+  pub plaintext_footer: bool,
+}
+
+impl EncryptionOptions {
+  /// Whether the top-level column `name`, or any of its fields, is encrypted.
+  pub fn encrypts(&self, name: &str) -> bool {
+    self.columns.keys().any(|c| c == name || c.strip_prefix(name).is_some_and(|rest| rest.starts_with('.')))
+  }
+
+  /// Master key of the leaf column at `path`, if it is encrypted.
+  fn key_of(&self, path: &[String]) -> Option<&str> {
+    (1..=path.len()).rev().find_map(|n| self.columns.get(&path[..n].join("."))).map(String::as_str)
+  }
+}
+
+/// Master keys by ID, from a JSON file of hex-encoded 128- or 256-bit AES keys: `{ "pii": "00112233…" }`.
+/// Like a KMS, it never hands out master keys; it only wraps and unwraps the data keys of each file.
+#[derive(Clone)]
+pub struct KeyStore {
+  keys: HashMap<String, Vec<u8>>,
+}
+
+/// Key metadata stored in a file for each data key: the master key that wrapped it, and the wrapped key.
+/// This is the key material format of the key tools of parquet-mr and Arrow (`PKMT1`, stored in the file, without
+/// double wrapping), whose readers unwrap the key with a KMS client for the same master keys.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeyMaterial {
+  key_material_type: String,
+  internal_storage: bool,
+  is_footer_key: bool,
+  /// Only set for the footer key.
+  #[serde(rename = "kmsInstanceID", default, skip_serializing_if = "Option::is_none")]
+  kms_instance_id: Option<String>,
+  #[serde(rename = "kmsInstanceURL", default, skip_serializing_if = "Option::is_none")]
+  kms_instance_url: Option<String>,
+  #[serde(rename = "masterKeyID")]
+  master_key_id: String,
+  /// Nonce, ciphertext and tag, base64-encoded. The master key's ID is the AAD.
+  #[serde(rename = "wrappedDEK")]
+  wrapped_dek: String,
+  double_wrapping: bool,
+}
+
+const KEY_MATERIAL_TYPE: &str = "PKMT1";
+/// What parquet-mr records for the KMS when none is configured.
+const DEFAULT_KMS: &str = "DEFAULT";
+
+impl KeyStore {
+  pub fn load(path: &str) -> anyhow::Result<Self> {
+    let raw = std::fs::read_to_string(path)
+      .map_err(|e| anyhow::anyhow!("Failed to read key file {}: {}", path, e))?;
+    let hex_keys: HashMap<String, String> = serde_json::from_str(&raw)
+      .map_err(|e| anyhow::anyhow!("Invalid key file {}: {}", path, e))?;
+    let mut keys = HashMap::new();
+    for (id, key) in hex_keys {
+      let key = hex::decode(key.trim()).map_err(|e| anyhow::anyhow!("Invalid key {} in {}: {}", id, path, e))?;
+      cipher(&key).map_err(|e| anyhow::anyhow!("Invalid key {} in {}: {}", id, path, e))?;
+      keys.insert(id, key);
+    }
+    Ok(Self { keys })
+  }
+
+  fn master(&self, id: &str) -> Result<LessSafeKey, Error> {
+    let key = self.keys.get(id).ok_or_else(|| Error::config(format!("key {} is not in the key file", id)))?;
+    cipher(key)
+  }
+
+  /// `data_key` wrapped by the master key `id`, as key metadata of the footer or of a column.
+  fn wrap(&self, id: &str, data_key: &[u8], footer: bool) -> Result<Vec<u8>, Error> {
+    let wrapped = seal(&self.master(id)?, id.as_bytes(), data_key)?;
+    Ok(serde_json::to_vec(&KeyMaterial {
+      key_material_type: KEY_MATERIAL_TYPE.to_string(),
+      internal_storage: true,
+      is_footer_key: footer,
+      kms_instance_id: footer.then(|| DEFAULT_KMS.to_string()),
+      kms_instance_url: footer.then(|| DEFAULT_KMS.to_string()),
+      master_key_id: id.to_string(),
+      wrapped_dek: BASE64_STANDARD.encode(wrapped),
+      double_wrapping: false,
+    })?)
+  }
+
+  /// The data key in `metadata`.
+  fn unwrap(&self, metadata: Option<&[u8]>) -> Result<LessSafeKey, Error> {
+    let metadata = metadata.ok_or_else(|| Error::Data("encrypted module without key metadata".into()))?;
+    let key: KeyMaterial = serde_json::from_slice(metadata)?;
+    if key.key_material_type != KEY_MATERIAL_TYPE || !key.internal_storage || key.double_wrapping {
+      return Err(Error::Data("unsupported key material: only PKMT1 keys stored in the file without double wrapping are".into()));
+    }
+    let wrapped = BASE64_STANDARD.decode(&key.wrapped_dek).map_err(|e| Error::Data(Box::new(e)))?;
+    let data_key = open(&self.master(&key.master_key_id)?, key.master_key_id.as_bytes(), &wrapped)
+      .map_err(|_| Error::config(format!("key {} in the key file isn't the one the file was written with", key.master_key_id)))?;
+    cipher(&data_key)
+  }
+}
+
+/// Encrypts a Parquet file as it is written, one row group at a time, as `opts` asks.
+///
+/// The writer's output is fed in as it grows, with the metadata of the row groups flushed so far; each row group
+/// comes out encrypted as soon as all of its pages are in. At the end, the writer's file metadata becomes the footer.
+pub struct Encryptor {
+  opts: EncryptionOptions,
+  file_aad: Vec<u8>,
+  /// Data key and key metadata of each master key.
+  keys: HashMap<String, (LessSafeKey, Vec<u8>)>,
+  /// Plaintext not encrypted yet, which starts `pending_start` bytes into the plain file.
+  pending: Vec<u8>,
+  pending_start: usize,
+  /// Bytes of the encrypted file handed out so far.
+  written: usize,
+  /// Metadata of the row groups encrypted so far, with offsets into the encrypted file.
+  row_groups: Vec<RowGroup>,
+}
+
+impl Encryptor {
+  pub fn new(opts: &EncryptionOptions, store: &KeyStore) -> Result<Self, Error> {
+    // One data key per master key, wrapped into the key metadata that goes with it
+    let mut keys = HashMap::new();
+    for id in std::iter::once(&opts.footer_key).chain(opts.columns.values()) {
+      if !keys.contains_key(id) {
+        let data_key = random::<DATA_KEY_LEN>()?;
+        keys.insert(id.clone(), (cipher(&data_key)?, store.wrap(id, &data_key, *id == opts.footer_key)?));
+      }
+    }
+    Ok(Self {
+      opts: opts.clone(),
+      file_aad: random::<8>()?.to_vec(),
+      keys,
+      pending: Vec::new(),
+      pending_start: 0,
+      written: 0,
+      row_groups: Vec::new(),
+    })
+  }
+
+  /// Take `plain`, the next bytes of the plain file, and return what can be encrypted so far: the row groups in
+  /// `flushed`, the file's row groups written up to now, whose pages are all in. The Parquet writer buffers the
+  /// last few kilobytes it writes, so a row group may only be complete once the next one is flushed.
+  pub fn write(&mut self, plain: &[u8], flushed: &[RowGroupMetaData]) -> Result<Vec<u8>, Error> {
+    self.pending.extend_from_slice(plain);
+    let mut out = Vec::new();
+    for row_group in &flushed[self.row_groups.len().min(flushed.len())..] {
+      let row_group = row_group.to_thrift();
+      let end = row_group.columns.iter().filter_map(|c| c.meta_data.as_ref()).map(chunk_end).max().unwrap_or_default();
+      if end as usize > self.pending_start + self.pending.len() {
+        break;
+      }
+      out.extend(self.row_group(row_group)?);
+    }
+    Ok(out)
+  }
+
+  /// Take the rest of the plain file, `plain`, and return the rest of the encrypted file: its remaining row groups
+  /// and the footer for `meta`, the metadata the writer finished the file with.
+  pub fn finish(mut self, plain: &[u8], mut meta: FileMetaData) -> Result<Vec<u8>, Error> {
+    self.pending.extend_from_slice(plain);
+    let mut out = Vec::new();
+    for row_group in meta.row_groups.split_off(self.row_groups.len().min(meta.row_groups.len())) {
+      out.extend(self.row_group(row_group)?);
+    }
+    if self.written == 0 {
+      out.extend(self.magic());
+    }
+    meta.row_groups = std::mem::take(&mut self.row_groups);
+
+    let (footer_key, footer_key_metadata) = &self.keys[&self.opts.footer_key];
+    let algorithm = EncryptionAlgorithm::AESGCMV1(AesGcmV1::new(None, self.file_aad.clone(), None));
+    let footer_aad = aad(&self.file_aad, FOOTER, &[])?;
+    let footer_start = out.len();
+    if self.opts.plaintext_footer {
+      meta.encryption_algorithm = Some(algorithm);
+      meta.footer_signing_key_metadata = Some(footer_key_metadata.clone());
+      let footer = to_thrift(&meta)?;
+      // The signature is the nonce and tag of the encrypted footer; the ciphertext itself is dropped
+      let signed = seal(footer_key, &footer_aad, &footer)?;
+      out.extend(&footer);
+      out.extend(&signed[..NONCE_LEN]);
+      out.extend(&signed[signed.len() - TAG_LEN..]);
+    } else {
+      out.extend(to_thrift(&FileCryptoMetaData::new(algorithm, footer_key_metadata.clone()))?);
+      out.extend(module(footer_key, &footer_aad, &to_thrift(&meta)?)?);
+    }
+    let footer_len = (out.len() - footer_start) as u32;
+    out.extend(footer_len.to_le_bytes());
+    out.extend(self.magic());
+    Ok(out)
+  }
+
+  fn magic(&self) -> &'static [u8; 4] {
+    if self.opts.plaintext_footer { PLAINTEXT_MAGIC } else { ENCRYPTED_MAGIC }
+  }
+
+  /// Encrypt the pending pages of `row_group`, then drop them.
+  fn row_group(&mut self, mut row_group: RowGroup) -> Result<Vec<u8>, Error> {
+    let opts = &self.opts;
+    let rg = self.row_groups.len();
+    if rg == 0 {
+      let paths: Vec<String> = row_group.columns.iter()
+        .filter_map(|c| c.meta_data.as_ref())
+        .map(|m| m.path_in_schema.join("."))
+        .collect();
+      if let Some(missing) = opts.columns.keys().find(|c| !paths.iter().any(|p| p == *c || p.starts_with(&format!("{}.", c)))) {
+        return Err(Error::config(format!("Encrypted column {} is not in the file", missing)));
+      }
+    }
+
+    // Offsets in the encrypted file are `written` ahead of positions in `out`
+    let mut out = if self.written == 0 { self.magic().to_vec() } else { Vec::new() };
+    let base = self.written;
+    let mut consumed = self.pending_start;
+    for (col, chunk) in row_group.columns.iter_mut().enumerate() {
+      let mut column = chunk.meta_data.take().ok_or_else(|| Error::Data("column chunk without metadata".into()))?;
+      consumed = consumed.max(chunk_end(&column) as usize);
+      let bytes = chunk_bytes(&self.pending, self.pending_start, &column)?;
+      let start = out.len();
+      let Some(id) = opts.key_of(&column.path_in_schema) else {
+        copy_chunk(bytes, &mut column, base, &mut out);
+        finish_chunk(chunk, column, base + out.len());
+        continue;
+      };
+      let (key, key_metadata) = &self.keys[id];
+
+      let mut pos = 0;
+      let mut page = 0;
+      while pos < bytes.len() {
+        let (mut header, header_len): (PageHeader, _) = read_thrift(&bytes[pos..])?;
+        let body = bytes.get(pos + header_len..pos + header_len + header.compressed_page_size as usize)
+          .ok_or_else(|| Error::Data("page runs past the end of its column chunk".into()))?;
+        pos += header_len + body.len();
+
+        let dictionary = header.type_ == PageType::DICTIONARY_PAGE;
+        let ordinals = if dictionary { vec![rg, col] } else { vec![rg, col, page] };
+        let (header_type, page_type) = if dictionary { (DICTIONARY_PAGE_HEADER, DICTIONARY_PAGE) } else { (DATA_PAGE_HEADER, DATA_PAGE) };
+        let body = module(key, &aad(&self.file_aad, page_type, &ordinals)?, body)?;
+        header.compressed_page_size = body.len() as i32;
+        if dictionary {
+          column.dictionary_page_offset = Some((base + out.len()) as i64);
+        } else {
+          if page == 0 {
+            column.data_page_offset = (base + out.len()) as i64;
+          }
+          page += 1;
+        }
+        out.extend(module(key, &aad(&self.file_aad, header_type, &ordinals)?, &to_thrift(&header)?)?);
+        out.extend(body);
+      }
+      column.total_compressed_size = (out.len() - start) as i64;
+      column.index_page_offset = None;
+      column.bloom_filter_offset = None;
+      column.bloom_filter_length = None;
+
+      let footer_key = id == opts.footer_key;
+      chunk.crypto_metadata = Some(if footer_key {
+        ColumnCryptoMetaData::ENCRYPTIONWITHFOOTERKEY(EncryptionWithFooterKey::new())
+      } else {
+        ColumnCryptoMetaData::ENCRYPTIONWITHCOLUMNKEY(EncryptionWithColumnKey::new(column.path_in_schema.clone(), key_metadata.clone()))
+      });
+      // An encrypted footer already hides the metadata of columns encrypted with its key
+      if footer_key && !opts.plaintext_footer {
+        finish_chunk(chunk, column, base + out.len());
+        continue;
+      }
+      chunk.encrypted_column_metadata = Some(module(key, &aad(&self.file_aad, COLUMN_META_DATA, &[rg, col])?, &to_thrift(&column)?)?);
+      // A plaintext footer keeps the metadata for readers that don't decrypt, minus the statistics that give values away
+      column.statistics = None;
+      column.encoding_stats = None;
+      column.size_statistics = None;
+      column.key_value_metadata = None;
+      finish_chunk(chunk, column, base + out.len());
+      if !opts.plaintext_footer {
+        chunk.meta_data = None;
+      }
+    }
+    let row_group_start = if base == 0 { PLAINTEXT_MAGIC.len() } else { base };
+    row_group.file_offset = Some(row_group_start as i64);
+    row_group.total_compressed_size = Some((base + out.len() - row_group_start) as i64);
+    row_group.ordinal = Some(rg as i16);
+
+    self.pending.drain(..(consumed - self.pending_start).min(self.pending.len()));
+    self.pending_start = consumed;
+    self.written += out.len();
+    self.row_groups.push(row_group);
+    Ok(out)
+  }
+}
+
+/// `bytes` as a plain Parquet file. Files that aren't encrypted are returned as they are.
+pub fn decrypt(bytes: &[u8], store: &KeyStore) -> Result<Vec<u8>, Error> {
+  let (magic, footer) = footer(bytes)?;
+  let footer_key_of = |metadata: Option<&[u8]>| store.unwrap(metadata).map_err(|e| context(e, "the footer"));
+  let (mut meta, file_aad, footer_key) = if magic == ENCRYPTED_MAGIC {
+    let (crypto, len): (FileCryptoMetaData, _) = read_thrift(footer)?;
+    let file_aad = file_aad(&crypto.encryption_algorithm)?;
+    let key = footer_key_of(crypto.key_metadata.as_deref())?;
+    let plain = open_module(&key, &aad(&file_aad, FOOTER, &[])?, &footer[len..])?.0;
+    (read_thrift(&plain)?.0, file_aad, key)
+  } else {
+    let (meta, len): (FileMetaData, _) = read_thrift(footer)?;
+    let Some(algorithm) = &meta.encryption_algorithm else {
+      return Ok(bytes.to_vec());
+    };
+    let file_aad = file_aad(algorithm)?;
+    let key = footer_key_of(meta.footer_signing_key_metadata.as_deref())?;
+    let signature = &footer[len..];
+    if signature.len() != NONCE_LEN + TAG_LEN {
+      return Err(Error::Data("footer signature is missing".into()));
+    }
+    let signed = seal_with(&key, &signature[..NONCE_LEN], &aad(&file_aad, FOOTER, &[])?, &footer[..len])?;
+    if signed[signed.len() - TAG_LEN..] != signature[NONCE_LEN..] {
+      return Err(Error::Data("footer signature doesn't match: the file was modified".into()));
+    }
+    (meta, file_aad, key)
+  };
+  meta.encryption_algorithm = None;
+  meta.footer_signing_key_metadata = None;
+
+  let mut out = PLAINTEXT_MAGIC.to_vec();
+  for (rg, row_group) in meta.row_groups.iter_mut().enumerate() {
+    let row_group_start = out.len();
+    for (col, chunk) in row_group.columns.iter_mut().enumerate() {
+      let start = out.len();
+      let Some(crypto) = chunk.crypto_metadata.take() else {
+        let mut column = chunk.meta_data.take().ok_or_else(|| Error::Data("column chunk without metadata".into()))?;
+        copy_chunk(chunk_bytes(bytes, 0, &column)?, &mut column, 0, &mut out);
+        finish_chunk(chunk, column, out.len());
+        continue;
+      };
+      let key = match &crypto {
+        ColumnCryptoMetaData::ENCRYPTIONWITHFOOTERKEY(_) => footer_key.clone(),
+        ColumnCryptoMetaData::ENCRYPTIONWITHCOLUMNKEY(c) => store.unwrap(c.key_metadata.as_deref())
+          .map_err(|e| context(e, &format!("column {}", c.path_in_schema.join("."))))?,
+      };
+      let mut column: ColumnMetaData = match chunk.encrypted_column_metadata.take() {
+        Some(encrypted) => read_thrift(&open_module(&key, &aad(&file_aad, COLUMN_META_DATA, &[rg, col])?, &encrypted)?.0)?.0,
+        None => chunk.meta_data.take().ok_or_else(|| Error::Data("column chunk without metadata".into()))?,
+      };
+      let encrypted = chunk_bytes(bytes, 0, &column)?;
+      let dictionary_offset = column.dictionary_page_offset;
+
+      let mut pos = 0;
+      let mut page = 0;
+      while pos < encrypted.len() {
+        let dictionary = dictionary_offset.is_some() && page == 0 && pos == 0;
+        let ordinals = if dictionary { vec![rg, col] } else { vec![rg, col, page] };
+        let (header_type, page_type) = if dictionary { (DICTIONARY_PAGE_HEADER, DICTIONARY_PAGE) } else { (DATA_PAGE_HEADER, DATA_PAGE) };
+        let (header, len) = open_module(&key, &aad(&file_aad, header_type, &ordinals)?, &encrypted[pos..])?;
+        pos += len;
+        let mut header: PageHeader = read_thrift(&header)?.0;
+        let (body, len) = open_module(&key, &aad(&file_aad, page_type, &ordinals)?, &encrypted[pos..])?;
+        if len != header.compressed_page_size as usize {
+          return Err(Error::Data("page size doesn't match its header".into()));
+        }
+        pos += len;
+        header.compressed_page_size = body.len() as i32;
+        if dictionary {
+          column.dictionary_page_offset = Some(out.len() as i64);
+        } else {
+          if page == 0 {
+            column.data_page_offset = out.len() as i64;
+          }
+          page += 1;
+        }
+        out.extend(to_thrift(&header)?);
+        out.extend(body);
+      }
+      column.total_compressed_size = (out.len() - start) as i64;
+      finish_chunk(chunk, column, out.len());
+    }
+    row_group.file_offset = Some(row_group_start as i64);
+    row_group.total_compressed_size = Some((out.len() - row_group_start) as i64);
+  }
+
+  let footer = to_thrift(&meta)?;
+  out.extend(&footer);
+  out.extend((footer.len() as u32).to_le_bytes());
+  out.extend(PLAINTEXT_MAGIC);
+  Ok(out)
+}
+
+/// Whether `bytes` is a Parquet file with encrypted columns.
+pub fn is_encrypted(bytes: &[u8]) -> Result<bool, Error> {
+  let (magic, footer) = footer(bytes)?;
+  Ok(magic == ENCRYPTED_MAGIC || read_thrift::<FileMetaData>(footer)?.0.encryption_algorithm.is_some())
+}
+
+/// Say what couldn't be decrypted when a key is missing or wrong.
+fn context(e: Error, what: &str) -> Error {
+  match e {
+    Error::Config(e) => Error::config(format!("can't decrypt {}: {}", what, e)),
+    e => e,
+  }
+}
+
+/// The magic number at the end of a Parquet file, and the footer before it.
+fn footer(bytes: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+  let not_parquet = || Error::Data("not a Parquet file".into());
+  let end = bytes.len().checked_sub(8).filter(|_| bytes.len() >= 12).ok_or_else(not_parquet)?;
+  let magic = &bytes[end + 4..];
+  if magic != PLAINTEXT_MAGIC && magic != ENCRYPTED_MAGIC {
+    return Err(not_parquet());
+  }
+  let len = u32::from_le_bytes(bytes[end..end + 4].try_into().expect("4 bytes")) as usize;
+  let start = end.checked_sub(len).ok_or_else(not_parquet)?;
+  Ok((magic, &bytes[start..end]))
+}
+
+/// The pages of a column chunk, in `bytes` that start `offset` bytes into the file.
+fn chunk_bytes<'a>(bytes: &'a [u8], offset: usize, column: &ColumnMetaData) -> Result<&'a [u8], Error> {
+  let start = (chunk_start(column) as usize).checked_sub(offset)
+    .ok_or_else(|| Error::Data(format!("column chunk {} starts before the bytes given", column.path_in_schema.join(".")).into()))?;
+  bytes.get(start..start + column.total_compressed_size as usize)
+    .ok_or_else(|| Error::Data(format!("column chunk {} runs past the end of the file", column.path_in_schema.join(".")).into()))
+}
+
+fn chunk_start(column: &ColumnMetaData) -> i64 {
+  column.dictionary_page_offset.map_or(column.data_page_offset, |d| d.min(column.data_page_offset))
+}
+
+fn chunk_end(column: &ColumnMetaData) -> i64 {
+  chunk_start(column) + column.total_compressed_size
+}
+
+/// Append a chunk's pages to `out`, which starts `base` bytes into the file, unchanged, moving its offsets along.
+fn copy_chunk(bytes: &[u8], column: &mut ColumnMetaData, base: usize, out: &mut Vec<u8>) {
+  let shift = (base + out.len()) as i64 - chunk_start(column);
+  column.data_page_offset += shift;
+  column.dictionary_page_offset = column.dictionary_page_offset.map(|o| o + shift);
+  column.index_page_offset = None;
+  column.bloom_filter_offset = None;
+  column.bloom_filter_length = None;
+  out.extend_from_slice(bytes);
+}
+
+/// Store a chunk's rewritten metadata. Its end is where the deprecated `file_offset` points.
+fn finish_chunk(chunk: &mut ColumnChunk, column: ColumnMetaData, end: usize) {
+  chunk.file_offset = end as i64;
+  chunk.meta_data = Some(column);
+  chunk.offset_index_offset = None;
+  chunk.offset_index_length = None;
+  chunk.column_index_offset = None;
+  chunk.column_index_length = None;
+}
+
+fn file_aad(algorithm: &EncryptionAlgorithm) -> Result<Vec<u8>, Error> {
+  match algorithm {
+    EncryptionAlgorithm::AESGCMV1(AesGcmV1 { aad_prefix: None, aad_file_unique: Some(aad), .. }) => Ok(aad.clone()),
+    EncryptionAlgorithm::AESGCMV1(_) => Err(Error::Data("AAD prefixes aren't supported".into())),
+    EncryptionAlgorithm::AESGCMCTRV1(_) => Err(Error::Data("only AES_GCM_V1 encryption is supported, not AES_GCM_CTR_V1".into())),
+  }
+}
+
+/// AAD of a module: the file's unique bytes, the module type, then its row group, column and page ordinals.
+fn aad(file_aad: &[u8], module_type: u8, ordinals: &[usize]) -> Result<Vec<u8>, Error> {
+  let mut aad = file_aad.to_vec();
+  aad.push(module_type);
+  for ordinal in ordinals {
+    let ordinal = i16::try_from(*ordinal).map_err(|_| Error::Data("too many row groups, columns or pages to encrypt".into()))?;
+    aad.extend(ordinal.to_le_bytes());
+  }
+  Ok(aad)
+}
+
+fn cipher(key: &[u8]) -> Result<LessSafeKey, Error> {
+  let algorithm = match key.len() {
+    16 => &AES_128_GCM,
+    32 => &AES_256_GCM,
+    n => return Err(Error::config(format!("AES keys must be 16 or 32 bytes, not {}", n))),
+  };
+  Ok(LessSafeKey::new(UnboundKey::new(algorithm, key).map_err(|_| Error::config("invalid AES key"))?))
+}
+
+fn random<const N: usize>() -> Result<[u8; N], Error> {
+  let mut bytes = [0u8; N];
+  SystemRandom::new().fill(&mut bytes).map_err(|_| Error::Retryable("system random number generator failed".into()))?;
+  Ok(bytes)
+}
+
+/// Nonce, ciphertext and tag.
+fn seal(key: &LessSafeKey, aad: &[u8], plain: &[u8]) -> Result<Vec<u8>, Error> {
+  seal_with(key, &random::<NONCE_LEN>()?, aad, plain)
+}
+
+fn seal_with(key: &LessSafeKey, nonce: &[u8], aad: &[u8], plain: &[u8]) -> Result<Vec<u8>, Error> {
+  let mut sealed = nonce.to_vec();
+  sealed.extend_from_slice(plain);
+  let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| Error::Data("invalid nonce".into()))?;
+  let tag = key.seal_in_place_separate_tag(nonce, Aad::from(aad), &mut sealed[NONCE_LEN..])
+    .map_err(|_| Error::Data("encryption failed".into()))?;
+  sealed.extend_from_slice(tag.as_ref());
+  Ok(sealed)
+}
+
+fn open(key: &LessSafeKey, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, Error> {
+  if sealed.len() < NONCE_LEN + TAG_LEN {
+    return Err(Error::Data("encrypted module is truncated".into()));
+  }
+  let nonce = Nonce::try_assume_unique_for_key(&sealed[..NONCE_LEN]).map_err(|_| Error::Data("invalid nonce".into()))?;
+  let mut plain = sealed[NONCE_LEN..].to_vec();
+  let len = key.open_in_place(nonce, Aad::from(aad), &mut plain)
+    .map_err(|_| Error::Data("decryption failed: the file is corrupt or was written with another key".into()))?
+    .len();
+  plain.truncate(len);
+  Ok(plain)
+}
+
+/// An encrypted module: its length, then nonce, ciphertext and tag.
+fn module(key: &LessSafeKey, aad: &[u8], plain: &[u8]) -> Result<Vec<u8>, Error> {
+  let sealed = seal(key, aad, plain)?;
+  let mut module = (sealed.len() as u32).to_le_bytes().to_vec();
+  module.extend(sealed);
+  Ok(module)
+}
+
+/// The plaintext of the module at the start of `bytes`, and the module's length.
+fn open_module(key: &LessSafeKey, aad: &[u8], bytes: &[u8]) -> Result<(Vec<u8>, usize), Error> {
+  let truncated = || Error::Data("encrypted module is truncated".into());
+  let len = u32::from_le_bytes(bytes.get(..4).ok_or_else(truncated)?.try_into().expect("4 bytes")) as usize;
+  let sealed = bytes.get(4..4 + len).ok_or_else(truncated)?;
+  Ok((open(key, aad, sealed)?, 4 + len))
+}
+
+fn read_thrift<T: TSerializable>(bytes: &[u8]) -> Result<(T, usize), Error> {
+  let mut cursor = Cursor::new(bytes);
+  let value = T::read_from_in_protocol(&mut TCompactInputProtocol::new(&mut cursor)).map_err(|e| Error::Data(Box::new(e)))?;
+  Ok((value, cursor.position() as usize))
+}
+
+fn to_thrift<T: TSerializable>(value: &T) -> Result<Vec<u8>, Error> {
+  let mut bytes = Vec::new();
+  value.write_to_out_protocol(&mut TCompactOutputProtocol::new(&mut bytes)).map_err(|e| Error::Data(Box::new(e)))?;
+  Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Arc;
+  use arrow::compute::concat_batches;
+  use arrow::datatypes::{DataType, Field, Schema};
+  use arrow_array::{ArrayRef, Int64Array, RecordBatch, StringArray};
+  use bytes::Bytes;
+  use parquet::arrow::ArrowWriter;
+  use parquet::arrow::ProjectionMask;
+  use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+  use parquet::file::metadata::KeyValue;
+  use parquet::file::properties::WriterProperties;
+
+  fn store(keys: &[(&str, u8)]) -> KeyStore {
+    KeyStore { keys: keys.iter().map(|(id, byte)| (id.to_string(), vec![*byte; 16])).collect() }
+  }
+
+  fn options(columns: &[(&str, &str)], plaintext_footer: bool) -> EncryptionOptions {
+    EncryptionOptions {
+      footer_key: "footer".to_string(),
+      columns: columns.iter().map(|(c, k)| (c.to_string(), k.to_string())).collect(),
+      plaintext_footer,
+    }
+  }
+
+  fn batch(from: i64) -> RecordBatch {
+    let ids: Vec<i64> = (from..from + 2_000).collect();
+    let emails: Vec<String> = ids.iter().map(|i| format!("customer{}@example.com", i % 7)).collect();
+    let notes: Vec<String> = ids.iter().map(|i| format!("note {}", i)).collect();
+    let columns: Vec<ArrayRef> = vec![
+      Arc::new(Int64Array::from(ids)),
+      Arc::new(StringArray::from(emails)),
+      Arc::new(StringArray::from(notes)),
+    ];
+    let schema = Schema::new(vec![
+      Field::new("id", DataType::Int64, false),
+      Field::new("email", DataType::Utf8, true),
+      Field::new("note", DataType::Utf8, true),
+    ]);
+    RecordBatch::try_new(Arc::new(schema), columns).unwrap()
+  }
+
+  /// `batches` written as row groups of their own, the way `ParquetFile` streams them, and what the encryptor
+  /// handed out after each one.
+  fn write(batches: &[RecordBatch], opts: &EncryptionOptions, store: &KeyStore) -> (Vec<u8>, Vec<usize>) {
+    let props = WriterProperties::builder().set_max_row_group_size(10_000).build();
+    let mut writer = ArrowWriter::try_new(Vec::new(), batches[0].schema(), Some(props)).unwrap();
+    writer.append_key_value_metadata(KeyValue::new("fire_to_ice.namespace".to_string(), "farm".to_string()));
+    let mut encryptor = Encryptor::new(opts, store).unwrap();
+    let mut out = Vec::new();
+    let mut streamed = Vec::new();
+    for batch in batches {
+      writer.write(batch).unwrap();
+      writer.flush().unwrap();
+      let plain = std::mem::take(writer.inner_mut());
+      out.extend(encryptor.write(&plain, writer.flushed_row_groups()).unwrap());
+      streamed.push(out.len());
+    }
+    let meta = writer.finish().unwrap();
+    out.extend(encryptor.finish(&std::mem::take(writer.inner_mut()), meta).unwrap());
+    (out, streamed)
+  }
+
+  fn read(plain: Vec<u8>) -> RecordBatch {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(Bytes::from(plain)).unwrap().build().unwrap();
+    let batches: Vec<RecordBatch> = reader.map(Result::unwrap).collect();
+    concat_batches(&batches[0].schema(), &batches).unwrap()
+  }
+
+  fn contains(haystack: &[u8], needle: &str) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle.as_bytes())
+  }
+
+  #[test]
+  fn round_trips_row_groups_encrypted_as_they_are_flushed() {
+    let keys = store(&[("footer", 1), ("pii", 2)]);
+    let opts = options(&[("email", "pii"), ("note", "footer")], false);
+    let batches = [batch(0), batch(2_000), batch(4_000)];
+    let (encrypted, streamed) = write(&batches, &opts, &keys);
+
+    // Row groups came out while the file was being written, not only at the end
+    assert!(streamed[1] > 0 && streamed[1] < streamed[2], "{:?}", streamed);
+    assert!(is_encrypted(&encrypted).unwrap());
+    assert_eq!(&encrypted[..4], ENCRYPTED_MAGIC);
+    assert!(!contains(&encrypted, "customer3@example.com"));
+    assert!(!contains(&encrypted, "fire_to_ice.namespace"));
+
+    let plain = decrypt(&encrypted, &keys).unwrap();
+    assert!(!is_encrypted(&plain).unwrap());
+    assert_eq!(read(plain), concat_batches(&batches[0].schema(), &batches).unwrap());
+  }
+
+  #[test]
+  fn plaintext_footer_files_can_be_read_without_keys() {
+    let keys = store(&[("footer", 1), ("pii", 2)]);
+    let batches = [batch(0), batch(2_000)];
+    let (encrypted, _) = write(&batches, &options(&[("email", "pii")], true), &keys);
+    assert_eq!(&encrypted[..4], PLAINTEXT_MAGIC);
+    assert!(!contains(&encrypted, "customer3@example.com"));
+    let all = concat_batches(&batches[0].schema(), &batches).unwrap();
+    assert_eq!(read(decrypt(&encrypted, &keys).unwrap()), all);
+
+    // A reader that knows nothing of encryption still gets the plaintext columns
+    let builder = ParquetRecordBatchReaderBuilder::try_new(Bytes::from(encrypted)).unwrap();
+    let mask = ProjectionMask::leaves(builder.parquet_schema(), [0, 2]);
+    let read: Vec<RecordBatch> = builder.with_projection(mask).build().unwrap().map(Result::unwrap).collect();
+    assert_eq!(concat_batches(&read[0].schema(), &read).unwrap(), all.project(&[0, 2]).unwrap());
+  }
+
+  #[test]
+  fn columns_are_encrypted_with_their_own_keys() {
+    let keys = store(&[("footer", 1), ("pii", 2), ("notes", 3)]);
+    let (encrypted, _) = write(&[batch(0)], &options(&[("email", "pii"), ("note", "notes")], true), &keys);
+
+    let (_, footer) = footer(&encrypted).unwrap();
+    let meta: FileMetaData = read_thrift(footer).unwrap().0;
+    let key_ids: Vec<Option<String>> = meta.row_groups[0].columns.iter().map(|c| match &c.crypto_metadata {
+      Some(ColumnCryptoMetaData::ENCRYPTIONWITHCOLUMNKEY(key)) => {
+        let material: KeyMaterial = serde_json::from_slice(key.key_metadata.as_deref().unwrap()).unwrap();
+        assert_eq!((material.key_material_type.as_str(), material.is_footer_key), ("PKMT1", false));
+        Some(material.master_key_id)
+      }
+      Some(ColumnCryptoMetaData::ENCRYPTIONWITHFOOTERKEY(_)) => Some("footer".to_string()),
+      None => None,
+    }).collect();
+    assert_eq!(key_ids, vec![None, Some("pii".to_string()), Some("notes".to_string())]);
+    let material: KeyMaterial = serde_json::from_slice(meta.footer_signing_key_metadata.as_deref().unwrap()).unwrap();
+    assert_eq!((material.master_key_id.as_str(), material.is_footer_key), ("footer", true));
+
+    // Without the key of one column, nothing is decrypted
+    match decrypt(&encrypted, &store(&[("footer", 1), ("pii", 2)])) {
+      Err(Error::Config(e)) => assert!(e.to_string().contains("column note"), "{}", e),
+      other => panic!("expected a missing key, got {:?}", other.map(|_| ())),
+    }
+  }
+
+  #[test]
+  fn wrong_keys_are_refused() {
+    let (encrypted, _) = write(&[batch(0)], &options(&[("email", "pii")], false), &store(&[("footer", 1), ("pii", 2)]));
+    match decrypt(&encrypted, &store(&[("footer", 9), ("pii", 2)])) {
+      Err(Error::Config(e)) => assert!(e.to_string().contains("footer"), "{}", e),
+      other => panic!("expected a wrong key, got {:?}", other.map(|_| ())),
+    }
+    match decrypt(&encrypted, &store(&[("footer", 1), ("pii", 9)])) {
+      Err(Error::Config(e)) => assert!(e.to_string().contains("column email"), "{}", e),
+      other => panic!("expected a wrong key, got {:?}", other.map(|_| ())),
+    }
+  }
+
+  #[test]
+  fn tampered_files_are_refused() {
+    let keys = store(&[("footer", 1), ("pii", 2)]);
+    let (encrypted, _) = write(&[batch(0)], &options(&[("email", "pii")], true), &keys);
+
+    // A flipped bit in an encrypted page
+    let (_, footer_bytes) = footer(&encrypted).unwrap();
+    let meta: FileMetaData = read_thrift(footer_bytes).unwrap().0;
+    let email = meta.row_groups[0].columns[1].meta_data.as_ref().unwrap();
+    let mut page = encrypted.clone();
+    page[(chunk_end(email) - 20) as usize] ^= 1;
+    assert!(matches!(decrypt(&page, &keys), Err(Error::Data(_))));
+
+    // A changed value in the signed plaintext footer
+    let at = encrypted.windows(4).rposition(|w| w == b"farm").unwrap();
+    let mut signed = encrypted.clone();
+    signed[at..at + 4].copy_from_slice(b"barn");
+    match decrypt(&signed, &keys) {
+      Err(Error::Data(e)) => assert!(e.to_string().contains("signature"), "{}", e),
+      other => panic!("expected a bad signature, got {:?}", other.map(|_| ())),
+    }
+  }
+
+  #[test]
+  fn missing_columns_are_refused() {
+    let opts = options(&[("shipping_address", "pii")], false);
+    let mut encryptor = Encryptor::new(&opts, &store(&[("footer", 1), ("pii", 2)])).unwrap();
+    let mut writer = ArrowWriter::try_new(Vec::new(), batch(0).schema(), None).unwrap();
+    writer.write(&batch(0)).unwrap();
+    writer.finish().unwrap();
+    match encryptor.write(&std::mem::take(writer.inner_mut()), writer.flushed_row_groups()) {
+      Err(Error::Config(e)) => assert!(e.to_string().contains("shipping_address"), "{}", e),
+      other => panic!("expected a missing column, got {:?}", other.map(|_| ())),
+    }
+  }
+}
@@ -0,0 +1,400 @@
+// src/sink/hadoop_catalog.rs
+// An Iceberg catalog kept as files in the warehouse, for when no REST catalog can be reached, e.g. offline or
+// in tests. As in Iceberg's Hadoop catalog, table `ns.table` lives in `<warehouse>/<ns>/<table>/`, version N
+// of its metadata is `metadata/v<N>.metadata.json`, and `metadata/version-hint.text` holds the latest N.
+// A commit checks its requirements against the latest version and creates the next one only if it doesn't
+// exist yet: of two writers racing for a version one fails, just as a REST catalog would turn it down. A
+// create retried after its response was lost finds its own version there, which isn't a conflict. The
+// hint is written after the version, so readers look past it for newer ones. Spark, Trino, DuckDB and
+// PyIceberg can read these tables as Hadoop tables.
+
+use futures::StreamExt;
+use object_store::{ObjectStore, PutMode, PutPayload, path::Path};
+use serde_json::{Value, json};
+use crate::error::Error;
+use crate::retry::RetryPolicy;
+use crate::store::Warehouse;
+
+const VERSION_HINT: &str = "version-hint.text";
+
+#[derive(Clone)]
+pub struct HadoopCatalog {
+  warehouse: Warehouse,
+  retry: RetryPolicy,
+}
+
+impl HadoopCatalog {
+  /// A catalog of the tables in `warehouse`. Its store must be able to create a file only if it doesn't
+  /// exist, as GCS, Azure, local directories and memory can, and S3 with conditional puts.
+  pub fn new(warehouse: &Warehouse, retry: RetryPolicy) -> Self {
+    Self { warehouse: warehouse.clone(), retry }
+  }
+
+  pub fn retry(&self) -> RetryPolicy {
+    self.retry
+  }
+
+  /// The table's latest metadata, or `None` if there's no such table.
+  pub async fn load_table(&self, ns: &str, table: &str) -> Result<Option<Value>, Error> {
+    Ok(self.latest(ns, table).await?.map(|(_, metadata)| metadata))
+  }
+
+  /// Create a table in `ns` as `request`, a REST `CreateTableRequest`, describes, with the field ids it
+  /// assigns. Returns `false` if it exists already.
+  pub async fn create_table(&self, ns: &str, request: &Value) -> Result<bool, Error> {
+    let table = request["name"].as_str().unwrap_or_default();
+    if self.latest(ns, table).await?.is_some() {
+      return Ok(false);
+    }
+    let (schema, spec, order) = (&request["schema"], &request["partition-spec"], &request["write-order"]);
+    let last_partition_id = spec["fields"].as_array().into_iter().flatten()
+      .filter_map(|f| f["field-id"].as_i64())
+      .max()
+      .unwrap_or(999);
+    let metadata = json!({
+      "format-version": 2,
+      "table-uuid": uuid::Uuid::new_v4().to_string(),
+      "location": request["location"],
+      "last-sequence-number": 0,
+      "last-updated-ms": chrono::Utc::now().timestamp_millis(),
+      "last-column-id": last_column_id(schema),
+      "current-schema-id": schema["schema-id"],
+      "schemas": [schema],
+      "default-spec-id": spec["spec-id"],
+      "partition-specs": [spec],
+      "last-partition-id": last_partition_id,
+      "default-sort-order-id": order["order-id"],
+      "sort-orders": [order],
+      "properties": request["properties"],
+      "refs": {},
+      "snapshots": [],
+      "snapshot-log": [],
+      "metadata-log": [],
+    });
+    self.write_version(ns, table, 1, &metadata).await
+  }
+
+  /// Apply `updates`, REST `TableUpdate`s, to the table if it meets `requirements`, REST `TableRequirement`s.
+  /// Returns the new metadata, or `None` if a requirement failed or another writer committed first.
+  pub async fn commit(&self, ns: &str, table: &str, requirements: &Value, updates: &Value) -> Result<Option<Value>, Error> {
+    let (version, mut metadata) = self.latest(ns, table).await?
+      .ok_or_else(|| Error::config(format!("Iceberg table {}.{} doesn't exist", ns, table)))?;
+    for requirement in requirements.as_array().into_iter().flatten() {
+      if !meets(&metadata, requirement)? {
+        return Ok(None);
+      }
+    }
+
+    let previous = json!({
+      "timestamp-ms": metadata["last-updated-ms"],
+      "metadata-file": self.warehouse.url(self.version_path(ns, table, version).as_ref()),
+    });
+    for update in updates.as_array().into_iter().flatten() {
+      apply_update(&mut metadata, update)?;
+    }
+    push(&mut metadata, "metadata-log", previous);
+    metadata["last-updated-ms"] = json!(chrono::Utc::now().timestamp_millis());
+    Ok(self.write_version(ns, table, version + 1, &metadata).await?.then_some(metadata))
+  }
+
+  /// The latest version of the table's metadata and its number, or `None` if there's no such table.
+  async fn latest(&self, ns: &str, table: &str) -> Result<Option<(u64, Value)>, Error> {
+    let dir = self.warehouse.table_path(ns, table).child("metadata");
+    let hint = self.get(&dir.child(VERSION_HINT)).await?
+      .and_then(|bytes| String::from_utf8_lossy(&bytes).trim().parse::<u64>().ok());
+    let mut version = match hint {
+      Some(version) => version,
+      // Without a hint, e.g. if the writer of version 1 died before writing it, look for the versions
+      None => {
+        let versions = self.retry.run(&format!("listing {}", dir), || async {
+          let mut listing = self.warehouse.store.list(Some(&dir));
+          let mut versions = Vec::new();
+          while let Some(meta) = listing.next().await {
+            versions.extend(meta?.location.filename().and_then(version_of));
+          }
+          Ok(versions)
+        }).await?;
+        match versions.into_iter().max() {
+          Some(version) => version,
+          None => return Ok(None),
+        }
+      }
+    };
+    loop {
+      let next = self.version_path(ns, table, version + 1);
+      let exists = self.retry.run(&format!("looking for {}", next), || async {
+        match self.warehouse.store.head(&next).await {
+          Ok(_) => Ok(true),
+          Err(object_store::Error::NotFound { .. }) => Ok(false),
+          Err(e) => Err(e.into()),
+        }
+      }).await?;
+      if !exists {
+        break;
+      }
+      version += 1;
+    }
+
+    let path = self.version_path(ns, table, version);
+    let bytes = self.get(&path).await?
+      .ok_or_else(|| Error::Data(format!("{} is missing, though {} names it", path, VERSION_HINT).into()))?;
+    Ok(Some((version, serde_json::from_slice(&bytes)?)))
+  }
+
+  /// Create version `version` of the table's metadata and point the hint at it. Returns `false` if another
+  /// writer created the version first.
+  async fn write_version(&self, ns: &str, table: &str, version: u64, metadata: &Value) -> Result<bool, Error> {
+    let path = self.version_path(ns, table, version);
+    let bytes = serde_json::to_vec_pretty(metadata)?;
+    let payload = PutPayload::from(bytes.clone());
+    let created = self.retry.run(&format!("writing {}", path), || async {
+      match self.warehouse.store.put_opts(&path, payload.clone(), PutMode::Create.into()).await {
+        Ok(_) => Ok(true),
+        Err(object_store::Error::AlreadyExists { .. }) => Ok(false),
+        Err(object_store::Error::NotImplemented) => Err(Error::config(format!(
+          "The warehouse store can't create {} only if it doesn't exist, which the Hadoop catalog needs", path))),
+        Err(e) => Err(e.into()),
+      }
+    }).await?;
+    // A retried create finds the version in place if an earlier attempt wrote it and only its response was lost
+    if !created && self.get(&path).await?.as_deref() != Some(bytes.as_slice()) {
+      return Ok(false);
+    }
+
+    let hint = self.warehouse.table_path(ns, table).child("metadata").child(VERSION_HINT);
+    let payload = PutPayload::from(version.to_string().into_bytes());
+    self.retry.run(&format!("writing {}", hint), || async {
+      self.warehouse.store.put(&hint, payload.clone()).await?;
+      Ok(())
+    }).await?;
+    Ok(true)
+  }
+
+  /// The file's contents, or `None` if it doesn't exist.
+  async fn get(&self, path: &Path) -> Result<Option<bytes::Bytes>, Error> {
+    self.retry.run(&format!("reading {}", path), || async {
+      match self.warehouse.store.get(path).await {
+        Ok(result) => Ok(Some(result.bytes().await?)),
+        Err(object_store::Error::NotFound { .. }) => Ok(None),
+        Err(e) => Err(e.into()),
+      }
+    }).await
+  }
+
+  fn version_path(&self, ns: &str, table: &str, version: u64) -> Path {
+    self.warehouse.table_path(ns, table).child("metadata").child(format!("v{}.metadata.json", version))
+  }
+}
+
+/// The version of a metadata file named like `v3.metadata.json`.
+fn version_of(name: &str) -> Option<u64> {
+  name.strip_prefix('v')?.strip_suffix(".metadata.json")?.parse().ok()
+}
+
+/// Whether `metadata` meets `requirement`.
+fn meets(metadata: &Value, requirement: &Value) -> Result<bool, Error> {
+  let (actual, expected) = match requirement["type"].as_str().unwrap_or_default() {
+    "assert-table-uuid" => (&metadata["table-uuid"], &requirement["uuid"]),
+    // A ref that must not exist yet is asserted with a null snapshot id
+    "assert-ref-snapshot-id" => {
+      let name = requirement["ref"].as_str().unwrap_or_default();
+      (&metadata["refs"][name]["snapshot-id"], &requirement["snapshot-id"])
+    }
+    "assert-current-schema-id" => (&metadata["current-schema-id"], &requirement["current-schema-id"]),
+    "assert-last-assigned-field-id" => (&metadata["last-column-id"], &requirement["last-assigned-field-id"]),
+    "assert-last-assigned-partition-id" => (&metadata["last-partition-id"], &requirement["last-assigned-partition-id"]),
+    "assert-default-spec-id" => (&metadata["default-spec-id"], &requirement["default-spec-id"]),
+    "assert-default-sort-order-id" => (&metadata["default-sort-order-id"], &requirement["default-sort-order-id"]),
+    other => return Err(Error::config(format!("The Hadoop catalog doesn't support the requirement {}", other))),
+  };
+  Ok(actual == expected)
+}
+
+/// Apply `update`, a REST `TableUpdate`, to `metadata` as a catalog would.
+pub fn apply_update(metadata: &mut Value, update: &Value) -> Result<(), Error> {
+  match update["action"].as_str().unwrap_or_default() {
+    "add-snapshot" => {
+      metadata["last-sequence-number"] = update["snapshot"]["sequence-number"].clone();
+      push(metadata, "snapshots", update["snapshot"].clone());
+    }
+    "set-snapshot-ref" => {
+      let name = update["ref-name"].as_str().unwrap_or_default().to_string();
+      let mut reference = update.clone();
+      if let Some(fields) = reference.as_object_mut() {
+        fields.remove("action");
+        fields.remove("ref-name");
+      }
+      metadata["refs"][&name] = reference;
+      if name == "main" {
+        let id = &update["snapshot-id"];
+        let timestamp = metadata["snapshots"].as_array().into_iter().flatten()
+          .find(|s| s["snapshot-id"] == *id)
+          .map_or(Value::Null, |s| s["timestamp-ms"].clone());
+        metadata["current-snapshot-id"] = id.clone();
+        push(metadata, "snapshot-log", json!({ "timestamp-ms": timestamp, "snapshot-id": id }));
+      }
+    }
+    "remove-snapshots" => {
+      let ids = update["snapshot-ids"].as_array().cloned().unwrap_or_default();
+      for list in ["snapshots", "snapshot-log"] {
+        if let Some(entries) = metadata[list].as_array_mut() {
+          entries.retain(|e| !ids.contains(&e["snapshot-id"]));
+        }
+      }
+    }
+    "set-properties" => {
+      for (key, value) in update["updates"].as_object().into_iter().flatten() {
+        metadata["properties"][key] = value.clone();
+      }
+    }
+    "remove-properties" => {
+      if let Some(properties) = metadata["properties"].as_object_mut() {
+        for key in update["removals"].as_array().into_iter().flatten().filter_map(Value::as_str) {
+          properties.remove(key);
+        }
+      }
+    }
+    "add-schema" => {
+      let last_column_id = update["last-column-id"].as_i64().unwrap_or_else(|| last_column_id(&update["schema"]));
+      let current = metadata["last-column-id"].as_i64().unwrap_or(0);
+      metadata["last-column-id"] = json!(current.max(last_column_id));
+      push(metadata, "schemas", update["schema"].clone());
+    }
+    "set-current-schema" => {
+      // -1 is the schema this commit added last
+      let id = match update["schema-id"].as_i64() {
+        Some(-1) => metadata["schemas"].as_array().and_then(|s| s.last()).map_or(Value::Null, |s| s["schema-id"].clone()),
+        _ => update["schema-id"].clone(),
+      };
+      metadata["current-schema-id"] = id;
+    }
+    other => return Err(Error::config(format!("The Hadoop catalog doesn't support the update {}", other))),
+  }
+  Ok(())
+}
+
+/// Append `value` to the list `key` of `metadata`, which may not have it yet.
+fn push(metadata: &mut Value, key: &str, value: Value) {
+  match metadata[key].as_array_mut() {
+    Some(list) => list.push(value),
+    None => metadata[key] = json!([value]),
+  }
+}
+
+/// The highest field id in `schema`, including those of list elements and map keys and values.
+fn last_column_id(schema: &Value) -> i64 {
+  match schema {
+    Value::Object(fields) => fields.iter()
+      .map(|(key, value)| match key.as_str() {
+        "id" | "element-id" | "key-id" | "value-id" => value.as_i64().unwrap_or(0),
+        _ => last_column_id(value),
+      })
+      .max()
+      .unwrap_or(0),
+    Value::Array(items) => items.iter().map(last_column_id).max().unwrap_or(0),
+    _ => 0,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use object_store::RetryConfig;
+  use crate::store::GcsOptions;
+
+  fn catalog() -> HadoopCatalog {
+    let warehouse = Warehouse::open("memory://", RetryConfig::default(), &GcsOptions::default()).unwrap();
+    HadoopCatalog::new(&warehouse, RetryPolicy::default())
+  }
+
+  fn create_request() -> Value {
+    json!({
+      "name": "orders",
+      "location": "memory:///farm/orders",
+      "schema": { "type": "struct", "schema-id": 0, "fields": [
+        { "id": 1, "name": "id", "required": true, "type": "string" },
+        { "id": 2, "name": "tags", "required": false, "type": { "type": "list", "element-id": 3, "element": "string", "element-required": false } },
+      ] },
+      "partition-spec": { "spec-id": 0, "fields": [] },
+      "write-order": { "order-id": 0, "fields": [] },
+      "properties": { "format-version": "2" },
+    })
+  }
+
+  /// Updates that add snapshot `id` and point `main` at it.
+  fn add_snapshot(id: i64, seq: i64) -> Value {
+    json!([
+      { "action": "add-snapshot", "snapshot": { "snapshot-id": id, "sequence-number": seq, "timestamp-ms": 1, "manifest-list": "", "summary": { "operation": "append" } } },
+      { "action": "set-snapshot-ref", "ref-name": "main", "type": "branch", "snapshot-id": id },
+    ])
+  }
+
+  fn main_is(id: Option<i64>) -> Value {
+    json!([{ "type": "assert-ref-snapshot-id", "ref": "main", "snapshot-id": id }])
+  }
+
+  async fn hint(catalog: &HadoopCatalog) -> Option<String> {
+    let path = catalog.warehouse.table_path("farm", "orders").child("metadata").child(VERSION_HINT);
+    catalog.get(&path).await.unwrap().map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+  }
+
+  #[tokio::test]
+  async fn creates_tables_and_commits_new_versions() {
+    let catalog = catalog();
+    assert_eq!(catalog.load_table("farm", "orders").await.unwrap(), None);
+    assert!(catalog.create_table("farm", &create_request()).await.unwrap());
+    assert!(!catalog.create_table("farm", &create_request()).await.unwrap());
+
+    let created = catalog.load_table("farm", "orders").await.unwrap().unwrap();
+    assert_eq!((created["last-column-id"].as_i64(), created["last-partition-id"].as_i64()), (Some(3), Some(999)));
+    assert_eq!(created["location"], "memory:///farm/orders");
+    assert_eq!(hint(&catalog).await.as_deref(), Some("1"));
+
+    let committed = catalog.commit("farm", "orders", &main_is(None), &add_snapshot(7, 1)).await.unwrap().unwrap();
+    assert_eq!((committed["current-snapshot-id"].as_i64(), committed["last-sequence-number"].as_i64()), (Some(7), Some(1)));
+    assert_eq!(committed["metadata-log"][0]["metadata-file"], "memory:///farm/orders/metadata/v1.metadata.json");
+    assert_eq!(catalog.load_table("farm", "orders").await.unwrap(), Some(committed));
+    assert_eq!(hint(&catalog).await.as_deref(), Some("2"));
+  }
+
+  #[tokio::test]
+  async fn concurrent_commits_to_the_same_table_conflict() {
+    let catalog = catalog();
+    catalog.create_table("farm", &create_request()).await.unwrap();
+    catalog.commit("farm", "orders", &main_is(None), &add_snapshot(7, 1)).await.unwrap().unwrap();
+    // A writer that built on the table before that commit is turned down
+    assert_eq!(catalog.commit("farm", "orders", &main_is(None), &add_snapshot(8, 1)).await.unwrap(), None);
+
+    // Of two writers that both checked the requirements against version 2, the second finds version 3 taken
+    let (version, metadata) = catalog.latest("farm", "orders").await.unwrap().unwrap();
+    let mut first = metadata.clone();
+    apply_update(&mut first, &add_snapshot(9, 2)[0]).unwrap();
+    let mut second = metadata;
+    apply_update(&mut second, &add_snapshot(10, 2)[0]).unwrap();
+    assert!(catalog.write_version("farm", "orders", version + 1, &first).await.unwrap());
+    assert!(!catalog.write_version("farm", "orders", version + 1, &second).await.unwrap());
+    // while the first writer retrying a create whose response was lost finds its own version
+    assert!(catalog.write_version("farm", "orders", version + 1, &first).await.unwrap());
+    assert_eq!(catalog.load_table("farm", "orders").await.unwrap(), Some(first));
+  }
+
+  #[tokio::test]
+  async fn finds_the_latest_version_without_a_current_hint() {
+    let catalog = catalog();
+    catalog.create_table("farm", &create_request()).await.unwrap();
+    catalog.commit("farm", "orders", &main_is(None), &add_snapshot(7, 1)).await.unwrap().unwrap();
+    let metadata = catalog.warehouse.table_path("farm", "orders").child("metadata");
+
+    // A hint behind the latest version, as a writer that died between the two leaves it
+    catalog.warehouse.store.put(&metadata.child(VERSION_HINT), PutPayload::from_static(b"1")).await.unwrap();
+    let latest = catalog.load_table("farm", "orders").await.unwrap().unwrap();
+    assert_eq!(latest["current-snapshot-id"].as_i64(), Some(7));
+
+    // No hint at all, as when the writer of version 1 died before writing it
+    catalog.warehouse.store.delete(&metadata.child(VERSION_HINT)).await.unwrap();
+    assert_eq!(catalog.load_table("farm", "orders").await.unwrap(), Some(latest));
+    let committed = catalog.commit("farm", "orders", &main_is(Some(7)), &add_snapshot(8, 2)).await.unwrap().unwrap();
+    assert_eq!(committed["current-snapshot-id"].as_i64(), Some(8));
+    assert_eq!(hint(&catalog).await.as_deref(), Some("3"));
+  }
+}
@@ -0,0 +1,2730 @@
+// src/sink/iceberg.rs
+// Commits the files written by `ParquetSink` to an Iceberg table through a REST catalog (BigLake,
+// Nessie, Polaris, or a local stand-in such as the apache/iceberg-rest-fixture image), or a Hadoop
+// catalog of metadata files in the warehouse. Each commit writes a manifest of the new files, with their
+// partition values and column stats, and a manifest list that puts it next to the current snapshot's
+// manifests. The catalog then moves the table's `main` branch to the new snapshot, but only if it still
+// points at the snapshot this one builds on: a writer that loses the race reloads the table and tries
+// again. Missing tables are created from the Arrow schema of the files, and the field ids the catalog
+// assigns are written into each file's Parquet schema. When files gain columns or widen types, the
+// commit that adds them also evolves the table's schema. Tables written as upserts get equality
+// delete files on the key column, in every partition that may hold earlier rows of the same documents,
+// and position delete files for the rows a later row of the same run replaced. Maintenance merges small manifests, expires old snapshots and deletes the files no snapshot
+// uses anymore. Tables can also defer their commits and send them with other tables' in one catalog
+// transaction. Snapshots committed with a source checkpoint carry it in their summary, so a run resumes
+// from the data readers see. Only format version 2 tables are supported.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Bound;
+use std::sync::Arc;
+use arrow::array::{ArrayData, make_array};
+use arrow::datatypes::{DataType, Field, Fields, Int64Type, Schema, SchemaRef};
+use arrow::compute::cast;
+use arrow_array::cast::AsArray;
+use arrow_array::{Array, ArrayRef, Int64Array, RecordBatch, StringArray};
+use bytes::Bytes;
+use futures::StreamExt;
+use chrono::{NaiveDate, NaiveDateTime};
+use object_store::{PutPayload, path::Path};
+use reqwest::{Method, StatusCode};
+use serde_json::{Value, json};
+use crate::error::Error;
+use crate::retry::RetryPolicy;
+use crate::sink::avro::{self, Container, optional};
+use crate::sink::encoding::Encoding;
+use crate::sink::encryption::{self, KeyStore};
+use crate::sink::hadoop_catalog::{self, HadoopCatalog};
+use crate::sink::parquet_commit::CommitRecord;
+use crate::sink::parquet_writer::DataFile;
+use crate::sink::partition::{PartitionSpec, Transform, partition_of, partition_values};
+use crate::sink::sort::RowOrder;
+use crate::sink::stats::{FileStats, StatValue};
+use crate::source::firestore_listen::Checkpoint;
+use crate::store::Warehouse;
+use parquet::arrow::{ArrowWriter, PARQUET_FIELD_ID_META_KEY, ProjectionMask};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+/// Longest string kept whole as a lower or upper bound, as in Iceberg's default `truncate(16)` metrics mode.
+const BOUND_STRING_PREFIX: usize = 16;
+
+/// Table property mapping column names to field ids, for files without field ids of their own.
+const NAME_MAPPING_PROPERTY: &str = "schema.name-mapping.default";
+
+/// Snapshot summary properties holding the source checkpoint a snapshot's data was read until.
+const RESUME_TOKEN_PROPERTY: &str = "fire-to-ice.resume-token";
+const WATERMARK_PROPERTY: &str = "fire-to-ice.watermark-ms";
+
+/// Manifest entry statuses.
+const EXISTING: i32 = 0;
+const ADDED: i32 = 1;
+const DELETED: i32 = 2;
+
+/// Manifest contents.
+const DATA: i32 = 0;
+const DELETES: i32 = 1;
+
+/// Content of a delete file that deletes rows by their position in a data file.
+const POSITION_DELETES: i32 = 1;
+
+/// Content of a delete file that deletes rows by column values.
+const EQUALITY_DELETES: i32 = 2;
+
+/// Field ids the spec reserves for the data file path and row position columns of position delete files.
+const DELETE_FILE_PATH_ID: i32 = 2147483546;
+const DELETE_POS_ID: i32 = 2147483545;
+
+/// Where Iceberg tables' metadata is kept and committed.
+#[derive(Clone)]
+pub enum IcebergCatalog {
+  /// A REST catalog such as BigLake, Nessie or Polaris.
+  Rest(RestCatalog),
+  /// Metadata files in the tables' directories in the warehouse.
+  Hadoop(HadoopCatalog),
+}
+
+impl IcebergCatalog {
+  /// The table's current metadata, or `None` if there's no such table.
+  async fn load_table(&self, ns: &str, table: &str) -> Result<Option<Value>, Error> {
+    match self {
+      Self::Rest(catalog) => catalog.load_table(ns, table).await,
+      Self::Hadoop(catalog) => catalog.load_table(ns, table).await,
+    }
+  }
+
+  /// Create namespace `ns` unless it exists. Hadoop namespaces are just directories.
+  async fn create_namespace(&self, ns: &str) -> Result<(), Error> {
+    match self {
+      Self::Rest(catalog) => catalog.create_namespace(ns).await,
+      Self::Hadoop(_) => Ok(()),
+    }
+  }
+
+  /// Create a table in `ns` as `request`, a REST `CreateTableRequest`, describes. Returns `false` if it
+  /// exists already.
+  async fn create_table(&self, ns: &str, request: &Value) -> Result<bool, Error> {
+    match self {
+      Self::Rest(catalog) => catalog.create_table(ns, request).await,
+      Self::Hadoop(catalog) => catalog.create_table(ns, request).await,
+    }
+  }
+
+  /// Apply `updates` to the table if it still meets `requirements`. Returns the new metadata, or `None` if the
+  /// table changed.
+  async fn commit(&self, ns: &str, table: &str, requirements: Value, updates: Value) -> Result<Option<Value>, Error> {
+    match self {
+      Self::Rest(catalog) => catalog.commit(ns, table, requirements, updates).await,
+      Self::Hadoop(catalog) => catalog.commit(ns, table, &requirements, &updates).await,
+    }
+  }
+
+  /// Apply the changes to several tables, each a REST `UpdateTableRequest` with the table's identifier, at once
+  /// if every table still meets its requirements. Returns `false` if one of them changed.
+  async fn commit_transaction(&self, changes: Vec<Value>) -> Result<bool, Error> {
+    match self {
+      Self::Rest(catalog) => catalog.commit_transaction(changes).await,
+      Self::Hadoop(_) => Err(Error::config("Committing several Iceberg tables at once needs a REST catalog")),
+    }
+  }
+
+  fn retry(&self) -> RetryPolicy {
+    match self {
+      Self::Rest(catalog) => catalog.retry,
+      Self::Hadoop(catalog) => catalog.retry(),
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct RestCatalog {
+  client: reqwest::Client,
+  /// Base URL of the API including the catalog's prefix, e.g. `http://localhost:8181/v1/`.
+  base: String,
+  token: Option<String>,
+  retry: RetryPolicy,
+}
+
+impl RestCatalog {
+  /// Connect to the catalog at `uri`, e.g. `http://localhost:8181`, and fetch its settings for `warehouse`.
+  /// `token` is sent as a bearer token.
+  pub async fn connect(uri: &str, warehouse: Option<&str>, token: Option<&str>, retry: RetryPolicy) -> Result<Self, Error> {
+    let mut catalog = Self {
+      client: reqwest::Client::new(),
+      base: format!("{}/v1/", uri.trim_end_matches('/')),
+      token: token.map(String::from),
+      retry,
+    };
+    let query: Vec<(&str, &str)> = warehouse.map(|w| ("warehouse", w)).into_iter().collect();
+    let config = catalog.get("config", &query, "fetching the Iceberg catalog config").await?;
+    let prefix = config["overrides"]["prefix"].as_str().or(config["defaults"]["prefix"].as_str());
+    if let Some(prefix) = prefix.filter(|p| !p.is_empty()) {
+      catalog.base = format!("{}{}/", catalog.base, prefix.trim_matches('/'));
+    }
+    Ok(catalog)
+  }
+
+  /// The table's current metadata, or `None` if there's no such table.
+  async fn load_table(&self, ns: &str, table: &str) -> Result<Option<Value>, Error> {
+    let what = format!("loading Iceberg table {}.{}", ns, table);
+    self.retry.run(&what, || async {
+      let (status, response) = self.send(Method::GET, &table_path(ns, table), &[], None).await?;
+      match status {
+        s if s.is_success() => Ok(Some(response["metadata"].clone())),
+        StatusCode::NOT_FOUND => Ok(None),
+        s => Err(status_error(s, &response, &what)),
+      }
+    }).await
+  }
+
+  /// Create namespace `ns` unless it exists.
+  async fn create_namespace(&self, ns: &str) -> Result<(), Error> {
+    let body = json!({ "namespace": [ns], "properties": {} });
+    let what = format!("creating Iceberg namespace {}", ns);
+    self.retry.run(&what, || async {
+      let (status, response) = self.send(Method::POST, "namespaces", &[], Some(&body)).await?;
+      match status {
+        s if s.is_success() || s == StatusCode::CONFLICT => Ok(()),
+        s => Err(status_error(s, &response, &what)),
+      }
+    }).await
+  }
+
+  /// Create a table in `ns` as `request` describes. Returns `false` if it exists already, e.g. because another
+  /// writer created it first.
+  async fn create_table(&self, ns: &str, request: &Value) -> Result<bool, Error> {
+    let what = format!("creating Iceberg table {}.{}", ns, request["name"].as_str().unwrap_or_default());
+    self.retry.run(&what, || async {
+      let (status, response) = self.send(Method::POST, &tables_path(ns), &[], Some(request)).await?;
+      match status {
+        s if s.is_success() => Ok(true),
+        StatusCode::CONFLICT => Ok(false),
+        s => Err(status_error(s, &response, &what)),
+      }
+    }).await
+  }
+
+  /// Apply `updates` to the table if it still meets `requirements`. Returns the new metadata, or `None` if a
+  /// requirement failed because the table changed. Retrying after an unknown outcome is safe: a repeated
+  /// commit fails its requirements.
+  async fn commit(&self, ns: &str, table: &str, requirements: Value, updates: Value) -> Result<Option<Value>, Error> {
+    let body = json!({
+      "identifier": { "namespace": [ns], "name": table },
+      "requirements": requirements,
+      "updates": updates,
+    });
+    let what = format!("committing to Iceberg table {}.{}", ns, table);
+    self.retry.run(&what, || async {
+      let (status, response) = self.send(Method::POST, &table_path(ns, table), &[], Some(&body)).await?;
+      match status {
+        s if s.is_success() => Ok(Some(response["metadata"].clone())),
+        StatusCode::CONFLICT => Ok(None),
+        s => Err(status_error(s, &response, &what)),
+      }
+    }).await
+  }
+
+  /// Apply `changes` to their tables in one transaction. Returns `false` if a requirement failed.
+  async fn commit_transaction(&self, changes: Vec<Value>) -> Result<bool, Error> {
+    let body = json!({ "table-changes": changes });
+    let what = "committing a transaction to the Iceberg catalog";
+    self.retry.run(what, || async {
+      let (status, response) = self.send(Method::POST, "transactions/commit", &[], Some(&body)).await?;
+      match status {
+        s if s.is_success() => Ok(true),
+        StatusCode::CONFLICT => Ok(false),
+        s => Err(status_error(s, &response, what)),
+      }
+    }).await
+  }
+
+  async fn get(&self, path: &str, query: &[(&str, &str)], what: &str) -> Result<Value, Error> {
+    self.retry.run(what, || async {
+      let (status, response) = self.send(Method::GET, path, query, None).await?;
+      if !status.is_success() {
+        return Err(status_error(status, &response, what));
+      }
+      Ok(response)
+    }).await
+  }
+
+  /// Send a request and return the status and JSON body, whatever the status.
+  async fn send(&self, method: Method, path: &str, query: &[(&str, &str)], body: Option<&Value>) -> Result<(StatusCode, Value), Error> {
+    let mut request = self.client.request(method, format!("{}{}", self.base, path)).query(query);
+    if let Some(token) = &self.token {
+      request = request.bearer_auth(token);
+    }
+    if let Some(body) = body {
+      request = request.json(body);
+    }
+    let response = request.send().await?;
+    let status = response.status();
+    let bytes = response.bytes().await?;
+    let body = serde_json::from_slice(&bytes).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into_owned()));
+    Ok((status, body))
+  }
+}
+
+/// A data file of an Iceberg table's current snapshot, to read it.
+pub struct ScanFile {
+  /// Path in the warehouse.
+  pub path: Path,
+  /// Values the table's equality deletes remove from the file, by column. Rows holding one are deleted.
+  pub deleted: HashMap<String, HashSet<String>>,
+  /// Positions of the rows the table's position deletes remove from the file.
+  pub deleted_positions: BTreeSet<u64>,
+}
+
+pub struct IcebergTable {
+  catalog: IcebergCatalog,
+  warehouse: Warehouse,
+  retry: RetryPolicy,
+  ns: String,
+  table: String,
+  /// The table's metadata as last loaded or committed.
+  metadata: Value,
+  /// Manifests of the current snapshot, as manifest list entries.
+  manifests: Vec<avro::Value>,
+  /// Data files in the current snapshot by URL, with the manifest listing each.
+  files: HashMap<String, String>,
+  /// The schema the next commit adds, with the table's last column id after it, when files written since the
+  /// last commit have columns or types the current schema lacks.
+  evolved: Option<(Value, i64)>,
+  /// Arrow schemas of those files, to evolve the schema again should another writer change it first.
+  evolved_from: Vec<SchemaRef>,
+  /// Column that identifies a document, for tables written as upserts.
+  key_column: Option<String>,
+  /// Whether commits wait for `commit_together`, which sends them with other tables'.
+  deferred: bool,
+  /// Files appended since, the files of a run whose missing documents to delete, and the keys of deleted
+  /// documents, for deferred tables.
+  pending: Vec<DataFile>,
+  missing: Option<Vec<DataFile>>,
+  deleted: BTreeSet<String>,
+  /// Source position that the snapshots committed next hold every change until.
+  checkpoint: Option<Checkpoint>,
+  /// Snapshots built for `commit_together` but not committed yet.
+  staged: Option<Staged>,
+}
+
+/// The range of a string column's values in a data file. A missing bound leaves that side open.
+struct KeyRange {
+  lower: Option<String>,
+  upper: Option<String>,
+}
+
+impl KeyRange {
+  /// Whether the file may hold `key`. A lower bound may be a prefix of the least value, which sorts before it.
+  fn contains(&self, key: &str) -> bool {
+    self.lower.as_deref().is_none_or(|lower| lower <= key) && self.upper.as_deref().is_none_or(|upper| key <= upper)
+  }
+
+  /// The keys in `keys` the file may hold.
+  fn select<'a>(&self, keys: &BTreeSet<&'a str>) -> Vec<&'a str> {
+    let lower = self.lower.as_deref().map_or(Bound::Unbounded, Bound::Included);
+    let upper = self.upper.as_deref().map_or(Bound::Unbounded, Bound::Included);
+    if let (Bound::Included(lower), Bound::Included(upper)) = (lower, upper) && lower > upper {
+      return Vec::new();
+    }
+    keys.range::<str, _>((lower, upper)).copied().collect()
+  }
+
+  /// The one value the file holds, if its bounds are the same.
+  fn only(&self) -> Option<&str> {
+    match (&self.lower, &self.upper) {
+      (Some(lower), Some(upper)) if lower == upper => Some(lower),
+      _ => None,
+    }
+  }
+}
+
+/// A deferred table's changes, as a catalog commit request, and the schema evolution they include.
+#[derive(Default)]
+struct Staged {
+  requirements: Vec<Value>,
+  updates: Vec<Value>,
+  evolved: Option<(Value, i64)>,
+  evolved_from: Vec<SchemaRef>,
+}
+
+impl IcebergTable {
+  /// Load `ns.table` from the catalog. The table must exist, and its location must be in `warehouse`,
+  /// where manifests are written.
+  pub async fn open(catalog: &IcebergCatalog, warehouse: &Warehouse, ns: &str, table: &str) -> Result<Self, Error> {
+    let mut iceberg = Self {
+      catalog: catalog.clone(),
+      warehouse: warehouse.clone(),
+      retry: catalog.retry(),
+      ns: ns.to_string(),
+      table: table.to_string(),
+      metadata: Value::Null,
+      manifests: Vec::new(),
+      files: HashMap::new(),
+      evolved: None,
+      evolved_from: Vec::new(),
+      key_column: None,
+      deferred: false,
+      pending: Vec::new(),
+      missing: None,
+      deleted: BTreeSet::new(),
+      checkpoint: None,
+      staged: None,
+    };
+    iceberg.refresh().await?;
+    Ok(iceberg)
+  }
+
+  /// Like `open`, but first creates the namespace and the table if they don't exist: with the columns of
+  /// `schema`, partitioned by `spec`, sorted by `order` and located in the table's directory in `warehouse`.
+  pub async fn create(catalog: &IcebergCatalog, warehouse: &Warehouse, ns: &str, table: &str, schema: &Schema, spec: &PartitionSpec, order: &RowOrder) -> Result<Self, Error> {
+    if catalog.load_table(ns, table).await?.is_none() {
+      let schema = iceberg_schema(schema)?;
+      let request = json!({
+        "name": table,
+        "location": warehouse.url(warehouse.table_path(ns, table).as_ref()),
+        "partition-spec": partition_spec(spec, &schema)?,
+        "write-order": sort_order(order, &schema)?,
+        "schema": schema,
+        "stage-create": false,
+        "properties": { "format-version": "2" },
+      });
+      catalog.create_namespace(ns).await?;
+      if catalog.create_table(ns, &request).await? {
+        println!("🧊 Created Iceberg table {}.{}", ns, table);
+      }
+    }
+    Self::open(catalog, warehouse, ns, table).await
+  }
+
+  /// Write the table as upserts: every file appended must carry the values of `column` it holds, and is
+  /// committed with an equality delete file on `column` that removes the earlier rows with those values.
+  pub fn with_key_column(mut self, column: &str) -> Self {
+    self.key_column = Some(column.into());
+    self
+  }
+
+  /// Hold back the table's commits until `commit_together` sends them in one commit, with other tables' if
+  /// given any. Until then `append`, `delete_missing` and `delete_documents` only note what to commit.
+  pub fn deferred(mut self) -> Self {
+    self.deferred = true;
+    self
+  }
+
+  /// Record `checkpoint` in the summary of the snapshots committed from now on.
+  pub fn set_checkpoint(&mut self, checkpoint: Checkpoint) {
+    self.checkpoint = Some(checkpoint);
+  }
+
+  /// The source checkpoint of the latest snapshot of `main` that has one, going back through its ancestors
+  /// past snapshots committed without one, as earlier versions of `compact` and `maintain` did.
+  pub fn last_checkpoint(&self) -> Option<Checkpoint> {
+    self.checkpoint_snapshot().map(|(_, checkpoint)| checkpoint)
+  }
+
+  /// The id of the snapshot `last_checkpoint` is read from, and the checkpoint.
+  fn checkpoint_snapshot(&self) -> Option<(i64, Checkpoint)> {
+    let snapshots: HashMap<i64, &Value> = self.metadata["snapshots"].as_array().into_iter().flatten()
+      .filter_map(|s| Some((s["snapshot-id"].as_i64()?, s)))
+      .collect();
+    let mut id = self.metadata["refs"]["main"]["snapshot-id"].as_i64();
+    while let Some(snapshot) = id.and_then(|id| snapshots.get(&id)) {
+      let summary = &snapshot["summary"];
+      if let (Some(token), Some(watermark)) = (summary[RESUME_TOKEN_PROPERTY].as_str(), summary[WATERMARK_PROPERTY].as_str()) {
+        let checkpoint = Checkpoint { resume_token: token.to_string(), watermark_ms: watermark.parse().ok()? };
+        return Some((snapshot["snapshot-id"].as_i64()?, checkpoint));
+      }
+      id = snapshot["parent-snapshot-id"].as_i64();
+    }
+    None
+  }
+
+  /// `batch` with the table's field id of each column in its Parquet field metadata, so readers match the
+  /// columns of the files it's written to by id. New columns and wider types are added to the schema the
+  /// next commit writes; changes Iceberg can't make to a table are refused.
+  pub fn with_field_ids(&mut self, batch: &RecordBatch) -> Result<RecordBatch, Error> {
+    self.evolve(&batch.schema())?;
+    let table_fields = &self.schema()?["fields"];
+    let (fields, columns): (Vec<Field>, Vec<ArrayRef>) = batch.schema().fields().iter().zip(batch.columns())
+      .map(|(field, column)| match find_field(table_fields, field.name()) {
+        Some(table_field) => {
+          let field = with_field_id(field, &table_field["id"], &table_field["type"]);
+          let column = make_array(retype(column.to_data(), field.data_type())?);
+          Ok((field, column))
+        }
+        None => Ok((field.as_ref().clone(), column.clone())),
+      })
+      .collect::<Result<Vec<_>, Error>>()?
+      .into_iter()
+      .unzip();
+    let schema = Schema::new_with_metadata(fields, batch.schema().metadata().clone());
+    Ok(RecordBatch::try_new(Arc::new(schema), columns)?)
+  }
+
+  /// Add the files an ingestion run committed in a new `append` snapshot. Files already in the table are
+  /// skipped, so committing the same files again, e.g. after a retried run, is a no-op. Only Parquet files
+  /// belong in an Iceberg table; other encodings are left out. Upserts make it an `overwrite` snapshot that
+  /// also deletes the earlier rows of the files' keys, whichever partition they're in, and the rows that a
+  /// later row of the run replaced. Returns the new snapshot's id, if any.
+  pub async fn append(&mut self, files: &[DataFile]) -> Result<Option<i64>, Error> {
+    let files: Vec<&DataFile> = files.iter().filter(|f| f.encoding == Encoding::Parquet).collect();
+    for f in &files {
+      self.evolve(&f.schema)?;
+    }
+    if self.deferred && self.staged.is_none() {
+      self.pending.extend(files.into_iter().cloned());
+      return Ok(None);
+    }
+    loop {
+      let added: Vec<&DataFile> = files.iter().filter(|f| !self.files.contains_key(&self.warehouse.url(&f.path))).copied().collect();
+      if added.is_empty() {
+        return Ok(None);
+      }
+
+      let (snapshot, seq) = self.next_snapshot();
+      let entries = added.iter()
+        .map(|f| self.entry(&f.path, &f.partition, f.size, &f.stats, snapshot))
+        .collect::<Result<Vec<_>, _>>()?;
+      let manifest = self.write_manifest(entries, DATA, snapshot, seq).await?;
+      let manifest_path = manifest.get("manifest_path").and_then(avro::Value::as_str).unwrap_or_default().to_string();
+      let mut manifests = vec![manifest];
+      let mut summary = json!({
+        "operation": "append",
+        "added-data-files": added.len().to_string(),
+        "added-records": added.iter().map(|f| f.rows).sum::<u64>().to_string(),
+        "added-files-size": added.iter().map(|f| f.size).sum::<u64>().to_string(),
+      });
+      if let Some(column) = &self.key_column {
+        let mut keys = BTreeSet::new();
+        for f in &added {
+          let file_keys = f.keys.as_ref().ok_or_else(|| Error::Data(format!(
+            "{} was written without recording its {} values, which upserts need", f.path, column).into()))?;
+          keys.extend(file_keys.iter().map(String::as_str));
+        }
+        // An equality delete only applies to data files with a lower sequence number, so it leaves the rows
+        // committed with it alone. A document's earlier rows may be in another partition than its new one, so
+        // every partition whose files' key bounds may hold some of the keys gets a delete file of those.
+        let mut by_partition: BTreeMap<String, BTreeSet<&str>> = BTreeMap::new();
+        for (url, range) in self.key_ranges(column).await? {
+          let in_range = range.select(&keys);
+          if !in_range.is_empty() {
+            let partition = partition_of(self.path_of(&url)?.as_ref()).unwrap_or_default().to_string();
+            by_partition.entry(partition).or_default().extend(in_range);
+          }
+        }
+        let mut deletes = self.write_key_deletes(&by_partition, "upserts", snapshot).await?;
+        // Position deletes also apply to data files of the same snapshot, so they remove the rows of the run
+        // that a later row replaced
+        for f in added.iter().filter(|f| !f.superseded.is_empty()) {
+          deletes.push(self.write_position_deletes(&self.delete_path(&f.path)?, f, snapshot).await?);
+        }
+        if !deletes.is_empty() {
+          add_delete_summary(&mut summary, &deletes);
+          manifests.push(self.write_manifest(deletes, DELETES, snapshot, seq).await?);
+        }
+      }
+      manifests.extend(self.live_manifests());
+
+      if self.commit(snapshot, seq, manifests, summary).await? {
+        for f in added {
+          self.files.insert(self.warehouse.url(&f.path), manifest_path.clone());
+        }
+        return Ok(Some(snapshot));
+      }
+    }
+  }
+
+  /// Make the table match `live`, the files committed under `_committed/`, after a compaction, in a new
+  /// `replace` snapshot. Stats are taken from `written` where a file was just written; other files are added
+  /// with a row count only. Open the table before listing `live`, so files appended in between are not
+  /// mistaken for removed ones.
+  pub async fn sync(&mut self, live: &[CommitRecord], written: &[DataFile]) -> Result<Option<i64>, Error> {
+    let live: Vec<&CommitRecord> = live.iter().filter(|f| Encoding::of(&f.path) == Some(Encoding::Parquet)).collect();
+    for f in written.iter().filter(|f| f.encoding == Encoding::Parquet) {
+      self.evolve(&f.schema)?;
+    }
+    loop {
+      let live_urls: HashSet<String> = live.iter().map(|f| self.warehouse.url(&f.path)).collect();
+      let mut removed: HashMap<&str, HashSet<&str>> = HashMap::new();
+      for (url, manifest) in &self.files {
+        if !live_urls.contains(url) {
+          removed.entry(manifest.as_str()).or_default().insert(url.as_str());
+        }
+      }
+      let added: Vec<&CommitRecord> = live.iter().filter(|f| !self.files.contains_key(&self.warehouse.url(&f.path))).copied().collect();
+      if removed.is_empty() && added.is_empty() {
+        return Ok(None);
+      }
+
+      let (snapshot, seq) = self.next_snapshot();
+      let mut manifests = Vec::new();
+      if !added.is_empty() {
+        let entries = added.iter()
+          .map(|record| match written.iter().find(|f| f.path == record.path) {
+            Some(f) => self.entry(&f.path, &f.partition, f.size, &f.stats, snapshot),
+            None => {
+              let stats = FileStats { rows: record.rows, ..Default::default() };
+              self.entry(&record.path, partition_of(&record.path).unwrap_or_default(), record.size, &stats, snapshot)
+            }
+          })
+          .collect::<Result<Vec<_>, _>>()?;
+        manifests.push(self.write_manifest(entries, DATA, snapshot, seq).await?);
+      }
+      let (mut deleted_files, mut deleted_rows) = (0, 0);
+      for manifest in self.live_manifests() {
+        let path = manifest.get("manifest_path").and_then(avro::Value::as_str).unwrap_or_default();
+        match removed.get(path) {
+          Some(paths) => {
+            let (rewritten, files, rows) = self.rewrite_manifest(&manifest, paths, snapshot, seq).await?;
+            manifests.push(rewritten);
+            deleted_files += files;
+            deleted_rows += rows;
+          }
+          None => manifests.push(manifest),
+        }
+      }
+      let summary = json!({
+        "operation": "replace",
+        "added-data-files": added.len().to_string(),
+        "added-records": added.iter().map(|f| f.rows).sum::<u64>().to_string(),
+        "deleted-data-files": deleted_files.to_string(),
+        "deleted-records": deleted_rows.to_string(),
+      });
+
+      if self.commit(snapshot, seq, manifests, summary).await? {
+        // Rewritten manifests moved files around; reading them back is simpler than tracking that
+        self.refresh().await?;
+        return Ok(Some(snapshot));
+      }
+    }
+  }
+
+  /// Delete the rows of documents an ingestion run didn't find, in a new `overwrite` snapshot: keys that files
+  /// committed before the run hold and none of `run_files` do. A run reads the whole collection, so those
+  /// documents are gone from it. Every earlier file is read for its keys but those whose key bounds show they
+  /// only hold a document the run saw. Encrypted files need `keys`. Returns the new snapshot's id, if any.
+  pub async fn delete_missing(&mut self, run_files: &[DataFile], keys: Option<&KeyStore>) -> Result<Option<i64>, Error> {
+    let Some(column) = self.key_column.clone() else {
+      return Ok(None);
+    };
+    if self.deferred && self.staged.is_none() {
+      self.missing = Some(run_files.to_vec());
+      return Ok(None);
+    }
+    let run_urls: HashSet<String> = run_files.iter().map(|f| self.warehouse.url(&f.path)).collect();
+    let seen: HashSet<&str> = run_files.iter().flat_map(|f| f.keys.iter().flatten()).map(String::as_str).collect();
+    loop {
+      let mut missing: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+      for (url, range) in self.key_ranges(&column).await? {
+        if run_urls.contains(&url) || range.only().is_some_and(|key| seen.contains(key)) {
+          continue;
+        }
+        let partition = partition_of(self.path_of(&url)?.as_ref()).unwrap_or_default().to_string();
+        for key in self.read_keys(&url, &column, keys).await? {
+          if !seen.contains(key.as_str()) {
+            missing.entry(partition.clone()).or_default().insert(key);
+          }
+        }
+      }
+      if missing.is_empty() {
+        return Ok(None);
+      }
+      if let Some(snapshot) = self.delete_keys(&missing, "missing").await? {
+        let count: usize = missing.values().map(BTreeSet::len).sum();
+        println!("🗑️ Deleted {} documents missing from the collection from {}.{}", count, self.ns, self.table);
+        return Ok(Some(snapshot));
+      }
+    }
+  }
+
+  /// Delete the rows of the documents with the keys in `deleted`, in a new `overwrite` snapshot, e.g. the
+  /// documents a run read the deletion of. Rows committed with or after the deletion's snapshot stay, so a
+  /// document created again must be left out. No data file is read: each partition gets the keys that its
+  /// files' key bounds hold. Returns the new snapshot's id, if any.
+  pub async fn delete_documents(&mut self, deleted: &BTreeSet<String>) -> Result<Option<i64>, Error> {
+    let Some(column) = self.key_column.clone() else {
+      return Ok(None);
+    };
+    if deleted.is_empty() {
+      return Ok(None);
+    }
+    if self.deferred && self.staged.is_none() {
+      self.deleted.extend(deleted.iter().cloned());
+      return Ok(None);
+    }
+    loop {
+      let mut found: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+      for (url, range) in self.key_ranges(&column).await? {
+        let in_range: BTreeSet<String> = deleted.iter().filter(|key| range.contains(key)).cloned().collect();
+        if !in_range.is_empty() {
+          let partition = partition_of(self.path_of(&url)?.as_ref()).unwrap_or_default().to_string();
+          found.entry(partition).or_default().extend(in_range);
+        }
+      }
+      if found.is_empty() {
+        return Ok(None);
+      }
+      if let Some(snapshot) = self.delete_keys(&found, "deleted").await? {
+        let count = found.values().flatten().collect::<BTreeSet<_>>().len();
+        println!("🗑️ Deleted {} documents deleted from the collection from {}.{}", count, self.ns, self.table);
+        return Ok(Some(snapshot));
+      }
+    }
+  }
+
+  /// Delete the rows holding the keys of each partition in `by_partition`, in a new `overwrite` snapshot of an
+  /// equality delete file per partition, named after `name`. Returns the snapshot's id, or `None` if the table
+  /// changed first and was reloaded.
+  async fn delete_keys(&mut self, by_partition: &BTreeMap<String, BTreeSet<String>>, name: &str) -> Result<Option<i64>, Error> {
+    let (snapshot, seq) = self.next_snapshot();
+    let by_partition = by_partition.iter()
+      .map(|(partition, keys)| (partition.clone(), keys.iter().map(String::as_str).collect()))
+      .collect();
+    let deletes = self.write_key_deletes(&by_partition, name, snapshot).await?;
+    let mut summary = json!({});
+    add_delete_summary(&mut summary, &deletes);
+    let mut manifests = vec![self.write_manifest(deletes, DELETES, snapshot, seq).await?];
+    manifests.extend(self.live_manifests());
+    Ok(self.commit(snapshot, seq, manifests, summary).await?.then_some(snapshot))
+  }
+
+  /// Write an equality delete file of the keys of each partition in `by_partition`, named after `name` and
+  /// `snapshot`, and return their manifest entries.
+  async fn write_key_deletes(&self, by_partition: &BTreeMap<String, BTreeSet<&str>>, name: &str, snapshot: i64) -> Result<Vec<avro::Value>, Error> {
+    let deletes_dir = self.warehouse.table_path(&self.ns, &self.table).child("deletes");
+    let mut deletes = Vec::new();
+    for (partition, keys) in by_partition {
+      let dir = if partition.is_empty() { deletes_dir.to_string() } else { format!("{}/{}", deletes_dir, partition) };
+      let path = Path::parse(format!("{}/{}-{}.parquet", dir, name, snapshot)).map_err(|e| Error::Data(Box::new(e)))?;
+      deletes.push(self.write_deletes(&path, partition, keys.clone(), snapshot).await?);
+    }
+    Ok(deletes)
+  }
+
+  /// The bounds of `column` in each data file of the current snapshot, by URL, as its manifests record them.
+  async fn key_ranges(&self, column: &str) -> Result<HashMap<String, KeyRange>, Error> {
+    let id = find_field(&self.schema()?["fields"], column).and_then(|f| f["id"].as_i64());
+    let bound = |file: &avro::Value, name: &str| -> Option<String> {
+      let Some(avro::Value::Array(bounds)) = file.get(name) else { return None };
+      bounds.iter()
+        .find(|b| b.get("key").and_then(avro::Value::as_long) == id)
+        .and_then(|b| match b.get("value") {
+          Some(avro::Value::Bytes(bytes)) => String::from_utf8(bytes.clone()).ok(),
+          _ => None,
+        })
+    };
+    let mut ranges = HashMap::new();
+    for manifest in self.live_manifests() {
+      if manifest.get("content").and_then(avro::Value::as_long).unwrap_or(0) != 0 {
+        continue;
+      }
+      let path = manifest.get("manifest_path").and_then(avro::Value::as_str).unwrap_or_default();
+      for entry in self.read(path).await?.records.into_iter().filter_map(|entry| existing_entry(entry, &manifest)) {
+        let Some(file) = entry.get("data_file") else { continue };
+        let url = file.get("file_path").and_then(avro::Value::as_str).unwrap_or_default().to_string();
+        ranges.insert(url, KeyRange { lower: bound(file, "lower_bounds"), upper: bound(file, "upper_bounds") });
+      }
+    }
+    Ok(ranges)
+  }
+
+  /// The data files of the current snapshot, with the rows the table's deletes remove from each: those with a
+  /// deleted value that an equality delete file committed after the data file lists, in the data file's
+  /// partition or in an unpartitioned one, and those at a position that a position delete file committed with
+  /// or after the data file lists. Encrypted delete files need `keys`.
+  pub async fn scan(&self, keys: Option<&KeyStore>) -> Result<Vec<ScanFile>, Error> {
+    let long = |v: &avro::Value, name: &str| v.get(name).and_then(avro::Value::as_long);
+    // Files as their URL, partition spec id, partition tuple and sequence number
+    let mut data = Vec::new();
+    let mut deletes = Vec::new();
+    // Positions deleted from each data file, with the sequence number of the delete file
+    let mut positions: HashMap<String, Vec<(i64, u64)>> = HashMap::new();
+    for manifest in &self.manifests {
+      let path = manifest.get("manifest_path").and_then(avro::Value::as_str).unwrap_or_default();
+      let spec = long(manifest, "partition_spec_id").unwrap_or(0);
+      for entry in self.read(path).await?.records.into_iter().filter_map(|entry| existing_entry(entry, manifest)) {
+        let Some(file) = entry.get("data_file") else { continue };
+        let url = file.get("file_path").and_then(avro::Value::as_str).unwrap_or_default().to_string();
+        let partition = file.get("partition").cloned().unwrap_or(avro::Value::Null);
+        let seq = long(&entry, "sequence_number").unwrap_or(0);
+        match long(file, "content").unwrap_or(0) as i32 {
+          0 => data.push((url, spec, partition, seq)),
+          EQUALITY_DELETES => {
+            let ids: Vec<i64> = match file.get("equality_ids") {
+              Some(avro::Value::Array(ids)) => ids.iter().filter_map(avro::Value::as_long).collect(),
+              _ => Vec::new(),
+            };
+            let column = match ids.as_slice() {
+              [id] => self.schema()?["fields"].as_array().into_iter().flatten()
+                .find(|f| f["id"] == *id)
+                .and_then(|f| f["name"].as_str())
+                .ok_or_else(|| Error::Data(format!("{} deletes by field {}, which isn't a column of {}.{}", url, id, self.ns, self.table).into()))?
+                .to_string(),
+              _ => return Err(Error::config(format!("{} deletes by several columns, which reading doesn't support", url))),
+            };
+            let values: HashSet<String> = self.read_keys(&url, &column, keys).await?.into_iter().collect();
+            deletes.push((spec, partition, seq, column, values));
+          }
+          POSITION_DELETES => {
+            for (path, position) in self.read_positions(&url, keys).await? {
+              positions.entry(path).or_default().push((seq, position));
+            }
+          }
+          content => return Err(Error::Data(format!("{} has unknown content {}", url, content).into())),
+        }
+      }
+    }
+
+    data.into_iter()
+      .map(|(url, spec, partition, seq)| {
+        let mut deleted: HashMap<String, HashSet<String>> = HashMap::new();
+        for (delete_spec, delete_partition, delete_seq, column, values) in &deletes {
+          let global = matches!(delete_partition, avro::Value::Record(fields) if fields.is_empty());
+          if *delete_seq > seq && (global || (*delete_spec == spec && *delete_partition == partition)) {
+            deleted.entry(column.clone()).or_default().extend(values.iter().cloned());
+          }
+        }
+        let deleted_positions = positions.get(&url).into_iter().flatten()
+          .filter(|(delete_seq, _)| *delete_seq >= seq)
+          .map(|(_, position)| *position)
+          .collect();
+        Ok(ScanFile { path: self.path_of(&url)?, deleted, deleted_positions })
+      })
+      .collect()
+  }
+
+  /// Merge the current snapshot's manifests smaller than `target_size` bytes into manifests of about that size,
+  /// in a new `replace` snapshot that leaves the table's files as they are. Only manifests written with the
+  /// same Avro schema, and so with the same content and partition spec, are merged. Returns the new snapshot's
+  /// id, if any.
+  pub async fn merge_manifests(&mut self, target_size: u64) -> Result<Option<i64>, Error> {
+    loop {
+      let mut manifests = Vec::new();
+      let mut small: BTreeMap<(i64, i64), Vec<avro::Value>> = BTreeMap::new();
+      for manifest in self.live_manifests() {
+        let long = |name: &str| manifest.get(name).and_then(avro::Value::as_long).unwrap_or(0);
+        let (length, group) = (long("manifest_length") as u64, (long("content"), long("partition_spec_id")));
+        if length < target_size {
+          small.entry(group).or_default().push(manifest);
+        } else {
+          manifests.push(manifest);
+        }
+      }
+
+      let (snapshot, seq) = self.next_snapshot();
+      let (mut created, mut replaced) = (0, 0);
+      for group in small.into_values() {
+        if group.len() < 2 {
+          manifests.extend(group);
+          continue;
+        }
+        // Each bin is a merged manifest, the manifests that went into it and their total size
+        let mut bins: Vec<(Container, Vec<avro::Value>, u64)> = Vec::new();
+        for manifest in group {
+          let path = manifest.get("manifest_path").and_then(avro::Value::as_str).unwrap_or_default();
+          let length = manifest.get("manifest_length").and_then(avro::Value::as_long).unwrap_or(0) as u64;
+          let mut container = self.read(path).await?;
+          let entries: Vec<avro::Value> = std::mem::take(&mut container.records).into_iter()
+            .filter_map(|entry| existing_entry(entry, &manifest))
+            .collect();
+          match bins.iter_mut().find(|(merged, _, size)| merged.schema == container.schema && size + length <= target_size) {
+            Some((merged, sources, size)) => {
+              merged.records.extend(entries);
+              sources.push(manifest);
+              *size += length;
+            }
+            None => {
+              container.records = entries;
+              bins.push((container, vec![manifest], length));
+            }
+          }
+        }
+        for (merged, sources, _) in bins {
+          if sources.len() < 2 {
+            manifests.extend(sources);
+            continue;
+          }
+          manifests.push(self.write_entries(merged, &sources[0], snapshot, seq).await?);
+          created += 1;
+          replaced += sources.len();
+        }
+      }
+      if created == 0 {
+        return Ok(None);
+      }
+      let summary = json!({
+        "operation": "replace",
+        "manifests-created": created.to_string(),
+        "manifests-replaced": replaced.to_string(),
+        "manifests-kept": (manifests.len() - created).to_string(),
+      });
+
+      if self.commit(snapshot, seq, manifests, summary).await? {
+        // The files are listed by other manifests now
+        self.refresh().await?;
+        println!("🧊 Merged {} manifests of {}.{} into {}", replaced, self.ns, self.table, created);
+        return Ok(Some(snapshot));
+      }
+    }
+  }
+
+  /// Remove the snapshots committed before `older_than_ms` from the table, except the `retain_last` latest
+  /// and any a branch or tag points at or `last_checkpoint` is read from. Their files stay until
+  /// `delete_orphans` finds that no snapshot left uses them. Returns the ids of the snapshots removed.
+  pub async fn expire_snapshots(&mut self, older_than_ms: i64, retain_last: usize) -> Result<Vec<i64>, Error> {
+    loop {
+      let mut snapshots: Vec<&Value> = self.metadata["snapshots"].as_array().into_iter().flatten().collect();
+      snapshots.sort_by_key(|s| std::cmp::Reverse(s["timestamp-ms"].as_i64().unwrap_or(0)));
+      let referenced: HashSet<i64> = self.metadata["refs"].as_object().into_iter().flatten()
+        .filter_map(|(_, r)| r["snapshot-id"].as_i64())
+        .chain(self.metadata["current-snapshot-id"].as_i64())
+        .chain(self.checkpoint_snapshot().map(|(id, _)| id))
+        .collect();
+      let expired: Vec<i64> = snapshots.iter().skip(retain_last)
+        .filter(|s| s["timestamp-ms"].as_i64().unwrap_or(0) < older_than_ms)
+        .filter_map(|s| s["snapshot-id"].as_i64())
+        .filter(|id| !referenced.contains(id))
+        .collect();
+      if expired.is_empty() {
+        return Ok(expired);
+      }
+
+      // A snapshot committed meanwhile would change which ones are the latest
+      let parent = current_snapshot(&self.metadata).and_then(|s| s["snapshot-id"].as_i64());
+      let requirements = json!([
+        { "type": "assert-table-uuid", "uuid": self.metadata["table-uuid"] },
+        { "type": "assert-ref-snapshot-id", "ref": "main", "snapshot-id": parent },
+      ]);
+      let updates = json!([{ "action": "remove-snapshots", "snapshot-ids": expired }]);
+      let committed = self.catalog.commit(&self.ns, &self.table, requirements, updates).await?.is_some();
+      self.refresh().await?;
+      if committed {
+        println!("⌛ Expired {} snapshots of {}.{}", expired.len(), self.ns, self.table);
+        return Ok(expired);
+      }
+      println!("⚠️ Iceberg table {}.{} changed since it was loaded, reloading it", self.ns, self.table);
+    }
+  }
+
+  /// Delete the files in the table's `data/`, `deletes/` and `metadata/` directories that no snapshot uses,
+  /// except those at `keep`, paths in the warehouse, and those modified within `grace`, which a commit in
+  /// progress may be about to add. Table metadata files are the catalog's to remove, so only manifests and
+  /// manifest lists are deleted from `metadata/`. Returns the number of files deleted.
+  pub async fn delete_orphans(&self, keep: &HashSet<String>, grace: std::time::Duration) -> Result<usize, Error> {
+    let used = self.used_files().await?;
+    let cutoff = chrono::Utc::now().timestamp_millis() - grace.as_millis() as i64;
+    let location = self.path_of(self.metadata["location"].as_str().unwrap_or_default())?;
+    let mut orphans = Vec::new();
+    for dir in ["data", "deletes", "metadata"] {
+      let prefix = location.child(dir);
+      let files = self.retry.run(&format!("listing {}", prefix), || async {
+        let mut listing = self.warehouse.store.list(Some(&prefix));
+        let mut files = Vec::new();
+        while let Some(meta) = listing.next().await {
+          files.push(meta?);
+        }
+        Ok(files)
+      }).await?;
+      for meta in files {
+        let path = meta.location.to_string();
+        if (dir == "metadata" && !path.ends_with(".avro"))
+          || meta.last_modified.timestamp_millis() > cutoff
+          || keep.contains(&path)
+          || used.contains(&path) {
+          continue;
+        }
+        orphans.push(meta.location);
+      }
+    }
+
+    for path in &orphans {
+      self.retry.run(&format!("deleting {}", path), || async {
+        match self.warehouse.store.delete(path).await {
+          Ok(_) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+          Err(e) => Err(e.into()),
+        }
+      }).await?;
+    }
+    Ok(orphans.len())
+  }
+
+  /// Paths of the files the table's snapshots use: their manifest lists and manifests, and the data and
+  /// delete files those list as live.
+  async fn used_files(&self) -> Result<HashSet<String>, Error> {
+    let mut urls = Vec::new();
+    let mut manifests = HashSet::new();
+    for list in self.metadata["snapshots"].as_array().into_iter().flatten().filter_map(|s| s["manifest-list"].as_str()) {
+      urls.push(list.to_string());
+      for manifest in self.read(list).await?.records {
+        let path = manifest.get("manifest_path").and_then(avro::Value::as_str).unwrap_or_default().to_string();
+        // Snapshots share most of their manifests
+        if !manifests.insert(path.clone()) {
+          continue;
+        }
+        for entry in self.read(&path).await?.records {
+          if entry.get("status").and_then(avro::Value::as_long) != Some(DELETED as i64)
+            && let Some(file) = entry.get("data_file").and_then(|f| f.get("file_path")).and_then(avro::Value::as_str) {
+            urls.push(file.to_string());
+          }
+        }
+        urls.push(path);
+      }
+    }
+    Ok(urls.iter().filter_map(|url| self.warehouse.path_of(url)).map(|path| path.to_string()).collect())
+  }
+
+  /// Build snapshots of the files and deletes noted since the last `commit_together`, on top of the table as
+  /// loaded, without committing them.
+  async fn stage(&mut self, keys: Option<&KeyStore>) -> Result<(), Error> {
+    self.staged = Some(Staged::default());
+    let pending = self.pending.clone();
+    self.append(&pending).await?;
+    if let Some(run_files) = self.missing.clone() {
+      self.delete_missing(&run_files, keys).await?;
+    }
+    let deleted = self.deleted.clone();
+    self.delete_documents(&deleted).await?;
+    // Without changes, a snapshot of the same files still moves the table to the new checkpoint
+    if self.staged.as_ref().is_some_and(|s| s.updates.is_empty()) && self.checkpoint.is_some() && self.checkpoint != self.last_checkpoint() {
+      let (snapshot, seq) = self.next_snapshot();
+      let manifests = self.manifests.clone();
+      self.commit(snapshot, seq, manifests, json!({ "operation": "append" })).await?;
+    }
+    Ok(())
+  }
+
+  /// Drop the staged snapshots and reload the table: they're in it now if `committed`, and else must be staged
+  /// again, with the schema evolution they included.
+  async fn unstage(&mut self, committed: bool) -> Result<(), Error> {
+    let Some(staged) = self.staged.take() else {
+      return Ok(());
+    };
+    if committed {
+      self.pending.clear();
+      self.missing = None;
+      self.deleted.clear();
+      if let Some((schema, _)) = staged.evolved {
+        println!("🧊 Evolved the schema of {}.{} to schema {}", self.ns, self.table, schema["schema-id"]);
+      }
+    } else if staged.evolved.is_some() {
+      self.evolved = staged.evolved;
+      self.evolved_from = staged.evolved_from;
+    }
+    self.refresh().await
+  }
+
+  /// Reload the table's metadata and the files in its current snapshot.
+  async fn refresh(&mut self) -> Result<(), Error> {
+    let metadata = self.catalog.load_table(&self.ns, &self.table).await?
+      .ok_or_else(|| Error::config(format!("Iceberg table {}.{} doesn't exist", self.ns, self.table)))?;
+    if metadata["format-version"] != 2 {
+      return Err(Error::config(format!(
+        "Iceberg table {}.{} has format version {}; only version 2 is supported", self.ns, self.table, metadata["format-version"])));
+    }
+
+    let mut manifests = Vec::new();
+    let mut files = HashMap::new();
+    if let Some(list) = current_snapshot(&metadata).and_then(|s| s["manifest-list"].as_str()) {
+      for manifest in self.read(list).await?.records {
+        let path = manifest.get("manifest_path").and_then(avro::Value::as_str).unwrap_or_default().to_string();
+        // Delete manifests don't list data files
+        if manifest.get("content").and_then(avro::Value::as_long).unwrap_or(0) == 0 {
+          for entry in self.read(&path).await?.records {
+            if entry.get("status").and_then(avro::Value::as_long) != Some(DELETED as i64)
+              && let Some(file) = entry.get("data_file").and_then(|f| f.get("file_path")).and_then(avro::Value::as_str) {
+              files.insert(file.to_string(), path.clone());
+            }
+          }
+        }
+        manifests.push(manifest);
+      }
+    }
+    self.metadata = metadata;
+    self.manifests = manifests;
+    self.files = files;
+
+    // Files written since the last commit carry the evolved schema's field ids, which the new one must keep
+    if let Some((evolved, _)) = self.evolved.take() {
+      for schema in std::mem::take(&mut self.evolved_from) {
+        self.evolve(&schema)?;
+      }
+      let (mut written, mut current) = (HashMap::new(), HashMap::new());
+      field_ids(&evolved["fields"], "", &mut written);
+      field_ids(&self.schema()?["fields"], "", &mut current);
+      if let Some(column) = written.iter().find(|(column, id)| current.get(*column) != Some(*id)).map(|(column, _)| column) {
+        return Err(Error::Data(format!(
+          "Iceberg table {}.{} changed while files were written for it: column {} has another field id now", self.ns, self.table, column).into()));
+      }
+    }
+    Ok(())
+  }
+
+  /// Evolve the schema the next commit writes to hold the columns of `schema`: missing columns are added,
+  /// types widened, and columns that may be null or absent made optional.
+  fn evolve(&mut self, schema: &SchemaRef) -> Result<(), Error> {
+    let current = self.schema()?.clone();
+    let mut last_id = match &self.evolved {
+      Some((_, last_id)) => *last_id,
+      None => self.metadata["last-column-id"].as_i64().unwrap_or(0),
+    } as i32;
+    let fields = evolve_fields(&current["fields"], schema.fields(), "", &mut last_id).map_err(|e| match e {
+      Error::Data(e) => Error::Data(format!("Can't write to Iceberg table {}.{}: {}", self.ns, self.table, e).into()),
+      e => e,
+    })?;
+    if fields == current["fields"] {
+      return Ok(());
+    }
+
+    let schema_id = self.metadata["schemas"].as_array().into_iter().flatten()
+      .filter_map(|s| s["schema-id"].as_i64())
+      .max()
+      .map_or(0, |id| id + 1);
+    let mut evolved = json!({ "type": "struct", "schema-id": schema_id, "fields": fields });
+    if let Some(ids) = current.get("identifier-field-ids") {
+      evolved["identifier-field-ids"] = ids.clone();
+    }
+    self.evolved = Some((evolved, last_id as i64));
+    if !self.evolved_from.contains(schema) {
+      self.evolved_from.push(schema.clone());
+    }
+    Ok(())
+  }
+
+  /// Write a manifest list of `manifests` and move `main` to a snapshot with it. The snapshot holds the
+  /// checkpoint set, or else the one of the snapshot before it, which its changes don't move. Returns `false`
+  /// if the table changed since it was loaded, after reloading it.
+  async fn commit(&mut self, snapshot: i64, seq: i64, manifests: Vec<avro::Value>, mut summary: Value) -> Result<bool, Error> {
+    if let Some(checkpoint) = self.checkpoint.clone().or_else(|| self.last_checkpoint()) {
+      summary[RESUME_TOKEN_PROPERTY] = json!(checkpoint.resume_token);
+      summary[WATERMARK_PROPERTY] = json!(checkpoint.watermark_ms.to_string());
+    }
+    let parent = current_snapshot(&self.metadata).and_then(|s| s["snapshot-id"].as_i64());
+    let metadata = BTreeMap::from([
+      ("snapshot-id".to_string(), snapshot.to_string().into_bytes()),
+      ("parent-snapshot-id".to_string(), parent.map_or("null".into(), |p| p.to_string()).into_bytes()),
+      ("sequence-number".to_string(), seq.to_string().into_bytes()),
+      ("format-version".to_string(), b"2".to_vec()),
+    ]);
+    let list = self.metadata_url(&format!("snap-{}-1-{}.avro", snapshot, uuid::Uuid::new_v4()));
+    self.write(&list, Container::new(manifest_list_schema(), metadata, manifests.clone()).write()?).await?;
+
+    let mut new_snapshot = json!({
+      "snapshot-id": snapshot,
+      "sequence-number": seq,
+      "timestamp-ms": chrono::Utc::now().timestamp_millis(),
+      "manifest-list": list,
+      "summary": summary,
+      "schema-id": self.schema()?["schema-id"],
+    });
+    if let Some(parent) = parent {
+      new_snapshot["parent-snapshot-id"] = json!(parent);
+    }
+    let mut requirements = vec![
+      json!({ "type": "assert-table-uuid", "uuid": self.metadata["table-uuid"] }),
+      json!({ "type": "assert-ref-snapshot-id", "ref": "main", "snapshot-id": parent }),
+    ];
+    let mut updates = Vec::new();
+    if let Some((schema, last_column_id)) = &self.evolved {
+      requirements.push(json!({ "type": "assert-current-schema-id", "current-schema-id": self.metadata["current-schema-id"] }));
+      requirements.push(json!({ "type": "assert-last-assigned-field-id", "last-assigned-field-id": self.metadata["last-column-id"] }));
+      updates.push(json!({ "action": "add-schema", "schema": schema, "last-column-id": last_column_id }));
+      updates.push(json!({ "action": "set-current-schema", "schema-id": -1 }));
+    }
+    updates.push(json!({ "action": "add-snapshot", "snapshot": new_snapshot }));
+    updates.push(json!({ "action": "set-snapshot-ref", "ref-name": "main", "type": "branch", "snapshot-id": snapshot }));
+    // Files written before the table had field ids, or by other tools, are read by column name
+    if self.metadata["properties"][NAME_MAPPING_PROPERTY].is_null() || self.evolved.is_some() {
+      let mapping = name_mapping(&self.schema()?["fields"]);
+      updates.push(json!({ "action": "set-properties", "updates": { NAME_MAPPING_PROPERTY: mapping.to_string() } }));
+    }
+
+    if let Some(staged) = &mut self.staged {
+      // The requirements hold for the table as loaded, which the first staged snapshot builds on
+      for requirement in requirements {
+        if !staged.requirements.iter().any(|r| r["type"] == requirement["type"]) {
+          staged.requirements.push(requirement);
+        }
+      }
+      for update in &updates {
+        hadoop_catalog::apply_update(&mut self.metadata, update)?;
+      }
+      staged.updates.extend(updates);
+      if let Some(evolved) = self.evolved.take() {
+        staged.evolved = Some(evolved);
+        staged.evolved_from.append(&mut self.evolved_from);
+      }
+      self.manifests = manifests;
+      println!("🧊 Staged Iceberg snapshot {} of {}.{}", snapshot, self.ns, self.table);
+      return Ok(true);
+    }
+
+    match self.catalog.commit(&self.ns, &self.table, json!(requirements), json!(updates)).await? {
+      Some(metadata) => {
+        if let Some((schema, _)) = self.evolved.take() {
+          println!("🧊 Evolved the schema of {}.{} to schema {}", self.ns, self.table, schema["schema-id"]);
+          self.evolved_from.clear();
+        }
+        println!("🧊 Committed Iceberg snapshot {} of {}.{}", snapshot, self.ns, self.table);
+        if metadata.is_object() {
+          self.metadata = metadata;
+          self.manifests = manifests;
+        } else {
+          self.refresh().await?;
+        }
+        Ok(true)
+      }
+      None => {
+        println!("⚠️ Iceberg table {}.{} changed since it was loaded, reloading it", self.ns, self.table);
+        self.refresh().await?;
+        Ok(false)
+      }
+    }
+  }
+
+  /// Manifests of the current snapshot, less those whose files were all deleted by an earlier snapshot.
+  fn live_manifests(&self) -> Vec<avro::Value> {
+    self.manifests.iter()
+      .filter(|m| {
+        let count = |name: &str| m.get(name).and_then(avro::Value::as_long).unwrap_or(0);
+        count("content") != 0 || count("added_files_count") + count("existing_files_count") > 0
+      })
+      .cloned()
+      .collect()
+  }
+
+  /// A random id and the sequence number for the next snapshot.
+  fn next_snapshot(&self) -> (i64, i64) {
+    let id = rand::random::<i64>() & i64::MAX;
+    (id, self.metadata["last-sequence-number"].as_i64().unwrap_or(0) + 1)
+  }
+
+  /// A manifest entry adding the file at `path`, a full path as in `DataFile::path`.
+  fn entry(&self, path: &str, partition: &str, size: u64, stats: &FileStats, snapshot: i64) -> Result<avro::Value, Error> {
+    let mut value_counts = Vec::new();
+    let mut null_counts = Vec::new();
+    let mut lower_bounds = Vec::new();
+    let mut upper_bounds = Vec::new();
+    for field in self.schema()?["fields"].as_array().into_iter().flatten() {
+      let (Some(id), Some(name), Some(iceberg_type)) = (field["id"].as_i64(), field["name"].as_str(), field["type"].as_str()) else { continue };
+      let Some(column) = stats.columns.get(name) else { continue };
+      let id = id as i32;
+      value_counts.push(map_entry(id, avro::Value::Long(stats.rows as i64)));
+      null_counts.push(map_entry(id, avro::Value::Long(column.null_count as i64)));
+      if let Some(lower) = column.min.as_ref().and_then(|v| bound(iceberg_type, v, false)) {
+        lower_bounds.push(map_entry(id, avro::Value::Bytes(lower)));
+      }
+      if let Some(upper) = column.max.as_ref().and_then(|v| bound(iceberg_type, v, true)) {
+        upper_bounds.push(map_entry(id, avro::Value::Bytes(upper)));
+      }
+    }
+    let map = |entries: Vec<avro::Value>| if entries.is_empty() { avro::Value::Null } else { avro::Value::Array(entries) };
+
+    let data_file = avro::Value::Record(vec![
+      ("content".into(), avro::Value::Int(0)),
+      ("file_path".into(), avro::Value::String(self.warehouse.url(path))),
+      ("file_format".into(), avro::Value::String("PARQUET".into())),
+      ("partition".into(), avro::Value::Record(self.partition(path, partition)?)),
+      ("record_count".into(), avro::Value::Long(stats.rows as i64)),
+      ("file_size_in_bytes".into(), avro::Value::Long(size as i64)),
+      ("value_counts".into(), map(value_counts)),
+      ("null_value_counts".into(), map(null_counts)),
+      ("lower_bounds".into(), map(lower_bounds)),
+      ("upper_bounds".into(), map(upper_bounds)),
+    ]);
+    Ok(avro::Value::Record(vec![
+      ("status".into(), avro::Value::Int(ADDED)),
+      ("snapshot_id".into(), avro::Value::Long(snapshot)),
+      // Inherited from the manifest list once the snapshot is committed
+      ("sequence_number".into(), avro::Value::Null),
+      ("file_sequence_number".into(), avro::Value::Null),
+      ("data_file".into(), data_file),
+    ]))
+  }
+
+  /// Write an equality delete file at `path`, in `partition`, that deletes the rows whose key column holds one
+  /// of `keys`, and return its manifest entry.
+  async fn write_deletes(&self, path: &Path, partition: &str, keys: BTreeSet<&str>, snapshot: i64) -> Result<avro::Value, Error> {
+    let column = self.key_column.as_deref().unwrap_or_default();
+    let field = find_field(&self.schema()?["fields"], column).filter(|f| f["type"] == "string")
+      .ok_or_else(|| Error::config(format!("Upserts need a string column {} in Iceberg table {}.{}", column, self.ns, self.table)))?;
+    let metadata = HashMap::from([(PARQUET_FIELD_ID_META_KEY.to_string(), field["id"].to_string())]);
+    let schema = Arc::new(Schema::new(vec![Field::new(column, DataType::Utf8, false).with_metadata(metadata)]));
+    let batch = RecordBatch::try_new(schema.clone(), vec![Arc::new(StringArray::from_iter_values(keys))])?;
+    let mut writer = ArrowWriter::try_new(Vec::new(), schema, None)?;
+    writer.write(&batch)?;
+    let size = self.write(&self.warehouse.url(path.as_ref()), writer.into_inner()?).await?;
+
+    let mut stats = FileStats::default();
+    stats.update(&batch);
+    let mut entry = self.entry(path.as_ref(), partition, size as u64, &stats, snapshot)?;
+    let Some(mut data_file) = entry.get("data_file").cloned() else {
+      unreachable!("entries have a data file")
+    };
+    data_file.set("content", avro::Value::Int(EQUALITY_DELETES));
+    let id = field["id"].as_i64().unwrap_or_default() as i32;
+    data_file.set("equality_ids", avro::Value::Array(vec![avro::Value::Int(id)]));
+    entry.set("data_file", data_file);
+    Ok(entry)
+  }
+
+  /// Write a position delete file at `path`, in the partition of `file`, that deletes the rows of `file` listed
+  /// in its `superseded`, and return its manifest entry.
+  async fn write_position_deletes(&self, path: &Path, file: &DataFile, snapshot: i64) -> Result<avro::Value, Error> {
+    let field = |name: &str, data_type: DataType, id: i32| Field::new(name, data_type, false)
+      .with_metadata(HashMap::from([(PARQUET_FIELD_ID_META_KEY.to_string(), id.to_string())]));
+    let schema = Arc::new(Schema::new(vec![
+      field("file_path", DataType::Utf8, DELETE_FILE_PATH_ID),
+      field("pos", DataType::Int64, DELETE_POS_ID),
+    ]));
+    let url = self.warehouse.url(&file.path);
+    // Sorted by position, as the spec requires
+    let mut rows = file.superseded.clone();
+    rows.sort_unstable();
+    let batch = RecordBatch::try_new(schema.clone(), vec![
+      Arc::new(StringArray::from_iter_values(rows.iter().map(|_| url.as_str()))),
+      Arc::new(Int64Array::from_iter_values(rows.iter().map(|row| *row as i64))),
+    ])?;
+    let mut writer = ArrowWriter::try_new(Vec::new(), schema, None)?;
+    writer.write(&batch)?;
+    let size = self.write(&self.warehouse.url(path.as_ref()), writer.into_inner()?).await?;
+
+    let stats = FileStats { rows: rows.len() as u64, ..Default::default() };
+    let mut entry = self.entry(path.as_ref(), &file.partition, size as u64, &stats, snapshot)?;
+    let Some(mut data_file) = entry.get("data_file").cloned() else {
+      unreachable!("entries have a data file")
+    };
+    data_file.set("content", avro::Value::Int(POSITION_DELETES));
+    // Equal bounds on the path tell readers the one data file the deletes apply to
+    let bounds = avro::Value::Array(vec![map_entry(DELETE_FILE_PATH_ID, avro::Value::Bytes(url.into_bytes()))]);
+    data_file.set("lower_bounds", bounds.clone());
+    data_file.set("upper_bounds", bounds);
+    entry.set("data_file", data_file);
+    Ok(entry)
+  }
+
+  /// Where the delete file committed with the data file at `path` goes: the same name under the table's
+  /// `deletes/` directory instead of `data/`.
+  fn delete_path(&self, path: &str) -> Result<Path, Error> {
+    let table = self.warehouse.table_path(&self.ns, &self.table);
+    let name = path.strip_prefix(&format!("{}/data/", table))
+      .ok_or_else(|| Error::Data(format!("{} is outside the data directory of {}.{}", path, self.ns, self.table).into()))?;
+    Path::parse(format!("{}/deletes/{}", table, name)).map_err(|e| Error::Data(Box::new(e)))
+  }
+
+  /// The non-null values of `column` in the data file at `url`. Files without the column have none.
+  async fn read_keys(&self, url: &str, column: &str, keys: Option<&KeyStore>) -> Result<Vec<String>, Error> {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(self.read_parquet(url, keys).await?)?;
+    let Ok(index) = builder.schema().index_of(column) else {
+      return Ok(Vec::new());
+    };
+    let mask = ProjectionMask::roots(builder.parquet_schema(), [index]);
+    let mut values = Vec::new();
+    for batch in builder.with_projection(mask).build()? {
+      let strings = cast(batch?.column(0), &DataType::Utf8)?;
+      values.extend(strings.as_string::<i32>().iter().flatten().map(String::from));
+    }
+    Ok(values)
+  }
+
+  /// The data file URLs and row positions that the position delete file at `url` lists.
+  async fn read_positions(&self, url: &str, keys: Option<&KeyStore>) -> Result<Vec<(String, u64)>, Error> {
+    let mut positions = Vec::new();
+    for batch in ParquetRecordBatchReaderBuilder::try_new(self.read_parquet(url, keys).await?)?.build()? {
+      let batch = batch?;
+      let (Some(paths), Some(rows)) = (batch.column_by_name("file_path"), batch.column_by_name("pos")) else {
+        return Err(Error::Data(format!("{} has no file_path and pos columns", url).into()));
+      };
+      let (paths, rows) = (cast(paths, &DataType::Utf8)?, cast(rows, &DataType::Int64)?);
+      positions.extend(paths.as_string::<i32>().iter().zip(rows.as_primitive::<Int64Type>().iter())
+        .filter_map(|(path, row)| Some((path?.to_string(), row? as u64))));
+    }
+    Ok(positions)
+  }
+
+  /// The Parquet file at `url`, decrypted if it has encrypted columns, which need `keys`.
+  async fn read_parquet(&self, url: &str, keys: Option<&KeyStore>) -> Result<Bytes, Error> {
+    let path = self.path_of(url)?;
+    let bytes = self.retry.run(&format!("reading {}", path), || async {
+      Ok(self.warehouse.store.get(&path).await?.bytes().await?)
+    }).await?;
+    if !encryption::is_encrypted(&bytes)? {
+      return Ok(bytes);
+    }
+    let keys = keys.ok_or_else(|| Error::config(format!(
+      "{} has encrypted columns: set ENCRYPTION_KEYS_PATH to a key file with its keys", path)))?;
+    Ok(Bytes::from(encryption::decrypt(&bytes, keys)?))
+  }
+
+  /// The partition tuple of a file in `partition`, e.g. `variety_id=v1/created_at_day=2024-01-15`, under the
+  /// table's default spec. Partition fields are matched by name, so `partition_by` must match the spec.
+  fn partition(&self, path: &str, partition: &str) -> Result<Vec<(String, avro::Value)>, Error> {
+    let values: HashMap<String, Option<String>> = partition_values(partition).into_iter().collect();
+    let schema = self.schema()?;
+    let mut tuple = Vec::new();
+    for field in self.spec()?["fields"].as_array().into_iter().flatten() {
+      let name = field["name"].as_str().unwrap_or_default();
+      let transform = field["transform"].as_str().unwrap_or_default();
+      let value = if transform == "void" {
+        avro::Value::Null
+      } else {
+        let raw = values.get(name).ok_or_else(|| Error::Data(format!(
+          "{} has no value for partition field {} of Iceberg table {}.{}; set partition_by to match the table's partition spec",
+          path, name, self.ns, self.table).into()))?;
+        let source_type = source_field(schema, &field["source-id"]).and_then(|f| f["type"].as_str()).unwrap_or_default();
+        partition_value(transform, source_type, raw.as_deref())
+          .ok_or_else(|| Error::Data(format!("Can't read partition value {:?} of {} in {}", raw, name, path).into()))?
+      };
+      tuple.push((name.to_string(), value));
+    }
+    Ok(tuple)
+  }
+
+  /// Write `entries`, data or delete files as `content` says, to a new manifest under the default spec and
+  /// return its manifest list entry.
+  async fn write_manifest(&self, entries: Vec<avro::Value>, content: i32, snapshot: i64, seq: i64) -> Result<avro::Value, Error> {
+    let spec = self.spec()?;
+    let schema = self.schema()?;
+    let partition_fields = spec["fields"].as_array().into_iter().flatten()
+      .map(|f| {
+        let source_type = source_field(schema, &f["source-id"]).map_or(&Value::Null, |s| &s["type"]);
+        let result_type = avro_type(f["transform"].as_str().unwrap_or_default(), source_type.as_str().unwrap_or_default())
+          .ok_or_else(|| Error::config(format!("Iceberg table {}.{} has an unsupported partition field {}", self.ns, self.table, f)))?;
+        Ok(json!({ "name": f["name"], "type": optional(result_type), "default": null, "field-id": f["field-id"] }))
+      })
+      .collect::<Result<Vec<_>, Error>>()?;
+    let metadata = BTreeMap::from([
+      ("schema".to_string(), schema.to_string().into_bytes()),
+      ("schema-id".to_string(), schema["schema-id"].to_string().into_bytes()),
+      ("partition-spec".to_string(), spec["fields"].to_string().into_bytes()),
+      ("partition-spec-id".to_string(), spec["spec-id"].to_string().into_bytes()),
+      ("format-version".to_string(), b"2".to_vec()),
+      ("content".to_string(), if content == DELETES { b"deletes".to_vec() } else { b"data".to_vec() }),
+    ]);
+
+    let rows: i64 = entries.iter()
+      .filter_map(|e| e.get("data_file").and_then(|f| f.get("record_count")).and_then(avro::Value::as_long))
+      .sum();
+    let summaries = partition_summaries(&entries);
+    let count = entries.len() as i32;
+    let url = self.metadata_url(&format!("{}-m0.avro", uuid::Uuid::new_v4()));
+    let length = self.write(&url, Container::new(manifest_schema(partition_fields), metadata, entries).write()?).await?;
+
+    Ok(avro::Value::Record(vec![
+      ("manifest_path".into(), avro::Value::String(url)),
+      ("manifest_length".into(), avro::Value::Long(length as i64)),
+      ("partition_spec_id".into(), avro::Value::Int(spec["spec-id"].as_i64().unwrap_or(0) as i32)),
+      ("content".into(), avro::Value::Int(content)),
+      ("sequence_number".into(), avro::Value::Long(seq)),
+      ("min_sequence_number".into(), avro::Value::Long(seq)),
+      ("added_snapshot_id".into(), avro::Value::Long(snapshot)),
+      ("added_files_count".into(), avro::Value::Int(count)),
+      ("existing_files_count".into(), avro::Value::Int(0)),
+      ("deleted_files_count".into(), avro::Value::Int(0)),
+      ("added_rows_count".into(), avro::Value::Long(rows)),
+      ("existing_rows_count".into(), avro::Value::Long(0)),
+      ("deleted_rows_count".into(), avro::Value::Long(0)),
+      ("partitions".into(), summaries),
+    ]))
+  }
+
+  /// Copy `manifest` with the files at `removed` marked as deleted by `snapshot` and the others as existing.
+  /// Returns its new manifest list entry, and the number of files and rows deleted.
+  async fn rewrite_manifest(&self, manifest: &avro::Value, removed: &HashSet<&str>, snapshot: i64, seq: i64) -> Result<(avro::Value, i32, i64), Error> {
+    let path = manifest.get("manifest_path").and_then(avro::Value::as_str).unwrap_or_default();
+    let mut container = self.read(path).await?;
+    container.records = std::mem::take(&mut container.records).into_iter()
+      .filter_map(|entry| existing_entry(entry, manifest))
+      .map(|mut entry| {
+        let file = entry.get("data_file").and_then(|f| f.get("file_path")).and_then(avro::Value::as_str).unwrap_or_default();
+        if removed.contains(file) {
+          entry.set("status", avro::Value::Int(DELETED));
+          entry.set("snapshot_id", avro::Value::Long(snapshot));
+        }
+        entry
+      })
+      .collect();
+
+    let rewritten = self.write_entries(container, manifest, snapshot, seq).await?;
+    let long = |name: &str| rewritten.get(name).and_then(avro::Value::as_long).unwrap_or(0);
+    let (deleted, deleted_rows) = (long("deleted_files_count") as i32, long("deleted_rows_count"));
+    Ok((rewritten, deleted, deleted_rows))
+  }
+
+  /// Write `container`, whose entries carry their own snapshot ids and sequence numbers, to a new manifest
+  /// and return its manifest list entry: `manifest`'s, counting the entries as existing or deleted in
+  /// `snapshot`.
+  async fn write_entries(&self, container: Container, manifest: &avro::Value, snapshot: i64, seq: i64) -> Result<avro::Value, Error> {
+    let long = |v: &avro::Value, name: &str| v.get(name).and_then(avro::Value::as_long);
+    let (mut existing, mut existing_rows, mut deleted, mut deleted_rows) = (0, 0, 0, 0);
+    let mut min_seq = seq;
+    for entry in &container.records {
+      let rows = entry.get("data_file").and_then(|f| long(f, "record_count")).unwrap_or(0);
+      if long(entry, "status") == Some(DELETED as i64) {
+        deleted += 1;
+        deleted_rows += rows;
+      } else {
+        existing += 1;
+        existing_rows += rows;
+      }
+      min_seq = min_seq.min(long(entry, "sequence_number").unwrap_or(seq));
+    }
+
+    let url = self.metadata_url(&format!("{}-m0.avro", uuid::Uuid::new_v4()));
+    let length = self.write(&url, container.write()?).await?;
+    let mut rewritten = manifest.clone();
+    rewritten.set("manifest_path", avro::Value::String(url));
+    rewritten.set("manifest_length", avro::Value::Long(length as i64));
+    rewritten.set("sequence_number", avro::Value::Long(seq));
+    rewritten.set("min_sequence_number", avro::Value::Long(min_seq));
+    rewritten.set("added_snapshot_id", avro::Value::Long(snapshot));
+    rewritten.set("added_files_count", avro::Value::Int(0));
+    rewritten.set("existing_files_count", avro::Value::Int(existing));
+    rewritten.set("deleted_files_count", avro::Value::Int(deleted));
+    rewritten.set("added_rows_count", avro::Value::Long(0));
+    rewritten.set("existing_rows_count", avro::Value::Long(existing_rows));
+    rewritten.set("deleted_rows_count", avro::Value::Long(deleted_rows));
+    rewritten.set("partitions", partition_summaries(&container.records));
+    Ok(rewritten)
+  }
+
+  /// The schema files are written with: the one the next commit adds, if any, or the current one.
+  fn schema(&self) -> Result<&Value, Error> {
+    if let Some((schema, _)) = &self.evolved {
+      return Ok(schema);
+    }
+    let id = &self.metadata["current-schema-id"];
+    self.metadata["schemas"].as_array().into_iter().flatten()
+      .find(|s| s["schema-id"] == *id)
+      .ok_or_else(|| Error::Data(format!("Iceberg table {}.{} has no current schema", self.ns, self.table).into()))
+  }
+
+  fn spec(&self) -> Result<&Value, Error> {
+    let id = &self.metadata["default-spec-id"];
+    self.metadata["partition-specs"].as_array().into_iter().flatten()
+      .find(|s| s["spec-id"] == *id)
+      .ok_or_else(|| Error::Data(format!("Iceberg table {}.{} has no default partition spec", self.ns, self.table).into()))
+  }
+
+  /// URL of a new file in the table's `metadata/` directory.
+  fn metadata_url(&self, name: &str) -> String {
+    format!("{}/metadata/{}", self.metadata["location"].as_str().unwrap_or_default().trim_end_matches('/'), name)
+  }
+
+  fn path_of(&self, url: &str) -> Result<Path, Error> {
+    self.warehouse.path_of(url).ok_or_else(|| Error::config(format!(
+      "{} of Iceberg table {}.{} is outside the warehouse {}", url, self.ns, self.table, self.warehouse.url(""))))
+  }
+
+  async fn read(&self, url: &str) -> Result<Container, Error> {
+    let path = self.path_of(url)?;
+    let bytes = self.retry.run(&format!("reading {}", path), || async {
+      Ok(self.warehouse.store.get(&path).await?.bytes().await?)
+    }).await?;
+    Container::read(&bytes)
+  }
+
+  /// Write `bytes` to `url` and return their length.
+  async fn write(&self, url: &str, bytes: Vec<u8>) -> Result<usize, Error> {
+    let path = self.path_of(url)?;
+    let length = bytes.len();
+    let payload = PutPayload::from(bytes);
+    self.retry.run(&format!("writing {}", path), || async {
+      self.warehouse.store.put(&path, payload.clone()).await?;
+      Ok(())
+    }).await?;
+    Ok(length)
+  }
+}
+
+/// Commit what the deferred `tables` noted in one transaction of their catalog, so readers see every table's
+/// new snapshots or none of them. If another writer changed one of the tables first, they're all reloaded and
+/// the snapshots built again. A single table is committed on its own, which any catalog can do. Encrypted
+/// files need `keys` to find the documents to delete.
+pub async fn commit_together(tables: &mut [IcebergTable], keys: Option<&KeyStore>) -> Result<(), Error> {
+  let Some(catalog) = tables.first().map(|t| t.catalog.clone()) else {
+    return Ok(());
+  };
+  loop {
+    let mut changes = Vec::new();
+    for table in tables.iter_mut() {
+      table.stage(keys).await?;
+      if let Some(staged) = table.staged.as_ref().filter(|s| !s.updates.is_empty()) {
+        changes.push(json!({
+          "identifier": { "namespace": [table.ns], "name": table.table },
+          "requirements": staged.requirements,
+          "updates": staged.updates,
+        }));
+      }
+    }
+    let committed = match (&*tables, changes.as_slice()) {
+      (_, []) => true,
+      ([table], [change]) => catalog.commit(&table.ns, &table.table, change["requirements"].clone(), change["updates"].clone()).await?.is_some(),
+      _ => catalog.commit_transaction(changes).await?,
+    };
+    for table in tables.iter_mut() {
+      table.unstage(committed).await?;
+    }
+    if committed {
+      let names: Vec<String> = tables.iter().map(|t| format!("{}.{}", t.ns, t.table)).collect();
+      println!("🧊 Committed Iceberg tables {} together", names.join(", "));
+      return Ok(());
+    }
+    println!("⚠️ An Iceberg table changed since it was loaded, reloading {} tables", tables.len());
+  }
+}
+
+/// `namespaces/<ns>/tables`, with the name percent-encoded.
+fn tables_path(ns: &str) -> String {
+  format!("namespaces/{}/tables", encode(ns))
+}
+
+/// `namespaces/<ns>/tables/<table>`, with the names percent-encoded.
+fn table_path(ns: &str, table: &str) -> String {
+  format!("{}/{}", tables_path(ns), encode(table))
+}
+
+fn encode(name: &str) -> String {
+  name.bytes()
+    .map(|b| if b.is_ascii_alphanumeric() || b"-._~".contains(&b) { (b as char).to_string() } else { format!("%{:02X}", b) })
+    .collect()
+}
+
+fn status_error(status: StatusCode, response: &Value, what: &str) -> Error {
+  let message = response["error"]["message"].as_str().map_or_else(|| response.to_string(), String::from);
+  let e = format!("{} failed with {}: {}", what, status, message);
+  match status {
+    StatusCode::TOO_MANY_REQUESTS | StatusCode::REQUEST_TIMEOUT => Error::Retryable(e.into()),
+    s if s.is_server_error() => Error::Retryable(e.into()),
+    StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND => Error::Config(e.into()),
+    _ => Error::Data(e.into()),
+  }
+}
+
+/// The snapshot `main` points at, if any.
+fn current_snapshot(metadata: &Value) -> Option<&Value> {
+  let id = metadata["refs"]["main"]["snapshot-id"].as_i64().or(metadata["current-snapshot-id"].as_i64())?;
+  metadata["snapshots"].as_array()?.iter().find(|s| s["snapshot-id"].as_i64() == Some(id))
+}
+
+/// `entry`, read from `manifest`, as an existing entry with the snapshot id and sequence numbers it was added
+/// with, which it may have inherited from `manifest`. Entries of deleted files are dropped.
+fn existing_entry(mut entry: avro::Value, manifest: &avro::Value) -> Option<avro::Value> {
+  let long = |v: &avro::Value, name: &str| v.get(name).and_then(avro::Value::as_long);
+  if long(&entry, "status") == Some(DELETED as i64) {
+    return None;
+  }
+  if long(&entry, "snapshot_id").is_none() {
+    entry.set("snapshot_id", avro::Value::Long(long(manifest, "added_snapshot_id").unwrap_or(0)));
+  }
+  for field in ["sequence_number", "file_sequence_number"] {
+    if long(&entry, field).is_none() {
+      entry.set(field, avro::Value::Long(long(manifest, "sequence_number").unwrap_or(0)));
+    }
+  }
+  entry.set("status", avro::Value::Int(EXISTING));
+  Some(entry)
+}
+
+fn source_field<'a>(schema: &'a Value, id: &Value) -> Option<&'a Value> {
+  schema["fields"].as_array()?.iter().find(|f| f["id"] == *id)
+}
+
+/// Name mapping for `fields` and the fields nested in them.
+fn name_mapping(fields: &Value) -> Value {
+  let nested = |t: &Value| -> Option<Value> {
+    match t["type"].as_str()? {
+      "struct" => Some(name_mapping(&t["fields"])),
+      "list" => Some(json!([{ "field-id": t["element-id"], "names": ["element"] }])),
+      "map" => Some(json!([{ "field-id": t["key-id"], "names": ["key"] }, { "field-id": t["value-id"], "names": ["value"] }])),
+      _ => None,
+    }
+  };
+  Value::Array(fields.as_array().into_iter().flatten()
+    .map(|f| {
+      let mut mapping = json!({ "field-id": f["id"], "names": [f["name"]] });
+      if let Some(fields) = nested(&f["type"]) {
+        mapping["fields"] = fields;
+      }
+      mapping
+    })
+    .collect())
+}
+
+/// An Iceberg schema with the columns of `schema`. Field ids are assigned the way Iceberg assigns fresh ones:
+/// the fields of a struct first, then the fields nested in each of them.
+fn iceberg_schema(schema: &Schema) -> Result<Value, Error> {
+  let mut last_id = 0;
+  Ok(json!({ "type": "struct", "schema-id": 0, "fields": struct_fields(schema.fields(), &mut last_id)? }))
+}
+
+fn struct_fields(fields: &Fields, last_id: &mut i32) -> Result<Vec<Value>, Error> {
+  let first_id = *last_id + 1;
+  *last_id += fields.len() as i32;
+  fields.iter().zip(first_id..)
+    .map(|(field, id)| Ok(json!({
+      "id": id,
+      "name": field.name(),
+      "required": !field.is_nullable(),
+      "type": iceberg_type(field.name(), field.data_type(), last_id)?,
+    })))
+    .collect()
+}
+
+/// The Iceberg type of `column`, assigning ids to the fields nested in it after `last_id`.
+fn iceberg_type(column: &str, data_type: &DataType, last_id: &mut i32) -> Result<Value, Error> {
+  let mut next_id = || {
+    *last_id += 1;
+    *last_id
+  };
+  Ok(match data_type {
+    DataType::Boolean => json!("boolean"),
+    DataType::Int8 | DataType::Int16 | DataType::Int32 | DataType::UInt8 | DataType::UInt16 => json!("int"),
+    DataType::Int64 | DataType::UInt32 => json!("long"),
+    DataType::Float32 => json!("float"),
+    DataType::Float64 => json!("double"),
+    DataType::Decimal128(precision, scale) => json!(format!("decimal({}, {})", precision, scale)),
+    DataType::Date32 => json!("date"),
+    DataType::Timestamp(_, None) => json!("timestamp"),
+    DataType::Timestamp(_, Some(_)) => json!("timestamptz"),
+    DataType::Utf8 | DataType::LargeUtf8 => json!("string"),
+    DataType::Binary | DataType::LargeBinary => json!("binary"),
+    DataType::FixedSizeBinary(length) => json!(format!("fixed[{}]", length)),
+    DataType::Dictionary(_, values) => iceberg_type(column, values, last_id)?,
+    DataType::Struct(fields) => json!({ "type": "struct", "fields": struct_fields(fields, last_id)? }),
+    DataType::List(element) | DataType::LargeList(element) => {
+      let id = next_id();
+      json!({
+        "type": "list",
+        "element-id": id,
+        "element": iceberg_type(column, element.data_type(), last_id)?,
+        "element-required": !element.is_nullable(),
+      })
+    }
+    DataType::Map(entries, _) => {
+      let DataType::Struct(entry) = entries.data_type() else { unreachable!("map entries are structs") };
+      let (key_id, value_id) = (next_id(), next_id());
+      json!({
+        "type": "map",
+        "key-id": key_id,
+        "key": iceberg_type(column, entry[0].data_type(), last_id)?,
+        "value-id": value_id,
+        "value": iceberg_type(column, entry[1].data_type(), last_id)?,
+        "value-required": !entry[1].is_nullable(),
+      })
+    }
+    other => return Err(Error::config(format!("Column {} has type {}, which Iceberg tables can't store", column, other))),
+  })
+}
+
+/// `fields` of a table's schema, evolved to hold `columns`. Missing columns are added as optional fields, as
+/// the files already in the table don't have them, and types are widened where Iceberg allows it.
+fn evolve_fields(fields: &Value, columns: &Fields, parent: &str, last_id: &mut i32) -> Result<Value, Error> {
+  let mut fields = fields.as_array().cloned().unwrap_or_default();
+  for column in columns {
+    let name = if parent.is_empty() { column.name().clone() } else { format!("{}.{}", parent, column.name()) };
+    match fields.iter_mut().find(|f| f["name"] == column.name().as_str()) {
+      Some(field) => {
+        if column.is_nullable() {
+          field["required"] = json!(false);
+        }
+        field["type"] = evolve_type(&field["type"], column.data_type(), &name, last_id)?;
+      }
+      None => {
+        *last_id += 1;
+        let id = *last_id;
+        fields.push(json!({ "id": id, "name": column.name(), "required": false, "type": iceberg_type(&name, column.data_type(), last_id)? }));
+      }
+    }
+  }
+  // Files without one of the table's columns hold nulls for it
+  for field in &mut fields {
+    if !columns.iter().any(|c| field["name"] == c.name().as_str()) {
+      field["required"] = json!(false);
+    }
+  }
+  Ok(Value::Array(fields))
+}
+
+/// `current`, the table's type of `column`, evolved to hold values of `data_type`.
+fn evolve_type(current: &Value, data_type: &DataType, column: &str, last_id: &mut i32) -> Result<Value, Error> {
+  let mut evolved = current.clone();
+  match (current["type"].as_str(), data_type) {
+    (_, DataType::Dictionary(_, values)) => return evolve_type(current, values, column, last_id),
+    (Some("struct"), DataType::Struct(fields)) => evolved["fields"] = evolve_fields(&current["fields"], fields, column, last_id)?,
+    (Some("list"), DataType::List(element) | DataType::LargeList(element)) => {
+      evolved["element"] = evolve_type(&current["element"], element.data_type(), &format!("{}.element", column), last_id)?;
+      if element.is_nullable() {
+        evolved["element-required"] = json!(false);
+      }
+    }
+    (Some("map"), DataType::Map(entries, _)) => {
+      let DataType::Struct(entry) = entries.data_type() else { unreachable!("map entries are structs") };
+      evolved["key"] = evolve_type(&current["key"], entry[0].data_type(), &format!("{}.key", column), last_id)?;
+      evolved["value"] = evolve_type(&current["value"], entry[1].data_type(), &format!("{}.value", column), last_id)?;
+      if entry[1].is_nullable() {
+        evolved["value-required"] = json!(false);
+      }
+    }
+    _ => {
+      let written = iceberg_type(column, data_type, &mut 0)?;
+      // Readers widen narrower values in older files themselves
+      if written == *current || promotes(&written, current) {
+        return Ok(current.clone());
+      }
+      if promotes(current, &written) {
+        return Ok(written);
+      }
+      return Err(Error::Data(format!(
+        "column {} can't change from {} to {}; Iceberg only widens int to long, float to double and the precision of decimals",
+        column, type_name(current), type_name(&written)).into()));
+    }
+  }
+  Ok(evolved)
+}
+
+/// Whether Iceberg can promote a column of type `from` to `to`.
+fn promotes(from: &Value, to: &Value) -> bool {
+  let decimal = |t: &Value| -> Option<(u32, u32)> {
+    let (precision, scale) = t.as_str()?.strip_prefix("decimal(")?.strip_suffix(')')?.split_once(',')?;
+    Some((precision.trim().parse().ok()?, scale.trim().parse().ok()?))
+  };
+  match (from.as_str(), to.as_str()) {
+    (Some("int"), Some("long")) | (Some("float"), Some("double")) => true,
+    _ => matches!((decimal(from), decimal(to)), (Some((p1, s1)), Some((p2, s2))) if s1 == s2 && p1 < p2),
+  }
+}
+
+fn type_name(iceberg_type: &Value) -> String {
+  iceberg_type.as_str().or(iceberg_type["type"].as_str()).unwrap_or_default().to_string()
+}
+
+/// The field ids in `fields` and the types nested in them, by column path.
+fn field_ids(fields: &Value, parent: &str, ids: &mut HashMap<String, i64>) {
+  fn nested(iceberg_type: &Value, path: &str, ids: &mut HashMap<String, i64>) {
+    match iceberg_type["type"].as_str() {
+      Some("struct") => field_ids(&iceberg_type["fields"], path, ids),
+      Some("list") => {
+        ids.insert(format!("{}.element", path), iceberg_type["element-id"].as_i64().unwrap_or_default());
+        nested(&iceberg_type["element"], &format!("{}.element", path), ids);
+      }
+      Some("map") => {
+        for part in ["key", "value"] {
+          ids.insert(format!("{}.{}", path, part), iceberg_type[format!("{}-id", part)].as_i64().unwrap_or_default());
+          nested(&iceberg_type[part], &format!("{}.{}", path, part), ids);
+        }
+      }
+      _ => {}
+    }
+  }
+  for field in fields.as_array().into_iter().flatten() {
+    let name = field["name"].as_str().unwrap_or_default();
+    let path = if parent.is_empty() { name.to_string() } else { format!("{}.{}", parent, name) };
+    ids.insert(path.clone(), field["id"].as_i64().unwrap_or_default());
+    nested(&field["type"], &path, ids);
+  }
+}
+
+/// An Iceberg partition spec for `spec`, with the partition fields named as in our file paths.
+fn partition_spec(spec: &PartitionSpec, schema: &Value) -> Result<Value, Error> {
+  let fields = spec.fields.iter().zip(1000..)
+    .map(|(field, field_id)| {
+      let source = find_field(&schema["fields"], &field.source)
+        .ok_or_else(|| Error::config(format!("Partition column {} is not a column of the table", field.source)))?;
+      let source_type = source["type"].as_str().unwrap_or_default();
+      let (transform, valid) = match field.transform {
+        Transform::Identity => ("identity".to_string(), true),
+        Transform::Year => ("year".to_string(), matches!(source_type, "date" | "timestamp" | "timestamptz")),
+        Transform::Month => ("month".to_string(), matches!(source_type, "date" | "timestamp" | "timestamptz")),
+        Transform::Day => ("day".to_string(), matches!(source_type, "date" | "timestamp" | "timestamptz")),
+        Transform::Hour => ("hour".to_string(), matches!(source_type, "timestamp" | "timestamptz")),
+        Transform::Bucket(n) => (format!("bucket[{}]", n), true),
+        Transform::Truncate(width) => (format!("truncate[{}]", width), true),
+      };
+      if !valid {
+        return Err(Error::config(format!(
+          "Iceberg can't partition by {}({}): {} is a {}", transform, field.source, field.source, source_type)));
+      }
+      Ok(json!({ "name": field.name(), "transform": transform, "source-id": source["id"], "field-id": field_id }))
+    })
+    .collect::<Result<Vec<_>, Error>>()?;
+  Ok(json!({ "spec-id": 0, "fields": fields }))
+}
+
+/// An Iceberg sort order for `order`. Clustered files have no order Iceberg can describe, so they're unsorted.
+fn sort_order(order: &RowOrder, schema: &Value) -> Result<Value, Error> {
+  let RowOrder::Sorted(columns) = order else {
+    return Ok(json!({ "order-id": 0, "fields": [] }));
+  };
+  let fields = columns.iter()
+    .map(|column| {
+      let source = find_field(&schema["fields"], &column.column)
+        .ok_or_else(|| Error::config(format!("Sort column {} is not a column of the table", column.column)))?;
+      Ok(json!({
+        "transform": "identity",
+        "source-id": source["id"],
+        "direction": if column.descending { "desc" } else { "asc" },
+        "null-order": if column.nulls_first { "nulls-first" } else { "nulls-last" },
+      }))
+    })
+    .collect::<Result<Vec<_>, Error>>()?;
+  Ok(json!({ "order-id": 1, "fields": fields }))
+}
+
+fn find_field<'a>(fields: &'a Value, name: &str) -> Option<&'a Value> {
+  fields.as_array()?.iter().find(|f| f["name"] == name)
+}
+
+/// `field` with field id `id`, and the ids in `iceberg_type` on the fields nested in it.
+fn with_field_id(field: &Field, id: &Value, iceberg_type: &Value) -> Field {
+  let nested = |field: &Field, id: &Value, iceberg_type: &Value| Arc::new(with_field_id(field, id, iceberg_type));
+  let data_type = match (field.data_type(), iceberg_type["type"].as_str()) {
+    (DataType::Struct(fields), Some("struct")) => DataType::Struct(fields.iter()
+      .map(|f| match find_field(&iceberg_type["fields"], f.name()) {
+        Some(t) => nested(f, &t["id"], &t["type"]),
+        None => f.clone(),
+      })
+      .collect()),
+    (DataType::List(element), Some("list")) =>
+      DataType::List(nested(element, &iceberg_type["element-id"], &iceberg_type["element"])),
+    (DataType::LargeList(element), Some("list")) =>
+      DataType::LargeList(nested(element, &iceberg_type["element-id"], &iceberg_type["element"])),
+    (DataType::Map(entries, sorted), Some("map")) => match entries.data_type() {
+      DataType::Struct(entry) => {
+        let entry = Fields::from(vec![
+          nested(&entry[0], &iceberg_type["key-id"], &iceberg_type["key"]),
+          nested(&entry[1], &iceberg_type["value-id"], &iceberg_type["value"]),
+        ]);
+        DataType::Map(Arc::new(entries.as_ref().clone().with_data_type(DataType::Struct(entry))), *sorted)
+      }
+      _ => field.data_type().clone(),
+    },
+    (data_type, _) => data_type.clone(),
+  };
+  let mut metadata = field.metadata().clone();
+  metadata.insert(PARQUET_FIELD_ID_META_KEY.to_string(), id.to_string());
+  field.clone().with_data_type(data_type).with_metadata(metadata)
+}
+
+/// `data` as `data_type`, which differs from its type at most in the metadata of nested fields.
+fn retype(data: ArrayData, data_type: &DataType) -> Result<ArrayData, Error> {
+  let children: Vec<&DataType> = match data_type {
+    DataType::Struct(fields) => fields.iter().map(|f| f.data_type()).collect(),
+    DataType::List(element) | DataType::LargeList(element) => vec![element.data_type()],
+    DataType::Map(entries, _) => vec![entries.data_type()],
+    _ => return Ok(data),
+  };
+  let child_data = data.child_data().iter().zip(children)
+    .map(|(child, data_type)| retype(child.clone(), data_type))
+    .collect::<Result<Vec<_>, Error>>()?;
+  Ok(data.into_builder().data_type(data_type.clone()).child_data(child_data).build()?)
+}
+
+fn map_entry(key: i32, value: avro::Value) -> avro::Value {
+  avro::Value::Record(vec![("key".into(), avro::Value::Int(key)), ("value".into(), value)])
+}
+
+/// A bound in Iceberg's single-value binary form. Long strings are cut to a prefix for the lower bound and
+/// left out of the upper bound, where a prefix would be too low.
+fn bound(iceberg_type: &str, value: &StatValue, upper: bool) -> Option<Vec<u8>> {
+  Some(match (iceberg_type, value) {
+    ("int", StatValue::Int(v)) | ("date", StatValue::Date(v)) => v.to_le_bytes().to_vec(),
+    ("long", StatValue::Int(v)) => (*v as i64).to_le_bytes().to_vec(),
+    ("long", StatValue::Long(v)) => v.to_le_bytes().to_vec(),
+    ("float", StatValue::Float(v)) if v.is_finite() => v.to_le_bytes().to_vec(),
+    ("double", StatValue::Float(v)) if v.is_finite() => (*v as f64).to_le_bytes().to_vec(),
+    ("double", StatValue::Double(v)) if v.is_finite() => v.to_le_bytes().to_vec(),
+    ("timestamp" | "timestamptz", StatValue::TimestampMs(ms)) => (ms * 1000).to_le_bytes().to_vec(),
+    ("string", StatValue::String(s)) if s.chars().count() <= BOUND_STRING_PREFIX => s.as_bytes().to_vec(),
+    ("string", StatValue::String(s)) if !upper => s.chars().take(BOUND_STRING_PREFIX).collect::<String>().into_bytes(),
+    _ => return None,
+  })
+}
+
+/// Avro type of a partition field, which follows from its transform and source column.
+fn avro_type(transform: &str, source_type: &str) -> Option<Value> {
+  let identity = |t: &str| -> Option<Value> {
+    Some(match t {
+      "boolean" | "int" | "long" | "float" | "double" | "string" => json!(t),
+      "binary" => json!("bytes"),
+      "date" => json!({ "type": "int", "logicalType": "date" }),
+      "timestamp" => json!({ "type": "long", "logicalType": "timestamp-micros", "adjust-to-utc": false }),
+      "timestamptz" => json!({ "type": "long", "logicalType": "timestamp-micros", "adjust-to-utc": true }),
+      _ => return None,
+    })
+  };
+  match transform {
+    "year" | "month" | "hour" => Some(json!("int")),
+    "day" => identity("date"),
+    t if t.starts_with("bucket[") => Some(json!("int")),
+    _ => identity(source_type),
+  }
+}
+
+/// A partition value from its directory name (see `PartitionField::display`). Identity partitions on dates
+/// and timestamps are named by their epoch days or milliseconds.
+fn partition_value(transform: &str, source_type: &str, raw: Option<&str>) -> Option<avro::Value> {
+  let Some(raw) = raw else { return Some(avro::Value::Null) };
+  let epoch = NaiveDate::default();
+  Some(match transform {
+    "year" => avro::Value::Int(raw.parse::<i32>().ok()? - 1970),
+    "month" => {
+      let (year, month) = raw.split_once('-')?;
+      avro::Value::Int((year.parse::<i32>().ok()? - 1970) * 12 + month.parse::<i32>().ok()? - 1)
+    }
+    "day" => avro::Value::Int((NaiveDate::parse_from_str(raw, "%Y-%m-%d").ok()? - epoch).num_days() as i32),
+    "hour" => {
+      let hour = NaiveDateTime::parse_from_str(&format!("{}:00", raw), "%Y-%m-%d-%H:%M").ok()?;
+      avro::Value::Int(hour.and_utc().timestamp().div_euclid(3_600) as i32)
+    }
+    t if t.starts_with("bucket[") => avro::Value::Int(raw.parse().ok()?),
+    _ => match source_type {
+      "string" => avro::Value::String(raw.to_string()),
+      "int" | "date" => avro::Value::Int(raw.parse().ok()?),
+      "long" => avro::Value::Long(raw.parse().ok()?),
+      "boolean" => avro::Value::Boolean(raw.parse().ok()?),
+      "timestamp" | "timestamptz" => avro::Value::Long(raw.parse::<i64>().ok()? * 1000),
+      _ => return None,
+    },
+  })
+}
+
+/// Per partition field, whether any file has a null value and the lowest and highest values.
+fn partition_summaries(entries: &[avro::Value]) -> avro::Value {
+  let tuples: Vec<&[(String, avro::Value)]> = entries.iter()
+    .filter_map(|e| match e.get("data_file").and_then(|f| f.get("partition")) {
+      Some(avro::Value::Record(fields)) => Some(fields.as_slice()),
+      _ => None,
+    })
+    .collect();
+  let width = tuples.first().map_or(0, |t| t.len());
+  avro::Value::Array((0..width)
+    .map(|i| {
+      let values: Vec<&avro::Value> = tuples.iter().filter_map(|t| t.get(i)).map(|(_, v)| v).collect();
+      let present: Vec<&avro::Value> = values.iter().copied().filter(|v| **v != avro::Value::Null).collect();
+      let lower = present.iter().copied().min_by(|a, b| compare(a, b));
+      let upper = present.iter().copied().max_by(|a, b| compare(a, b));
+      let binary = |v: Option<&avro::Value>| v.and_then(single_value).map_or(avro::Value::Null, avro::Value::Bytes);
+      avro::Value::Record(vec![
+        ("contains_null".into(), avro::Value::Boolean(present.len() < values.len())),
+        ("contains_nan".into(), avro::Value::Null),
+        ("lower_bound".into(), binary(lower)),
+        ("upper_bound".into(), binary(upper)),
+      ])
+    })
+    .collect())
+}
+
+fn compare(a: &avro::Value, b: &avro::Value) -> Ordering {
+  match (a, b) {
+    (avro::Value::String(a), avro::Value::String(b)) => a.cmp(b),
+    (avro::Value::Boolean(a), avro::Value::Boolean(b)) => a.cmp(b),
+    _ => a.as_long().cmp(&b.as_long()),
+  }
+}
+
+fn single_value(value: &avro::Value) -> Option<Vec<u8>> {
+  Some(match value {
+    avro::Value::Int(v) => v.to_le_bytes().to_vec(),
+    avro::Value::Long(v) => v.to_le_bytes().to_vec(),
+    avro::Value::String(s) => s.as_bytes().to_vec(),
+    avro::Value::Boolean(b) => vec![*b as u8],
+    _ => return None,
+  })
+}
+
+/// Avro schema of a v2 manifest entry whose partition tuple has `partition_fields`. Only the data file
+/// fields this writer fills in are declared; readers resolve the rest to null.
+fn manifest_schema(partition_fields: Vec<Value>) -> Value {
+  let map = |name: &str, id: i32, key_id: i32, value_id: i32, value_type: &str| json!({
+    "name": name,
+    "type": optional(json!({
+      "type": "array",
+      "logicalType": "map",
+      "items": {
+        "type": "record",
+        "name": format!("k{}_v{}", key_id, value_id),
+        "fields": [
+          { "name": "key", "type": "int", "field-id": key_id },
+          { "name": "value", "type": value_type, "field-id": value_id },
+        ],
+      },
+    })),
+    "default": null,
+    "field-id": id,
+  });
+  json!({
+    "type": "record",
+    "name": "manifest_entry",
+    "fields": [
+      { "name": "status", "type": "int", "field-id": 0 },
+      { "name": "snapshot_id", "type": optional(json!("long")), "default": null, "field-id": 1 },
+      { "name": "sequence_number", "type": optional(json!("long")), "default": null, "field-id": 3 },
+      { "name": "file_sequence_number", "type": optional(json!("long")), "default": null, "field-id": 4 },
+      { "name": "data_file", "field-id": 2, "type": {
+        "type": "record",
+        "name": "r2",
+        "fields": [
+          { "name": "content", "type": "int", "field-id": 134 },
+          { "name": "file_path", "type": "string", "field-id": 100 },
+          { "name": "file_format", "type": "string", "field-id": 101 },
+          { "name": "partition", "type": { "type": "record", "name": "r102", "fields": partition_fields }, "field-id": 102 },
+          { "name": "record_count", "type": "long", "field-id": 103 },
+          { "name": "file_size_in_bytes", "type": "long", "field-id": 104 },
+          map("value_counts", 109, 119, 120, "long"),
+          map("null_value_counts", 110, 121, 122, "long"),
+          map("lower_bounds", 125, 126, 127, "bytes"),
+          map("upper_bounds", 128, 129, 130, "bytes"),
+          { "name": "equality_ids", "type": optional(json!({ "type": "array", "items": "int", "element-id": 136 })), "default": null, "field-id": 135 },
+        ],
+      }},
+    ],
+  })
+}
+
+/// Count the delete files in `deletes` and the rows or keys they delete into a snapshot `summary`.
+fn add_delete_summary(summary: &mut Value, deletes: &[avro::Value]) {
+  summary["operation"] = json!("overwrite");
+  summary["added-delete-files"] = json!(deletes.len().to_string());
+  for (content, kind) in [(EQUALITY_DELETES, "equality"), (POSITION_DELETES, "position")] {
+    let (files, rows) = deletes.iter()
+      .filter_map(|e| e.get("data_file"))
+      .filter(|f| f.get("content").and_then(avro::Value::as_long) == Some(content as i64))
+      .fold((0, 0), |(files, rows), f| (files + 1, rows + f.get("record_count").and_then(avro::Value::as_long).unwrap_or(0)));
+    if files > 0 {
+      summary[format!("added-{}-delete-files", kind)] = json!(files.to_string());
+      summary[format!("added-{}-deletes", kind)] = json!(rows.to_string());
+    }
+  }
+}
+
+/// Avro schema of a v2 manifest list entry.
+fn manifest_list_schema() -> Value {
+  json!({
+    "type": "record",
+    "name": "manifest_file",
+    "fields": [
+      { "name": "manifest_path", "type": "string", "field-id": 500 },
+      { "name": "manifest_length", "type": "long", "field-id": 501 },
+      { "name": "partition_spec_id", "type": "int", "field-id": 502 },
+      { "name": "content", "type": "int", "field-id": 517 },
+      { "name": "sequence_number", "type": "long", "field-id": 515 },
+      { "name": "min_sequence_number", "type": "long", "field-id": 516 },
+      { "name": "added_snapshot_id", "type": "long", "field-id": 503 },
+      { "name": "added_files_count", "type": "int", "field-id": 504 },
+      { "name": "existing_files_count", "type": "int", "field-id": 505 },
+      { "name": "deleted_files_count", "type": "int", "field-id": 506 },
+      { "name": "added_rows_count", "type": "long", "field-id": 512 },
+      { "name": "existing_rows_count", "type": "long", "field-id": 513 },
+      { "name": "deleted_rows_count", "type": "long", "field-id": 514 },
+      { "name": "partitions", "default": null, "field-id": 507, "type": optional(json!({
+        "type": "array",
+        "element-id": 508,
+        "items": {
+          "type": "record",
+          "name": "r508",
+          "fields": [
+            { "name": "contains_null", "type": "boolean", "field-id": 509 },
+            { "name": "contains_nan", "type": optional(json!("boolean")), "default": null, "field-id": 518 },
+            { "name": "lower_bound", "type": optional(json!("bytes")), "default": null, "field-id": 510 },
+            { "name": "upper_bound", "type": optional(json!("bytes")), "default": null, "field-id": 511 },
+          ],
+        },
+      }))},
+      { "name": "key_metadata", "type": optional(json!("bytes")), "default": null, "field-id": 519 },
+    ],
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+  use std::time::Duration;
+  use arrow_array::{Float64Array, Int64Array};
+  use object_store::RetryConfig;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+  use tokio::net::{TcpListener, TcpStream};
+  use crate::sink::partition::PartitionField;
+  use crate::store::GcsOptions;
+
+  fn warehouse() -> Warehouse {
+    Warehouse::open("memory://", RetryConfig::default(), &GcsOptions::default()).unwrap()
+  }
+
+  fn retry() -> RetryPolicy {
+    RetryPolicy { max_attempts: 3, initial_backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(5) }
+  }
+
+  fn hadoop(warehouse: &Warehouse) -> IcebergCatalog {
+    IcebergCatalog::Hadoop(HadoopCatalog::new(warehouse, retry()))
+  }
+
+  fn schema() -> Schema {
+    Schema::new(vec![
+      Field::new("id", DataType::Utf8, false),
+      Field::new("variety", DataType::Utf8, true),
+      Field::new("qty", DataType::Int64, true),
+      Field::new("price", DataType::Float64, true),
+      Field::new("note", DataType::Utf8, true),
+    ])
+  }
+
+  fn spec(fields: &[&str]) -> PartitionSpec {
+    PartitionSpec { fields: fields.iter().map(|f| PartitionField::parse(f).unwrap()).collect() }
+  }
+
+  /// A data file of `farm.orders` in `partition` holding the documents `ids`, with the stats and keys the
+  /// writer records. The file itself isn't written.
+  fn data_file(warehouse: &Warehouse, name: &str, partition: &str, ids: &[&str]) -> DataFile {
+    let n = ids.len() as i64;
+    let batch = RecordBatch::try_new(Arc::new(schema()), vec![
+      Arc::new(StringArray::from(ids.to_vec())),
+      Arc::new(StringArray::from(vec!["v1"; ids.len()])),
+      Arc::new(Int64Array::from((0..n).map(|i| (i > 0).then_some(i * 10)).collect::<Vec<_>>())),
+      Arc::new(Float64Array::from((0..n).map(|i| i as f64 + 0.5).collect::<Vec<_>>())),
+      Arc::new(StringArray::from((0..n).map(|i| format!("a note about order {} that runs long", i)).collect::<Vec<_>>())),
+    ]).unwrap();
+    let mut stats = FileStats::default();
+    stats.update(&batch);
+    let table = warehouse.table_path("farm", "orders");
+    let dir = if partition.is_empty() { String::new() } else { format!("{}/", partition) };
+    DataFile {
+      path: format!("{}/data/{}{}", table, dir, name),
+      staging: format!("{}/_staging/{}{}", table, dir, name),
+      partition: partition.to_string(),
+      encoding: Encoding::Parquet,
+      schema: batch.schema(),
+      size: 1_000,
+      rows: ids.len() as u64,
+      stats,
+      encrypted: false,
+      keys: Some(ids.iter().map(|id| id.to_string()).collect()),
+      superseded: Vec::new(),
+    }
+  }
+
+  async fn create(catalog: &IcebergCatalog, warehouse: &Warehouse, partition_by: &[&str]) -> IcebergTable {
+    IcebergTable::create(catalog, warehouse, "farm", "orders", &schema(), &spec(partition_by), &RowOrder::Unsorted).await.unwrap()
+  }
+
+  /// URLs of the data files in the table's current snapshot.
+  fn files(table: &IcebergTable) -> BTreeSet<String> {
+    table.files.keys().cloned().collect()
+  }
+
+  /// The manifest entries of the table's current snapshot, live or not.
+  async fn entries(table: &IcebergTable) -> Vec<avro::Value> {
+    let mut entries = Vec::new();
+    for manifest in &table.manifests {
+      let path = manifest.get("manifest_path").and_then(avro::Value::as_str).unwrap();
+      entries.extend(table.read(path).await.unwrap().records);
+    }
+    entries
+  }
+
+  /// A map of a data file, such as its `lower_bounds`, by field id.
+  fn by_field(file: &avro::Value, name: &str) -> HashMap<i64, avro::Value> {
+    let Some(avro::Value::Array(entries)) = file.get(name) else { return HashMap::new() };
+    entries.iter().map(|e| (e.get("key").and_then(avro::Value::as_long).unwrap(), e.get("value").cloned().unwrap())).collect()
+  }
+
+  /// A REST catalog serving the tables of a Hadoop catalog, which can be told to fail commits.
+  struct FakeRest {
+    catalog: HadoopCatalog,
+    /// Commits still to fail with a server error before one goes through.
+    failing: AtomicUsize,
+    /// Responses to commits so far: successes, conflicts and server errors.
+    committed: AtomicUsize,
+    conflicts: AtomicUsize,
+    errors: AtomicUsize,
+  }
+
+  impl FakeRest {
+    /// Serve `warehouse`'s tables on a local port and return the catalog's URI.
+    async fn start(warehouse: &Warehouse) -> (Arc<Self>, String) {
+      let fake = Arc::new(Self {
+        catalog: HadoopCatalog::new(warehouse, retry()),
+        failing: AtomicUsize::new(0),
+        committed: AtomicUsize::new(0),
+        conflicts: AtomicUsize::new(0),
+        errors: AtomicUsize::new(0),
+      });
+      let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+      let uri = format!("http://{}", listener.local_addr().unwrap());
+      let server = fake.clone();
+      tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+          tokio::spawn(server.clone().serve(stream));
+        }
+      });
+      (fake, uri)
+    }
+
+    /// Answer one request and close the connection.
+    async fn serve(self: Arc<Self>, mut stream: TcpStream) {
+      let mut request = Vec::new();
+      let mut buf = [0u8; 8192];
+      let (head, body_start) = loop {
+        let n = stream.read(&mut buf).await.unwrap();
+        request.extend_from_slice(&buf[..n]);
+        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+          break (String::from_utf8_lossy(&request[..end]).into_owned(), end + 4);
+        }
+      };
+      let length = head.lines()
+        .filter_map(|l| l.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .map_or(0, |(_, value)| value.trim().parse().unwrap());
+      while request.len() < body_start + length {
+        let n = stream.read(&mut buf).await.unwrap();
+        request.extend_from_slice(&buf[..n]);
+      }
+      let body: Value = serde_json::from_slice(&request[body_start..]).unwrap_or(Value::Null);
+      let mut request_line = head.lines().next().unwrap().split(' ');
+      let (method, target) = (request_line.next().unwrap(), request_line.next().unwrap());
+      let path = target.split('?').next().unwrap().trim_start_matches("/v1/");
+
+      let (status, response) = self.respond(method, path, body).await;
+      let response = response.to_string();
+      let head = format!(
+        "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, response.len());
+      stream.write_all(head.as_bytes()).await.unwrap();
+      stream.write_all(response.as_bytes()).await.unwrap();
+    }
+
+    async fn respond(&self, method: &str, path: &str, body: Value) -> (u16, Value) {
+      let parts: Vec<&str> = path.split('/').collect();
+      let not_found = json!({ "error": { "message": "not found", "code": 404 } });
+      match (method, parts.as_slice()) {
+        ("GET", ["config"]) => (200, json!({ "defaults": {}, "overrides": {} })),
+        ("POST", ["namespaces"]) => (200, json!({})),
+        ("GET", ["namespaces", ns, "tables", table]) => match self.catalog.load_table(ns, table).await.unwrap() {
+          Some(metadata) => (200, json!({ "metadata": metadata })),
+          None => (404, not_found),
+        },
+        ("POST", ["namespaces", ns, "tables"]) => match self.catalog.create_table(ns, &body).await.unwrap() {
+          true => (200, json!({ "metadata": self.catalog.load_table(ns, body["name"].as_str().unwrap()).await.unwrap() })),
+          false => (409, json!({ "error": { "message": "exists", "code": 409 } })),
+        },
+        ("POST", ["namespaces", ns, "tables", table]) => {
+          if self.failing.fetch_update(AtomicOrdering::SeqCst, AtomicOrdering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+            self.errors.fetch_add(1, AtomicOrdering::SeqCst);
+            return (503, json!({ "error": { "message": "try again", "code": 503 } }));
+          }
+          match self.catalog.commit(ns, table, &body["requirements"], &body["updates"]).await.unwrap() {
+            Some(metadata) => {
+              self.committed.fetch_add(1, AtomicOrdering::SeqCst);
+              (200, json!({ "metadata": metadata }))
+            }
+            None => {
+              self.conflicts.fetch_add(1, AtomicOrdering::SeqCst);
+              (409, json!({ "error": { "message": "requirement failed", "code": 409 } }))
+            }
+          }
+        }
+        _ => (404, not_found),
+      }
+    }
+
+    fn counts(&self) -> (usize, usize, usize) {
+      let load = |n: &AtomicUsize| n.load(AtomicOrdering::SeqCst);
+      (load(&self.committed), load(&self.conflicts), load(&self.errors))
+    }
+  }
+
+  #[tokio::test]
+  async fn rest_commits_retry_server_errors_and_lost_races() {
+    let warehouse = warehouse();
+    let (fake, uri) = FakeRest::start(&warehouse).await;
+    let catalog = IcebergCatalog::Rest(RestCatalog::connect(&uri, None, None, retry()).await.unwrap());
+    let mut table = create(&catalog, &warehouse, &[]).await;
+    let mut stale = IcebergTable::open(&catalog, &warehouse, "farm", "orders").await.unwrap();
+
+    // A server error is retried
+    fake.failing.store(1, AtomicOrdering::SeqCst);
+    let a = data_file(&warehouse, "a.parquet", "", &["a"]);
+    let first = table.append(std::slice::from_ref(&a)).await.unwrap().unwrap();
+    assert_eq!(fake.counts(), (1, 0, 1));
+
+    // A writer that built on the table as it was is turned down, reloads it and commits on top
+    let b = data_file(&warehouse, "b.parquet", "", &["b"]);
+    let second = stale.append(std::slice::from_ref(&b)).await.unwrap().unwrap();
+    assert_eq!(fake.counts(), (2, 1, 1));
+
+    let reopened = IcebergTable::open(&catalog, &warehouse, "farm", "orders").await.unwrap();
+    assert_eq!(files(&reopened), BTreeSet::from([warehouse.url(&a.path), warehouse.url(&b.path)]));
+    let current = current_snapshot(&reopened.metadata).unwrap();
+    assert_eq!((current["snapshot-id"].as_i64(), current["parent-snapshot-id"].as_i64()), (Some(second), Some(first)));
+    assert_eq!(current["sequence-number"], 2);
+
+    // Committing the same files again is a no-op
+    assert_eq!(stale.append(&[a, b]).await.unwrap(), None);
+    assert_eq!(fake.counts(), (2, 1, 1));
+  }
+
+  #[tokio::test]
+  async fn manifests_record_partitions_and_column_stats() {
+    let warehouse = warehouse();
+    let catalog = hadoop(&warehouse);
+    let mut table = create(&catalog, &warehouse, &["variety"]).await;
+    let file = data_file(&warehouse, "a.parquet", "variety=v1", &["a", "b", "c"]);
+    let snapshot = table.append(&[file]).await.unwrap().unwrap();
+
+    let list = &table.manifests[0];
+    let long = |v: &avro::Value, name: &str| v.get(name).and_then(avro::Value::as_long);
+    assert_eq!((long(list, "added_snapshot_id"), long(list, "sequence_number")), (Some(snapshot), Some(1)));
+    assert_eq!((long(list, "added_files_count"), long(list, "added_rows_count")), (Some(1), Some(3)));
+    let Some(avro::Value::Array(partitions)) = list.get("partitions") else { panic!("no partition summaries") };
+    assert_eq!(partitions[0].get("lower_bound"), Some(&avro::Value::Bytes(b"v1".to_vec())));
+    assert_eq!(partitions[0].get("upper_bound"), Some(&avro::Value::Bytes(b"v1".to_vec())));
+    assert_eq!(partitions[0].get("contains_null"), Some(&avro::Value::Boolean(false)));
+
+    let entries = entries(&table).await;
+    let file = entries[0].get("data_file").unwrap();
+    assert_eq!(file.get("partition"), Some(&avro::Value::Record(vec![("variety".into(), avro::Value::String("v1".into()))])));
+    assert_eq!(long(file, "record_count"), Some(3));
+    // Field ids: id 1, variety 2, qty 3, price 4, note 5
+    assert_eq!(by_field(file, "value_counts")[&3], avro::Value::Long(3));
+    assert_eq!(by_field(file, "null_value_counts")[&3], avro::Value::Long(1));
+    let (lower, upper) = (by_field(file, "lower_bounds"), by_field(file, "upper_bounds"));
+    assert_eq!(lower[&1], avro::Value::Bytes(b"a".to_vec()));
+    assert_eq!(upper[&1], avro::Value::Bytes(b"c".to_vec()));
+    assert_eq!(lower[&3], avro::Value::Bytes(10i64.to_le_bytes().to_vec()));
+    assert_eq!(upper[&3], avro::Value::Bytes(20i64.to_le_bytes().to_vec()));
+    assert_eq!(lower[&4], avro::Value::Bytes(0.5f64.to_le_bytes().to_vec()));
+    assert_eq!(upper[&4], avro::Value::Bytes(2.5f64.to_le_bytes().to_vec()));
+    // Long strings keep a prefix as their lower bound and no upper bound
+    assert_eq!(lower[&5], avro::Value::Bytes(b"a note about ord".to_vec()));
+    assert!(!upper.contains_key(&5));
+
+    let summary = &current_snapshot(&table.metadata).unwrap()["summary"];
+    assert_eq!((summary["operation"].as_str(), summary["added-records"].as_str()), (Some("append"), Some("3")));
+  }
+
+  fn nested_schema() -> Schema {
+    let address = Fields::from(vec![Field::new("city", DataType::Utf8, true), Field::new("zip", DataType::Utf8, true)]);
+    let entry = Fields::from(vec![Field::new("keys", DataType::Utf8, false), Field::new("values", DataType::Int64, true)]);
+    Schema::new(vec![
+      Field::new("id", DataType::Utf8, false),
+      Field::new("address", DataType::Struct(address), true),
+      Field::new("tags", DataType::List(Arc::new(Field::new("item", DataType::Utf8, true))), true),
+      Field::new("counts", DataType::Map(Arc::new(Field::new("entries", DataType::Struct(entry), false)), false), true),
+      Field::new("created_at", DataType::Timestamp(arrow::datatypes::TimeUnit::Millisecond, None), true),
+    ])
+  }
+
+  #[test]
+  fn assigns_field_ids_to_columns_before_nested_fields() {
+    let schema = iceberg_schema(&nested_schema()).unwrap();
+    let mut ids = HashMap::new();
+    field_ids(&schema["fields"], "", &mut ids);
+    let expected = [
+      ("id", 1), ("address", 2), ("tags", 3), ("counts", 4), ("created_at", 5),
+      ("address.city", 6), ("address.zip", 7), ("tags.element", 8), ("counts.key", 9), ("counts.value", 10),
+    ];
+    assert_eq!(ids, expected.iter().map(|(path, id)| (path.to_string(), *id)).collect());
+    assert_eq!(schema["fields"][0]["required"], true);
+    assert_eq!(schema["fields"][3]["type"]["value-required"], false);
+    assert_eq!(schema["fields"][4]["type"], "timestamp");
+  }
+
+  #[test]
+  fn partition_specs_reference_source_ids() {
+    let schema = iceberg_schema(&nested_schema()).unwrap();
+    let partitioned = partition_spec(&spec(&["day(created_at)", "bucket(8, id)"]), &schema).unwrap();
+    assert_eq!(partitioned["fields"], json!([
+      { "name": "created_at_day", "transform": "day", "source-id": 5, "field-id": 1000 },
+      { "name": "id_bucket", "transform": "bucket[8]", "source-id": 1, "field-id": 1001 },
+    ]));
+    assert!(partition_spec(&spec(&["missing"]), &schema).is_err());
+    assert!(partition_spec(&spec(&["day(id)"]), &schema).is_err());
+  }
+
+  #[tokio::test]
+  async fn creates_tables_once_and_writes_field_ids_into_batches() {
+    let warehouse = warehouse();
+    let catalog = hadoop(&warehouse);
+    let schema = Arc::new(nested_schema());
+    let mut table = IcebergTable::create(&catalog, &warehouse, "farm", "orders", &schema, &spec(&["day(created_at)"]), &RowOrder::Unsorted).await.unwrap();
+    assert_eq!(table.metadata["location"], warehouse.url("farm/orders"));
+    assert_eq!(table.metadata["last-column-id"], 10);
+    assert_eq!(table.metadata["last-partition-id"], 1000);
+    assert_eq!(table.metadata["format-version"], 2);
+
+    // Creating it again opens the table as it is
+    let other = Schema::new(vec![Field::new("other", DataType::Int64, true)]);
+    let again = IcebergTable::create(&catalog, &warehouse, "farm", "orders", &other, &PartitionSpec::default(), &RowOrder::Unsorted).await.unwrap();
+    assert_eq!(again.metadata, table.metadata);
+
+    let batch = RecordBatch::new_empty(schema);
+    let batch = table.with_field_ids(&batch).unwrap();
+    let id = |field: &Field| field.metadata().get(PARQUET_FIELD_ID_META_KEY).cloned().unwrap();
+    let fields = batch.schema().fields().clone();
+    assert_eq!(fields.iter().map(|f| id(f)).collect::<Vec<_>>(), ["1", "2", "3", "4", "5"]);
+    let DataType::Struct(address) = fields[1].data_type() else { panic!("address isn't a struct") };
+    assert_eq!((id(&address[0]), id(&address[1])), ("6".into(), "7".into()));
+    let DataType::List(element) = fields[2].data_type() else { panic!("tags isn't a list") };
+    assert_eq!(id(element), "8");
+    let DataType::Map(entries, _) = fields[3].data_type() else { panic!("counts isn't a map") };
+    let DataType::Struct(entry) = entries.data_type() else { panic!("map entries aren't structs") };
+    assert_eq!((id(&entry[0]), id(&entry[1])), ("9".into(), "10".into()));
+    // Nothing changed, so there's no schema to evolve
+    assert!(table.evolved.is_none());
+  }
+
+  /// The fields of a table created with `table` evolved to hold `columns`, and the last field id after it.
+  fn evolved(table: &Schema, columns: &Schema) -> Result<(Value, i32), Error> {
+    let schema = iceberg_schema(table)?;
+    let mut ids = HashMap::new();
+    field_ids(&schema["fields"], "", &mut ids);
+    let mut last_id = ids.values().max().copied().unwrap_or_default() as i32;
+    Ok((evolve_fields(&schema["fields"], columns.fields(), "", &mut last_id)?, last_id))
+  }
+
+  fn address(fields: &[&str]) -> DataType {
+    DataType::Struct(fields.iter().map(|name| Field::new(*name, DataType::Utf8, true)).collect())
+  }
+
+  #[test]
+  fn evolution_widens_types_and_adds_optional_fields() {
+    let table = Schema::new(vec![
+      Field::new("id", DataType::Utf8, false),
+      Field::new("qty", DataType::Int32, false),
+      Field::new("price", DataType::Float32, true),
+      Field::new("address", address(&["city"]), true),
+    ]);
+    let columns = Schema::new(vec![
+      Field::new("id", DataType::Utf8, true),
+      Field::new("qty", DataType::Int64, false),
+      Field::new("price", DataType::Float64, true),
+      Field::new("address", address(&["city", "country"]), true),
+      Field::new("note", DataType::Utf8, false),
+    ]);
+    let (fields, last_id) = evolved(&table, &columns).unwrap();
+    assert_eq!(fields, json!([
+      { "id": 1, "name": "id", "required": false, "type": "string" },
+      { "id": 2, "name": "qty", "required": true, "type": "long" },
+      { "id": 3, "name": "price", "required": false, "type": "double" },
+      { "id": 4, "name": "address", "required": false, "type": { "type": "struct", "fields": [
+        { "id": 5, "name": "city", "required": false, "type": "string" },
+        { "id": 6, "name": "country", "required": false, "type": "string" },
+      ] } },
+      { "id": 7, "name": "note", "required": false, "type": "string" },
+    ]));
+    assert_eq!(last_id, 7);
+
+    // Columns files leave out become optional
+    let (fields, _) = evolved(&table, &Schema::new(vec![Field::new("id", DataType::Utf8, false)])).unwrap();
+    assert_eq!((&fields[0]["required"], &fields[1]["required"]), (&json!(true), &json!(false)));
+  }
+
+  #[test]
+  fn evolution_never_narrows_types() {
+    let table = Schema::new(vec![
+      Field::new("qty", DataType::Int64, true),
+      Field::new("price", DataType::Float64, true),
+      Field::new("amount", DataType::Decimal128(12, 2), true),
+    ]);
+    // Narrower values are written into the wider columns
+    let narrower = Schema::new(vec![
+      Field::new("qty", DataType::Int32, true),
+      Field::new("price", DataType::Float32, true),
+      Field::new("amount", DataType::Decimal128(10, 2), true),
+    ]);
+    let (fields, _) = evolved(&table, &narrower).unwrap();
+    assert_eq!(fields, iceberg_schema(&table).unwrap()["fields"]);
+
+    // Anything but a widening is refused
+    for (column, data_type) in [("qty", DataType::Utf8), ("qty", DataType::Float64), ("amount", DataType::Decimal128(12, 4))] {
+      let columns = Schema::new(vec![Field::new(column, data_type.clone(), true)]);
+      let Err(Error::Data(e)) = evolved(&table, &columns) else { panic!("{} as {} wasn't refused", column, data_type) };
+      assert!(e.to_string().starts_with(&format!("column {} can't change from", column)), "{}", e);
+    }
+  }
+
+  #[tokio::test]
+  async fn commits_evolve_the_table_schema() {
+    let warehouse = warehouse();
+    let catalog = hadoop(&warehouse);
+    let narrow = Schema::new(vec![
+      Field::new("id", DataType::Utf8, false),
+      Field::new("qty", DataType::Int32, false),
+      Field::new("address", address(&["city"]), true),
+    ]);
+    let wide = Arc::new(Schema::new(vec![
+      Field::new("id", DataType::Utf8, false),
+      Field::new("qty", DataType::Int64, true),
+      Field::new("address", address(&["city", "country"]), true),
+      Field::new("note", DataType::Utf8, true),
+    ]));
+    let mut table = IcebergTable::create(&catalog, &warehouse, "farm", "orders", &narrow, &PartitionSpec::default(), &RowOrder::Unsorted).await.unwrap();
+
+    // Batches written before the commit carry the ids the evolved schema gives the new fields
+    let batch = table.with_field_ids(&RecordBatch::new_empty(wide.clone())).unwrap();
+    let id = |field: &Field| field.metadata()[PARQUET_FIELD_ID_META_KEY].clone();
+    assert_eq!(batch.schema().fields().iter().map(|f| id(f)).collect::<Vec<_>>(), ["1", "2", "3", "6"]);
+    let mut file = data_file(&warehouse, "a.parquet", "", &["a"]);
+    file.schema = batch.schema();
+    table.append(&[file]).await.unwrap().unwrap();
+
+    let reopened = IcebergTable::open(&catalog, &warehouse, "farm", "orders").await.unwrap();
+    assert_eq!((reopened.metadata["current-schema-id"].as_i64(), reopened.metadata["last-column-id"].as_i64()), (Some(1), Some(6)));
+    assert_eq!(reopened.metadata["schemas"].as_array().map(Vec::len), Some(2));
+    let mut ids = HashMap::new();
+    field_ids(&reopened.schema().unwrap()["fields"], "", &mut ids);
+    assert_eq!((ids["qty"], ids["address.city"], ids["address.country"], ids["note"]), (2, 4, 5, 6));
+    let qty = find_field(&reopened.schema().unwrap()["fields"], "qty").unwrap();
+    assert_eq!((&qty["type"], &qty["required"]), (&json!("long"), &json!(false)));
+    let mapping = reopened.metadata["properties"][NAME_MAPPING_PROPERTY].as_str().unwrap();
+    assert!(mapping.contains("\"country\"") && mapping.contains("\"note\""), "{}", mapping);
+
+    // A file the table can't hold fails before anything is committed
+    let mut table = reopened;
+    let refused = Schema::new(vec![Field::new("qty", DataType::Utf8, true)]);
+    let Err(Error::Data(e)) = table.with_field_ids(&RecordBatch::new_empty(Arc::new(refused))) else { panic!("qty as a string wasn't refused") };
+    assert!(e.to_string().starts_with("Can't write to Iceberg table farm.orders: column qty"), "{}", e);
+    assert!(table.evolved.is_none());
+  }
+
+  /// The data files `scan` returns, by name, with the keys and positions deleted from each.
+  async fn scanned(table: &IcebergTable) -> BTreeMap<String, (BTreeSet<String>, BTreeSet<u64>)> {
+    table.scan(None).await.unwrap().into_iter()
+      .map(|f| {
+        let keys = f.deleted.get("id").into_iter().flatten().cloned().collect();
+        (f.path.as_ref().split("/data/").nth(1).unwrap().to_string(), (keys, f.deleted_positions))
+      })
+      .collect()
+  }
+
+  fn deleted(keys: &[&str], positions: &[u64]) -> (BTreeSet<String>, BTreeSet<u64>) {
+    (keys.iter().map(|k| k.to_string()).collect(), positions.iter().copied().collect())
+  }
+
+  #[tokio::test]
+  async fn upserts_delete_earlier_rows_in_every_partition_and_rows_the_run_replaced() {
+    let warehouse = warehouse();
+    let catalog = hadoop(&warehouse);
+    let mut table = create(&catalog, &warehouse, &["variety"]).await.with_key_column("id");
+    let first = data_file(&warehouse, "run_id=r1/part-00000.parquet", "variety=v1", &["a", "b"]);
+    let other = data_file(&warehouse, "run_id=r1/part-00000.parquet", "variety=v3", &["x", "y"]);
+    table.append(&[first, other]).await.unwrap().unwrap();
+    assert_eq!(current_snapshot(&table.metadata).unwrap()["summary"]["operation"], "append");
+
+    // The next run changes `a` twice after moving it to v2, and `b` once in v1 and then in v2
+    let mut moved = data_file(&warehouse, "run_id=r2/part-00000.parquet", "variety=v2", &["a", "b", "a"]);
+    moved.superseded = vec![0];
+    let mut stayed = data_file(&warehouse, "run_id=r2/part-00000.parquet", "variety=v1", &["b"]);
+    stayed.superseded = vec![0];
+    table.append(&[moved, stayed]).await.unwrap().unwrap();
+
+    // Each document is left with its last row only: the second and third of the file in v2
+    assert_eq!(scanned(&table).await, BTreeMap::from([
+      ("variety=v1/run_id=r1/part-00000.parquet".to_string(), deleted(&["a", "b"], &[])),
+      ("variety=v3/run_id=r1/part-00000.parquet".to_string(), deleted(&[], &[])),
+      ("variety=v2/run_id=r2/part-00000.parquet".to_string(), deleted(&[], &[0])),
+      ("variety=v1/run_id=r2/part-00000.parquet".to_string(), deleted(&[], &[0])),
+    ]));
+    let summary = &current_snapshot(&table.metadata).unwrap()["summary"];
+    assert_eq!(summary["operation"], "overwrite");
+    assert_eq!((summary["added-equality-delete-files"].as_str(), summary["added-equality-deletes"].as_str()), (Some("1"), Some("2")));
+    assert_eq!((summary["added-position-delete-files"].as_str(), summary["added-position-deletes"].as_str()), (Some("2"), Some("2")));
+
+    // The equality deletes only went to the partition that held the keys
+    let deletes: Vec<String> = entries(&table).await.iter()
+      .filter_map(|e| e.get("data_file"))
+      .filter(|f| f.get("content").and_then(avro::Value::as_long) != Some(0))
+      .filter_map(|f| f.get("file_path").and_then(avro::Value::as_str))
+      .map(|url| url.split("/deletes/").nth(1).unwrap().to_string())
+      .collect();
+    let snapshot = current_snapshot(&table.metadata).unwrap()["snapshot-id"].as_i64().unwrap();
+    assert_eq!(deletes.into_iter().collect::<BTreeSet<_>>(), BTreeSet::from([
+      format!("variety=v1/upserts-{}.parquet", snapshot),
+      "variety=v2/run_id=r2/part-00000.parquet".to_string(),
+      "variety=v1/run_id=r2/part-00000.parquet".to_string(),
+    ]));
+  }
+
+  /// Every file under `dir`, recursively.
+  fn files_under(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    std::fs::read_dir(dir).unwrap().flatten()
+      .flat_map(|entry| if entry.path().is_dir() { files_under(&entry.path()) } else { vec![entry.path()] })
+      .collect()
+  }
+
+  #[tokio::test]
+  async fn maintenance_keeps_what_snapshots_use_and_deletes_old_orphans() {
+    // A local warehouse, whose files can be made older than the grace period
+    let dir = std::env::temp_dir().join(format!("fire-to-ice-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let warehouse = Warehouse::open(&format!("file://{}", dir.display()), RetryConfig::default(), &GcsOptions::default()).unwrap();
+    let catalog = hadoop(&warehouse);
+    let mut table = create(&catalog, &warehouse, &[]).await.with_key_column("id");
+    let put = |path: String| {
+      let store = warehouse.store.clone();
+      async move { store.put(&Path::from(path.as_str()), PutPayload::from_static(b"x")).await.unwrap() }
+    };
+
+    let a = data_file(&warehouse, "run_id=r1/part-00000.parquet", "", &["a"]);
+    put(a.path.clone()).await;
+    let first = table.append(std::slice::from_ref(&a)).await.unwrap().unwrap();
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    // `b` changes `a` again, so an equality delete file goes with it
+    let b = data_file(&warehouse, "run_id=r2/part-00000.parquet", "", &["a", "b"]);
+    put(b.path.clone()).await;
+    table.set_checkpoint(Checkpoint { resume_token: "c2".into(), watermark_ms: 2000 });
+    let second = table.append(std::slice::from_ref(&b)).await.unwrap().unwrap();
+    tokio::time::sleep(Duration::from_millis(5)).await;
+
+    // Another tool commits a snapshot without a checkpoint on top
+    let mut third = current_snapshot(&table.metadata).unwrap().clone();
+    third["snapshot-id"] = json!(second + 1);
+    third["parent-snapshot-id"] = json!(second);
+    third["sequence-number"] = json!(3);
+    third["timestamp-ms"] = json!(chrono::Utc::now().timestamp_millis());
+    third["summary"] = json!({ "operation": "replace" });
+    let requirements = json!([{ "type": "assert-ref-snapshot-id", "ref": "main", "snapshot-id": second }]);
+    let updates = json!([
+      { "action": "add-snapshot", "snapshot": third },
+      { "action": "set-snapshot-ref", "ref-name": "main", "type": "branch", "snapshot-id": second + 1 },
+    ]);
+    catalog.commit("farm", "orders", requirements, updates).await.unwrap().unwrap();
+    let mut table = IcebergTable::open(&catalog, &warehouse, "farm", "orders").await.unwrap();
+    let first_list = table.metadata["snapshots"][0]["manifest-list"].as_str().unwrap().to_string();
+
+    // Only the first snapshot goes: the latest is kept, and the second has the checkpoint runs resume from
+    let now = chrono::Utc::now().timestamp_millis();
+    assert_eq!(table.expire_snapshots(now + 1, 1).await.unwrap(), vec![first]);
+    let ids: Vec<i64> = table.metadata["snapshots"].as_array().unwrap().iter().filter_map(|s| s["snapshot-id"].as_i64()).collect();
+    assert_eq!(ids, vec![second, second + 1]);
+    assert_eq!(table.last_checkpoint().map(|c| c.resume_token), Some("c2".to_string()));
+    assert!(table.expire_snapshots(now + 1, 1).await.unwrap().is_empty());
+
+    // The files the table uses: data and delete files, manifests and the manifest lists of the snapshots left
+    let table_dir = warehouse.table_path("farm", "orders");
+    let list = || async {
+      let files: Vec<_> = warehouse.store.list(Some(&table_dir)).collect().await;
+      files.into_iter().map(|meta| meta.unwrap().location.to_string()).collect::<BTreeSet<_>>()
+    };
+    let used: BTreeSet<String> = list().await.into_iter().filter(|path| !first_list.ends_with(path.as_str())).collect();
+    assert!(used.iter().any(|path| path.contains("/deletes/")), "{:?}", used);
+
+    let stray = |name: &str| format!("{}/{}", table_dir, name);
+    for name in ["data/run_id=r0/part-00000.parquet", "deletes/upserts-1.parquet", "metadata/snap-1-1-stray.avro", "data/run_id=r0/kept.parquet"] {
+      put(stray(name)).await;
+    }
+    // Everything but one stray file is older than the grace period
+    let old = std::time::SystemTime::now() - Duration::from_secs(2 * 3600);
+    for file in files_under(&dir) {
+      std::fs::File::options().write(true).open(&file).unwrap().set_modified(old).unwrap();
+    }
+    put(stray("data/run_id=r3/part-00000.parquet")).await;
+
+    let keep = HashSet::from([stray("data/run_id=r0/kept.parquet")]);
+    let deleted = table.delete_orphans(&keep, Duration::from_secs(3600)).await.unwrap();
+    let left = list().await;
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(deleted, 4);
+    let expected: BTreeSet<String> = used.into_iter()
+      .chain([stray("data/run_id=r0/kept.parquet"), stray("data/run_id=r3/part-00000.parquet")])
+      .collect();
+    assert_eq!(left, expected);
+  }
+}
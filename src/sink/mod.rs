@@ -1,2 +1,13 @@
 pub mod parquet_writer;
-pub mod parquet_commit;
\ No newline at end of file
+pub mod parquet_commit;
+pub mod multipart;
+pub mod rolling;
+pub mod partition;
+pub mod compact;
+pub mod stats;
+pub mod delta;
+pub mod encoding;
+pub mod sort;
+pub mod encryption;pub mod avro;
+pub mod iceberg;
+pub mod hadoop_catalog;
@@ -0,0 +1,143 @@
+// src/sink/multipart.rs
+// Streams encoded bytes into an object store multipart upload, so a file never has to be held in memory.
+// Individual part requests are retried by the object store client (see `Config::warehouse`);
+// if a part still fails, or the writer is dropped before completing, the upload is aborted.
+
+use bytes::Bytes;
+use futures::future::{BoxFuture, FutureExt};
+use object_store::{MultipartUpload, ObjectStore, PutPayloadMut, path::Path};
+use parquet::arrow::async_writer::AsyncFileWriter;
+use parquet::errors::ParquetError;
+use tokio::task::JoinSet;
+use crate::error::Error;
+
+pub struct UploadOptions {
+  /// Size of each uploaded part. GCS and S3 require at least 5 MiB for all but the last part.
+  pub part_size: usize,
+  /// Number of parts in flight at once.
+  pub max_concurrency: usize,
+}
+
+impl Default for UploadOptions {
+  fn default() -> Self {
+    Self { part_size: 10 * 1024 * 1024, max_concurrency: 4 }
+  }
+}
+
+pub struct MultipartWriter {
+  upload: Option<Box<dyn MultipartUpload>>,
+  buffer: PutPayloadMut,
+  tasks: JoinSet<object_store::Result<()>>,
+  part_size: usize,
+  max_concurrency: usize,
+  parts: usize,
+  path: Path,
+}
+
+impl MultipartWriter {
+  pub async fn new(store: &dyn ObjectStore, path: &Path, opts: &UploadOptions) -> Result<Self, Error> {
+    let upload = store.put_multipart(path).await?;
+    Ok(Self {
+      upload: Some(upload),
+      buffer: PutPayloadMut::new(),
+      tasks: JoinSet::new(),
+      part_size: opts.part_size,
+      max_concurrency: opts.max_concurrency.max(1),
+      parts: 0,
+      path: path.clone(),
+    })
+  }
+
+  fn put_part(&mut self) -> object_store::Result<()> {
+    let upload = self.upload.as_mut().ok_or_else(|| closed(&self.path))?;
+    let part = std::mem::take(&mut self.buffer).freeze();
+    self.tasks.spawn(upload.put_part(part));
+    self.parts += 1;
+    Ok(())
+  }
+
+  /// Wait until at most `max_in_flight` parts are still uploading.
+  async fn wait_for_parts(&mut self, max_in_flight: usize) -> object_store::Result<()> {
+    while self.tasks.len() > max_in_flight {
+      if let Some(result) = self.tasks.join_next().await {
+        result.map_err(|e| object_store::Error::Generic { store: "multipart", source: Box::new(e) })??;
+      }
+    }
+    Ok(())
+  }
+
+  async fn write_bytes(&mut self, mut bytes: Bytes) -> object_store::Result<()> {
+    while !bytes.is_empty() {
+      let remaining = self.part_size - self.buffer.content_length();
+      if bytes.len() < remaining {
+        self.buffer.push(bytes);
+        break;
+      }
+      self.buffer.push(bytes.split_to(remaining));
+      self.put_part()?;
+      self.wait_for_parts(self.max_concurrency).await?;
+    }
+    Ok(())
+  }
+
+  async fn finish(&mut self) -> object_store::Result<()> {
+    // An upload needs at least one part, even for an empty file
+    if !self.buffer.is_empty() || self.parts == 0 {
+      self.put_part()?;
+    }
+    self.wait_for_parts(0).await?;
+    let upload = self.upload.as_mut().ok_or_else(|| closed(&self.path))?;
+    upload.complete().await?;
+    self.upload = None;
+    Ok(())
+  }
+
+  async fn abort(&mut self) {
+    self.tasks.shutdown().await;
+    if let Some(mut upload) = self.upload.take()
+      && let Err(e) = upload.abort().await {
+      tracing::warn!("failed to abort upload of {}: {}", self.path, e);
+    }
+  }
+}
+
+impl AsyncFileWriter for MultipartWriter {
+  fn write(&mut self, bs: Bytes) -> BoxFuture<'_, parquet::errors::Result<()>> {
+    async move {
+      if let Err(e) = self.write_bytes(bs).await {
+        self.abort().await;
+        return Err(ParquetError::External(Box::new(e)));
+      }
+      Ok(())
+    }.boxed()
+  }
+
+  fn complete(&mut self) -> BoxFuture<'_, parquet::errors::Result<()>> {
+    async move {
+      if let Err(e) = self.finish().await {
+        self.abort().await;
+        return Err(ParquetError::External(Box::new(e)));
+      }
+      Ok(())
+    }.boxed()
+  }
+}
+
+impl Drop for MultipartWriter {
+  fn drop(&mut self) {
+    // Dropped without completing, e.g. because encoding failed: clean up the parts already uploaded
+    if let Some(mut upload) = self.upload.take() {
+      self.tasks.abort_all();
+      if let Ok(handle) = tokio::runtime::Handle::try_current() {
+        handle.spawn(async move { let _ = upload.abort().await; });
+      }
+    }
+  }
+}
+
+fn closed(path: &Path) -> object_store::Error {
+  object_store::Error::Generic {
+    store: "multipart",
+    source: format!("upload of {} is already closed", path).into(),
+  }
+}
@@ -1,19 +1,407 @@
 // src/sink/parquet_commit.rs
-use crate::sink::parquet_writer::ParquetSink;
+// Publishes staged files into the table's `data/` directory and records them under `<table>/_committed/`.
+// Readers only trust files with a record, so a file that was copied but never recorded stays invisible.
+// An ingestion run records all its files in one entry, keyed by the source position it started from, together
+// with the checkpoint it read up to, so the next run resumes exactly where the committed data ends. A run tried
+// again from the same position writes the same paths and replaces the entry of the attempt before it, and the
+// files of that attempt it didn't write again are deleted, so none are left behind that nothing references.
+// The whole commit is retried on transient errors. Entries of single files, as earlier versions recorded them,
+// are still read.
+// At the end of a run, `<table>/_runs/<run_id>/_SUCCESS` lists every file the run produced.
+// Compaction swaps files with a single replace entry, which adds its outputs and removes its inputs at once.
+
+use std::collections::HashSet;
+use futures::StreamExt;
+use object_store::{ObjectStore, PutPayload, path::Path};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::error::Error;
+use crate::retry::RetryPolicy;
+use crate::sink::parquet_writer::DataFile;
+use crate::source::firestore_listen::Checkpoint;
+use crate::store::Warehouse;
+
+/// One committed file, as recorded under `_committed/` and in run manifests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommitRecord {
+  pub path: String,
+  pub size: u64,
+  pub rows: u64,
+  /// Parquet with encrypted columns, which only readers with the keys can read.
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  pub encrypted: bool,
+}
+
+/// A set of files replaced by another, e.g. by compaction.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplaceRecord {
+  pub added: Vec<CommitRecord>,
+  pub removed: Vec<String>,
+  pub committed_at_ms: i64,
+}
+
+/// The files of an ingestion run and the source position they hold every change until.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+  pub run_id: String,
+  pub added: Vec<CommitRecord>,
+  pub checkpoint: Checkpoint,
+  pub committed_at_ms: i64,
+}
+
+/// An entry under `_committed/`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CommitEntry {
+  File(CommitRecord),
+  Replace(ReplaceRecord),
+  Run(RunRecord),
+}
+
+/// The files visible after applying `entries`. Entries can be applied in any order, because paths are
+/// never reused once removed.
+pub fn live_files(entries: &[CommitEntry]) -> Vec<CommitRecord> {
+  let removed: HashSet<&str> = entries.iter()
+    .filter_map(|e| match e { CommitEntry::Replace(r) => Some(r), _ => None })
+    .flat_map(|r| r.removed.iter().map(String::as_str))
+    .collect();
+  entries.iter()
+    .flat_map(|e| match e {
+      CommitEntry::File(f) => std::slice::from_ref(f),
+      CommitEntry::Replace(r) => r.added.as_slice(),
+      CommitEntry::Run(r) => r.added.as_slice(),
+    })
+    .filter(|f| !removed.contains(f.path.as_str()))
+    .cloned()
+    .collect()
+}
 
 pub struct ParquetCommit {
-  #[allow(dead_code)]
-  parquet_sink: ParquetSink,
+  warehouse: Warehouse,
+  retry: RetryPolicy,
 }
 
 impl ParquetCommit {
-  pub async fn new(gcs_bucket: &str, gcs_prefix: &str) -> anyhow::Result<Self> {
-    let parquet_sink = ParquetSink::new(gcs_bucket, gcs_prefix).await?;
-    Ok(Self { parquet_sink })
+  pub async fn new(warehouse: &Warehouse, retry: RetryPolicy) -> anyhow::Result<Self> {
+    Ok(Self { warehouse: warehouse.clone(), retry })
+  }
+
+  /// Publish the files of run `run_id`, which read the source from `from` until `checkpoint`, and commit them
+  /// to `ns.table` in one entry. Replaces the entry of an earlier attempt from the same position, which no
+  /// table format committed either, as its checkpoint would then be later, and deletes that attempt's files,
+  /// including ones it staged but crashed before committing.
+  pub async fn commit_run(&self, ns: &str, table: &str, run_id: &str, from: Option<&Checkpoint>, files: &[DataFile], checkpoint: &Checkpoint) -> Result<(), Error> {
+    let start = hex::encode(Sha256::digest(from.map_or("", |c| c.resume_token.as_str()).as_bytes()));
+    let record = self.table_path(ns, table, &format!("_committed/run-{}.json", start))?;
+    self.retry.run(&format!("committing run {} of {}", run_id, table), || async {
+      let superseded = match self.warehouse.store.get(&record).await {
+        Ok(earlier) => match serde_json::from_slice(&earlier.bytes().await?)? {
+          CommitEntry::Run(earlier) => earlier.added,
+          _ => Vec::new(),
+        },
+        Err(object_store::Error::NotFound { .. }) => Vec::new(),
+        Err(e) => return Err(e.into()),
+      };
+      for file in files {
+        let staging = Path::parse(&file.staging).map_err(|e| Error::Data(Box::new(e)))?;
+        let path = Path::parse(&file.path).map_err(|e| Error::Data(Box::new(e)))?;
+        // An earlier attempt may have published the file already
+        match self.warehouse.store.copy(&staging, &path).await {
+          Ok(_) => println!("Appending parquet file: {} to table: {} ({} bytes, {} rows)", self.warehouse.url(&file.path), table, file.size, file.rows),
+          Err(object_store::Error::NotFound { .. }) if self.warehouse.store.head(&path).await.is_ok() => {}
+          Err(e) => return Err(e.into()),
+        }
+      }
+      let body = RunRecord {
+        run_id: run_id.to_string(),
+        added: files.iter().map(|f| CommitRecord { path: f.path.clone(), size: f.size, rows: f.rows, encrypted: f.encrypted }).collect(),
+        checkpoint: checkpoint.clone(),
+        committed_at_ms: chrono::Utc::now().timestamp_millis(),
+      };
+      self.warehouse.store.put(&record, PutPayload::from(serde_json::to_vec(&body)?)).await?;
+      let written: HashSet<&str> = files.iter().map(|f| f.path.as_str()).collect();
+      let stale = superseded.iter().map(|f| f.path.as_str()).filter(|p| !written.contains(p));
+      let mut delete = files.iter().map(|f| Path::parse(&f.staging)).chain(stale.map(Path::parse))
+        .collect::<Result<Vec<_>, _>>().map_err(|e| Error::Data(Box::new(e)))?;
+      delete.extend(self.staged(ns, table, run_id).await?);
+      for path in delete {
+        match self.warehouse.store.delete(&path).await {
+          Ok(_) | Err(object_store::Error::NotFound { .. }) => {}
+          Err(e) => return Err(e.into()),
+        }
+      }
+      Ok(())
+    }).await
+  }
+
+  /// Every file left under the `_staging/` directory of `ns.table` by run `run_id`.
+  async fn staged(&self, ns: &str, table: &str, run_id: &str) -> Result<Vec<Path>, Error> {
+    let prefix = self.table_path(ns, table, "_staging")?;
+    let dir = format!("run_id={}", run_id);
+    let mut listed = self.warehouse.store.list(Some(&prefix));
+    let mut staged = Vec::new();
+    while let Some(meta) = listed.next().await {
+      let location = meta?.location;
+      if location.parts().any(|part| part.as_ref() == dir) {
+        staged.push(location);
+      }
+    }
+    Ok(staged)
+  }
+
+  /// The checkpoint of the run committed last to `ns.table`, the one with the latest watermark, if any.
+  pub async fn last_checkpoint(&self, ns: &str, table: &str) -> Result<Option<Checkpoint>, Error> {
+    Ok(self.entries(ns, table).await?.into_iter()
+      .filter_map(|(_, entry)| match entry { CommitEntry::Run(r) => Some(r), _ => None })
+      .max_by_key(|r| (r.checkpoint.watermark_ms, r.committed_at_ms))
+      .map(|r| r.checkpoint))
+  }
+
+  /// Atomically swap `removed` for `added`. Returns `false` if this swap was already committed.
+  /// The removed files stay in place for readers that already listed them; see `cleanup`.
+  pub async fn replace_files(&self, ns: &str, table: &str, added: &[DataFile], removed: &[String]) -> Result<bool, Error> {
+    let digest = hex::encode(Sha256::digest(removed.join("\n").as_bytes()));
+    let record = self.table_path(ns, table, &format!("_committed/replace-{}.json", digest))?;
+    self.retry.run(&format!("committing replacement of {} files", removed.len()), || async {
+      let committed = match self.warehouse.store.head(&record).await {
+        Ok(_) => false,
+        Err(object_store::Error::NotFound { .. }) => {
+          for file in added {
+            let staging = Path::parse(&file.staging).map_err(|e| Error::Data(Box::new(e)))?;
+            let path = Path::parse(&file.path).map_err(|e| Error::Data(Box::new(e)))?;
+            self.warehouse.store.copy(&staging, &path).await?;
+          }
+          let body = ReplaceRecord {
+            added: added.iter().map(|f| CommitRecord { path: f.path.clone(), size: f.size, rows: f.rows, encrypted: f.encrypted }).collect(),
+            removed: removed.to_vec(),
+            committed_at_ms: chrono::Utc::now().timestamp_millis(),
+          };
+          self.warehouse.store.put(&record, PutPayload::from(serde_json::to_vec(&body)?)).await?;
+          true
+        }
+        Err(e) => return Err(e.into()),
+      };
+      for file in added {
+        let staging = Path::parse(&file.staging).map_err(|e| Error::Data(Box::new(e)))?;
+        match self.warehouse.store.delete(&staging).await {
+          Ok(_) | Err(object_store::Error::NotFound { .. }) => {}
+          Err(e) => return Err(e.into()),
+        }
+      }
+      Ok(committed)
+    }).await
+  }
+
+  /// Delete files that were replaced more than `grace` ago, once no reader should still be using them.
+  /// Returns the number of files deleted.
+  pub async fn cleanup(&self, ns: &str, table: &str, grace: std::time::Duration) -> Result<usize, Error> {
+    let cutoff = chrono::Utc::now().timestamp_millis() - grace.as_millis() as i64;
+    let cleaned_prefix = self.table_path(ns, table, "_cleaned")?;
+    let cleaned: HashSet<String> = self.retry.run(&format!("listing {}", cleaned_prefix), || async {
+      let mut markers = self.warehouse.store.list(Some(&cleaned_prefix));
+      let mut cleaned = HashSet::new();
+      while let Some(meta) = markers.next().await {
+        cleaned.insert(meta?.location.filename().unwrap_or_default().to_string());
+      }
+      Ok(cleaned)
+    }).await?;
+
+    let mut deleted = 0;
+    for (name, entry) in self.entries(ns, table).await? {
+      let CommitEntry::Replace(replace) = entry else { continue };
+      if replace.committed_at_ms > cutoff || cleaned.contains(&name) {
+        continue;
+      }
+      for file in &replace.removed {
+        let path = Path::parse(file).map_err(|e| Error::Data(Box::new(e)))?;
+        self.retry.run(&format!("deleting {}", path), || async {
+          match self.warehouse.store.delete(&path).await {
+            Ok(_) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(e) => Err(e.into()),
+          }
+        }).await?;
+        deleted += 1;
+      }
+      let marker = self.table_path(ns, table, &format!("_cleaned/{}", name))?;
+      self.retry.run(&format!("writing {}", marker), || async {
+        self.warehouse.store.put(&marker, PutPayload::default()).await?;
+        Ok(())
+      }).await?;
+    }
+    Ok(deleted)
+  }
+
+  /// Mark a run as finished by writing its manifest: every file it produced, including ones already
+  /// committed by an earlier attempt.
+  pub async fn finish_run(&self, ns: &str, table: &str, run_id: &str, files: &[DataFile]) -> Result<(), Error> {
+    let marker = self.table_path(ns, table, &format!("_runs/{}/_SUCCESS", run_id))?;
+    let files: Vec<CommitRecord> = files.iter()
+      .map(|f| CommitRecord { path: f.path.clone(), size: f.size, rows: f.rows, encrypted: f.encrypted })
+      .collect();
+    let body = serde_json::to_vec(&serde_json::json!({ "run_id": run_id, "files": files }))?;
+    self.retry.run(&format!("writing {}", marker), || async {
+      self.warehouse.store.put(&marker, PutPayload::from(body.clone())).await?;
+      Ok(())
+    }).await
+  }
+
+  /// Every file committed to `ns.table` and not since replaced.
+  pub async fn committed_files(&self, ns: &str, table: &str) -> Result<Vec<CommitRecord>, Error> {
+    let entries: Vec<CommitEntry> = self.entries(ns, table).await?.into_iter().map(|(_, e)| e).collect();
+    Ok(live_files(&entries))
+  }
+
+  /// Every file an entry under `_committed/` names, as a path in the warehouse, including replaced files
+  /// `cleanup` hasn't deleted yet.
+  pub async fn recorded_files(&self, ns: &str, table: &str) -> Result<HashSet<String>, Error> {
+    Ok(self.entries(ns, table).await?.into_iter()
+      .flat_map(|(_, entry)| match entry {
+        CommitEntry::File(f) => vec![f.path],
+        CommitEntry::Replace(r) => r.added.into_iter().map(|f| f.path).chain(r.removed).collect(),
+        CommitEntry::Run(r) => r.added.into_iter().map(|f| f.path).collect(),
+      })
+      .collect())
+  }
+
+  /// Every entry under `_committed/`, with its file name.
+  async fn entries(&self, ns: &str, table: &str) -> Result<Vec<(String, CommitEntry)>, Error> {
+    let prefix = self.table_path(ns, table, "_committed")?;
+    self.retry.run(&format!("listing {}", prefix), || async {
+      let mut records = self.warehouse.store.list(Some(&prefix));
+      let mut entries = Vec::new();
+      while let Some(meta) = records.next().await {
+        let location = meta?.location;
+        let bytes = self.warehouse.store.get(&location).await?.bytes().await?;
+        entries.push((location.filename().unwrap_or_default().to_string(), serde_json::from_slice(&bytes)?));
+      }
+      Ok(entries)
+    }).await
+  }
+
+  fn table_path(&self, ns: &str, table: &str, rest: &str) -> Result<Path, Error> {
+    Path::parse(format!("{}/{}", self.warehouse.table_path(ns, table), rest)).map_err(|e| Error::Data(Box::new(e)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Arc;
+  use arrow::datatypes::Schema;
+  use object_store::RetryConfig;
+  use crate::sink::encoding::Encoding;
+  use crate::sink::stats::FileStats;
+  use crate::store::GcsOptions;
+
+  fn warehouse() -> Warehouse {
+    Warehouse::open("memory://", RetryConfig::default(), &GcsOptions::default()).unwrap()
+  }
+
+  /// A file of `run_id` staged with `body`.
+  async fn staged(warehouse: &Warehouse, run_id: &str, name: &str, body: &str) -> DataFile {
+    let table = warehouse.table_path("farm", "orders");
+    let file = DataFile {
+      path: format!("{}/data/run_id={}/{}", table, run_id, name),
+      staging: format!("{}/_staging/run_id={}/{}", table, run_id, name),
+      partition: String::new(),
+      encoding: Encoding::Parquet,
+      schema: Arc::new(Schema::empty()),
+      size: body.len() as u64,
+      rows: 1,
+      stats: FileStats::default(),
+      encrypted: false,
+      keys: None,
+      superseded: Vec::new(),
+    };
+    warehouse.store.put(&Path::from(file.staging.as_str()), PutPayload::from(body.to_string())).await.unwrap();
+    file
   }
 
-  pub async fn append_parquet(&self, ns: &str, table: &str, gcs_path: &str, file_len: i64, row_count: i64) -> anyhow::Result<()> {
-    println!("Appending parquet file: {} to table: {} ({} bytes, {} rows)", gcs_path, table, file_len, row_count);
-    Ok(())
+  fn checkpoint(token: &str, watermark_ms: i64) -> Checkpoint {
+    Checkpoint { resume_token: token.to_string(), watermark_ms }
+  }
+
+  async fn exists(warehouse: &Warehouse, path: &str) -> bool {
+    warehouse.store.head(&Path::from(path)).await.is_ok()
+  }
+
+  #[tokio::test]
+  async fn commits_a_run_with_its_checkpoint() {
+    let warehouse = warehouse();
+    let commit = ParquetCommit::new(&warehouse, RetryPolicy::default()).await.unwrap();
+    let files = vec![staged(&warehouse, "r1", "part-00000.parquet", "a").await];
+    commit.commit_run("farm", "orders", "r1", None, &files, &checkpoint("01", 1000)).await.unwrap();
+
+    assert!(exists(&warehouse, &files[0].path).await);
+    assert!(!exists(&warehouse, &files[0].staging).await);
+    let live: Vec<String> = commit.committed_files("farm", "orders").await.unwrap().into_iter().map(|f| f.path).collect();
+    assert_eq!(live, vec![files[0].path.clone()]);
+    assert_eq!(commit.last_checkpoint("farm", "orders").await.unwrap(), Some(checkpoint("01", 1000)));
+  }
+
+  #[tokio::test]
+  async fn a_retried_run_replaces_the_earlier_attempt() {
+    let warehouse = warehouse();
+    let commit = ParquetCommit::new(&warehouse, RetryPolicy::default()).await.unwrap();
+    let first = vec![
+      staged(&warehouse, "r1", "part-00000.parquet", "first").await,
+      staged(&warehouse, "r1", "part-00001.parquet", "first").await,
+    ];
+    commit.commit_run("farm", "orders", "r1", Some(&checkpoint("01", 1000)), &first, &checkpoint("02", 2000)).await.unwrap();
+
+    // The retry read fewer batches, up to a later checkpoint
+    let retry = vec![staged(&warehouse, "r1", "part-00000.parquet", "retry").await];
+    commit.commit_run("farm", "orders", "r1", Some(&checkpoint("01", 1000)), &retry, &checkpoint("03", 3000)).await.unwrap();
+
+    let live: Vec<String> = commit.committed_files("farm", "orders").await.unwrap().into_iter().map(|f| f.path).collect();
+    assert_eq!(live, vec![retry[0].path.clone()]);
+    let body = warehouse.store.get(&Path::from(retry[0].path.as_str())).await.unwrap().bytes().await.unwrap();
+    assert_eq!(body.as_ref(), b"retry");
+    assert!(!exists(&warehouse, &first[1].path).await, "the earlier attempt's other file is deleted");
+    assert_eq!(commit.last_checkpoint("farm", "orders").await.unwrap(), Some(checkpoint("03", 3000)));
+  }
+
+  #[tokio::test]
+  async fn a_retry_after_a_crash_leaves_no_files_behind() {
+    let warehouse = warehouse();
+    let commit = ParquetCommit::new(&warehouse, RetryPolicy::default()).await.unwrap();
+    // The first attempt committed its run, but crashed before any table format did
+    let first = vec![
+      staged(&warehouse, "r1", "part-00000.parquet", "first").await,
+      staged(&warehouse, "r1", "part-00001.parquet", "first").await,
+    ];
+    commit.commit_run("farm", "orders", "r1", Some(&checkpoint("01", 1000)), &first, &checkpoint("02", 2000)).await.unwrap();
+    // The second staged more files, then crashed before committing them
+    for name in ["part-00000.parquet", "part-00001.parquet", "part-00002.parquet"] {
+      staged(&warehouse, "r1", name, "second").await;
+    }
+    let other = staged(&warehouse, "r2", "part-00000.parquet", "other").await;
+
+    // The third resumes from the same position, as no table format moved the checkpoint on
+    let retry = vec![staged(&warehouse, "r1", "part-00000.parquet", "retry").await];
+    commit.commit_run("farm", "orders", "r1", Some(&checkpoint("01", 1000)), &retry, &checkpoint("03", 3000)).await.unwrap();
+
+    let table = warehouse.table_path("farm", "orders");
+    let mut left: Vec<String> = warehouse.store.list(Some(&table)).map(|meta| meta.unwrap().location.to_string())
+      .filter(|path| futures::future::ready(!path.contains("/_committed/"))).collect().await;
+    left.sort();
+    assert_eq!(left, vec![other.staging.clone(), retry[0].path.clone()], "only the retry's file and other runs' staged files are left");
+    let live: Vec<String> = commit.committed_files("farm", "orders").await.unwrap().into_iter().map(|f| f.path).collect();
+    assert_eq!(live, vec![retry[0].path.clone()]);
+  }
+
+  #[tokio::test]
+  async fn runs_from_other_positions_are_kept() {
+    let warehouse = warehouse();
+    let commit = ParquetCommit::new(&warehouse, RetryPolicy::default()).await.unwrap();
+    let first = vec![staged(&warehouse, "r1", "part-00000.parquet", "a").await];
+    commit.commit_run("farm", "orders", "r1", None, &first, &checkpoint("01", 1000)).await.unwrap();
+    let second = vec![staged(&warehouse, "r2", "part-00000.parquet", "b").await];
+    commit.commit_run("farm", "orders", "r2", Some(&checkpoint("01", 1000)), &second, &checkpoint("02", 2000)).await.unwrap();
+
+    let mut live: Vec<String> = commit.committed_files("farm", "orders").await.unwrap().into_iter().map(|f| f.path).collect();
+    live.sort();
+    assert_eq!(live, vec![first[0].path.clone(), second[0].path.clone()]);
+    assert_eq!(commit.last_checkpoint("farm", "orders").await.unwrap(), Some(checkpoint("02", 2000)));
   }
 }
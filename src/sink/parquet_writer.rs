@@ -1,39 +1,400 @@
 // src/sink/parquet_writer.rs
-use object_store::{ObjectStore, path::Path};
-use object_store::gcp::GoogleCloudStorageBuilder;
-use parquet::arrow::ArrowWriter;
-use parquet::file::properties::WriterProperties;
+use object_store::path::Path;
+use parquet::arrow::{ArrowWriter, AsyncArrowWriter};
+use parquet::basic::Compression;
+use parquet::file::metadata::KeyValue;
+use parquet::file::properties::{EnabledStatistics, WriterProperties, WriterPropertiesBuilder};
+use parquet::schema::types::ColumnPath;
+use arrow::datatypes::SchemaRef;
 use arrow_array::RecordBatch;
-use uuid::Uuid;
+use bytes::Bytes;
+use parquet::arrow::async_writer::AsyncFileWriter;
+use serde::Deserialize;
+use std::collections::HashMap;
+use crate::sink::encoding::{BatchEncoder, Encoding, FileFormat};
+use crate::sink::encryption::{EncryptionOptions, Encryptor, KeyStore};
+use crate::sink::multipart::{MultipartWriter, UploadOptions};
+use crate::sink::partition::PartitionKey;
+use crate::sink::sort::RowOrder;
+use crate::sink::stats::FileStats;
+use crate::store::Warehouse;
+use crate::schema;
+use crate::error::Error;
+use crate::retry::RetryPolicy;
+
+/// Per-table Parquet writer settings. Anything left unset keeps the `parquet` crate default.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ParquetOptions {
+  /// Codec name: `uncompressed`, `snappy`, `gzip`, `lzo`, `brotli`, `lz4`, `lz4_raw` or `zstd`.
+  pub compression: Option<String>,
+  /// Required by `gzip`, `brotli` and `zstd`; rejected by the other codecs.
+  pub compression_level: Option<u32>,
+  pub max_row_group_size: Option<usize>,
+  /// Target size of a data page in bytes.
+  pub data_page_size: Option<usize>,
+  /// Dictionary encoding default for all columns.
+  pub dictionary_enabled: Option<bool>,
+  /// Per-column overrides of `dictionary_enabled`.
+  pub dictionary_columns: HashMap<String, bool>,
+  /// Statistics level: `none`, `chunk` or `page`.
+  pub statistics: Option<String>,
+  /// Columns that get a bloom filter, e.g. `id` or `variety_id`.
+  pub bloom_filter_columns: Vec<String>,
+  pub bloom_filter_fpp: Option<f64>,
+  pub bloom_filter_ndv: Option<u64>,
+  /// Columns to encrypt with Parquet modular encryption, and their keys.
+  pub encryption: Option<EncryptionOptions>,
+}
+
+impl ParquetOptions {
+  pub fn writer_properties(&self) -> anyhow::Result<WriterProperties> {
+    Ok(self.builder()?.build())
+  }
+
+  /// The settings as a builder, for per-file additions such as sorting columns.
+  pub fn builder(&self) -> anyhow::Result<WriterPropertiesBuilder> {
+    let mut builder = WriterProperties::builder();
+
+    if let Some(codec) = &self.compression {
+      let spec = match self.compression_level {
+        Some(level) => format!("{}({})", codec, level),
+        None => codec.clone(),
+      };
+      let compression: Compression = spec.parse()
+        .map_err(|e| anyhow::anyhow!("Invalid compression {}: {}", spec, e))?;
+      builder = builder.set_compression(compression);
+    } else if self.compression_level.is_some() {
+      return Err(anyhow::anyhow!("compression_level requires compression to be set"));
+    }
+    if let Some(rows) = self.max_row_group_size {
+      builder = builder.set_max_row_group_size(rows);
+    }
+    if let Some(bytes) = self.data_page_size {
+      builder = builder.set_data_page_size_limit(bytes);
+    }
+    if let Some(enabled) = self.dictionary_enabled {
+      builder = builder.set_dictionary_enabled(enabled);
+    }
+    for (column, enabled) in &self.dictionary_columns {
+      builder = builder.set_column_dictionary_enabled(ColumnPath::from(column.as_str()), *enabled);
+    }
+    if let Some(level) = &self.statistics {
+      let level: EnabledStatistics = level.parse().map_err(|e: String| anyhow::anyhow!(e))?;
+      builder = builder.set_statistics_enabled(level);
+    }
+    for column in &self.bloom_filter_columns {
+      let path = ColumnPath::from(column.as_str());
+      builder = builder.set_column_bloom_filter_enabled(path.clone(), true);
+      if let Some(fpp) = self.bloom_filter_fpp {
+        builder = builder.set_column_bloom_filter_fpp(path.clone(), fpp);
+      }
+      if let Some(ndv) = self.bloom_filter_ndv {
+        builder = builder.set_column_bloom_filter_ndv(path, ndv);
+      }
+    }
+
+    Ok(builder)
+  }
+}
+
+/// Parquet key-value metadata naming the order of the rows in a file, e.g. `variety_id ASC NULLS FIRST`
+/// or `zorder(variety_id, created_at)`.
+pub const ROW_ORDER_KEY: &str = "fire_to_ice.row_order";
+
+/// Prefix of the key-value metadata that records where a Parquet file came from.
+pub const METADATA_PREFIX: &str = "fire_to_ice.";
+
+/// Where a file's rows come from. Embedded in every Parquet file as `fire_to_ice.*` key-value metadata,
+/// together with the batches, update times, schema and row count of the file, so that a file can be traced
+/// back to the run that wrote it.
+#[derive(Debug, Clone, Default)]
+pub struct Provenance {
+  pub namespace: String,
+  pub collection: String,
+  pub run_id: String,
+  /// Source position the run started reading from; unset for files merged by `compact`.
+  pub checkpoint: Option<String>,
+  /// Column whose range is recorded as the file's source update times.
+  pub update_time_column: Option<String>,
+}
 
 pub struct ParquetSink {
-  store: Box<dyn ObjectStore>,
-  bucket: String,
-  prefix: String,
+  warehouse: Warehouse,
+  upload: UploadOptions,
+  retry: RetryPolicy,
+  keys: Option<KeyStore>,
 }
 
-impl ParquetSink {
-  pub async fn new(bucket: &str, prefix: &str) -> anyhow::Result<Self> {
-    let store = GoogleCloudStorageBuilder::new().with_bucket_name(bucket).build()?;
-    Ok(Self { store: Box::new(store), bucket: bucket.into(), prefix: prefix.into() })
+/// A finished file in the staging area, ready to be committed.
+#[derive(Debug, Clone)]
+pub struct DataFile {
+  /// Where the file is published on commit, under the table's `data/` directory.
+  pub path: String,
+  /// Where the file was written, under the table's `_staging/` directory.
+  pub staging: String,
+  /// Partition directory, e.g. `variety_id=v1/created_at_day=2024-01-15`.
+  pub partition: String,
+  pub encoding: Encoding,
+  pub schema: SchemaRef,
+  pub size: u64,
+  pub rows: u64,
+  pub stats: FileStats,
+  /// Parquet with encrypted columns, which only readers with the keys can read.
+  pub encrypted: bool,
+  /// Values of the key column in the file, when the writer records them for upserts.
+  pub keys: Option<Vec<String>>,
+  /// Positions of the rows that a later row of the same run and key replaces, for upserts.
+  pub superseded: Vec<u64>,
+}
+
+enum FileWriter {
+  /// Flushed after every batch when rows are sorted, so that each row group is sorted too.
+  Parquet { writer: AsyncArrowWriter<MultipartWriter>, flush_each_batch: bool },
+  Encoded { upload: MultipartWriter, encoder: BatchEncoder, bytes_written: usize },
+  /// Parquet with encrypted columns, whose row groups are encrypted and uploaded as they are flushed.
+  Encrypted {
+    writer: ArrowWriter<Vec<u8>>,
+    upload: MultipartWriter,
+    flush_each_batch: bool,
+    encryption: EncryptionOptions,
+    encryptor: Box<Encryptor>,
+    bytes_written: usize,
+  },
+}
+
+/// A file being streamed to the object store: Parquet one row group at a time, other encodings batch by batch.
+pub struct ParquetFile {
+  writer: FileWriter,
+  encoding: Encoding,
+  path: Path,
+  staging: Path,
+  partition: String,
+  schema: SchemaRef,
+  stats: FileStats,
+  provenance: Provenance,
+  /// Sequence numbers of the first and last batch written.
+  batches: Option<(u64, u64)>,
+}
+
+impl ParquetFile {
+  /// Append `batch`, the `seq`th batch of the run if it comes straight from the source.
+  pub async fn write(&mut self, batch: &RecordBatch, seq: Option<u64>) -> Result<(), Error> {
+    match &mut self.writer {
+      FileWriter::Parquet { writer, flush_each_batch } => {
+        writer.write(batch).await?;
+        if *flush_each_batch {
+          writer.flush().await?;
+        }
+      }
+      FileWriter::Encoded { upload, encoder, bytes_written } => {
+        let bytes = encoder.encode(batch)?;
+        *bytes_written += bytes.len();
+        upload.write(Bytes::from(bytes)).await?;
+      }
+      FileWriter::Encrypted { writer, upload, flush_each_batch, encryptor, bytes_written, .. } => {
+        writer.write(batch)?;
+        if *flush_each_batch {
+          writer.flush()?;
+        }
+        // The writer flushes full row groups on its own too
+        let plain = std::mem::take(writer.inner_mut());
+        let bytes = encryptor.write(&plain, writer.flushed_row_groups())?;
+        if !bytes.is_empty() {
+          *bytes_written += bytes.len();
+          upload.write(Bytes::from(bytes)).await?;
+        }
+      }
+    }
+    self.stats.update(batch);
+    if let Some(seq) = seq {
+      self.batches = Some(self.batches.map_or((seq, seq), |(first, last)| (first.min(seq), last.max(seq))));
+    }
+    Ok(())
+  }
+
+  /// The file's provenance as Parquet key-value metadata.
+  fn metadata(&self) -> Vec<KeyValue> {
+    let p = &self.provenance;
+    let mut entries = vec![
+      ("namespace", p.namespace.clone()),
+      ("collection", p.collection.clone()),
+      ("run_id", p.run_id.clone()),
+      ("schema_version", schema::SCHEMA_VERSION.to_string()),
+      ("schema_fingerprint", schema::fingerprint(&self.schema)),
+      ("row_count", self.stats.rows.to_string()),
+      ("crate_version", env!("CARGO_PKG_VERSION").to_string()),
+    ];
+    if let Some(checkpoint) = &p.checkpoint {
+      entries.push(("checkpoint", checkpoint.clone()));
+    }
+    if let Some((first, last)) = self.batches {
+      entries.push(("first_batch_seq", first.to_string()));
+      entries.push(("last_batch_seq", last.to_string()));
+    }
+    if let Some(column) = &p.update_time_column
+      && let Some(stats) = self.stats.columns.get(column) {
+      entries.push(("update_time_column", column.clone()));
+      if let Some(min) = &stats.min {
+        entries.push(("min_update_time", min.to_string()));
+      }
+      if let Some(max) = &stats.max {
+        entries.push(("max_update_time", max.to_string()));
+      }
+    }
+    entries.into_iter().map(|(key, value)| KeyValue::new(format!("{}{}", METADATA_PREFIX, key), value)).collect()
+  }
+
+  /// Where the file is published on commit.
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// Bytes written so far plus, for Parquet, the estimated size of the row group still in memory.
+  pub fn size(&self) -> usize {
+    match &self.writer {
+      FileWriter::Parquet { writer, .. } => writer.bytes_written() + writer.in_progress_size(),
+      FileWriter::Encoded { bytes_written, .. } => *bytes_written,
+      FileWriter::Encrypted { writer, bytes_written, .. } => *bytes_written + writer.in_progress_size(),
+    }
   }
 
-  pub async fn write(&self, ns: &str, table: &str, batch: &RecordBatch) -> anyhow::Result<String> {
-    let mut buf = Vec::new();
-    {
-      let props = WriterProperties::builder().build();
-      let mut writer = ArrowWriter::try_new(&mut buf, batch.schema(), Some(props))?;
-      writer.write(batch)?;
-      writer.close()?;
+  /// Flush the last row group, write the footer and complete the upload.
+  /// Parts are retried by the store client, but a failed upload can't be resumed: the file is aborted.
+  pub async fn close(mut self) -> Result<DataFile, Error> {
+    let encrypted = matches!(self.writer, FileWriter::Encrypted { .. });
+    if let FileWriter::Encrypted { encryption, .. } = &self.writer {
+      // Table formats and the file's own metadata record bounds in plaintext, so leave out encrypted columns
+      for (column, stats) in self.stats.columns.iter_mut() {
+        if encryption.encrypts(column) {
+          stats.min = None;
+          stats.max = None;
+        }
+      }
     }
+    let metadata = self.metadata();
+    let size = match self.writer {
+      FileWriter::Parquet { mut writer, .. } => {
+        for kv in metadata {
+          writer.append_key_value_metadata(kv);
+        }
+        writer.finish().await?;
+        writer.bytes_written()
+      }
+      FileWriter::Encoded { mut upload, mut encoder, mut bytes_written } => {
+        let tail = encoder.finish()?;
+        bytes_written += tail.len();
+        upload.write(Bytes::from(tail)).await?;
+        upload.complete().await?;
+        bytes_written
+      }
+      FileWriter::Encrypted { mut writer, mut upload, encryptor, bytes_written, .. } => {
+        for kv in metadata {
+          writer.append_key_value_metadata(kv);
+        }
+        let meta = writer.finish()?;
+        let bytes = encryptor.finish(&std::mem::take(writer.inner_mut()), meta)?;
+        let size = bytes_written + bytes.len();
+        upload.write(Bytes::from(bytes)).await?;
+        upload.complete().await?;
+        size
+      }
+    };
+    Ok(DataFile {
+      path: self.path.to_string(),
+      staging: self.staging.to_string(),
+      partition: self.partition,
+      encoding: self.encoding,
+      schema: self.schema,
+      size: size as u64,
+      rows: self.stats.rows,
+      stats: self.stats,
+      encrypted,
+      keys: None,
+      superseded: Vec::new(),
+    })
+  }
+}
+
+impl ParquetSink {
+  pub async fn new(warehouse: &Warehouse, upload: UploadOptions, retry: RetryPolicy) -> anyhow::Result<Self> {
+    Ok(Self { warehouse: warehouse.clone(), upload, retry, keys: None })
+  }
+
+  /// Master keys for tables that encrypt columns.
+  pub fn with_keys(mut self, keys: Option<KeyStore>) -> Self {
+    self.keys = keys;
+    self
+  }
 
-    let run_id = Uuid::new_v4().to_string();
-    let date = chrono::Utc::now().date_naive();
-    let path = format!("{}/{}/{}/data/ingest_date={}/run_id={}/part-{}.parquet",
-                       self.prefix, ns, table, date, run_id, "000");
-    let path = Path::from(path);
+  pub fn keys(&self) -> Option<&KeyStore> {
+    self.keys.as_ref()
+  }
+
+  /// Start a new file at `file`, relative to the `data/` directory of the table `provenance` names, encoded as `format`.
+  /// Batches are uploaded as row groups fill up. Until it is committed, the file lives under `_staging/`,
+  /// where readers don't look. Parquet files record `order`, the order the caller writes rows in.
+  pub async fn create(&self, provenance: &Provenance, partition: &PartitionKey, file: &str, schema: SchemaRef, format: &FileFormat, order: &RowOrder) -> anyhow::Result<ParquetFile> {
+    let (ns, table) = (&provenance.namespace, &provenance.collection);
+    // Partition values are already percent-encoded, so parse rather than encode the path again
+    let table_path = self.warehouse.table_path(ns, table);
+    let path = Path::parse(format!("{}/data/{}/{}", table_path, partition.path, file))?;
+    let staging = Path::parse(format!("{}/_staging/{}/{}", table_path, partition.path, file))?;
+    if let FileFormat::Parquet(ParquetOptions { encryption: Some(_), .. }) = format && self.keys.is_none() {
+      return Err(Error::config(format!("{}.{} encrypts columns, but no key file is set: set ENCRYPTION_KEYS_PATH", ns, table)).into());
+    }
 
-    self.store.put(&path, buf.into()).await?;
-    Ok(path.to_string())
+    let upload = self.retry.run(&format!("starting upload of {}", staging), || {
+      MultipartWriter::new(self.warehouse.store.as_ref(), &staging, &self.upload)
+    }).await?;
+    let writer = match format {
+      FileFormat::Parquet(opts) => {
+        let mut builder = opts.builder()?.set_sorting_columns(order.sorting_columns(&schema));
+        if opts.encryption.is_some() && opts.statistics.is_none() {
+          // Page statistics only go into page indexes, which encrypted files don't have
+          builder = builder.set_statistics_enabled(EnabledStatistics::Chunk);
+        }
+        let props = builder.build();
+        let row_order = (*order != RowOrder::Unsorted).then(|| KeyValue::new(ROW_ORDER_KEY.to_string(), order.to_string()));
+        match &opts.encryption {
+          None => {
+            let mut writer = AsyncArrowWriter::try_new(upload, schema.clone(), Some(props)).map_err(Error::from)?;
+            if let Some(kv) = row_order {
+              writer.append_key_value_metadata(kv);
+            }
+            FileWriter::Parquet { writer, flush_each_batch: order.is_sorted() }
+          }
+          Some(encryption) => {
+            let mut writer = ArrowWriter::try_new(Vec::new(), schema.clone(), Some(props)).map_err(Error::from)?;
+            if let Some(kv) = row_order {
+              writer.append_key_value_metadata(kv);
+            }
+            let encryptor = Box::new(Encryptor::new(encryption, self.keys.as_ref().expect("checked above"))?);
+            FileWriter::Encrypted {
+              writer,
+              upload,
+              flush_each_batch: order.is_sorted(),
+              encryption: encryption.clone(),
+              encryptor,
+              bytes_written: 0,
+            }
+          }
+        }
+      }
+      FileFormat::Ndjson => FileWriter::Encoded { upload, encoder: BatchEncoder::Ndjson, bytes_written: 0 },
+      FileFormat::Csv(opts) => FileWriter::Encoded { upload, encoder: BatchEncoder::csv(opts)?, bytes_written: 0 },
+      FileFormat::Ipc => FileWriter::Encoded { upload, encoder: BatchEncoder::ipc(&schema)?, bytes_written: 0 },
+    };
+    Ok(ParquetFile {
+      writer,
+      encoding: format.encoding(),
+      path,
+      staging,
+      partition: partition.path.clone(),
+      schema,
+      stats: FileStats::default(),
+      provenance: provenance.clone(),
+      batches: None,
+    })
   }
 }
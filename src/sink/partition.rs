@@ -0,0 +1,461 @@
+// src/sink/partition.rs
+// Hive-style partitioning by data columns. Transforms follow the Iceberg spec
+// (identity, year, month, day, hour, bucket, truncate) so the values can be reused as Iceberg partitions.
+
+use std::collections::HashMap;
+use arrow::compute::take_record_batch;
+use arrow::datatypes::{DataType, TimeUnit};
+use arrow_array::{
+  Array, ArrayRef, BooleanArray, Date32Array, Int32Array, Int64Array, RecordBatch, StringArray,
+  TimestampMillisecondArray, UInt32Array,
+};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transform {
+  Identity,
+  Year,
+  Month,
+  Day,
+  Hour,
+  Bucket(u32),
+  Truncate(u32),
+}
+
+#[derive(Debug, Clone)]
+pub struct PartitionField {
+  pub source: String,
+  pub transform: Transform,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PartitionValue {
+  Int(i32),
+  Long(i64),
+  String(String),
+  Boolean(bool),
+}
+
+/// The partition a file belongs to: its directory relative to `data/` and the transformed values.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PartitionKey {
+  pub path: String,
+  pub values: Vec<Option<PartitionValue>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PartitionSpec {
+  pub fields: Vec<PartitionField>,
+}
+
+impl PartitionField {
+  /// Parse `variety_id`, `day(created_at)`, `month(delivery_date)`, `bucket(16, id)` or `truncate(4, name)`.
+  pub fn parse(spec: &str) -> anyhow::Result<Self> {
+    let spec = spec.trim();
+    let Some((func, rest)) = spec.split_once('(') else {
+      return Ok(Self { source: spec.to_string(), transform: Transform::Identity });
+    };
+    let args: Vec<&str> = rest.strip_suffix(')')
+      .ok_or_else(|| anyhow::anyhow!("Invalid partition field: {}", spec))?
+      .split(',').map(str::trim).collect();
+
+    let width = |arg: &str| -> anyhow::Result<u32> {
+      arg.parse().ok().filter(|n| *n > 0)
+        .ok_or_else(|| anyhow::anyhow!("Invalid width {} in partition field: {}", arg, spec))
+    };
+    let (transform, source) = match (func.trim(), args.as_slice()) {
+      ("identity", [col]) => (Transform::Identity, col),
+      ("year", [col]) => (Transform::Year, col),
+      ("month", [col]) => (Transform::Month, col),
+      ("day", [col]) => (Transform::Day, col),
+      ("hour", [col]) => (Transform::Hour, col),
+      ("bucket", [n, col]) => (Transform::Bucket(width(n)?), col),
+      ("truncate", [n, col]) => (Transform::Truncate(width(n)?), col),
+      _ => return Err(anyhow::anyhow!("Unsupported partition field: {}", spec)),
+    };
+    Ok(Self { source: source.to_string(), transform })
+  }
+
+  /// Directory name for this field, e.g. `created_at_day` or `variety_id`.
+  pub fn name(&self) -> String {
+    match self.transform {
+      Transform::Identity => self.source.clone(),
+      Transform::Year => format!("{}_year", self.source),
+      Transform::Month => format!("{}_month", self.source),
+      Transform::Day => format!("{}_day", self.source),
+      Transform::Hour => format!("{}_hour", self.source),
+      Transform::Bucket(_) => format!("{}_bucket", self.source),
+      Transform::Truncate(_) => format!("{}_trunc", self.source),
+    }
+  }
+
+  fn display(&self, value: &Option<PartitionValue>) -> String {
+    let Some(value) = value else { return "null".into() };
+    match (&self.transform, value) {
+      (Transform::Year, PartitionValue::Int(y)) => format!("{}", 1970 + y),
+      (Transform::Month, PartitionValue::Int(m)) => format!("{:04}-{:02}", 1970 + m.div_euclid(12), m.rem_euclid(12) + 1),
+      (Transform::Day, PartitionValue::Int(d)) => epoch_date(*d as i64).to_string(),
+      (Transform::Hour, PartitionValue::Int(h)) => {
+        let ts = DateTime::from_timestamp(*h as i64 * 3600, 0).unwrap_or_default();
+        ts.format("%Y-%m-%d-%H").to_string()
+      }
+      (_, PartitionValue::Int(v)) => v.to_string(),
+      (_, PartitionValue::Long(v)) => v.to_string(),
+      (_, PartitionValue::Boolean(v)) => v.to_string(),
+      (_, PartitionValue::String(v)) => escape(v),
+    }
+  }
+
+  fn values(&self, col: &ArrayRef) -> anyhow::Result<Vec<Option<PartitionValue>>> {
+    let unsupported = || anyhow::anyhow!(
+      "Partition transform {:?} is not supported for column {} of type {}", self.transform, self.source, col.data_type());
+
+    match &self.transform {
+      Transform::Identity => match col.data_type() {
+        DataType::Utf8 => Ok(strings(col).map(|v| v.map(|s| PartitionValue::String(s.to_string()))).collect()),
+        DataType::Boolean => Ok(col.as_any().downcast_ref::<BooleanArray>().unwrap()
+          .iter().map(|v| v.map(PartitionValue::Boolean)).collect()),
+        DataType::Date32 => Ok(col.as_any().downcast_ref::<Date32Array>().unwrap()
+          .iter().map(|v| v.map(PartitionValue::Int)).collect()),
+        _ => Ok(longs(col).ok_or_else(unsupported)?.into_iter().map(|v| v.map(PartitionValue::Long)).collect()),
+      },
+      Transform::Year | Transform::Month | Transform::Day | Transform::Hour => {
+        let times = timestamps(col).ok_or_else(unsupported)?;
+        Ok(times.into_iter().map(|t| t.map(|t| PartitionValue::Int(match self.transform {
+          Transform::Year => t.year() - 1970,
+          Transform::Month => (t.year() - 1970) * 12 + t.month0() as i32,
+          Transform::Day => t.and_utc().timestamp().div_euclid(86_400) as i32,
+          _ => t.and_utc().timestamp().div_euclid(3_600) as i32,
+        }))).collect())
+      }
+      Transform::Bucket(n) => {
+        // Iceberg hashes strings as UTF-8 and every integer, date and timestamp (in micros) as an 8-byte long
+        let hashes: Vec<Option<u32>> = match col.data_type() {
+          DataType::Utf8 => strings(col).map(|v| v.map(|s| murmur3_32(s.as_bytes()))).collect(),
+          DataType::Date32 => col.as_any().downcast_ref::<Date32Array>().unwrap()
+            .iter().map(|v| v.map(|d| murmur3_32(&(d as i64).to_le_bytes()))).collect(),
+          DataType::Timestamp(TimeUnit::Millisecond, _) => col.as_any().downcast_ref::<TimestampMillisecondArray>().unwrap()
+            .iter().map(|v| v.map(|ms| murmur3_32(&(ms * 1000).to_le_bytes()))).collect(),
+          _ => longs(col).ok_or_else(unsupported)?.into_iter().map(|v| v.map(|l| murmur3_32(&l.to_le_bytes()))).collect(),
+        };
+        Ok(hashes.into_iter()
+          .map(|h| h.map(|h| PartitionValue::Int(((h & i32::MAX as u32) % n) as i32)))
+          .collect())
+      }
+      Transform::Truncate(w) => match col.data_type() {
+        DataType::Utf8 => Ok(strings(col)
+          .map(|v| v.map(|s| PartitionValue::String(s.chars().take(*w as usize).collect())))
+          .collect()),
+        _ => {
+          let w = *w as i64;
+          Ok(longs(col).ok_or_else(unsupported)?.into_iter()
+            .map(|v| v.map(|v| PartitionValue::Long(v - v.rem_euclid(w))))
+            .collect())
+        }
+      },
+    }
+  }
+}
+
+impl PartitionSpec {
+  pub fn parse(fields: &[String]) -> anyhow::Result<Self> {
+    Ok(Self { fields: fields.iter().map(|f| PartitionField::parse(f)).collect::<anyhow::Result<_>>()? })
+  }
+
+  /// Split `batch` into one batch per partition. Without partition fields, everything goes
+  /// into the processing-time partition `ingest_date=<today>`.
+  pub fn split(&self, batch: &RecordBatch) -> anyhow::Result<Vec<(PartitionKey, RecordBatch)>> {
+    if self.fields.is_empty() {
+      let path = format!("ingest_date={}", chrono::Utc::now().date_naive());
+      return Ok(vec![(PartitionKey { path, values: Vec::new() }, batch.clone())]);
+    }
+
+    let mut columns = Vec::with_capacity(self.fields.len());
+    for field in &self.fields {
+      let col = batch.column_by_name(&field.source)
+        .ok_or_else(|| anyhow::anyhow!("Partition source column {} not found", field.source))?;
+      columns.push(field.values(col)?);
+    }
+
+    let mut order: Vec<PartitionKey> = Vec::new();
+    let mut rows: HashMap<PartitionKey, Vec<u32>> = HashMap::new();
+    for row in 0..batch.num_rows() {
+      let values: Vec<Option<PartitionValue>> = columns.iter().map(|c| c[row].clone()).collect();
+      let path = self.fields.iter().zip(&values)
+        .map(|(f, v)| format!("{}={}", f.name(), f.display(v)))
+        .collect::<Vec<_>>()
+        .join("/");
+      let key = PartitionKey { path, values };
+      rows.entry(key.clone()).or_insert_with(|| { order.push(key); Vec::new() }).push(row as u32);
+    }
+
+    order.into_iter()
+      .map(|key| {
+        let indices = UInt32Array::from(rows.remove(&key).unwrap_or_default());
+        Ok((key, take_record_batch(batch, &indices)?))
+      })
+      .collect()
+  }
+}
+
+fn strings(col: &ArrayRef) -> impl Iterator<Item = Option<&str>> {
+  col.as_any().downcast_ref::<StringArray>().expect("Utf8 column").iter()
+}
+
+fn longs(col: &ArrayRef) -> Option<Vec<Option<i64>>> {
+  match col.data_type() {
+    DataType::Int32 => Some(col.as_any().downcast_ref::<Int32Array>()?.iter().map(|v| v.map(i64::from)).collect()),
+    DataType::Int64 => Some(col.as_any().downcast_ref::<Int64Array>()?.iter().collect()),
+    DataType::UInt32 => Some(col.as_any().downcast_ref::<UInt32Array>()?.iter().map(|v| v.map(i64::from)).collect()),
+    DataType::Timestamp(TimeUnit::Millisecond, _) => Some(col.as_any().downcast_ref::<TimestampMillisecondArray>()?.iter().collect()),
+    _ => None,
+  }
+}
+
+/// Timestamps from timestamp or date columns, or from ISO date/datetime strings such as `delivery_date`.
+fn timestamps(col: &ArrayRef) -> Option<Vec<Option<NaiveDateTime>>> {
+  match col.data_type() {
+    DataType::Timestamp(TimeUnit::Millisecond, _) => Some(col.as_any().downcast_ref::<TimestampMillisecondArray>()?
+      .iter().map(|v| v.and_then(DateTime::from_timestamp_millis).map(|t| t.naive_utc())).collect()),
+    DataType::Date32 => Some(col.as_any().downcast_ref::<Date32Array>()?
+      .iter().map(|v| v.map(|d| epoch_date(d as i64).and_hms_opt(0, 0, 0).unwrap_or_default())).collect()),
+    DataType::Utf8 => Some(strings(col).map(|v| v.and_then(parse_time)).collect()),
+    _ => None,
+  }
+}
+
+fn parse_time(s: &str) -> Option<NaiveDateTime> {
+  NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f").ok()
+    .or_else(|| DateTime::parse_from_rfc3339(s).ok().map(|t| t.naive_utc()))
+    .or_else(|| NaiveDate::parse_from_str(s, "%Y-%m-%d").ok().and_then(|d| d.and_hms_opt(0, 0, 0)))
+}
+
+fn epoch_date(days: i64) -> NaiveDate {
+  NaiveDate::default() + chrono::Duration::days(days)
+}
+
+/// Percent-encode everything but `[A-Za-z0-9._-]`, so values can't break a `key=value` path segment.
+/// Paths built from these segments must go through `Path::parse`, which doesn't encode them again.
+fn escape(value: &str) -> String {
+  value.chars()
+    .map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c.to_string() } else {
+      let mut buf = [0u8; 4];
+      c.encode_utf8(&mut buf).bytes().map(|b| format!("%{:02X}", b)).collect()
+    })
+    .collect()
+}
+
+/// The partition directory of a file at `<table>/data/<partition>/run_id=<id>/<file>`.
+pub fn partition_of(path: &str) -> Option<&str> {
+  let (_, rest) = path.split_once("/data/")?;
+  let mut parts = rest.rsplitn(3, '/');
+  match (parts.next(), parts.next(), parts.next()) {
+    (Some(_), Some(_), partition) => Some(partition.unwrap_or_default()),
+    _ => None,
+  }
+}
+
+/// Names and decoded values of a partition directory such as `variety_id=v1/created_at_day=2024-01-15`.
+pub fn partition_values(partition: &str) -> Vec<(String, Option<String>)> {
+  partition.split('/')
+    .filter_map(|segment| segment.split_once('='))
+    .map(|(name, value)| (name.to_string(), (value != "null").then(|| unescape(value))))
+    .collect()
+}
+
+/// Undo the percent-encoding of partition values.
+fn unescape(value: &str) -> String {
+  let bytes = value.as_bytes();
+  let mut out = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok()).and_then(|h| u8::from_str_radix(h, 16).ok());
+    match (bytes[i], hex) {
+      (b'%', Some(b)) => {
+        out.push(b);
+        i += 3;
+      }
+      (b, _) => {
+        out.push(b);
+        i += 1;
+      }
+    }
+  }
+  String::from_utf8_lossy(&out).into_owned()
+}
+
+/// 32-bit MurmurHash3 (x86 variant, seed 0) as used by Iceberg's bucket transform.
+pub fn murmur3_32(data: &[u8]) -> u32 {
+  const C1: u32 = 0xcc9e_2d51;
+  const C2: u32 = 0x1b87_3593;
+  let mut h: u32 = 0;
+
+  let chunks = data.chunks_exact(4);
+  let tail = chunks.remainder();
+  for chunk in chunks {
+    let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    h ^= k;
+    h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+  }
+  if !tail.is_empty() {
+    let mut k: u32 = 0;
+    for (i, b) in tail.iter().enumerate() {
+      k |= (*b as u32) << (8 * i);
+    }
+    h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+  }
+
+  h ^= data.len() as u32;
+  h ^= h >> 16;
+  h = h.wrapping_mul(0x85eb_ca6b);
+  h ^= h >> 13;
+  h = h.wrapping_mul(0xc2b2_ae35);
+  h ^= h >> 16;
+  h
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Arc;
+  use arrow::datatypes::{Field, Schema};
+
+  fn column(array: impl Array + 'static) -> ArrayRef {
+    Arc::new(array)
+  }
+
+  fn values(spec: &str, col: ArrayRef) -> Vec<Option<PartitionValue>> {
+    PartitionField::parse(spec).unwrap().values(&col).unwrap()
+  }
+
+  fn millis(date: &str) -> i64 {
+    parse_time(date).unwrap().and_utc().timestamp_millis()
+  }
+
+  fn days(date: &str) -> i32 {
+    (NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap() - NaiveDate::default()).num_days() as i32
+  }
+
+  /// The bucket Iceberg puts a value with murmur3 hash `hash` in.
+  fn bucket(hash: i32, n: u32) -> Option<PartitionValue> {
+    Some(PartitionValue::Int(((hash as u32 & i32::MAX as u32) % n) as i32))
+  }
+
+  #[test]
+  fn murmur3_matches_the_iceberg_spec() {
+    // Appendix B of the Iceberg table spec
+    assert_eq!(murmur3_32(&34i64.to_le_bytes()) as i32, 2017239379);
+    assert_eq!(murmur3_32(&(days("2017-11-16") as i64).to_le_bytes()) as i32, -653330422);
+    assert_eq!(murmur3_32(&(millis("2017-11-16 22:31:08") * 1000).to_le_bytes()) as i32, -2047944441);
+    assert_eq!(murmur3_32(b"iceberg") as i32, 1210000089);
+    assert_eq!(murmur3_32(&[0, 1, 2, 3]) as i32, -188683207);
+  }
+
+  #[test]
+  fn buckets_hash_values_as_iceberg_does() {
+    // Ints hash as longs, dates as days and timestamps as microseconds
+    assert_eq!(values("bucket(16, n)", column(Int32Array::from(vec![Some(34), None]))), vec![bucket(2017239379, 16), None]);
+    assert_eq!(values("bucket(16, n)", column(Int64Array::from(vec![34]))), vec![bucket(2017239379, 16)]);
+    assert_eq!(values("bucket(7, d)", column(Date32Array::from(vec![days("2017-11-16")]))), vec![bucket(-653330422, 7)]);
+    assert_eq!(
+      values("bucket(7, t)", column(TimestampMillisecondArray::from(vec![millis("2017-11-16 22:31:08")]))),
+      vec![bucket(-2047944441, 7)]);
+    assert_eq!(values("bucket(100, s)", column(StringArray::from(vec!["iceberg"]))), vec![bucket(1210000089, 100)]);
+  }
+
+  #[test]
+  fn time_transforms_count_from_the_epoch() {
+    let times = column(TimestampMillisecondArray::from(vec![
+      Some(millis("2024-01-15 10:30:00")),
+      Some(millis("1969-12-31 23:59:59")),
+      None,
+    ]));
+    let ints = |values: &[i32]| values.iter().map(|v| Some(PartitionValue::Int(*v))).chain([None]).collect::<Vec<_>>();
+    assert_eq!(values("year(t)", times.clone()), ints(&[54, -1]));
+    assert_eq!(values("month(t)", times.clone()), ints(&[54 * 12, -1]));
+    assert_eq!(values("day(t)", times.clone()), ints(&[days("2024-01-15"), -1]));
+    assert_eq!(values("hour(t)", times), ints(&[days("2024-01-15") * 24 + 10, -1]));
+
+    // Dates and ISO strings are transformed too
+    let dates = column(Date32Array::from(vec![Some(days("2024-02-29")), None]));
+    assert_eq!(values("month(d)", dates.clone()), ints(&[54 * 12 + 1]));
+    assert_eq!(values("day(d)", dates), ints(&[days("2024-02-29")]));
+    let strings = column(StringArray::from(vec![Some("2024-02-29"), Some("2024-02-29T23:00:00+02:00"), None]));
+    assert_eq!(values("day(s)", strings), ints(&[days("2024-02-29"), days("2024-02-29")]));
+
+    let field = PartitionField::parse("hour(t)").unwrap();
+    assert!(field.values(&column(Int32Array::from(vec![1]))).is_err());
+  }
+
+  #[test]
+  fn truncate_keeps_prefixes_and_rounds_down() {
+    let strings = column(StringArray::from(vec![Some("iceberg"), Some("été"), Some("ic"), None]));
+    let prefixes = ["ice", "été", "ic"].map(|s| Some(PartitionValue::String(s.into())));
+    assert_eq!(values("truncate(3, s)", strings), [prefixes.to_vec(), vec![None]].concat());
+    let longs = column(Int64Array::from(vec![15, 10, 0, -1, -10]));
+    let expected = [10, 10, 0, -10, -10].map(|v| Some(PartitionValue::Long(v)));
+    assert_eq!(values("truncate(10, n)", longs), expected);
+  }
+
+  #[test]
+  fn parses_partition_fields() {
+    let field = PartitionField::parse(" bucket(16, id) ").unwrap();
+    assert_eq!((field.source.as_str(), &field.transform, field.name()), ("id", &Transform::Bucket(16), "id_bucket".to_string()));
+    let field = PartitionField::parse("variety_id").unwrap();
+    assert_eq!((&field.transform, field.name()), (&Transform::Identity, "variety_id".to_string()));
+    for invalid in ["bucket(0, id)", "truncate(x, name)", "day(created_at", "week(created_at)", "bucket(id)"] {
+      assert!(PartitionField::parse(invalid).is_err(), "{} parsed", invalid);
+    }
+  }
+
+  #[test]
+  fn splits_batches_into_escaped_partition_directories() {
+    let schema = Schema::new(vec![
+      Field::new("variety", DataType::Utf8, true),
+      Field::new("created_at", DataType::Timestamp(TimeUnit::Millisecond, None), true),
+    ]);
+    let t = millis("2024-01-15 10:30:00");
+    let batch = RecordBatch::try_new(Arc::new(schema), vec![
+      column(StringArray::from(vec![Some("a/b c"), None, Some("a/b c"), Some("été=1%")])),
+      column(TimestampMillisecondArray::from(vec![Some(t), Some(t), Some(t), None])),
+    ]).unwrap();
+    let spec = PartitionSpec::parse(&["variety".into(), "hour(created_at)".into()]).unwrap();
+    let split = spec.split(&batch).unwrap();
+    let paths: Vec<(&str, usize)> = split.iter().map(|(key, batch)| (key.path.as_str(), batch.num_rows())).collect();
+    assert_eq!(paths, vec![
+      ("variety=a%2Fb%20c/created_at_hour=2024-01-15-10", 2),
+      ("variety=null/created_at_hour=2024-01-15-10", 1),
+      ("variety=%C3%A9t%C3%A9%3D1%25/created_at_hour=null", 1),
+    ]);
+
+    // Reading the directories back gives the values
+    assert_eq!(partition_values(paths[0].0), vec![
+      ("variety".to_string(), Some("a/b c".to_string())),
+      ("created_at_hour".to_string(), Some("2024-01-15-10".to_string())),
+    ]);
+    assert_eq!(partition_values(paths[2].0), vec![
+      ("variety".to_string(), Some("été=1%".to_string())),
+      ("created_at_hour".to_string(), None),
+    ]);
+  }
+
+  #[test]
+  fn escaping_round_trips() {
+    for value in ["plain-value_1.0", "a/b", "100%", "x=y z", "ünïcødé 🍇", "%zz", ""] {
+      assert_eq!(unescape(&escape(value)), value);
+      assert!(escape(value).chars().all(|c| c.is_ascii_alphanumeric() || "-_.%".contains(c)));
+    }
+    // Stray percent signs are kept
+    assert_eq!(unescape("100%"), "100%");
+    assert_eq!(unescape("%4"), "%4");
+  }
+
+  #[test]
+  fn finds_the_partition_of_data_files() {
+    assert_eq!(partition_of("gs://bucket/farm/orders/data/variety=v1/day=2024-01-15/run_id=r1/part-0.parquet"), Some("variety=v1/day=2024-01-15"));
+    assert_eq!(partition_of("farm/orders/data/run_id=r1/part-0.parquet"), Some(""));
+    assert_eq!(partition_of("farm/orders/data/part-0.parquet"), None);
+    assert_eq!(partition_of("farm/orders/metadata/snap-1.avro"), None);
+  }
+}
@@ -0,0 +1,240 @@
+// src/sink/rolling.rs
+// Keeps one Parquet file open per partition and appends each batch to it as new row groups.
+// A file is closed once it reaches the target size or age, and handed back to the caller to commit.
+// File paths depend only on the table, the source position the run started from and the batch sequence,
+// so a run tried again from the same position writes the same paths instead of new ones.
+// For upserts, each Parquet file records its keys, and the writer notes the rows that a later row of
+// the run with the same key replaces: the commit deletes them, so only a document's last change stays.
+
+use std::collections::HashMap;
+use std::time::Duration;
+use arrow::compute::cast;
+use arrow::datatypes::DataType;
+use arrow_array::RecordBatch;
+use arrow_array::cast::AsArray;
+use tokio::time::Instant;
+use sha2::{Digest, Sha256};
+use crate::sink::encoding::{Encoding, FileFormat};
+use crate::sink::parquet_writer::{DataFile, ParquetFile, ParquetSink, Provenance};
+use crate::sink::partition::PartitionSpec;
+use crate::sink::sort::RowOrder;
+
+pub struct RollingOptions {
+  /// Close a file once its encoded size reaches this many bytes.
+  pub target_file_size: usize,
+  /// Close a file once it has been open this long, even if it is small.
+  pub max_file_age: Duration,
+}
+
+struct OpenFile {
+  file: ParquetFile,
+  opened: Instant,
+  /// Values of the key column written so far, for Parquet files of tables written as upserts.
+  keys: Option<Vec<String>>,
+  rows: u64,
+}
+
+impl OpenFile {
+  async fn close(self) -> anyhow::Result<DataFile> {
+    let mut file = self.file.close().await?;
+    file.keys = self.keys;
+    Ok(file)
+  }
+}
+
+pub struct RollingWriter<'a> {
+  sink: &'a ParquetSink,
+  provenance: Provenance,
+  formats: Vec<FileFormat>,
+  spec: PartitionSpec,
+  opts: RollingOptions,
+  order: RowOrder,
+  /// Column whose values each Parquet file records, for upserts.
+  key_column: Option<String>,
+  /// Open files by partition directory and encoding.
+  open: HashMap<(String, Encoding), OpenFile>,
+  /// The file and position of the last row written with each key, for upserts.
+  latest: HashMap<String, (String, u64)>,
+  /// Positions of rows that a later row with the same key replaced, by file.
+  superseded: HashMap<String, Vec<u64>>,
+}
+
+impl<'a> RollingWriter<'a> {
+  /// `checkpoint` identifies the source position this run starts reading from. Every batch is written
+  /// in each of `formats`, to files that differ only in their extension.
+  pub fn new(sink: &'a ParquetSink, ns: &str, table: &str, checkpoint: &str, formats: Vec<FileFormat>, spec: PartitionSpec, opts: RollingOptions) -> Self {
+    Self {
+      sink,
+      provenance: Provenance {
+        namespace: ns.into(),
+        collection: table.into(),
+        run_id: run_id(ns, table, checkpoint),
+        checkpoint: Some(checkpoint.into()),
+        update_time_column: None,
+      },
+      formats,
+      spec,
+      opts,
+      order: RowOrder::Unsorted,
+      key_column: None,
+      open: HashMap::new(),
+      latest: HashMap::new(),
+      superseded: HashMap::new(),
+    }
+  }
+
+  /// Record the range of `column` in each file as its source update times.
+  pub fn with_update_time_column(mut self, column: &str) -> Self {
+    self.provenance.update_time_column = Some(column.into());
+    self
+  }
+
+  /// Sort the rows of every batch before writing it.
+  pub fn with_order(mut self, order: RowOrder) -> Self {
+    self.order = order;
+    self
+  }
+
+  /// Record the values of `column` in each Parquet file, so that commits can delete the rows they replace.
+  pub fn with_key_column(mut self, column: &str) -> Self {
+    self.key_column = Some(column.into());
+    self
+  }
+
+  pub fn run_id(&self) -> &str {
+    &self.provenance.run_id
+  }
+
+  /// Split `batch` by partition and append each part to that partition's open file in every format.
+  /// `seq` is the batch's position in the run; a file opened by this batch is named after it.
+  /// Returns any files that were closed as a result.
+  pub async fn write(&mut self, batch: &RecordBatch, seq: u64) -> anyhow::Result<Vec<DataFile>> {
+    if batch.num_rows() == 0 {
+      return Ok(Vec::new());
+    }
+
+    let mut closed = Vec::new();
+    for (partition, part) in self.spec.split(batch)? {
+      let part = self.order.apply(&part)?;
+      for format in &self.formats {
+        let key = (partition.path.clone(), format.encoding());
+        if !self.open.contains_key(&key) {
+          // At most one file per partition and encoding is opened by each batch, so the sequence number is unique
+          let file = format!("run_id={}/part-{:05}.{}", self.provenance.run_id, seq, format.encoding().extension());
+          let file = self.sink.create(&self.provenance, &partition, &file, part.schema(), format, &self.order).await?;
+          let keys = self.key_column.as_ref().filter(|_| format.encoding() == Encoding::Parquet).map(|_| Vec::new());
+          self.open.insert(key.clone(), OpenFile { file, opened: Instant::now(), keys, rows: 0 });
+        }
+        let open = self.open.get_mut(&key).expect("file was just opened");
+        open.file.write(&part, Some(seq)).await?;
+        if let (Some(keys), Some(column)) = (&mut open.keys, &self.key_column) {
+          let values = part.column_by_name(column)
+            .ok_or_else(|| anyhow::anyhow!("Upserts need a {} column, which the batch doesn't have", column))?;
+          let path = open.file.path().to_string();
+          for (row, value) in cast(values, &DataType::Utf8)?.as_string::<i32>().iter().enumerate() {
+            let Some(value) = value else { continue };
+            if let Some((file, position)) = self.latest.insert(value.to_string(), (path.clone(), open.rows + row as u64)) {
+              self.superseded.entry(file).or_default().push(position);
+            }
+            keys.push(value.to_string());
+          }
+        }
+        open.rows += part.num_rows() as u64;
+
+        if open.file.size() >= self.opts.target_file_size
+          && let Some(open) = self.open.remove(&key) {
+          closed.push(open.close().await?);
+        }
+      }
+    }
+    closed.extend(self.close_expired().await?);
+    Ok(closed)
+  }
+
+  /// Close every file that has been open longer than `max_file_age`.
+  pub async fn close_expired(&mut self) -> anyhow::Result<Vec<DataFile>> {
+    let expired: Vec<(String, Encoding)> = self.open.iter()
+      .filter(|(_, f)| f.opened.elapsed() >= self.opts.max_file_age)
+      .map(|(key, _)| key.clone())
+      .collect();
+    let mut closed = Vec::new();
+    for key in expired {
+      if let Some(open) = self.open.remove(&key) {
+        closed.push(open.close().await?);
+      }
+    }
+    Ok(closed)
+  }
+
+  /// Note in `files`, the files this run wrote, the positions of their rows that a later row with the same key
+  /// replaced. Files closed before the run ends may still have rows replaced, so call it once every file is closed.
+  pub fn mark_superseded(&self, files: &mut [DataFile]) {
+    for file in files {
+      if let Some(positions) = self.superseded.get(&file.path) {
+        file.superseded = positions.clone();
+        file.superseded.sort_unstable();
+      }
+    }
+  }
+
+  /// Close all open files, e.g. at the end of a run.
+  pub async fn close_all(&mut self) -> anyhow::Result<Vec<DataFile>> {
+    let mut closed = Vec::new();
+    for (_, open) in self.open.drain() {
+      closed.push(open.close().await?);
+    }
+    Ok(closed)
+  }
+}
+
+/// Short, stable ID for a run over `ns.table` starting at `checkpoint`.
+fn run_id(ns: &str, table: &str, checkpoint: &str) -> String {
+  let digest = Sha256::digest(format!("{}/{}@{}", ns, table, checkpoint));
+  hex::encode(&digest[..8])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Arc;
+  use arrow::datatypes::{Field, Schema};
+  use arrow_array::StringArray;
+  use object_store::RetryConfig;
+  use crate::retry::RetryPolicy;
+  use crate::sink::multipart::UploadOptions;
+  use crate::sink::parquet_writer::ParquetOptions;
+  use crate::store::{GcsOptions, Warehouse};
+
+  fn batch(rows: &[(&str, &str)]) -> RecordBatch {
+    let schema = Schema::new(vec![Field::new("id", DataType::Utf8, false), Field::new("variety", DataType::Utf8, true)]);
+    RecordBatch::try_new(Arc::new(schema), vec![
+      Arc::new(StringArray::from_iter_values(rows.iter().map(|(id, _)| *id))),
+      Arc::new(StringArray::from_iter_values(rows.iter().map(|(_, variety)| *variety))),
+    ]).unwrap()
+  }
+
+  #[tokio::test]
+  async fn notes_the_rows_a_later_row_of_the_same_key_replaces() {
+    let warehouse = Warehouse::open("memory://", RetryConfig::default(), &GcsOptions::default()).unwrap();
+    let sink = ParquetSink::new(&warehouse, UploadOptions::default(), RetryPolicy::default()).await.unwrap();
+    let opts = RollingOptions { target_file_size: usize::MAX, max_file_age: Duration::from_secs(3600) };
+    let spec = PartitionSpec::parse(&["variety".into()]).unwrap();
+    let formats = vec![FileFormat::Parquet(ParquetOptions::default()), FileFormat::Ndjson];
+    let mut writer = RollingWriter::new(&sink, "farm", "orders", "snapshot", formats, spec, opts).with_key_column("id");
+
+    // `a` changes twice in one batch, then moves to another partition; `b` changes in a later batch
+    let mut files = writer.write(&batch(&[("a", "v1"), ("b", "v1"), ("a", "v1")]), 0).await.unwrap();
+    files.extend(writer.write(&batch(&[("c", "v1"), ("b", "v1"), ("a", "v2")]), 1).await.unwrap());
+    files.extend(writer.close_all().await.unwrap());
+    writer.mark_superseded(&mut files);
+
+    let parquet = |variety: &str| files.iter()
+      .find(|f| f.encoding == Encoding::Parquet && f.partition == format!("variety={}", variety))
+      .unwrap();
+    assert_eq!(parquet("v1").keys.as_deref(), Some(&["a", "b", "a", "c", "b"].map(String::from)[..]));
+    assert_eq!(parquet("v1").superseded, vec![0, 1, 2]);
+    assert_eq!(parquet("v2").superseded, Vec::<u64>::new());
+    // Only Parquet files are upserted
+    assert!(files.iter().filter(|f| f.encoding != Encoding::Parquet).all(|f| f.keys.is_none() && f.superseded.is_empty()));
+  }
+}
@@ -0,0 +1,250 @@
+// src/sink/sort.rs
+// Row order within files. A sort order is applied to each batch before it is written, so min/max statistics
+// on the leading sort columns are narrow enough to prune on. Compaction can instead cluster rows along a
+// Z-order or Hilbert curve over several columns, which keeps the ranges of all of them fairly narrow rather
+// than just the first one's.
+
+use std::cmp::Ordering;
+use std::fmt;
+use arrow::array::make_comparator;
+use arrow::compute::{SortColumn, SortOptions, lexsort_to_indices, sort_to_indices, take_record_batch};
+use arrow::datatypes::{DataType, Schema};
+use arrow_array::{RecordBatch, UInt64Array};
+use parquet::format::SortingColumn;
+use serde::Deserialize;
+
+/// Most columns a curve can cluster on; each gets `64 / columns` bits of the curve key.
+const MAX_CURVE_COLUMNS: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortField {
+  pub column: String,
+  pub descending: bool,
+  pub nulls_first: bool,
+}
+
+impl SortField {
+  /// Parse `created_at`, `created_at desc` or `variety_id asc nulls last`. Nulls come first in ascending
+  /// order and last in descending order unless stated otherwise.
+  pub fn parse(spec: &str) -> anyhow::Result<Self> {
+    let words: Vec<&str> = spec.split_whitespace().collect();
+    let Some((column, rest)) = words.split_first() else {
+      return Err(anyhow::anyhow!("Empty sort field"));
+    };
+    let rest: Vec<String> = rest.iter().map(|w| w.to_ascii_lowercase()).collect();
+    let rest: Vec<&str> = rest.iter().map(String::as_str).collect();
+    let (descending, nulls) = match rest.as_slice() {
+      ["asc", nulls @ ..] => (false, nulls),
+      ["desc", nulls @ ..] => (true, nulls),
+      nulls => (false, nulls),
+    };
+    let nulls_first = match nulls {
+      [] => !descending,
+      ["nulls", "first"] => true,
+      ["nulls", "last"] => false,
+      _ => return Err(anyhow::anyhow!("Invalid sort field: {}", spec)),
+    };
+    Ok(Self { column: column.to_string(), descending, nulls_first })
+  }
+}
+
+impl fmt::Display for SortField {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{} {} NULLS {}", self.column,
+      if self.descending { "DESC" } else { "ASC" },
+      if self.nulls_first { "FIRST" } else { "LAST" })
+  }
+}
+
+/// Space-filling curve used to cluster rows on several columns.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Curve {
+  #[default]
+  Zorder,
+  /// Better locality than Z-order: neighbours on the curve are always neighbours in every column.
+  Hilbert,
+}
+
+impl std::str::FromStr for Curve {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_ascii_lowercase().as_str() {
+      "zorder" | "z-order" => Ok(Curve::Zorder),
+      "hilbert" => Ok(Curve::Hilbert),
+      _ => Err(format!("Unknown curve {}: expected zorder or hilbert", s)),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum RowOrder {
+  /// Rows stay in the order they arrive.
+  #[default]
+  Unsorted,
+  Sorted(Vec<SortField>),
+  Clustered { curve: Curve, columns: Vec<String> },
+}
+
+impl RowOrder {
+  /// A sort order such as `["variety_id", "created_at desc"]`; unsorted if empty.
+  pub fn sorted(fields: &[String]) -> anyhow::Result<Self> {
+    if fields.is_empty() {
+      return Ok(RowOrder::Unsorted);
+    }
+    Ok(RowOrder::Sorted(fields.iter().map(|f| SortField::parse(f)).collect::<anyhow::Result<_>>()?))
+  }
+
+  pub fn clustered(curve: Curve, columns: Vec<String>) -> anyhow::Result<Self> {
+    if columns.is_empty() || columns.len() > MAX_CURVE_COLUMNS {
+      return Err(anyhow::anyhow!("Clustering needs 1 to {} columns, got {}", MAX_CURVE_COLUMNS, columns.len()));
+    }
+    Ok(RowOrder::Clustered { curve, columns })
+  }
+
+  pub fn is_sorted(&self) -> bool {
+    matches!(self, RowOrder::Sorted(_))
+  }
+
+  /// `batch` with its rows in this order.
+  pub fn apply(&self, batch: &RecordBatch) -> anyhow::Result<RecordBatch> {
+    let column = |name: &str| batch.column_by_name(name).ok_or_else(|| anyhow::anyhow!("Sort column {} not found", name));
+    let indices = match self {
+      RowOrder::Unsorted => return Ok(batch.clone()),
+      _ if batch.num_rows() < 2 => return Ok(batch.clone()),
+      RowOrder::Sorted(fields) => {
+        let columns = fields.iter()
+          .map(|f| Ok(SortColumn {
+            values: column(&f.column)?.clone(),
+            options: Some(SortOptions { descending: f.descending, nulls_first: f.nulls_first }),
+          }))
+          .collect::<anyhow::Result<Vec<_>>>()?;
+        lexsort_to_indices(&columns, None)?
+      }
+      RowOrder::Clustered { curve, columns } => {
+        let columns = columns.iter().map(|c| column(c)).collect::<anyhow::Result<Vec<_>>>()?;
+        let keys = curve_keys(*curve, &columns.iter().map(|c| c.as_ref()).collect::<Vec<_>>(), batch.num_rows())?;
+        sort_to_indices(&keys, None, None)?
+      }
+    };
+    Ok(take_record_batch(batch, &indices)?)
+  }
+
+  /// The sort order as Parquet `sorting_columns`, up to the first column that isn't a top-level
+  /// primitive column of `schema`. `None` unless rows are sorted.
+  pub fn sorting_columns(&self, schema: &Schema) -> Option<Vec<SortingColumn>> {
+    let RowOrder::Sorted(fields) = self else { return None };
+    let mut columns = Vec::new();
+    for field in fields {
+      let Ok(index) = schema.index_of(&field.column) else { break };
+      if schema.field(index).data_type().is_nested() {
+        break;
+      }
+      // Parquet numbers leaf columns, so count the leaves of the columns before this one
+      let leaf: usize = schema.fields()[..index].iter().map(|f| leaves(f.data_type())).sum();
+      columns.push(SortingColumn { column_idx: leaf as i32, descending: field.descending, nulls_first: field.nulls_first });
+    }
+    (!columns.is_empty()).then_some(columns)
+  }
+}
+
+impl fmt::Display for RowOrder {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      RowOrder::Unsorted => write!(f, "unsorted"),
+      RowOrder::Sorted(fields) => {
+        write!(f, "{}", fields.iter().map(SortField::to_string).collect::<Vec<_>>().join(", "))
+      }
+      RowOrder::Clustered { curve, columns } => {
+        let name = match curve { Curve::Zorder => "zorder", Curve::Hilbert => "hilbert" };
+        write!(f, "{}({})", name, columns.join(", "))
+      }
+    }
+  }
+}
+
+fn leaves(data_type: &DataType) -> usize {
+  match data_type {
+    DataType::Struct(fields) => fields.iter().map(|f| leaves(f.data_type())).sum(),
+    DataType::List(item) | DataType::LargeList(item) | DataType::FixedSizeList(item, _) | DataType::Map(item, _) => leaves(item.data_type()),
+    _ => 1,
+  }
+}
+
+/// The position of every row on the curve. Each column is mapped to its rank among the rows, scaled to
+/// the bits it gets in the key, so columns of any sortable type and range weigh the same.
+fn curve_keys(curve: Curve, columns: &[&dyn arrow_array::Array], rows: usize) -> anyhow::Result<UInt64Array> {
+  let bits = (64 / columns.len()).min(32) as u32;
+  let max = (1u64 << bits) - 1;
+  let mut coords = vec![vec![0u32; columns.len()]; rows];
+  for (dim, column) in columns.iter().enumerate() {
+    let order = sort_to_indices(*column, None, None)?;
+    let compare = make_comparator(*column, *column, SortOptions::default())?;
+    // Equal values share the rank of the first of them
+    let mut rank = 0;
+    for pos in 0..order.len() {
+      let row = order.value(pos) as usize;
+      if pos > 0 && compare(order.value(pos - 1) as usize, row) != Ordering::Equal {
+        rank = pos;
+      }
+      coords[row][dim] = (rank as u64 * max / (rows as u64 - 1).max(1)) as u32;
+    }
+  }
+  Ok(coords.into_iter()
+    .map(|c| match curve {
+      Curve::Zorder => interleave(&c, bits),
+      Curve::Hilbert => hilbert(c, bits),
+    })
+    .collect())
+}
+
+/// Interleave the top `bits` bits of each coordinate, most significant first.
+fn interleave(coords: &[u32], bits: u32) -> u64 {
+  let mut key = 0u64;
+  for bit in (0..bits).rev() {
+    for c in coords {
+      key = (key << 1) | ((c >> bit) & 1) as u64;
+    }
+  }
+  key
+}
+
+/// Hilbert index of a point, using Skilling's transform of the coordinates ("Programming the Hilbert
+/// curve", 2004) followed by interleaving.
+fn hilbert(mut x: Vec<u32>, bits: u32) -> u64 {
+  let n = x.len();
+  let m = 1u32 << (bits - 1);
+
+  let mut q = m;
+  while q > 1 {
+    let p = q - 1;
+    for i in 0..n {
+      if x[i] & q != 0 {
+        x[0] ^= p;
+      } else {
+        let t = (x[0] ^ x[i]) & p;
+        x[0] ^= t;
+        x[i] ^= t;
+      }
+    }
+    q >>= 1;
+  }
+
+  // Gray encode
+  for i in 1..n {
+    x[i] ^= x[i - 1];
+  }
+  let mut t = 0;
+  let mut q = m;
+  while q > 1 {
+    if x[n - 1] & q != 0 {
+      t ^= q - 1;
+    }
+    q >>= 1;
+  }
+  for v in x.iter_mut() {
+    *v ^= t;
+  }
+  interleave(&x, bits)
+}
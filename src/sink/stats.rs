@@ -0,0 +1,110 @@
+// src/sink/stats.rs
+// Per-column min/max/null counts, accumulated batch by batch as a file is written.
+// Table formats that support file pruning (Delta, Iceberg) record these alongside each file.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use arrow::array::{Array, ArrayRef, AsArray};
+use arrow::compute::{max, max_string, min, min_string};
+use arrow::datatypes::{DataType, Date32Type, Float32Type, Float64Type, Int32Type, Int64Type, TimeUnit, TimestampMillisecondType};
+use arrow_array::RecordBatch;
+
+/// A column bound. Only types with a well-defined order across formats are tracked.
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum StatValue {
+  Int(i32),
+  Long(i64),
+  Float(f32),
+  Double(f64),
+  String(String),
+  /// Days since the epoch.
+  Date(i32),
+  /// Milliseconds since the epoch.
+  TimestampMs(i64),
+}
+
+impl fmt::Display for StatValue {
+  /// Dates and timestamps in ISO 8601, timestamps without a time zone.
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      StatValue::Int(v) => write!(f, "{}", v),
+      StatValue::Long(v) => write!(f, "{}", v),
+      StatValue::Float(v) => write!(f, "{}", v),
+      StatValue::Double(v) => write!(f, "{}", v),
+      StatValue::String(v) => write!(f, "{}", v),
+      StatValue::Date(days) => match chrono::DateTime::from_timestamp(*days as i64 * 86_400, 0) {
+        Some(date) => write!(f, "{}", date.date_naive()),
+        None => write!(f, "{}", days),
+      },
+      StatValue::TimestampMs(ms) => match chrono::DateTime::from_timestamp_millis(*ms) {
+        Some(ts) => write!(f, "{}", ts.format("%Y-%m-%dT%H:%M:%S%.3f")),
+        None => write!(f, "{}", ms),
+      },
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ColumnStats {
+  pub min: Option<StatValue>,
+  pub max: Option<StatValue>,
+  pub null_count: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FileStats {
+  pub rows: u64,
+  /// Top-level columns by name.
+  pub columns: BTreeMap<String, ColumnStats>,
+}
+
+impl FileStats {
+  pub fn update(&mut self, batch: &RecordBatch) {
+    self.rows += batch.num_rows() as u64;
+    for (field, col) in batch.schema().fields().iter().zip(batch.columns()) {
+      let stats = self.columns.entry(field.name().clone()).or_default();
+      stats.null_count += col.null_count() as u64;
+      let Some((lo, hi)) = bounds(col) else { continue };
+      if stats.min.as_ref().is_none_or(|m| lo < *m) {
+        stats.min = Some(lo);
+      }
+      if stats.max.as_ref().is_none_or(|m| hi > *m) {
+        stats.max = Some(hi);
+      }
+    }
+  }
+}
+
+fn bounds(col: &ArrayRef) -> Option<(StatValue, StatValue)> {
+  Some(match col.data_type() {
+    DataType::Int32 => {
+      let a = col.as_primitive::<Int32Type>();
+      (StatValue::Int(min(a)?), StatValue::Int(max(a)?))
+    }
+    DataType::Int64 => {
+      let a = col.as_primitive::<Int64Type>();
+      (StatValue::Long(min(a)?), StatValue::Long(max(a)?))
+    }
+    DataType::Float32 => {
+      let a = col.as_primitive::<Float32Type>();
+      (StatValue::Float(min(a)?), StatValue::Float(max(a)?))
+    }
+    DataType::Float64 => {
+      let a = col.as_primitive::<Float64Type>();
+      (StatValue::Double(min(a)?), StatValue::Double(max(a)?))
+    }
+    DataType::Utf8 => {
+      let a = col.as_string::<i32>();
+      (StatValue::String(min_string(a)?.to_string()), StatValue::String(max_string(a)?.to_string()))
+    }
+    DataType::Date32 => {
+      let a = col.as_primitive::<Date32Type>();
+      (StatValue::Date(min(a)?), StatValue::Date(max(a)?))
+    }
+    DataType::Timestamp(TimeUnit::Millisecond, _) => {
+      let a = col.as_primitive::<TimestampMillisecondType>();
+      (StatValue::TimestampMs(min(a)?), StatValue::TimestampMs(max(a)?))
+    }
+    _ => return None,
+  })
+}
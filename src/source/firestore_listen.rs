@@ -1,102 +1,146 @@
 // src/source/firestore_listen.rs
-use firestore::FirestoreDb;
+// Reads a collection through Firestore's listen API. Without a checkpoint the stream holds every document; with
+// one, only the documents changed or deleted since. Either way it ends at the first consistent point after the
+// target caught up, with a checkpoint whose resume token the next run passes back to pick up where this one ended.
+use std::collections::HashMap;
+use std::fmt;
+use firestore::{
+    FirestoreDb, FirestoreListenEvent, FirestoreListenSupport, FirestoreListenerTarget, FirestoreListenerTargetParams,
+    FirestoreListenerTargetResumeType, FirestoreListenerToken, FirestoreQueryParams, FirestoreTargetType,
+};
+use futures::StreamExt;
+use futures::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-pub async fn stream_orders(db: &FirestoreDb, col: &str) -> anyhow::Result<impl futures::Stream<Item=serde_json::Value>> {
-    println!("Querying Firestore collection: {}", col);
-    
-    // Use the proper firestore-rs API to get documents
-    let documents: Vec<serde_json::Value> = db
-        .fluent()
-        .select()
-        .from(col)
-        .obj()
-        .query()
-        .await?;
-    
-    println!("Found {} documents in collection '{}'", documents.len(), col);
-    
-    // Convert documents to a stream - they should already be proper JSON
-    let document_stream = futures::stream::iter(documents.into_iter());
-    
-    Ok(document_stream)
+/// Id of the one target each listen stream watches.
+const TARGET_ID: u32 = 1;
+
+// Values of Firestore's `TargetChange.TargetChangeType`
+const NO_CHANGE: i32 = 0;
+const REMOVE: i32 = 2;
+const CURRENT: i32 = 3;
+const RESET: i32 = 4;
+
+/// A consistent position in a collection: the changes before it have all been read, and none after it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Firestore's listen resume token, hex-encoded.
+    pub resume_token: String,
+    /// Read time of the position in milliseconds since the epoch: changes committed until then have been read.
+    pub watermark_ms: i64,
+}
+
+impl fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match chrono::DateTime::from_timestamp_millis(self.watermark_ms) {
+            Some(time) => write!(f, "{}", time.to_rfc3339()),
+            None => write!(f, "{} ms", self.watermark_ms),
+        }
+    }
 }
 
-pub async fn stream_varieties(db: &FirestoreDb, col: &str) -> anyhow::Result<impl futures::Stream<Item=serde_json::Value>> {
-    println!("Querying Firestore collection: {}", col);
-    
-    let documents: Vec<serde_json::Value> = db
-        .fluent()
-        .select()
-        .from(col)
-        .obj()
-        .query()
-        .await?;
-    
-    println!("Found {} documents in collection '{}'", documents.len(), col);
-    let document_stream = futures::stream::iter(documents.into_iter());
-    Ok(document_stream)
+/// One change read from a collection.
+#[derive(Debug, Clone)]
+pub enum Change {
+    /// A document as it is now, new or updated.
+    Doc(Value),
+    /// The id of a deleted document.
+    Deleted(String),
+    /// The position after every change so far. Always the last item of a stream.
+    Checkpoint(Checkpoint),
 }
 
-pub async fn stream_variety_inventory(db: &FirestoreDb, col: &str) -> anyhow::Result<impl futures::Stream<Item=serde_json::Value>> {
-    println!("Querying Firestore collection: {}", col);
-    
-    let documents: Vec<serde_json::Value> = db
-        .fluent()
-        .select()
-        .from(col)
-        .obj()
-        .query()
-        .await?;
-    
-    println!("Found {} documents in collection '{}'", documents.len(), col);
-    let document_stream = futures::stream::iter(documents.into_iter());
-    Ok(document_stream)
+pub async fn stream_orders(db: &FirestoreDb, col: &str, from: Option<&Checkpoint>) -> anyhow::Result<BoxStream<'static, anyhow::Result<Change>>> {
+    listen(db, col, from).await
 }
 
-pub async fn stream_materials(db: &FirestoreDb, col: &str) -> anyhow::Result<impl futures::Stream<Item=serde_json::Value>> {
-    println!("Querying Firestore collection: {}", col);
-    
-    let documents: Vec<serde_json::Value> = db
-        .fluent()
-        .select()
-        .from(col)
-        .obj()
-        .query()
-        .await?;
-    
-    println!("Found {} documents in collection '{}'", documents.len(), col);
-    let document_stream = futures::stream::iter(documents.into_iter());
-    Ok(document_stream)
+pub async fn stream_varieties(db: &FirestoreDb, col: &str, from: Option<&Checkpoint>) -> anyhow::Result<BoxStream<'static, anyhow::Result<Change>>> {
+    listen(db, col, from).await
 }
 
-pub async fn stream_batches(db: &FirestoreDb, col: &str) -> anyhow::Result<impl futures::Stream<Item=serde_json::Value>> {
-    println!("Querying Firestore collection: {}", col);
-    
-    let documents: Vec<serde_json::Value> = db
-        .fluent()
-        .select()
-        .from(col)
-        .obj()
-        .query()
-        .await?;
-    
-    println!("Found {} documents in collection '{}'", documents.len(), col);
-    let document_stream = futures::stream::iter(documents.into_iter());
-    Ok(document_stream)
+pub async fn stream_variety_inventory(db: &FirestoreDb, col: &str, from: Option<&Checkpoint>) -> anyhow::Result<BoxStream<'static, anyhow::Result<Change>>> {
+    listen(db, col, from).await
+}
+
+pub async fn stream_materials(db: &FirestoreDb, col: &str, from: Option<&Checkpoint>) -> anyhow::Result<BoxStream<'static, anyhow::Result<Change>>> {
+    listen(db, col, from).await
+}
+
+pub async fn stream_batches(db: &FirestoreDb, col: &str, from: Option<&Checkpoint>) -> anyhow::Result<BoxStream<'static, anyhow::Result<Change>>> {
+    listen(db, col, from).await
+}
+
+pub async fn stream_inventory_transactions(db: &FirestoreDb, col: &str, from: Option<&Checkpoint>) -> anyhow::Result<BoxStream<'static, anyhow::Result<Change>>> {
+    listen(db, col, from).await
+}
+
+/// Listen to `col` from `from`, or from the start, until the first consistent point after the target caught up.
+async fn listen(db: &FirestoreDb, col: &str, from: Option<&Checkpoint>) -> anyhow::Result<BoxStream<'static, anyhow::Result<Change>>> {
+    let query = FirestoreTargetType::Query(FirestoreQueryParams::new(col.into()));
+    let mut target = FirestoreListenerTargetParams::new(FirestoreListenerTarget::new(TARGET_ID), query, HashMap::new());
+    match from {
+        Some(checkpoint) => {
+            println!("Listening to changes of Firestore collection {} since {}", col, checkpoint);
+            let token = FirestoreListenerToken::new(hex::decode(&checkpoint.resume_token)?);
+            target = target.with_resume_type(FirestoreListenerTargetResumeType::Token(token));
+        }
+        None => println!("Listening to all documents of Firestore collection: {}", col),
+    }
+    let responses = db.listen_doc_changes(vec![target]).await?;
+
+    let col = col.to_string();
+    // The state is the response stream and whether the target caught up, until the stream ends
+    let changes = futures::stream::unfold(Some((responses, false)), move |state| {
+        let col = col.clone();
+        async move {
+            let (mut responses, mut current) = state?;
+            loop {
+                let response = match responses.next().await {
+                    Some(Ok(response)) => response,
+                    Some(Err(e)) => return Some((Err(e.into()), None)),
+                    None => return Some((Err(anyhow::anyhow!("Firestore closed the listen stream of {} before it caught up", col)), None)),
+                };
+                let target = TARGET_ID as i32;
+                let change = match response.response_type {
+                    Some(FirestoreListenEvent::DocumentChange(change)) if change.removed_target_ids.contains(&target) => {
+                        change.document.map(|doc| Ok(Change::Deleted(doc_id(&doc.name))))
+                    }
+                    Some(FirestoreListenEvent::DocumentChange(change)) if change.target_ids.contains(&target) => {
+                        change.document.map(|doc| FirestoreDb::deserialize_doc_to::<Value>(&doc).map(Change::Doc).map_err(Into::into))
+                    }
+                    Some(FirestoreListenEvent::DocumentDelete(delete)) => Some(Ok(Change::Deleted(doc_id(&delete.document)))),
+                    Some(FirestoreListenEvent::DocumentRemove(remove)) => Some(Ok(Change::Deleted(doc_id(&remove.document)))),
+                    Some(FirestoreListenEvent::TargetChange(change)) => match change.target_change_type {
+                        CURRENT => {
+                            current = true;
+                            None
+                        }
+                        // A change of no target in particular marks a consistent point of all of them
+                        NO_CHANGE if current && change.target_ids.is_empty() && !change.resume_token.is_empty() => {
+                            let watermark_ms = change.read_time.map_or(0, |t| t.seconds * 1000 + i64::from(t.nanos) / 1_000_000);
+                            let checkpoint = Checkpoint { resume_token: hex::encode(&change.resume_token), watermark_ms };
+                            println!("Read the changes of Firestore collection {} until {}", col, checkpoint);
+                            return Some((Ok(Change::Checkpoint(checkpoint)), None));
+                        }
+                        REMOVE | RESET => {
+                            let cause = change.cause.map(|c| c.message).unwrap_or_default();
+                            return Some((Err(anyhow::anyhow!("Firestore dropped the listen target of {}: {}", col, cause)), None));
+                        }
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                if let Some(change) = change {
+                    return Some((change, Some((responses, current))));
+                }
+            }
+        }
+    });
+    Ok(changes.boxed())
 }
 
-pub async fn stream_inventory_transactions(db: &FirestoreDb, col: &str) -> anyhow::Result<impl futures::Stream<Item=serde_json::Value>> {
-    println!("Querying Firestore collection: {}", col);
-    
-    let documents: Vec<serde_json::Value> = db
-        .fluent()
-        .select()
-        .from(col)
-        .obj()
-        .query()
-        .await?;
-    
-    println!("Found {} documents in collection '{}'", documents.len(), col);
-    let document_stream = futures::stream::iter(documents.into_iter());
-    Ok(document_stream)
+/// The id of a document, the last segment of its full name.
+fn doc_id(name: &str) -> String {
+    name.rsplit('/').next().unwrap_or(name).to_string()
 }
@@ -0,0 +1,139 @@
+// src/store.rs
+// Resolves the warehouse URL to an object store, so the same pipeline runs against GCS, S3, Azure,
+// a local directory or memory. One store is built per process and shared by the sink, commit and reader.
+
+use std::sync::Arc;
+use object_store::{ObjectStore, ObjectStoreScheme, RetryConfig, path::Path};
+use object_store::aws::AmazonS3Builder;
+use object_store::azure::MicrosoftAzureBuilder;
+use object_store::gcp::{GoogleCloudStorageBuilder, GoogleConfigKey};
+use object_store::local::LocalFileSystem;
+use object_store::memory::InMemory;
+use url::Url;
+use crate::error::Error;
+
+/// GCS settings for a specific service account or a local emulator such as fake-gcs-server.
+#[derive(Debug, Clone, Default)]
+pub struct GcsOptions {
+  /// Service-account JSON key file. Without one, application default credentials are used.
+  pub service_account_path: Option<String>,
+  /// Base URL of the storage API, e.g. `http://localhost:4443`.
+  pub endpoint: Option<String>,
+  /// Allow plain `http://` endpoints.
+  pub allow_http: bool,
+}
+
+impl GcsOptions {
+  /// The settings as `object_store` config keys, which Polars understands as well.
+  fn config(&self) -> Result<Vec<(String, String)>, Error> {
+    let mut config = Vec::new();
+    match (&self.service_account_path, &self.endpoint) {
+      (Some(path), None) => config.push(("google_service_account".into(), path.clone())),
+      (path, Some(endpoint)) => {
+        // A custom endpoint can only be given as `gcs_base_url` in the service-account key
+        let mut key = match path {
+          Some(path) => {
+            let raw = std::fs::read_to_string(path)
+              .map_err(|e| Error::config(format!("Failed to read service account {}: {}", path, e)))?;
+            serde_json::from_str(&raw)
+              .map_err(|e| Error::config(format!("Invalid service account {}: {}", path, e)))?
+          }
+          // Emulators accept any request, so skip OAuth entirely
+          None => serde_json::json!({ "client_email": "", "private_key": "", "private_key_id": "", "disable_oauth": true }),
+        };
+        key.as_object_mut()
+          .ok_or_else(|| Error::config("Service account key must be a JSON object"))?
+          .insert("gcs_base_url".into(), endpoint.trim_end_matches('/').into());
+        config.push(("google_service_account_key".into(), key.to_string()));
+      }
+      (None, None) => {}
+    }
+    if self.allow_http {
+      config.push(("allow_http".into(), "true".into()));
+    }
+    Ok(config)
+  }
+}
+
+#[derive(Clone)]
+pub struct Warehouse {
+  pub store: Arc<dyn ObjectStore>,
+  scheme: ObjectStoreScheme,
+  /// Store settings beyond the environment, as `object_store` config keys.
+  options: Vec<(String, String)>,
+  /// Everything before the warehouse path, e.g. `gs://my-lake/` or `file:///`.
+  root: String,
+  /// Location of the warehouse inside the store, e.g. `warehouse`.
+  prefix: Path,
+}
+
+impl Warehouse {
+  /// Open the store for a URL such as `gs://my-lake/warehouse`, `s3://my-lake/warehouse`,
+  /// `az://container/warehouse`, `file:///var/lib/lake` or `memory://`.
+  /// Cloud credentials are taken from the environment (`GOOGLE_*`, `AWS_*`, `AZURE_*`),
+  /// except where `gcs` overrides them.
+  pub fn open(url: &str, retry: RetryConfig, gcs: &GcsOptions) -> anyhow::Result<Self> {
+    let parsed = Url::parse(url).map_err(|e| Error::config(format!("Invalid warehouse URL {}: {}", url, e)))?;
+    let (scheme, prefix) = ObjectStoreScheme::parse(&parsed)
+      .map_err(|e| Error::config(format!("Unsupported warehouse URL {}: {}", url, e)))?;
+
+    let options = match scheme {
+      ObjectStoreScheme::GoogleCloudStorage => gcs.config()?,
+      _ => Vec::new(),
+    };
+
+    let store: Arc<dyn ObjectStore> = match scheme {
+      ObjectStoreScheme::Local => Arc::new(LocalFileSystem::new()),
+      ObjectStoreScheme::Memory => Arc::new(InMemory::new()),
+      ObjectStoreScheme::GoogleCloudStorage => {
+        let mut builder = GoogleCloudStorageBuilder::from_env().with_url(url).with_retry(retry);
+        for (key, value) in &options {
+          builder = builder.with_config(key.parse::<GoogleConfigKey>().map_err(Error::config)?, value);
+        }
+        Arc::new(builder.build().map_err(Error::config)?)
+      }
+      ObjectStoreScheme::AmazonS3 => Arc::new(AmazonS3Builder::from_env().with_url(url).with_retry(retry).build().map_err(Error::config)?),
+      ObjectStoreScheme::MicrosoftAzure => Arc::new(MicrosoftAzureBuilder::from_env().with_url(url).with_retry(retry).build().map_err(Error::config)?),
+      _ => return Err(Error::config(format!("Unsupported warehouse URL {}: expected gs://, s3://, az://, file:// or memory://", url)).into()),
+    };
+
+    // Drop the warehouse path from the URL, keeping the bucket or container
+    let segments: Vec<&str> = parsed.path().split('/').filter(|s| !s.is_empty()).collect();
+    let keep = segments.len().saturating_sub(prefix.parts().count());
+    let mut root = parsed.clone();
+    root.set_query(None);
+    root.set_fragment(None);
+    root.set_path(&format!("/{}", segments[..keep].join("/")));
+    let mut root = root.to_string();
+    if !root.ends_with('/') {
+      root.push('/');
+    }
+
+    Ok(Self { store, scheme, options, root, prefix })
+  }
+
+  /// Location of a table inside the store: `<prefix>/<ns>/<table>`.
+  pub fn table_path(&self, ns: &str, table: &str) -> Path {
+    self.prefix.child(ns).child(table)
+  }
+
+  /// Full URL of an object, e.g. `gs://my-lake/warehouse/farm/orders/data/...`.
+  pub fn url(&self, path: &str) -> String {
+    format!("{}{}", self.root, path)
+  }
+
+  /// Path of an object given its full URL, or `None` if the URL is outside this store.
+  pub fn path_of(&self, url: &str) -> Option<Path> {
+    Path::parse(url.strip_prefix(&self.root)?).ok()
+  }
+
+  /// The store settings that aren't taken from the environment, for Polars' `CloudOptions`.
+  pub fn options(&self) -> &[(String, String)] {
+    &self.options
+  }
+
+  /// Whether Polars can scan objects in this store by URL. An in-memory store only exists in this process.
+  pub fn polars_can_scan(&self) -> bool {
+    !matches!(self.scheme, ObjectStoreScheme::Memory)
+  }
+}
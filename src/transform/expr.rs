@@ -0,0 +1,262 @@
+// src/transform/expr.rs
+// Per-table transform stage that runs Polars expressions or a SQL statement over each batch.
+// Batches cross between arrow-rs and Polars as in-memory Arrow IPC files.
+
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::sync::Arc;
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::ipc::reader::FileReader;
+use arrow::ipc::writer::FileWriter;
+use arrow_array::{Array, ArrayRef, RecordBatch};
+use polars::prelude::*;
+use polars::sql::{SQLContext, sql_expr};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransformConfig {
+    /// Derived columns, evaluated in order as SQL expressions, e.g. `quantity_in_kg * price_in_euro`.
+    pub with_columns: Vec<DerivedColumn>,
+    /// Row filter as a SQL predicate, applied after `with_columns`.
+    pub filter: Option<String>,
+    /// A full SQL statement over the batch, registered as the table `batch`.
+    /// Mutually exclusive with `with_columns` and `filter`.
+    pub sql: Option<String>,
+    /// Expected output columns. When absent, only the input columns are checked.
+    pub output_schema: Option<Vec<OutputColumn>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DerivedColumn {
+    pub name: String,
+    pub expr: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputColumn {
+    pub name: String,
+    /// Arrow type name, e.g. `Float64`, `Utf8` or `Timestamp(Millisecond, None)`.
+    #[serde(rename = "type")]
+    pub data_type: String,
+    #[serde(default = "default_nullable")]
+    pub nullable: bool,
+}
+
+fn default_nullable() -> bool { true }
+
+enum Plan {
+    /// Expressions of the derived columns, with their names, and the row filter.
+    Exprs { with_columns: Vec<Expr>, names: HashSet<String>, filter: Option<Expr> },
+    Sql(String),
+}
+
+pub struct ExprTransformer {
+    plan: Option<Plan>,
+    output_schema: Option<Vec<Field>>,
+}
+
+impl ExprTransformer {
+    /// Parses all expressions up front so a bad config fails at startup, not on the first batch.
+    pub fn new(cfg: Option<&TransformConfig>) -> anyhow::Result<Self> {
+        let Some(cfg) = cfg else {
+            return Ok(Self { plan: None, output_schema: None });
+        };
+
+        let plan = match &cfg.sql {
+            Some(_) if !cfg.with_columns.is_empty() || cfg.filter.is_some() => {
+                return Err(anyhow::anyhow!("Transform `sql` can't be combined with `with_columns` or `filter`"));
+            }
+            Some(sql) => Some(Plan::Sql(sql.clone())),
+            None if cfg.with_columns.is_empty() && cfg.filter.is_none() => None,
+            None => {
+                let with_columns = cfg.with_columns.iter()
+                    .map(|c| sql_expr(&c.expr)
+                        .map(|e| e.alias(c.name.as_str()))
+                        .map_err(|e| anyhow::anyhow!("Invalid expression for column {}: {}", c.name, e)))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                let filter = cfg.filter.as_deref()
+                    .map(|f| sql_expr(f).map_err(|e| anyhow::anyhow!("Invalid filter `{}`: {}", f, e)))
+                    .transpose()?;
+                let names = cfg.with_columns.iter().map(|c| c.name.clone()).collect();
+                Some(Plan::Exprs { with_columns, names, filter })
+            }
+        };
+
+        let output_schema = cfg.output_schema.as_ref()
+            .map(|cols| cols.iter()
+                .map(|c| {
+                    let data_type: DataType = c.data_type.parse()
+                        .map_err(|e| anyhow::anyhow!("Invalid type for output column {}: {}", c.name, e))?;
+                    Ok(Field::new(&c.name, data_type, c.nullable))
+                })
+                .collect::<anyhow::Result<Vec<_>>>())
+            .transpose()?;
+
+        Ok(Self { plan, output_schema })
+    }
+
+    pub fn apply(&self, batch: &RecordBatch) -> anyhow::Result<RecordBatch> {
+        let Some(plan) = &self.plan else {
+            return Ok(batch.clone());
+        };
+
+        let lf = to_polars(batch)?.lazy();
+        let lf = match plan {
+            Plan::Exprs { with_columns, filter, .. } => {
+                let mut lf = lf;
+                for expr in with_columns {
+                    lf = lf.with_column(expr.clone());
+                }
+                match filter {
+                    Some(f) => lf.filter(f.clone()),
+                    None => lf,
+                }
+            }
+            Plan::Sql(sql) => {
+                let mut ctx = SQLContext::new();
+                ctx.register("batch", lf);
+                ctx.execute(sql)?
+            }
+        };
+        let out = from_polars(lf.collect()?)?;
+        self.check_schema(batch.schema().as_ref(), out)
+    }
+
+    /// Cast Polars' output back to the declared types and verify the schema.
+    /// Input columns passed through keep their original type and nullability; derived and rewritten
+    /// columns follow `output_schema`, or else keep the type they were given.
+    fn check_schema(&self, input: &Schema, out: RecordBatch) -> anyhow::Result<RecordBatch> {
+        let expected: Vec<Field> = match &self.output_schema {
+            Some(fields) => {
+                let actual: Vec<&str> = out.schema_ref().fields().iter().map(|f| f.name().as_str()).collect();
+                let wanted: Vec<&str> = fields.iter().map(|f| f.name().as_str()).collect();
+                if actual != wanted {
+                    return Err(anyhow::anyhow!("Transform produced columns {:?}, expected {:?}", actual, wanted));
+                }
+                fields.clone()
+            }
+            None => out.schema_ref().fields().iter()
+                .map(|f| match input.field_with_name(f.name()) {
+                    Ok(orig) if !self.rewrites(orig, f) => orig.clone(),
+                    _ => Field::new(f.name(), normalize(f.data_type()), true),
+                })
+                .collect(),
+        };
+
+        let mut cols: Vec<ArrayRef> = Vec::with_capacity(expected.len());
+        for (field, col) in expected.iter().zip(out.columns()) {
+            let col = if col.data_type() == field.data_type() { col.clone() } else {
+                cast(col, field.data_type())
+                    .map_err(|e| anyhow::anyhow!("Transform output column {} is not {}: {}", field.name(), field.data_type(), e))?
+            };
+            if !field.is_nullable() && col.null_count() > 0 {
+                return Err(anyhow::anyhow!("Transform produced nulls in non-nullable column {}", field.name()));
+            }
+            cols.push(col);
+        }
+
+        let schema = Schema::new_with_metadata(expected, input.metadata().clone());
+        RecordBatch::try_new(Arc::new(schema), cols).map_err(Into::into)
+    }
+
+    /// Whether the transform rewrote the input column `input` as `output`: `with_columns` names it, or a SQL
+    /// statement gave it another kind of type than Polars' own for the input's.
+    fn rewrites(&self, input: &Field, output: &Field) -> bool {
+        match &self.plan {
+            Some(Plan::Exprs { names, .. }) => names.contains(input.name()),
+            _ => std::mem::discriminant(&normalize(input.data_type())) != std::mem::discriminant(&normalize(output.data_type())),
+        }
+    }
+}
+
+/// Polars writes large/view types; map them back to the plain types used in `schema.rs`.
+fn normalize(data_type: &DataType) -> DataType {
+    match data_type {
+        DataType::LargeUtf8 | DataType::Utf8View => DataType::Utf8,
+        DataType::LargeBinary | DataType::BinaryView => DataType::Binary,
+        DataType::LargeList(item) | DataType::ListView(item) | DataType::LargeListView(item) => {
+            DataType::List(Arc::new(item.as_ref().clone().with_data_type(normalize(item.data_type()))))
+        }
+        other => other.clone(),
+    }
+}
+
+fn to_polars(batch: &RecordBatch) -> anyhow::Result<DataFrame> {
+    let mut buf = Vec::new();
+    {
+        let mut writer = FileWriter::try_new(&mut buf, batch.schema_ref())?;
+        writer.write(batch)?;
+        writer.finish()?;
+    }
+    Ok(IpcReader::new(Cursor::new(buf)).finish()?)
+}
+
+fn from_polars(mut df: DataFrame) -> anyhow::Result<RecordBatch> {
+    let mut buf = Vec::new();
+    IpcWriter::new(&mut buf)
+        .with_compat_level(CompatLevel::oldest())
+        .finish(&mut df)?;
+    let reader = FileReader::try_new(Cursor::new(buf), None)?;
+    let schema = reader.schema();
+    let batches = reader.collect::<Result<Vec<_>, _>>()?;
+    arrow::compute::concat_batches(&schema, &batches).map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{Float64Array, StringArray};
+
+    fn batch() -> RecordBatch {
+        let schema = Schema::new(vec![
+            Field::new("id", DataType::Utf8, false),
+            Field::new("amount", DataType::Float64, false),
+        ]);
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from(vec!["a", "b"])),
+            Arc::new(Float64Array::from(vec![1.5, 2.0])),
+        ];
+        RecordBatch::try_new(Arc::new(schema), columns).unwrap()
+    }
+
+    fn transformer(cfg: serde_json::Value) -> ExprTransformer {
+        ExprTransformer::new(Some(&serde_json::from_value(cfg).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn rewritten_columns_keep_their_new_type() {
+        let out = transformer(serde_json::json!({
+            "with_columns": [{ "name": "amount", "expr": "CAST(amount AS VARCHAR)" }],
+        })).apply(&batch()).unwrap();
+
+        let schema = out.schema();
+        assert_eq!(schema.field_with_name("amount").unwrap().data_type(), &DataType::Utf8);
+        assert_eq!(schema.field_with_name("id").unwrap(), batch().schema().field_with_name("id").unwrap());
+    }
+
+    #[test]
+    fn passed_through_columns_keep_their_input_field() {
+        let out = transformer(serde_json::json!({
+            "with_columns": [{ "name": "double", "expr": "amount * 2" }],
+        })).apply(&batch()).unwrap();
+
+        let schema = out.schema();
+        assert_eq!(schema.field_with_name("amount").unwrap(), batch().schema().field_with_name("amount").unwrap());
+        assert_eq!(schema.field_with_name("double").unwrap().data_type(), &DataType::Float64);
+    }
+
+    #[test]
+    fn sql_rewrites_are_recognized_by_type() {
+        let out = transformer(serde_json::json!({
+            "sql": "SELECT id, CAST(amount AS VARCHAR) AS amount FROM batch",
+        })).apply(&batch()).unwrap();
+
+        let schema = out.schema();
+        assert_eq!(schema.field_with_name("amount").unwrap().data_type(), &DataType::Utf8);
+        assert_eq!(schema.field_with_name("id").unwrap(), batch().schema().field_with_name("id").unwrap());
+    }
+}
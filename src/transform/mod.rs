@@ -0,0 +1,2 @@
+pub mod pii;
+pub mod expr;
@@ -0,0 +1,131 @@
+// src/transform/pii.rs
+// Column-level PII transforms. The same rules are applied to record batches before
+// they reach the sink and to raw documents before they are logged.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow_array::{Array, ArrayRef, RecordBatch, StringArray};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ColumnTransform {
+    /// Remove the column entirely.
+    Drop,
+    /// Replace the value with a hex HMAC-SHA256 token keyed by `PII_HMAC_KEY`.
+    /// Equal inputs map to equal tokens, so the column can still be joined on.
+    Hash,
+    /// Replace every character except the last `keep_last` with `*`.
+    Mask {
+        #[serde(default)]
+        keep_last: usize,
+    },
+    /// Keep at most the first `max_chars` characters.
+    Truncate { max_chars: usize },
+}
+
+pub struct PiiTransformer {
+    columns: HashMap<String, ColumnTransform>,
+    key: Option<Vec<u8>>,
+}
+
+impl PiiTransformer {
+    pub fn new(columns: HashMap<String, ColumnTransform>, key: Option<&str>) -> anyhow::Result<Self> {
+        let needs_key = columns.values().any(|t| matches!(t, ColumnTransform::Hash));
+        if needs_key && key.is_none_or(|k| k.is_empty()) {
+            return Err(anyhow::anyhow!("PII_HMAC_KEY must be set to use `hash` column transforms"));
+        }
+        Ok(Self { columns, key: key.map(|k| k.as_bytes().to_vec()) })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    /// Apply the configured transforms to `batch`. Columns named in the config must exist.
+    pub fn apply(&self, batch: &RecordBatch) -> anyhow::Result<RecordBatch> {
+        if self.is_empty() {
+            return Ok(batch.clone());
+        }
+        let schema = batch.schema();
+        for name in self.columns.keys() {
+            if schema.column_with_name(name).is_none() {
+                return Err(anyhow::anyhow!("PII transform configured for unknown column: {}", name));
+            }
+        }
+
+        let mut fields = Vec::with_capacity(schema.fields().len());
+        let mut cols: Vec<ArrayRef> = Vec::with_capacity(schema.fields().len());
+        for (field, col) in schema.fields().iter().zip(batch.columns()) {
+            match self.columns.get(field.name()) {
+                None => {
+                    fields.push(field.as_ref().clone());
+                    cols.push(col.clone());
+                }
+                Some(ColumnTransform::Drop) => {}
+                Some(transform) => {
+                    let strings = cast(col, &DataType::Utf8)?;
+                    let strings = strings.as_any().downcast_ref::<StringArray>()
+                        .ok_or_else(|| anyhow::anyhow!("Column {} could not be read as text", field.name()))?;
+                    let out: StringArray = strings.iter()
+                        .map(|v| v.map(|s| self.transform_str(transform, s)))
+                        .collect();
+                    fields.push(Field::new(field.name(), DataType::Utf8, field.is_nullable())
+                        .with_metadata(field.metadata().clone()));
+                    cols.push(Arc::new(out));
+                }
+            }
+        }
+
+        let schema = Arc::new(Schema::new_with_metadata(fields, schema.metadata().clone()));
+        RecordBatch::try_new(schema, cols).map_err(Into::into)
+    }
+
+    /// Redact a source document the same way `apply` redacts its column, for logging.
+    pub fn redact_doc(&self, doc: &serde_json::Value) -> serde_json::Value {
+        let mut doc = doc.clone();
+        if let Some(obj) = doc.as_object_mut() {
+            for (name, transform) in &self.columns {
+                match transform {
+                    ColumnTransform::Drop => { obj.remove(name); }
+                    _ => {
+                        if let Some(value) = obj.get_mut(name) {
+                            let text = match &*value {
+                                serde_json::Value::Null => continue,
+                                serde_json::Value::String(s) => s.clone(),
+                                other => other.to_string(),
+                            };
+                            *value = serde_json::Value::String(self.transform_str(transform, &text));
+                        }
+                    }
+                }
+            }
+        }
+        doc
+    }
+
+    fn transform_str(&self, transform: &ColumnTransform, value: &str) -> String {
+        match transform {
+            ColumnTransform::Drop => String::new(),
+            ColumnTransform::Hash => {
+                // The constructor guarantees a key whenever a hash transform is configured
+                let key = self.key.as_deref().unwrap_or_default();
+                let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+                mac.update(value.as_bytes());
+                hex::encode(mac.finalize().into_bytes())
+            }
+            ColumnTransform::Mask { keep_last } => {
+                let len = value.chars().count();
+                let keep = (*keep_last).min(len);
+                value.chars().enumerate()
+                    .map(|(i, c)| if i < len - keep { '*' } else { c })
+                    .collect()
+            }
+            ColumnTransform::Truncate { max_chars } => value.chars().take(*max_chars).collect(),
+        }
+    }
+}